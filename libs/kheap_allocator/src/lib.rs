@@ -13,11 +13,27 @@ use linked_list_allocator::Heap;
 extern crate spin;
 use spin::{Once, Mutex};
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 
 pub static KHEAP_ALLOCATOR: Mutex<Heap> = Mutex::new(Heap::empty());
 
 static INIT: Once<()> = Once::new();
 
+/// bytes handed out and not freed yet, and the highest it has been.
+/// freed blocks go back to the heap's free list, so a workload that frees
+/// what it allocates keeps the watermark flat.
+static USED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+pub fn used() -> usize {
+    USED.load(Ordering::SeqCst)
+}
+
+pub fn peak() -> usize {
+    PEAK.load(Ordering::SeqCst)
+}
+
 pub fn init(start: usize, size: usize) {
     INIT.call_once(|| {
         unsafe {
@@ -30,11 +46,24 @@ pub struct Allocator;
 
 unsafe impl<'a> Alloc for &'a Allocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        KHEAP_ALLOCATOR.lock().allocate_first_fit(layout)
+        let size = layout.size();
+        let mut heap = KHEAP_ALLOCATOR.lock();
+        let ret = heap.allocate_first_fit(layout);
+        if ret.is_ok() {
+            // counters only change under heap lock
+            let used = USED.fetch_add(size, Ordering::SeqCst) + size;
+            if used > PEAK.load(Ordering::SeqCst) {
+                PEAK.store(used, Ordering::SeqCst);
+            }
+        }
+        ret
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        KHEAP_ALLOCATOR.lock().deallocate(ptr, layout)
+        let size = layout.size();
+        let mut heap = KHEAP_ALLOCATOR.lock();
+        heap.deallocate(ptr, layout);
+        USED.fetch_sub(size, Ordering::SeqCst);
     }
 }
 
@@ -0,0 +1,248 @@
+//! just enough acpi to learn cpu topology: find RSDP by scanning bios areas,
+//! walk RSDT/XSDT to MADT and collect local apic entries.
+//! tables are mapped read-only into kernel mapping on demand and stay there.
+
+use collections::Vec;
+use spin::Once;
+use core::mem::size_of;
+
+use ::kern::memory::{MM, KERNEL_MAPPING, PAGE_SIZE};
+use ::kern::memory::paging::{Page, NO_EXECUTE};
+use ::kern::memory::frame::Frame;
+use ::kern::arch::cpu;
+use ::kern::console as con;
+use con::LogLevel::*;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // since revision 2
+    length: u32,
+    xsdt_address: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+// enabled, or can be brought online later
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// logical cpu as described by MADT, index in cpus() is the cpu id
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+static CPUS: Once<Vec<CpuInfo>> = Once::new();
+
+/// make physical range readable through kernel mapping, in every address
+/// space as kernel slots are shared. None if range is empty or out of
+/// kernel mapping, firmware tables may point anywhere.
+fn map_phys(start: usize, len: usize) -> Option<usize> {
+    let kernel_base = KERNEL_MAPPING.KernelMap.start;
+    match start.checked_add(len) {
+        Some(end) if len > 0 && end <= ::kern::memory::region_size(&KERNEL_MAPPING.KernelMap) => {},
+        _ => return None
+    }
+    let mut mm = MM.try().expect("acpi: memory is not initialized").lock();
+
+    let first = start / PAGE_SIZE;
+    let last = (start + len - 1) / PAGE_SIZE;
+    for n in first..last+1 {
+        let paddr = n * PAGE_SIZE;
+        if mm.activePML4Table.translate(paddr + kernel_base).is_none() {
+            let page = Page::from_vaddress(paddr + kernel_base);
+            mm.activePML4Table.map_to(page, Frame::from_paddress(paddr), NO_EXECUTE);
+        }
+    }
+    Some(start + kernel_base)
+}
+
+fn checksum_ok(vaddr: usize, len: usize) -> bool {
+    let bytes = unsafe { ::core::slice::from_raw_parts(vaddr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// search "RSD PTR " on 16 bytes boundary in [start, end)
+fn scan_rsdp(start: usize, end: usize) -> Option<&'static Rsdp> {
+    let base = match map_phys(start, end - start) {
+        Some(base) => base,
+        None => return None
+    };
+    for off in (0..end - start).filter(|off| off % 16 == 0) {
+        let vaddr = base + off;
+        let sig = unsafe { &*(vaddr as *const [u8; 8]) };
+        // only the acpi 1.0 part is covered by checksum
+        if sig == b"RSD PTR " && checksum_ok(vaddr, 20) {
+            return Some(unsafe { &*(vaddr as *const Rsdp) });
+        }
+    }
+    None
+}
+
+fn find_rsdp() -> Option<&'static Rsdp> {
+    // first KB of EBDA, whose segment is stored at 0x40e, then bios rom
+    let ebda = match map_phys(0x40e, 2) {
+        Some(vaddr) => unsafe { *(vaddr as *const u16) as usize } << 4,
+        None => 0
+    };
+    let from_ebda = if ebda != 0 { scan_rsdp(ebda, ebda + 1024) } else { None };
+    from_ebda.or_else(|| scan_rsdp(0xe0000, 0x100000))
+}
+
+/// map whole table at paddr, None if it is out of reach, shorter than its
+/// header or checksum is wrong
+fn map_table(paddr: usize) -> Option<&'static SdtHeader> {
+    let vaddr = match map_phys(paddr, size_of::<SdtHeader>()) {
+        Some(vaddr) => vaddr,
+        None => return None
+    };
+    let len = unsafe { (*(vaddr as *const SdtHeader)).length as usize };
+    if len < size_of::<SdtHeader>() || map_phys(paddr, len).is_none() {
+        printk!(Warn, "acpi: bad table length {} at {:#x}\n\r", len, paddr);
+        return None;
+    }
+    if checksum_ok(vaddr, len) {
+        Some(unsafe { &*(vaddr as *const SdtHeader) })
+    } else {
+        None
+    }
+}
+
+fn find_table(rsdp: &Rsdp, signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    // xsdt holds 64-bit pointers, rsdt 32-bit ones
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (map_table(rsdp.xsdt_address as usize), 8)
+    } else {
+        (map_table(rsdp.rsdt_address as usize), 4)
+    };
+    let root = match root {
+        Some(root) => root,
+        None => return None
+    };
+
+    // map_table made sure length covers the header
+    let entries = root as *const _ as usize + size_of::<SdtHeader>();
+    let n = (root.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..n {
+        let paddr = unsafe {
+            match entry_size {
+                8 => *((entries + i * 8) as *const u64) as usize,
+                _ => *((entries + i * 4) as *const u32) as usize,
+            }
+        };
+        match map_table(paddr) {
+            Some(sdt) if &sdt.signature == signature => return Some(sdt),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_madt(madt: &SdtHeader) -> Vec<CpuInfo> {
+    let mut cpus = Vec::new();
+    let base = madt as *const _ as usize;
+    // local apic address and flags precede the entries
+    let mut off = size_of::<SdtHeader>() + 8;
+
+    while off + 2 <= madt.length as usize {
+        let entry = base + off;
+        let (kind, len) = unsafe { (*(entry as *const u8), *((entry + 1) as *const u8) as usize) };
+        if len < 2 {
+            printk!(Warn, "acpi: malformed madt entry at {}\n\r", off);
+            break;
+        }
+
+        let info = unsafe {
+            match kind {
+                MADT_LOCAL_APIC => Some((*((entry + 3) as *const u8) as u32, *((entry + 4) as *const u32))),
+                MADT_LOCAL_X2APIC => Some((*((entry + 4) as *const u32), *((entry + 8) as *const u32))),
+                _ => None
+            }
+        };
+        if let Some((apic_id, flags)) = info {
+            if flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0 {
+                cpus.push(CpuInfo { apic_id: apic_id, enabled: flags & LAPIC_ENABLED != 0 });
+            }
+        }
+        off += len;
+    }
+    cpus
+}
+
+/// detect cpus, fall back to a single cpu if acpi is not there.
+/// need memory and kernel heap.
+pub fn init() {
+    let cpus = CPUS.call_once(|| {
+        let madt = find_rsdp().and_then(|rsdp| find_table(rsdp, b"APIC"));
+        match madt.map(parse_madt) {
+            Some(ref cpus) if cpus.len() > 0 => cpus.clone(),
+            _ => {
+                printk!(Warn, "acpi: no madt found, assume single cpu\n\r");
+                vec![CpuInfo { apic_id: 0, enabled: true }]
+            }
+        }
+    });
+
+    cpu::set_num_cpus(cpus.len());
+    printk!(Info, "{} CPUs detected (using 1)\n\r", cpus.len());
+    for (id, c) in cpus.iter().enumerate() {
+        printk!(Debug, "  cpu {}: apic id {}{}\n\r", id, c.apic_id, if c.enabled { "" } else { " (offline)" });
+    }
+}
+
+/// cpus found by init(), indexed by cpu id
+pub fn cpus() -> &'static [CpuInfo] {
+    CPUS.try().map(|v| v.as_slice()).unwrap_or(&[])
+}
+
+pub fn test_acpi_bounds() {
+    use ::kern::memory::frame;
+
+    let limit = ::kern::memory::region_size(&KERNEL_MAPPING.KernelMap);
+    test_assert!(map_phys(0x1000, 0).is_none());
+    test_assert!(map_phys(!0, 2).is_none());
+    test_assert!(map_phys(limit - 1, 2).is_none());
+    test_assert!(map_phys(0x40e, 2).is_some());
+
+    // a table too short for its own header, kernel mapping of it is read
+    // only so it is written through a temporary page
+    let f = frame::alloc_frame().expect("no more mem");
+    {
+        use ::kern::memory::paging::ActivePML4Table;
+        use ::kern::memory::inactive::TemporaryPage;
+
+        let mut active = ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        let vaddr = temp_page.map(f, &mut active);
+        unsafe {
+            ::core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE);
+            (*(vaddr as *mut SdtHeader)).length = 4;
+        }
+        temp_page.unmap(&mut active);
+    }
+    test_assert!(map_table(f.start_address()).is_none());
+    frame::dealloc_frame(f);
+}
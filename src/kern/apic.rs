@@ -0,0 +1,223 @@
+//! local apic of boot cpu, used as one-shot timer for short precise delays
+//! and deadlines independent of the 100Hz PIT. its counter runs at bus
+//! clock, which is learned by counting against PIT ticks at init.
+//! without a lapic everything here reports unavailable and callers keep
+//! using PIT ticks.
+
+use spin::Once;
+use x86_64::registers::msr;
+
+use ::kern::memory::{MM, KERNEL_MAPPING};
+use ::kern::memory::paging::{Page, WRITABLE, NO_EXECUTE, DISABLE_CACHE, WRITE_THROUGH};
+use ::kern::memory::frame::Frame;
+use ::kern::interrupts::timer;
+use ::kern::arch::cpu;
+use ::kern::util::cpu_relax;
+use ::kern::console as con;
+use con::LogLevel::*;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+// cpuid(1).edx
+const CPUID_APIC: u32 = 1 << 9;
+
+// register offsets
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INIT: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const SPURIOUS_VECTOR: u32 = 0xff;
+const LVT_MASKED: u32 = 1 << 16;
+// divide bus clock by 16
+const DIVIDE_BY_16: u32 = 0x3;
+
+/// PIT ticks counted against during calibration
+const CALIBRATE_TICKS: usize = 5;
+
+struct Lapic {
+    base: usize,
+    // timer counts per PIT tick
+    counts_per_tick: u64,
+}
+
+static LAPIC: Once<Lapic> = Once::new();
+
+unsafe fn read(base: usize, reg: usize) -> u32 {
+    ::core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+unsafe fn write(base: usize, reg: usize, val: u32) {
+    ::core::ptr::write_volatile((base + reg) as *mut u32, val);
+}
+
+/// map register page uncached into kernel mapping. kernel slots are shared
+/// by all address spaces (see paging::create_address_space), so the page is
+/// reachable under every cr3, whichever one is loaded now.
+fn map_registers(paddr: usize) -> usize {
+    let vaddr = paddr + KERNEL_MAPPING.KernelMap.start;
+    let mut mm = MM.try().expect("apic: memory is not initialized").lock();
+    if mm.activePML4Table.translate(vaddr).is_none() {
+        mm.activePML4Table.map_to(Page::from_vaddress(vaddr), Frame::from_paddress(paddr),
+                                  WRITABLE | NO_EXECUTE | DISABLE_CACHE | WRITE_THROUGH);
+    }
+    vaddr
+}
+
+/// count timer down from its max while PIT ticks pass, needs PIT running
+/// and interrupts on
+fn calibrate(base: usize) -> u64 {
+    unsafe {
+        write(base, REG_TIMER_DIVIDE, DIVIDE_BY_16);
+        write(base, REG_LVT_TIMER, LVT_MASKED);
+    }
+
+    // start right at a tick edge
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        cpu_relax();
+    }
+    let start = timer::ticks();
+    unsafe { write(base, REG_TIMER_INIT, !0); }
+    while timer::ticks() < start + CALIBRATE_TICKS {
+        cpu_relax();
+    }
+    let left = unsafe { read(base, REG_TIMER_CURRENT) };
+    unsafe { write(base, REG_TIMER_INIT, 0); }
+
+    (!0 - left) as u64 / CALIBRATE_TICKS as u64
+}
+
+/// enable lapic of boot cpu and calibrate its timer. needs memory, and PIT
+/// ticking with interrupts on.
+pub fn init() {
+    let (_, _, _, edx) = cpu::cpuid(1);
+    if edx & CPUID_APIC == 0 {
+        printk!(Warn, "apic: no local apic, timers stay on PIT\n\r");
+        return;
+    }
+
+    let lapic = LAPIC.call_once(|| {
+        let msr_val = unsafe { msr::rdmsr(IA32_APIC_BASE) };
+        unsafe { msr::wrmsr(IA32_APIC_BASE, msr_val | APIC_BASE_ENABLE); }
+        let base = map_registers((msr_val & APIC_BASE_MASK) as usize);
+
+        // pic stays in charge of legacy irqs, lapic only serves its timer
+        unsafe { write(base, REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR); }
+        Lapic { base: base, counts_per_tick: calibrate(base) }
+    });
+
+    printk!(Info, "apic: timer at {} counts per {}ms\n\r", lapic.counts_per_tick, 1000 / timer::HZ);
+}
+
+pub fn available() -> bool {
+    LAPIC.try().is_some()
+}
+
+/// timer counts covering at least ns nanoseconds, None without lapic or if
+/// it does not fit the 32-bit counter
+pub fn ns_to_count(ns: u64) -> Option<u32> {
+    LAPIC.try().and_then(|lapic| {
+        let tick_ns = 1_000_000_000 / timer::HZ as u64;
+        let count = ns.checked_mul(lapic.counts_per_tick).map(|c| (c + tick_ns - 1) / tick_ns);
+        match count {
+            Some(c) if c <= !0u32 as u64 => Some(::core::cmp::max(1, c as u32)),
+            _ => None
+        }
+    })
+}
+
+/// fire interrupt vector once after count timer counts, replacing any timer
+/// armed before. handler of vector must call eoi(). return false if there is
+/// no lapic, caller should fall back to PIT ticks.
+pub fn timer_oneshot(count: u32, vector: u8) -> bool {
+    match LAPIC.try() {
+        Some(lapic) => {
+            assert!(vector >= 32, "apic: vector {} is reserved for exceptions", vector);
+            unsafe {
+                write(lapic.base, REG_TIMER_DIVIDE, DIVIDE_BY_16);
+                // one-shot is mode 0
+                write(lapic.base, REG_LVT_TIMER, vector as u32);
+                write(lapic.base, REG_TIMER_INIT, count);
+            }
+            true
+        },
+        None => false
+    }
+}
+
+/// stop an armed timer
+pub fn timer_cancel() {
+    if let Some(lapic) = LAPIC.try() {
+        unsafe {
+            write(lapic.base, REG_LVT_TIMER, LVT_MASKED);
+            write(lapic.base, REG_TIMER_INIT, 0);
+        }
+    }
+}
+
+/// acknowledge interrupt raised by lapic
+pub fn eoi() {
+    if let Some(lapic) = LAPIC.try() {
+        cpu::mfence();
+        unsafe { write(lapic.base, REG_EOI, 0); }
+    }
+}
+
+/// spin for at least ns nanoseconds. counts the lapic timer down masked
+/// when there is one, which cancels an armed timer_oneshot; otherwise waits
+/// whole PIT ticks (needs interrupts on).
+pub fn delay_ns(ns: u64) {
+    match (LAPIC.try(), ns_to_count(ns)) {
+        (Some(lapic), Some(count)) => unsafe {
+            write(lapic.base, REG_TIMER_DIVIDE, DIVIDE_BY_16);
+            write(lapic.base, REG_LVT_TIMER, LVT_MASKED);
+            write(lapic.base, REG_TIMER_INIT, count);
+            while read(lapic.base, REG_TIMER_CURRENT) != 0 {
+                cpu_relax();
+            }
+        },
+        _ => {
+            let ms = (ns + 999_999) / 1_000_000;
+            let until = timer::ticks() + timer::ms_to_ticks(ms as usize) + 1;
+            while timer::ticks() < until {
+                cpu_relax();
+            }
+        }
+    }
+}
+
+/// kernel threads run under whatever cr3 is loaded, lapic must work under
+/// a space created after it was mapped as well as one created before
+pub fn test_lapic_other_space() {
+    use ::kern::memory::paging::{self, ActivePML4Table};
+
+    let lapic = match LAPIC.try() {
+        Some(lapic) => lapic,
+        None => {
+            printk!(Warn, "apic: no local apic, skip test\n\r");
+            return;
+        }
+    };
+
+    let space = {
+        let mm = MM.try().unwrap().lock();
+        paging::create_address_space(mm.mbinfo)
+    };
+    let oflags = unsafe { cpu::push_flags() };
+    let old = paging::switch(space);
+    let mapped = ActivePML4Table::new().translate(lapic.base).is_some();
+    if mapped {
+        delay_ns(10_000);
+        test_assert_eq!(unsafe { read(lapic.base, REG_TIMER_CURRENT) }, 0);
+    }
+    let space = paging::switch(old);
+    unsafe { cpu::pop_flags(oflags); }
+
+    test_assert!(mapped);
+    space.destroy(&mut ActivePML4Table::new());
+}
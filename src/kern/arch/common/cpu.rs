@@ -1,12 +1,13 @@
 use kern::memory::paging::{VirtualAddress, PhysicalAddress};
 use x86_64::registers::msr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /// Invalidate the given address in the TLB using the `invlpg` instruction.
 pub fn tlb_flush(addr: VirtualAddress) {
     unsafe { asm!("invlpg ($0)"::"r" (addr) : "memory") };
 }
 
-/// Invalidate the TLB completely by reloading the CR3 register.
+/// Invalidate the TLB by reloading the CR3 register, GLOBAL pages stay.
 pub fn tlb_flush_all() {
     unsafe { cr3_set(cr3()) }
 }
@@ -46,6 +47,33 @@ pub unsafe fn cr0_set(val: usize) {
     asm!("mov $0, %cr0" :: "r" (val) : "memory");
 }
 
+/// Read CR4
+pub fn cr4() -> usize {
+    let ret: usize;
+    unsafe { asm!("mov %cr4, $0" : "=r" (ret)) };
+    ret
+}
+
+pub unsafe fn cr4_set(val: usize) {
+    asm!("mov $0, %cr4" :: "r" (val) : "memory");
+}
+
+pub const CR4_PAGE_GLOBAL: usize = 1 << 7;
+
+/// enable PGE, GLOBAL pages then survive cr3 reloads
+pub fn enable_global_pages() {
+    unsafe { cr4_set(cr4() | CR4_PAGE_GLOBAL) };
+}
+
+/// Invalidate the TLB completely including GLOBAL pages by toggling CR4.PGE.
+pub fn tlb_flush_global() {
+    unsafe {
+        let cr4 = cr4();
+        cr4_set(cr4 & !CR4_PAGE_GLOBAL);
+        cr4_set(cr4);
+    }
+}
+
 /// enable NXE bit, so page flag NO_EXECUTE is applicable
 pub fn enable_nxe_bit() {
     let nxe_bit = 1 << 11;
@@ -69,6 +97,93 @@ pub fn enable_write_protect_bit() {
     unsafe { cr0_set(cr0() | CR0_WRITE_PROTECT) };
 }
 
+/// execute cpuid for leaf, returns (eax, ebx, ecx, edx)
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    // rbx is reserved by llvm, save it manually
+    unsafe {
+        asm!("xchg %rbx, %rsi
+              cpuid
+              xchg %rbx, %rsi"
+             : "={eax}"(eax), "={esi}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(0)
+             : : "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+// memory ordering and cache control for drivers.
+// x86 keeps ordinary (write-back) stores in order, and uncached MMIO is
+// strongly ordered, so most of what a driver needs is keeping the compiler
+// from reordering (all helpers below clobber "memory"). the fences matter
+// for write-combining memory (framebuffer), non-temporal stores and DMA:
+//   - fill DMA descriptors, sfence (or mfence), then ring the doorbell/notify
+//   - read DMA status written by device, lfence before reading the payload
+//   - APIC/IOAPIC registers are uncached MMIO, a compiler barrier is enough,
+//     except EOI/ICR writes which must follow prior stores: use mfence
+//   - device reads memory not snooped by caches: clflush the lines first
+
+/// serialize all loads and stores issued before it
+#[inline(always)]
+pub fn mfence() {
+    unsafe { asm!("mfence" ::: "memory" : "volatile"); }
+}
+
+/// loads before it complete before loads after it
+#[inline(always)]
+pub fn lfence() {
+    unsafe { asm!("lfence" ::: "memory" : "volatile"); }
+}
+
+/// stores before it are globally visible before stores after it
+#[inline(always)]
+pub fn sfence() {
+    unsafe { asm!("sfence" ::: "memory" : "volatile"); }
+}
+
+/// write back and invalidate all caches, very slow
+pub unsafe fn wbinvd() {
+    asm!("wbinvd" ::: "memory" : "volatile");
+}
+
+/// write back and invalidate the cache line containing addr
+#[inline(always)]
+pub unsafe fn clflush(addr: VirtualAddress) {
+    asm!("clflush ($0)" :: "r"(addr) : "memory" : "volatile");
+}
+
+/// read time stamp counter
+pub fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) ::: "volatile"); }
+    (hi as u64) << 32 | lo as u64
+}
+
+/// cpu vendor string, e.g "GenuineIntel"
+pub fn vendor() -> [u8; 12] {
+    let (_, ebx, ecx, edx) = cpuid(0);
+    let mut s = [0u8; 12];
+    for i in 0..4 {
+        s[i] = (ebx >> (i * 8)) as u8;
+        s[i+4] = (edx >> (i * 8)) as u8;
+        s[i+8] = (ecx >> (i * 8)) as u8;
+    }
+    s
+}
+
+/// logical cpus present in system, only cpu 0 runs for now
+static NUM_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn num_cpus() -> usize {
+    NUM_CPUS.load(Ordering::SeqCst)
+}
+
+/// set by acpi once topology is known
+pub fn set_num_cpus(n: usize) {
+    assert!(n > 0, "at least the boot cpu exists");
+    NUM_CPUS.store(n, Ordering::SeqCst);
+}
+
 pub use x86_64::registers::flags;
 pub unsafe fn push_flags() -> flags::Flags {
     use x86_64::instructions::interrupts;
@@ -81,3 +196,101 @@ pub unsafe fn pop_flags(old: flags::Flags) {
     use x86_64::instructions::interrupts;
     flags::set_flags(old);
 }
+
+/// port of qemu's isa-debug-exit device (-device isa-debug-exit,iobase=0xf4,iosize=4)
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// leave qemu with status (code << 1) | 1, just halt on real hardware.
+/// `make test` maps the status back to code.
+pub fn qemu_exit(code: u32) -> ! {
+    unsafe {
+        super::io::outl(QEMU_EXIT_PORT, code);
+        loop {
+            asm!("cli; hlt" :::: "volatile");
+        }
+    }
+}
+
+/// number of hardware watchpoints (DR0-DR3)
+pub const NR_WATCHPOINTS: usize = 4;
+/// DR6 bits telling which of DR0-DR3 triggered
+pub const DR6_HIT_MASK: usize = 0xf;
+
+unsafe fn dr_get(n: usize) -> usize {
+    let ret: usize;
+    match n {
+        0 => asm!("mov %dr0, $0" : "=r"(ret)),
+        1 => asm!("mov %dr1, $0" : "=r"(ret)),
+        2 => asm!("mov %dr2, $0" : "=r"(ret)),
+        3 => asm!("mov %dr3, $0" : "=r"(ret)),
+        6 => asm!("mov %dr6, $0" : "=r"(ret)),
+        7 => asm!("mov %dr7, $0" : "=r"(ret)),
+        _ => panic!("no debug register dr{}", n)
+    }
+    ret
+}
+
+unsafe fn dr_set(n: usize, val: usize) {
+    match n {
+        0 => asm!("mov $0, %dr0" :: "r"(val) :: "volatile"),
+        1 => asm!("mov $0, %dr1" :: "r"(val) :: "volatile"),
+        2 => asm!("mov $0, %dr2" :: "r"(val) :: "volatile"),
+        3 => asm!("mov $0, %dr3" :: "r"(val) :: "volatile"),
+        6 => asm!("mov $0, %dr6" :: "r"(val) :: "volatile"),
+        7 => asm!("mov $0, %dr7" :: "r"(val) :: "volatile"),
+        _ => panic!("no debug register dr{}", n)
+    }
+}
+
+/// address watched by slot
+pub fn watchpoint(slot: usize) -> VirtualAddress {
+    assert!(slot < NR_WATCHPOINTS);
+    unsafe { dr_get(slot) }
+}
+
+/// read DR6 and reset it, the cpu never clears it by itself
+pub fn take_dr6() -> usize {
+    unsafe {
+        let dr6 = dr_get(6);
+        dr_set(6, 0);
+        dr6
+    }
+}
+
+/// trap (#DB) after an instruction writes (or reads/writes unless on_write)
+/// any of len bytes at addr. len is 1, 2, 4 or 8 and addr must be aligned
+/// to it. return the slot used, None if all four are busy.
+pub fn set_watchpoint(addr: VirtualAddress, len: usize, on_write: bool) -> Option<usize> {
+    let len_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        _ => panic!("watchpoint: bad length {}", len)
+    };
+    assert!(addr % len == 0, "watchpoint: {:#x} is not aligned to {}", addr, len);
+    let rw_bits = if on_write { 0b01 } else { 0b11 };
+
+    unsafe {
+        let dr7 = dr_get(7);
+        let slot = match (0..NR_WATCHPOINTS).find(|&i| dr7 & (1 << (i * 2)) == 0) {
+            Some(slot) => slot,
+            None => return None
+        };
+
+        let shift = 16 + slot * 4;
+        let dr7 = (dr7 & !(0xf << shift)) | ((rw_bits | len_bits << 2) << shift) | (1 << (slot * 2));
+        dr_set(slot, addr);
+        dr_set(7, dr7);
+        Some(slot)
+    }
+}
+
+pub fn clear_watchpoint(slot: usize) {
+    assert!(slot < NR_WATCHPOINTS);
+    unsafe {
+        let dr7 = dr_get(7) & !(1 << (slot * 2)) & !(0xf << (16 + slot * 4));
+        dr_set(7, dr7);
+        dr_set(slot, 0);
+    }
+}
@@ -1,16 +1,23 @@
 extern syscall_dispatch
 global syscall_entry
+global syscall_return
+
+; TLSSegment field, see tls.rs
+%define TLS_USER_RSP 0
 
 section .text
 bits 64
 syscall_entry:
 	swapgs
-	mov [gs:0], rsp ; save user rsp
-	mov rsp, [gs:8] ; load kern rsp
+	mov [gs:0], rsp ; per cpu scratch, see percpu.rs for layout
+	mov rsp, [gs:8] ; load kern rsp of current task
+	push qword [gs:0] ; scratch is reused by next syscall, keep user rsp on stack
 
 	push rbp
 	push r11
 	push rcx
+	mov rcx, [gs:0]
+	mov [rsp + 32 + TLS_USER_RSP], rcx ; TLSSegment sits above the 4 pushes
 
 	; args: rdi, rsi, rdx, r8, r9, r10
 	; rax is syscall number, and return value
@@ -22,12 +29,29 @@ syscall_entry:
     push rsi
 	push rdi
 
+	; callee saved regs of user, below args so dispatch sees the same
+	; layout. fork copies the whole frame, see SyscallFrame in task.rs
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+
 	sti
 	mov rdi, rax
-	mov rsi, rsp
+	lea rsi, [rsp + 40]
 	mov rcx, syscall_dispatch
 	call rcx
 	cli
+	mov [rsp + 40 + 48], rax ; return value goes into saved rax
+
+	; forked child starts here with its copy of the frame
+syscall_return:
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
 
 	pop rdi
     pop rsi
@@ -41,7 +65,7 @@ syscall_entry:
 	pop r11
 	pop rbp
 
-	mov rsp, [gs:0]
+	pop rsp ; user rsp
 	swapgs
 
 	db 0x48
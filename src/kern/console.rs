@@ -1,9 +1,9 @@
 use core::ptr;
-use core::mem::size_of_val;
 use core::ptr::{Unique, write_volatile};
 use core::fmt::{Write, Result};
 use core::intrinsics::transmute;
-use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock, Once};
 
 use ::kern::arch::port::{Port};
 use ::kern::driver::video::terminal::FramebufferDriver;
@@ -13,6 +13,9 @@ const CRTC_ADDR_REG: u16 = 0x3D4;
 const CRTC_ADDR_DATA: u16 = 0x3D5;
 const CURSOR_LOCATION_HIGH_IND: u8 = 0x0E;
 const CURSOR_LOCATION_LOW_IND: u8 = 0x0F;
+const CURSOR_START_IND: u8 = 0x0A;
+// in cursor start register
+const CURSOR_DISABLE: u8 = 1 << 5;
 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
@@ -62,6 +65,7 @@ pub struct Char {
 
 pub trait TerminalDriver {
     fn update_cursor(&mut self, row: usize, col: usize);
+    fn show_cursor(&mut self, visible: bool);
     fn draw_byte(&mut self, cursor: usize, byte: Char);
     fn get_max_cols(&self) -> usize; 
     fn get_max_rows(&self) -> usize;
@@ -79,9 +83,21 @@ struct Buffer {
     data: [Char; CONSOLE_WIDTH * CONSOLE_HEIGHT]
 }
 
-// text only terminal
+const BLANK: Char = Char {
+    ascii: b' ',
+    attr: Attribute::new(Color::White, Color::Black)
+};
+
+/// text only terminal. all drawing goes to its own buffer, which is mirrored
+/// into VGA memory while the terminal is visible, so hidden ttys keep their
+/// screen and cursor until switch() brings them up.
 pub struct ConsoleDriver {
     buf: Unique<Buffer>,
+    shadow: Buffer,
+    visible: bool,
+    // offset of cursor and whether it is shown, restored when made visible
+    cursor: usize,
+    cursor_shown: bool,
     crtc_reg: Port<u8>,
     crtc_data: Port<u8>,
 }
@@ -89,42 +105,42 @@ pub struct ConsoleDriver {
 impl TerminalDriver for ConsoleDriver {
     fn scroll_up(&mut self, cursor: usize) {
         let (cy, _) = (cursor / CONSOLE_WIDTH, cursor % CONSOLE_WIDTH);
-        let blank_line = [Char {
-            ascii: b' ',
-            attr: Attribute::new(Color::White, Color::Black)
-        }; CONSOLE_WIDTH];
         let off = CONSOLE_WIDTH * (CONSOLE_HEIGHT - 1);
 
-
         if cy < CONSOLE_HEIGHT - 1 {
             return;
         }
 
         unsafe {
-            let data = (&mut self.buf.as_mut().data).as_mut_ptr();
+            let data = self.shadow.data.as_mut_ptr();
             ptr::copy(data.offset(CONSOLE_WIDTH as isize), data, off);
-            ptr::copy_nonoverlapping(&blank_line, data.offset(off as isize) as *mut _, 1);
         }
+        for c in self.shadow.data[off..].iter_mut() {
+            *c = BLANK;
+        }
+        self.sync();
     }
 
     fn clear(&mut self) {
-        let blank_line = [Char {
-            ascii: b' ',
-            attr: Attribute::new(Color::White, Color::Black)
-        }; CONSOLE_WIDTH];
-
-        unsafe {
-            let data = (&mut self.buf.as_mut().data).as_mut_ptr();
-            for off in 0..CONSOLE_HEIGHT {
-                ptr::copy_nonoverlapping((&blank_line).as_ptr(),
-                    data.offset((off * CONSOLE_WIDTH) as isize), size_of_val(&blank_line));
-            }
+        for c in self.shadow.data.iter_mut() {
+            *c = BLANK;
         }
+        self.sync();
     }
 
     fn update_cursor(&mut self, row: usize, col: usize) {
         let v = row * CONSOLE_WIDTH + col;
-        self.set_phy_cursor(v);
+        self.cursor = v;
+        if self.visible {
+            self.set_phy_cursor(v);
+        }
+    }
+
+    fn show_cursor(&mut self, visible: bool) {
+        self.cursor_shown = visible;
+        if self.visible {
+            self.set_phy_cursor_shown(visible);
+        }
     }
 
     fn get_max_cols(&self) -> usize {
@@ -143,23 +159,48 @@ impl TerminalDriver for ConsoleDriver {
     }
 
     fn draw_byte(&mut self, cursor: usize, byte: Char) {
-        unsafe {
-            let p = &mut self.buf.as_mut().data[cursor];
-            write_volatile(p, byte);
+        self.shadow.data[cursor] = byte;
+        if self.visible {
+            unsafe {
+                let p = &mut self.buf.as_mut().data[cursor];
+                write_volatile(p, byte);
+            }
         }
     }
 }
 
 impl ConsoleDriver {
-    const fn new() -> ConsoleDriver {
+    const fn new(visible: bool) -> ConsoleDriver {
         use ::kern::memory::KERNEL_MAPPING;
         ConsoleDriver {
             buf: unsafe { Unique::new_unchecked((KERNEL_MAPPING.KernelMap.start + 0xb8000) as *mut _) },
+            shadow: Buffer { data: [BLANK; CONSOLE_WIDTH * CONSOLE_HEIGHT] },
+            visible: visible,
+            cursor: 0,
+            cursor_shown: true,
             crtc_reg: Port::new(CRTC_ADDR_REG),
             crtc_data: Port::new(CRTC_ADDR_DATA)
         }
     }
 
+    /// copy whole buffer to VGA memory if visible
+    fn sync(&mut self) {
+        if self.visible {
+            unsafe { ptr::copy_nonoverlapping(&self.shadow, self.buf.as_ptr(), 1); }
+        }
+    }
+
+    /// show or hide this terminal, a shown one takes over screen and cursor
+    fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+        if visible {
+            self.sync();
+            let (cursor, shown) = (self.cursor, self.cursor_shown);
+            self.set_phy_cursor(cursor);
+            self.set_phy_cursor_shown(shown);
+        }
+    }
+
     fn set_phy_cursor(&mut self, cursor: usize) {
         let linear = cursor as u16;
         self.crtc_reg.write(CURSOR_LOCATION_HIGH_IND);
@@ -167,17 +208,65 @@ impl ConsoleDriver {
         self.crtc_reg.write(CURSOR_LOCATION_LOW_IND);
         self.crtc_data.write(linear as u8);
     }
-    
+
+    fn set_phy_cursor_shown(&mut self, shown: bool) {
+        self.crtc_reg.write(CURSOR_START_IND);
+        let v = self.crtc_data.read();
+        self.crtc_data.write(if shown { v & !CURSOR_DISABLE } else { v | CURSOR_DISABLE });
+    }
 }
 
 
 
+const DEFAULT_ATTR: Attribute = Attribute::new(Color::Green, Color::Black);
+
+/// max parameters of a CSI sequence, more are dropped
+const ESC_MAX_PARAMS: usize = 4;
+
+/// progress through an escape sequence. only SGR (ESC [ n;... m) has an
+/// effect, everything else is swallowed up to its final byte.
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// got ESC
+    Esc,
+    /// got ESC [, params so far and index of the one being read
+    Csi([u16; ESC_MAX_PARAMS], usize),
+}
+
+/// ANSI color order to VGA colors
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray
+];
+
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White
+];
+
+/// attribute after applying SGR parameter n to attr
+fn apply_sgr(attr: Attribute, n: u16) -> Attribute {
+    let (fg, bg) = (attr.fg(), attr.bg());
+    match n {
+        0 => DEFAULT_ATTR,
+        30...37 => Attribute::new(ANSI_COLORS[n as usize - 30], bg),
+        39 => Attribute::new(DEFAULT_ATTR.fg(), bg),
+        40...47 => Attribute::new(fg, ANSI_COLORS[n as usize - 40]),
+        49 => Attribute::new(fg, DEFAULT_ATTR.bg()),
+        90...97 => Attribute::new(ANSI_BRIGHT_COLORS[n as usize - 90], bg),
+        100...107 => Attribute::new(fg, ANSI_BRIGHT_COLORS[n as usize - 100]),
+        _ => attr
+    }
+}
+
 pub struct TerminalHelper<T> {
     pub cursor: usize,  // cursor as offset
     pub attr: Attribute, // current char attribute
     pub cols: usize,
     pub rows: usize,
-    pub drv: T
+    pub drv: T,
+    escape: Escape
 }
 
 impl<T: TerminalDriver> TerminalHelper<T> {
@@ -185,13 +274,18 @@ impl<T: TerminalDriver> TerminalHelper<T> {
         TerminalHelper {
             drv: drv,
             cursor: 0,
-            attr: Attribute::new(Color::Green, Color::Black),
+            attr: DEFAULT_ATTR,
             cols: CONSOLE_WIDTH,
-            rows: CONSOLE_HEIGHT
+            rows: CONSOLE_HEIGHT,
+            escape: Escape::None
         }
     }
 
+    /// out of range (row, col) is clamped into the last row/col, so callers
+    /// with fixed positions never make drivers draw off screen
     pub fn update_cursor(&mut self, row: usize, col: usize) {
+        use core::cmp::min;
+        let (row, col) = (min(row, self.rows - 1), min(col, self.cols - 1));
         let v = self.contract_cursor(row, col);
         self.cursor = v;
         self.drv.update_cursor(row, col);
@@ -261,7 +355,40 @@ impl<T: TerminalDriver> TerminalHelper<T> {
     }
 
 
+    /// feed byte to escape sequence parser, true if it was consumed
+    fn escape(&mut self, byte: u8) -> bool {
+        self.escape = match (self.escape, byte) {
+            (Escape::None, 0x1b) => Escape::Esc,
+            (Escape::None, _) => return false,
+            (Escape::Esc, b'[') => Escape::Csi([0; ESC_MAX_PARAMS], 0),
+            // two byte sequences are not supported
+            (Escape::Esc, _) => Escape::None,
+            (Escape::Csi(mut params, i), b'0'...b'9') => {
+                if i < ESC_MAX_PARAMS {
+                    params[i] = params[i].saturating_mul(10).saturating_add((byte - b'0') as u16);
+                }
+                Escape::Csi(params, i)
+            },
+            (Escape::Csi(params, i), b';') => Escape::Csi(params, i + 1),
+            (Escape::Csi(params, i), b'm') => {
+                use core::cmp::min;
+                for &n in params[..min(i + 1, ESC_MAX_PARAMS)].iter() {
+                    self.attr = apply_sgr(self.attr, n);
+                }
+                Escape::None
+            },
+            // any other final byte ends an unsupported sequence
+            (Escape::Csi(_, _), 0x40...0x7e) => Escape::None,
+            (esc @ Escape::Csi(_, _), _) => esc,
+        };
+        true
+    }
+
     fn write_byte(&mut self, byte: u8) {
+        if self.escape(byte) {
+            return;
+        }
+
         let (mut cy, mut cx) = self.extract_cursor(self.cursor);
         let blank = Char {
             ascii: b' ',
@@ -269,11 +396,11 @@ impl<T: TerminalDriver> TerminalHelper<T> {
         };
 
         match byte {
-            0x08 => { // backspace
+            0x08 => { // backspace, erase char left of cursor
                 if cx > 0 {
+                    self.retreat();
                     let cur = self.cursor;
                     self.drv.draw_byte(cur, blank);
-                    self.retreat();
                 }
             }, 
             b'\t' => {
@@ -320,15 +447,33 @@ pub enum Console {
 
 impl Console {
     pub const fn new_with_text_only() -> Console {
-        Console::TextTerminal(TerminalHelper::new(ConsoleDriver::new()))
+        Console::TextTerminal(TerminalHelper::new(ConsoleDriver::new(true)))
+    }
+
+    /// text console that stays off screen until switch() shows it
+    pub const fn new_with_hidden_text() -> Console {
+        Console::TextTerminal(TerminalHelper::new(ConsoleDriver::new(false)))
     }
 
-    pub fn new_with_fb(fb: Framebuffer) -> Console {
+    /// rows and cols follow framebuffer size and font cell,
+    /// None if framebuffer can not hold a single cell
+    pub fn new_with_fb(fb: Framebuffer) -> Option<Console> {
         let mut helper = TerminalHelper::new(FramebufferDriver::new(fb));
         helper.cols = helper.drv.get_max_cols();
         helper.rows = helper.drv.get_max_rows();
+        if helper.cols == 0 || helper.rows == 0 {
+            return None;
+        }
             
-        Console::FbTerminal(helper)
+        Some(Console::FbTerminal(helper))
+    }
+
+    /// (rows, cols) of text area
+    pub fn size(&self) -> (usize, usize) {
+        match *self {
+            Console::TextTerminal(ref drv) => (drv.rows, drv.cols),
+            Console::FbTerminal(ref drv) => (drv.rows, drv.cols)
+        }
     }
 
     pub fn putchar(&mut self, byte: u8) {
@@ -338,6 +483,13 @@ impl Console {
         }
     }
 
+    pub fn get_attr(&self) -> Attribute {
+        match *self {
+            Console::TextTerminal(ref drv) => drv.get_attr(),
+            Console::FbTerminal(ref drv) => drv.get_attr()
+        }
+    }
+
     pub fn set_attr(&mut self, val: Attribute) -> Attribute {
         match *self {
             Console::TextTerminal(ref mut drv) => drv.set_attr(val),
@@ -359,6 +511,28 @@ impl Console {
         }
     }
 
+    pub fn show_cursor(&mut self, visible: bool) {
+        match *self {
+            Console::TextTerminal(ref mut drv) => drv.drv.show_cursor(visible),
+            Console::FbTerminal(ref mut drv) => drv.drv.show_cursor(visible)
+        }
+    }
+
+    /// only text consoles can be hidden, framebuffer one owns the screen
+    fn set_visible(&mut self, visible: bool) -> Result {
+        match *self {
+            Console::TextTerminal(ref mut drv) => { drv.drv.set_visible(visible); Ok(()) },
+            Console::FbTerminal(_) => Err(::core::fmt::Error)
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        match *self {
+            Console::TextTerminal(_) => true,
+            Console::FbTerminal(_) => false
+        }
+    }
+
     pub fn get_cursor(&self) -> usize {
         match *self {
             Console::TextTerminal(ref drv) => drv.cursor,
@@ -373,7 +547,27 @@ impl Console {
         }
     }
 
-    /// safely call f without potential deadlock of console
+    /// confine console to a sub region of framebuffer, (x, y) in pixels
+    pub fn set_text_region(&mut self, x: usize, y: usize, cols: usize, rows: usize) -> Result {
+        match *self {
+            Console::TextTerminal(_) => Err(::core::fmt::Error),
+            Console::FbTerminal(ref mut helper) => {
+                let (cols, rows) = match helper.drv.set_region(x, y, cols, rows) {
+                    Some(size) => size,
+                    None => return Err(::core::fmt::Error)
+                };
+                helper.cols = cols;
+                helper.rows = rows;
+                helper.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// call f with cursor of con moved to (row, col), restore it after.
+    /// spins on con like printk!, irq and syscall paths use kprintln!.
+    /// row and col are clamped to console size, so on a small screen
+    /// output lands on the last row instead of off screen.
     pub fn with<F>(con: &Mutex<Console>, row: usize, col: usize, f: F) where F: FnOnce() {
         use ::kern::arch::cpu;
         let oflags = unsafe { cpu::push_flags() };
@@ -397,19 +591,122 @@ impl Write for Console {
             self.putchar(b);
         }
 
-        unsafe {
-            let mut com1 = serial::COM1.lock();
-            for b in s.bytes() {
-                com1.write(b);
+        for sink in SINKS.read().iter() {
+            if let Some(sink) = *sink {
+                let _ = sink.lock().write_str(s);
             }
         }
         Ok(())
     }
 }
 
+/// extra destination of everything written to a console
+pub type Sink = &'static Mutex<Write + Send>;
 
+const MAX_SINKS: usize = 4;
+
+/// COM1 mirrors the screen from the start. only add_sink and remove_sink
+/// take the write lock, with interrupts off, so readers never wait on each other.
+static SINKS: RwLock<[Option<Sink>; MAX_SINKS]> = RwLock::new([
+    Some(&serial::COM1 as Sink), None, None, None
+]);
+
+fn same_sink(a: Sink, b: Sink) -> bool {
+    a as *const _ as *const u8 == b as *const _ as *const u8
+}
+
+/// fan console output out to sink as well. false if all slots are taken,
+/// sink is already added, or sink is a tty: writing to it would lock a
+/// console from inside another console's write and fan out again.
+pub fn add_sink(sink: Sink) -> bool {
+    use ::kern::arch::cpu;
+
+    if TTYS.iter().any(|&tty| same_sink(sink, tty)) {
+        return false;
+    }
+
+    let oflags = unsafe { cpu::push_flags() };
+    let added = {
+        let mut sinks = SINKS.write();
+        if sinks.iter().any(|slot| slot.map_or(false, |s| same_sink(s, sink))) {
+            false
+        } else {
+            match sinks.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => { *slot = Some(sink); true },
+                None => false
+            }
+        }
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    added
+}
+
+/// stop fanning out to sink, false if it was not added
+pub fn remove_sink(sink: Sink) -> bool {
+    use ::kern::arch::cpu;
+    let oflags = unsafe { cpu::push_flags() };
+    let removed = match SINKS.write().iter_mut().find(|slot| slot.map_or(false, |s| same_sink(s, sink))) {
+        Some(slot) => { *slot = None; true },
+        None => false
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    removed
+}
+
+
+/// tty1 is the kernel tty, print! and printk! always write to it whichever
+/// tty is on screen
 #[allow(non_upper_case_globals)]
 pub static tty1: Mutex<Console> = Mutex::new(Console::new_with_text_only());
+#[allow(non_upper_case_globals)]
+pub static tty2: Mutex<Console> = Mutex::new(Console::new_with_hidden_text());
+#[allow(non_upper_case_globals)]
+pub static tty3: Mutex<Console> = Mutex::new(Console::new_with_hidden_text());
+#[allow(non_upper_case_globals)]
+pub static tty4: Mutex<Console> = Mutex::new(Console::new_with_hidden_text());
+
+static TTYS: [&'static Mutex<Console>; 4] = [&tty1, &tty2, &tty3, &tty4];
+
+/// number of tty on screen, starting from 1
+static ACTIVE_TTY: AtomicUsize = AtomicUsize::new(1);
+
+pub fn active_tty() -> usize {
+    ACTIVE_TTY.load(Ordering::SeqCst)
+}
+
+fn switch_between(old: &mut Console, new: &mut Console) -> Result {
+    if !old.is_text() || !new.is_text() {
+        return Err(::core::fmt::Error);
+    }
+    old.set_visible(false)?;
+    new.set_visible(true)
+}
+
+/// bring tty n (1 to 4) on screen. never waits, so it is safe from irq
+/// context; fails if either tty is busy or one of them is not a text
+/// console (framebuffer console can not be switched away).
+pub fn switch(n: usize) -> Result {
+    use ::kern::arch::cpu;
+
+    let cur = active_tty();
+    if n == 0 || n > TTYS.len() {
+        return Err(::core::fmt::Error);
+    }
+    if n == cur {
+        return Ok(());
+    }
+
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = match (TTYS[cur - 1].try_lock(), TTYS[n - 1].try_lock()) {
+        (Some(mut old), Some(mut new)) => switch_between(&mut old, &mut new),
+        _ => Err(::core::fmt::Error)
+    };
+    if ret.is_ok() {
+        ACTIVE_TTY.store(n, Ordering::SeqCst);
+    }
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
 
 macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
@@ -428,6 +725,84 @@ pub fn _print(args: ::core::fmt::Arguments) -> ::core::fmt::Result {
     con.write_fmt(args)
 }
 
+/// output that could not take the console lock, written out by the next
+/// kprint! that does get the lock. oldest bytes get dropped on overflow.
+struct DeferredLog {
+    buf: [u8; DEFERRED_SIZE],
+    head: usize,
+    len: usize,
+}
+
+const DEFERRED_SIZE: usize = 4096;
+
+static DEFERRED: Mutex<DeferredLog> = Mutex::new(DeferredLog {
+    buf: [0; DEFERRED_SIZE],
+    head: 0,
+    len: 0
+});
+
+impl Write for DeferredLog {
+    fn write_str(&mut self, s: &str) -> Result {
+        for b in s.bytes() {
+            let tail = (self.head + self.len) % DEFERRED_SIZE;
+            self.buf[tail] = b;
+            if self.len == DEFERRED_SIZE {
+                self.head = (self.head + 1) % DEFERRED_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeferredLog {
+    fn drain_into(&mut self, con: &mut Console) {
+        while self.len > 0 {
+            let b = self.buf[self.head];
+            self.head = (self.head + 1) % DEFERRED_SIZE;
+            self.len -= 1;
+            let _ = con.write_str(unsafe { ::core::str::from_utf8_unchecked(&[b]) });
+        }
+    }
+}
+
+/// printk! and print! spin on console lock, and deadlock when they interrupt
+/// a holder of it on the same cpu. kprintln! is safe to use from any context
+/// (irq, syscall, kernel thread): it never waits, output gets deferred if
+/// console is busy.
+macro_rules! kprintln {
+    ($fmt:expr) => (kprint!(concat!($fmt, "\n\r")));
+    ($fmt:expr, $($arg:tt)*) => (kprint!(concat!($fmt, "\n\r"), $($arg)*));
+}
+
+macro_rules! kprint {
+    ($($arg:tt)*) => ({
+        $crate::kern::console::_kprint(format_args!($($arg)*));
+    });
+}
+
+pub fn _kprint(args: ::core::fmt::Arguments) {
+    use ::kern::arch::cpu;
+
+    let oflags = unsafe { cpu::push_flags() };
+    match tty1.try_lock() {
+        Some(mut con) => {
+            if let Some(mut deferred) = DEFERRED.try_lock() {
+                deferred.drain_into(&mut con);
+            }
+            let _ = con.write_fmt(args);
+        },
+        None => {
+            // if even the deferred buffer is busy, message is lost
+            if let Some(mut deferred) = DEFERRED.try_lock() {
+                let _ = deferred.write_fmt(args);
+            }
+        }
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LogLevel {
     Debug,
@@ -437,12 +812,26 @@ pub enum LogLevel {
     Critical
 }
 
+/// printk below this level is dropped, Debug needs kdebug feature as well
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Debug as usize);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as usize, Ordering::SeqCst);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    (level != LogLevel::Debug || cfg!(feature = "kdebug"))
+        && level as usize >= LOG_LEVEL.load(Ordering::SeqCst)
+}
+
+/// raw leveled logging, spins on console lock. callers must make sure
+/// the context can not interrupt a console holder, use kprintln! otherwise.
 macro_rules! printk {
     ($lv:expr, $($arg:tt)*) => ({
         use $crate::kern::console::*;
         use $crate::kern::arch::cpu;
 
-        if $lv != LogLevel::Debug || cfg!(feature = "kdebug") {
+        if log_enabled($lv) {
             let attr = match $lv {
                 LogLevel::Debug => Attribute::new(Color::Green, Color::Black),
                 LogLevel::Normal => Attribute::new(Color::White, Color::Black),
@@ -468,6 +857,36 @@ macro_rules! printk {
     });
 }
 
+/// confine tty1 rendering and scrolling into cols x rows chars at pixel (x, y),
+/// rest of framebuffer is left for free drawing. only for framebuffer console.
+pub fn set_text_region(x: usize, y: usize, cols: usize, rows: usize) -> Result {
+    use ::kern::arch::cpu;
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = tty1.lock().set_text_region(x, y, cols, rows);
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
+
+/// move cursor of tty1 to (row, col), clamped to screen, and show it
+pub fn set_cursor(row: usize, col: usize) {
+    use ::kern::arch::cpu;
+    let oflags = unsafe { cpu::push_flags() };
+    {
+        let mut con = tty1.lock();
+        con.update_cursor(row, col);
+        con.show_cursor(true);
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// hide cursor of tty1 until the next set_cursor
+pub fn hide_cursor() {
+    use ::kern::arch::cpu;
+    let oflags = unsafe { cpu::push_flags() };
+    tty1.lock().show_cursor(false);
+    unsafe { cpu::pop_flags(oflags); }
+}
+
 pub fn clear() {
     use ::kern::arch::cpu;
     let oflags = unsafe { cpu::push_flags() };
@@ -476,3 +895,138 @@ pub fn clear() {
     unsafe { cpu::pop_flags(oflags); }
 }
 
+
+pub fn test_ansi_sgr() {
+    use collections::Vec;
+    use ::kern::driver::video::Rgba;
+
+    let (width, height) = (64usize, 16usize);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0); width * height];
+    let fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width as i32, height as i32, 0) };
+    let mut con = Console::new_with_fb(fb).expect("64x16 fb holds a row");
+    let colors = |con: &Console| (con.get_attr().fg() as u8, con.get_attr().bg() as u8);
+
+    let _ = con.write_str("\x1b[31mred");
+    test_assert_eq!(colors(&con), (Color::Red as u8, Color::Black as u8));
+    // only the visible chars moved the cursor
+    test_assert_eq!(con.get_cursor(), 3);
+
+    let _ = con.write_str("\x1b[0m");
+    test_assert_eq!(colors(&con), (DEFAULT_ATTR.fg() as u8, DEFAULT_ATTR.bg() as u8));
+
+    let _ = con.write_str("\x1b[1;93;44m");
+    test_assert_eq!(colors(&con), (Color::Yellow as u8, Color::Blue as u8));
+    let _ = con.write_str("\x1b[m");
+    test_assert_eq!(colors(&con), (DEFAULT_ATTR.fg() as u8, DEFAULT_ATTR.bg() as u8));
+
+    // unsupported sequences vanish without a trace
+    let _ = con.write_str("\x1b[2J\x1b[1;2;3;4;5;6H\x1bc");
+    test_assert_eq!(con.get_cursor(), 3);
+    test_assert_eq!(colors(&con), (DEFAULT_ATTR.fg() as u8, DEFAULT_ATTR.bg() as u8));
+}
+
+pub fn test_console_backspace() {
+    use collections::Vec;
+    use ::kern::driver::video::Rgba;
+
+    let (width, height) = (64usize, 16usize);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0); width * height];
+    let fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width as i32, height as i32, 0) };
+    let mut con = Console::new_with_fb(fb).expect("64x16 fb holds a row");
+    // cell 1 is x in [8, 16)
+    let cell_lit = |mem: &Vec<Rgba>| (0..height).any(|y| (8..16).any(|x| mem[y * width + x].0 != 0));
+
+    let _ = con.write_str("ab");
+    test_assert!(cell_lit(&mem));
+    let _ = con.write_str("\x08");
+    test_assert_eq!(con.get_cursor(), 1);
+    test_assert!(!cell_lit(&mem));
+
+    // nothing left of column 0 to erase
+    let _ = con.write_str("\x08\x08\x08");
+    test_assert_eq!(con.get_cursor(), 0);
+}
+
+pub fn test_console_switch() {
+    use collections::Vec;
+
+    let mut vga: Vec<Char> = vec![Char { ascii: b'?', attr: BLANK.attr }; CONSOLE_WIDTH * CONSOLE_HEIGHT];
+    let text = |visible: bool, vga: &mut Vec<Char>| {
+        Console::TextTerminal(TerminalHelper::new(ConsoleDriver {
+            buf: unsafe { Unique::new_unchecked(vga.as_mut_ptr() as *mut Buffer) },
+            ..ConsoleDriver::new(visible)
+        }))
+    };
+    let mut a = text(true, &mut vga);
+    let mut b = text(false, &mut vga);
+
+    a.putchar(b'a');
+    b.putchar(b'b');
+    b.putchar(b'\n');
+    test_assert_eq!(vga[0].ascii, b'a');
+    test_assert_eq!(vga[1].ascii, b'?');
+
+    // hidden tty kept its text and cursor
+    test_assert!(switch_between(&mut a, &mut b).is_ok());
+    test_assert!(vga.iter().enumerate().all(|(i, c)| c.ascii == if i == 0 { b'b' } else { b' ' }));
+    test_assert_eq!(b.get_cursor(), CONSOLE_WIDTH);
+    a.putchar(b'x');
+    test_assert_eq!(vga[1].ascii, b' ');
+
+    test_assert!(switch_between(&mut b, &mut a).is_ok());
+    test_assert_eq!((vga[0].ascii, vga[1].ascii), (b'a', b'x'));
+}
+
+struct Capture {
+    buf: [u8; 32],
+    len: usize
+}
+
+impl Write for Capture {
+    fn write_str(&mut self, s: &str) -> Result {
+        for &b in s.as_bytes() {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = b;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture { buf: [0; 32], len: 0 });
+
+pub fn test_console_sinks() {
+    use collections::Vec;
+    use ::kern::arch::cpu;
+
+    // a tty sink would deadlock on its own lock
+    test_assert!(!add_sink(&tty1));
+    test_assert!(!add_sink(&tty3));
+    test_assert!(!remove_sink(&CAPTURE));
+
+    let mut vga: Vec<Char> = vec![BLANK; CONSOLE_WIDTH * CONSOLE_HEIGHT];
+    let mut con = Console::TextTerminal(TerminalHelper::new(ConsoleDriver {
+        buf: unsafe { Unique::new_unchecked(vga.as_mut_ptr() as *mut Buffer) },
+        ..ConsoleDriver::new(true)
+    }));
+
+    // nothing else may print into the capture meanwhile
+    let oflags = unsafe { cpu::push_flags() };
+    test_assert!(add_sink(&CAPTURE));
+    test_assert!(!add_sink(&CAPTURE));
+    let _ = con.write_str("fan");
+    let _ = con.write_str("out");
+    test_assert!(remove_sink(&CAPTURE));
+    let _ = con.write_str("gone");
+    unsafe { cpu::pop_flags(oflags); }
+
+    let captured = {
+        let cap = CAPTURE.lock();
+        cap.buf[..cap.len].to_vec()
+    };
+    test_assert_eq!(&captured[..], b"fanout");
+    // the console itself got every write
+    test_assert_eq!(vga[0].ascii, b'f');
+    test_assert_eq!(vga[6].ascii, b'g');
+}
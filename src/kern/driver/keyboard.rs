@@ -1,10 +1,11 @@
 use ::kern::arch::port::Port;
-use core::sync::atomic::{AtomicBool, Ordering};
 use ::kern::interrupts::idt::*;
-use ::kern::interrupts::irq::PIC_CHAIN;
+use ::kern::interrupts::irq::{PIC_CHAIN, register_irq};
 use spin::Mutex;
 use ::kern::console::LogLevel::*;
 use ::kern::console::{Console, tty1};
+use ::kern::interrupts::timer;
+use ::kern::task::{self, ProcId, TaskList};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
@@ -196,6 +197,9 @@ pub struct MousePacket {
     rely: i16,
 }
 
+/// PIC line of keyboard controller
+const IRQ_KBD: u8 = 1;
+
 // KB_ENCODER_IO 
 const KB_ENC_INPUT_BUF: u16 =   0x60;
 const KB_ENC_CMD_REG: u16   =   0x60;
@@ -405,14 +409,142 @@ fn get_extend_keycode(data: u8) -> KeyCode {
     return KeyCode::KEY_UNKNOWN;
 }
 
-static _is_extended: AtomicBool = AtomicBool::new(false);
+// physical modifier keys, so releasing one shift keeps the other in effect
+const MOD_LSHIFT: u8 = 1 << 0;
+const MOD_RSHIFT: u8 = 1 << 1;
+const MOD_LCTRL: u8 = 1 << 2;
+const MOD_RCTRL: u8 = 1 << 3;
+const MOD_LALT: u8 = 1 << 4;
+const MOD_RALT: u8 = 1 << 5;
+const MOD_CAPS: u8 = 1 << 6;
+
+const REPEAT_DELAY_MS: usize = 500;
+const REPEAT_RATE_HZ: usize = 10;
+
+/// software typematic. only the most recently pressed key repeats, and it
+/// stops when that key is released. make codes the keyboard itself repeats
+/// for the held key are swallowed so they don't double up with ours.
+#[derive(Debug, Clone, Copy)]
+pub struct Repeat {
+    delay_ms: usize,
+    // 0 turns repetition off
+    rate_hz: usize,
+    // matched against break codes, re-emitted under the modifiers of the moment
+    held: Option<KeyCode>,
+    // tick of next emission
+    next: usize,
+}
+
+impl Repeat {
+    pub const fn new(delay_ms: usize, rate_hz: usize) -> Repeat {
+        Repeat {
+            delay_ms: delay_ms,
+            rate_hz: rate_hz,
+            held: None,
+            next: 0,
+        }
+    }
+
+    fn period(&self) -> usize {
+        ::core::cmp::max(1, timer::ms_to_ticks(1000 / self.rate_hz))
+    }
+
+    /// key went down at tick now. return false if it's a repeated make code
+    /// of the key already held.
+    pub fn press(&mut self, key: KeyCode, now: usize) -> bool {
+        if key.modifier() {
+            return true;
+        }
+        if self.held == Some(key) {
+            return false;
+        }
+        self.held = if self.rate_hz > 0 { Some(key) } else { None };
+        self.next = now + timer::ms_to_ticks(self.delay_ms);
+        true
+    }
+
+    /// releasing other keys leaves the most recent one repeating
+    pub fn release(&mut self, key: KeyCode) {
+        if self.held == Some(key) {
+            self.held = None;
+        }
+    }
+
+    /// keycode to re-emit at tick now, if it's time
+    pub fn due(&mut self, now: usize) -> Option<KeyCode> {
+        match self.held {
+            Some(key) if now >= self.next => {
+                self.next = now + self.period();
+                Some(key)
+            },
+            _ => None
+        }
+    }
+}
+
+const KB_BUF_SIZE: usize = 128;
+
+/// decoded chars waiting to be read. when full, new chars are dropped so
+/// what the reader gets is still a prefix of what was typed.
+pub struct KeyBuffer {
+    buf: [u8; KB_BUF_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl KeyBuffer {
+    pub const fn new() -> KeyBuffer {
+        KeyBuffer {
+            buf: [0; KB_BUF_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// return false if buffer is full and ch is dropped
+    pub fn push(&mut self, ch: u8) -> bool {
+        if self.len == KB_BUF_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % KB_BUF_SIZE] = ch;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let ch = self.buf[self.head];
+        self.head = (self.head + 1) % KB_BUF_SIZE;
+        self.len -= 1;
+        Some(ch)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl ::core::fmt::Debug for KeyBuffer {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(f, "KeyBuffer {{ len: {} }}", self.len)
+    }
+}
 
 #[derive(Debug)]
 pub struct Keyboard {
     encoder: Port<u8>,
     ctrl: Port<u8>,
     status: Option<KeyStatus>,
-    //kb_buf: KeyBuffer,
+    // physical modifier keys down, see MOD_*
+    mods: u8,
+    // last byte was the 0xE0 prefix
+    extended: bool,
+    repeat: Repeat,
+    kb_buf: KeyBuffer,
+    // the one task sleeping in read() until a char comes
+    reader: Option<ProcId>,
 }
 
 pub static KBD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
@@ -422,10 +554,133 @@ impl Keyboard {
         Keyboard {
             encoder: Port::new(KB_ENC_CMD_REG),
             ctrl: Port::new(KB_CTRL_CMD_REG),
-            status: None
+            status: None,
+            mods: 0,
+            extended: false,
+            repeat: Repeat::new(REPEAT_DELAY_MS, REPEAT_RATE_HZ),
+            kb_buf: KeyBuffer::new(),
+            reader: None,
         }
     }
 
+    /// oldest char typed and not read yet
+    pub fn read_char(&mut self) -> Option<u8> {
+        self.kb_buf.pop()
+    }
+
+    /// shift, ctrl, alt and lock keys in effect
+    pub fn modifiers(&self) -> KeyStatus {
+        self.status.unwrap_or(KeyStatus::empty())
+    }
+
+    /// queue decoded key for readers and echo it
+    fn input(&mut self, key: KeyCode) {
+        if let Some(ch) = self.to_ascii(key) {
+            self.kb_buf.push(ch);
+            kprint!("{}", ch as char);
+            self.wake_reader();
+        }
+    }
+
+    /// task list may be busy in irq, then reader stays recorded and the
+    /// next timer tick retries
+    fn wake_reader(&mut self) {
+        if let Some(pid) = self.reader {
+            if TaskList::try_get_mut().map_or(false, |mut tasks| tasks.wake(pid)) {
+                self.reader = None;
+            }
+        }
+    }
+
+    /// decode one byte from the encoder (scan code set 1) and track modifier
+    /// state. None for bytes that are no key event: the 0xE0 prefix, which
+    /// marks the next byte as extended, and acks of our led commands.
+    fn decode(&mut self, data: u8) -> Option<KeyPacket> {
+        match data {
+            0xE0 => { self.extended = true; return None; },
+            0xFA => return None,
+            _ => {}
+        }
+
+        let extended = self.extended;
+        self.extended = false;
+        let scan = (data & 0x7f) as usize;
+        let keycode = match extended {
+            true => get_extend_keycode(data),
+            false if scan < _xtkb_scancode_std.len() => _xtkb_scancode_std[scan],
+            _ => KeyCode::KEY_UNKNOWN
+        };
+        let down = data & 0x80 == 0;
+
+        let bit = match keycode {
+            KeyCode::KEY_LSHIFT => MOD_LSHIFT,
+            KeyCode::KEY_RSHIFT => MOD_RSHIFT,
+            KeyCode::KEY_LCTRL => MOD_LCTRL,
+            KeyCode::KEY_RCTRL => MOD_RCTRL,
+            KeyCode::KEY_LALT => MOD_LALT,
+            KeyCode::KEY_RALT => MOD_RALT,
+            KeyCode::KEY_CAPSLOCK => MOD_CAPS,
+            _ => 0
+        };
+        // caps lock toggles once per press, not on the keyboard's repeats
+        if keycode == KeyCode::KEY_CAPSLOCK && down && self.mods & MOD_CAPS == 0 {
+            let caps = !self.modifiers().contains(KB_CAPS_LOCK);
+            self.set_lock(KB_CAPS_LOCK, caps);
+        }
+        if down { self.mods |= bit; } else { self.mods &= !bit; }
+
+        let mods = self.mods;
+        self.set_shift_down(mods & (MOD_LSHIFT | MOD_RSHIFT) != 0);
+        self.set_ctrl_down(mods & (MOD_LCTRL | MOD_RCTRL) != 0);
+        self.set_alt_down(mods & (MOD_LALT | MOD_RALT) != 0);
+
+        let event = if down { KB_PRESS } else { KB_RELEASE };
+        Some(KeyPacket {
+            keycode: keycode,
+            status: event.bits() | self.modifiers().bits(),
+        })
+    }
+
+    /// byte key produces under current modifiers (us layout), None if none.
+    /// letters follow caps lock xor shift, ctrl+letter gives control codes.
+    fn to_ascii(&mut self, key: KeyCode) -> Option<u8> {
+        if !key.printable() {
+            return None;
+        }
+
+        let ch = key as u8;
+        if b'a' <= ch && ch <= b'z' {
+            let upper = self.shift_down() != self.modifiers().contains(KB_CAPS_LOCK);
+            return Some(if self.ctrl_down() {
+                ch - b'a' + 1
+            } else if upper {
+                ch - b'a' + b'A'
+            } else {
+                ch
+            });
+        }
+        if self.shift_down() { Some(key.shifted() as u8) } else { Some(ch) }
+    }
+
+    fn set_lock(&mut self, lock: KeyStatus, on: bool) {
+        let mut status = self.modifiers();
+        if on { status.insert(lock); } else { status.remove(lock); }
+        self.status = if status.is_empty() { None } else { Some(status) };
+    }
+
+    /// tell keyboard which lock leds to light
+    fn sync_leds(&mut self) {
+        let leds = self.modifiers() & (KB_SCROLL_LOCK | KB_NUM_LOCK | KB_CAPS_LOCK);
+        self.kbe_send(KeyboardEncoderCommand::SET_LEDS as u8);
+        self.kbe_send(leds.bits() as u8);
+    }
+
+    /// key held longer than delay_ms repeats rate_hz times a second,
+    /// rate_hz 0 disables repetition
+    pub fn set_repeat(&mut self, delay_ms: usize, rate_hz: usize) {
+        self.repeat = Repeat::new(delay_ms, rate_hz);
+    }
+
 
     // 0: ok
     // 1: resend
@@ -453,7 +708,7 @@ impl Keyboard {
         self.check_reply();
 
         self.set_leds(false, false, false);
-        //register_isr_handler(IRQ_KBD, keyboard_irq);
+        register_irq(IRQ_KBD, keyboard_irq);
         //register_isr_handler(IRQ_MOUSE, mouse_irq);
     }
 
@@ -620,105 +875,245 @@ impl KeyCode {
             _ => false
         }
     }
+
+    /// us layout char of a printable key, shift already applied
+    fn ascii(&self) -> Option<u8> {
+        if self.printable() { Some(*self as u8) } else { None }
+    }
+
+    /// key on the same cap with shift held, us layout
+    fn shifted(&self) -> KeyCode {
+        match *self {
+            KeyCode::KEY_0 =>             KeyCode::KEY_RIGHTPARENTHESIS,
+            KeyCode::KEY_1 =>             KeyCode::KEY_EXCLAMATION,
+            KeyCode::KEY_2 =>             KeyCode::KEY_AT,
+            KeyCode::KEY_3 =>             KeyCode::KEY_HASH,
+            KeyCode::KEY_4 =>             KeyCode::KEY_DOLLAR,
+            KeyCode::KEY_5 =>             KeyCode::KEY_PERCENT,
+            KeyCode::KEY_6 =>             KeyCode::KEY_CARRET,
+            KeyCode::KEY_7 =>             KeyCode::KEY_AMPERSAND,
+            KeyCode::KEY_8 =>             KeyCode::KEY_ASTERISK,
+            KeyCode::KEY_9 =>             KeyCode::KEY_LEFTPARENTHESIS,
+            KeyCode::KEY_MINUS =>         KeyCode::KEY_UNDERSCORE,
+            KeyCode::KEY_EQUAL =>         KeyCode::KEY_PLUS,
+            KeyCode::KEY_GRAVE =>         KeyCode::KEY_TILDE,
+            KeyCode::KEY_COMMA =>         KeyCode::KEY_LESS,
+            KeyCode::KEY_DOT =>           KeyCode::KEY_GREATER,
+            KeyCode::KEY_SLASH =>         KeyCode::KEY_QUESTION,
+            KeyCode::KEY_SEMICOLON =>     KeyCode::KEY_COLON,
+            KeyCode::KEY_LEFTBRACKET =>   KeyCode::KEY_LEFTCURL,
+            KeyCode::KEY_RIGHTBRACKET =>  KeyCode::KEY_RIGHTCURL,
+            KeyCode::KEY_BACKSLASH =>     KeyCode::KEY_BAR,
+            KeyCode::KEY_QUOTE =>         KeyCode::KEY_QUOTEDOUBLE,
+            key => key
+        }
+    }
+
+    fn modifier(&self) -> bool {
+        match *self {
+            KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT | KeyCode::KEY_LCTRL |
+                KeyCode::KEY_RCTRL | KeyCode::KEY_LALT | KeyCode::KEY_RALT => true,
+            _ => false
+        }
+    }
+}
+
+/// called by timer irq, re-emit the held key when due
+pub fn timer_tick(now: usize) {
+    // key irq is working on it, try again next tick
+    if let Some(mut kbd) = KBD.try_lock() {
+        if let Some(key) = kbd.repeat.due(now) {
+            kbd.input(key);
+        }
+        if kbd.kb_buf.len() > 0 {
+            kbd.wake_reader();
+        }
+    }
+}
+
+/// block until some chars are typed, then move as many as fit into buf.
+/// return number of bytes read, 0 if buf is empty or a signal came first.
+/// only one task can wait for input, others get -EBUSY meanwhile.
+pub fn read(buf: &mut [u8]) -> Result<usize, isize> {
+    use ::kern::syscall::EBUSY;
+
+    if buf.len() == 0 {
+        return Ok(0);
+    }
+
+    let me = task::current_id();
+    let mut busy = false;
+    let n = task::wait_for(|| {
+        let mut kbd = KBD.lock();
+        match kbd.reader {
+            Some(pid) if pid != me => {
+                busy = true;
+                return Some(0);
+            },
+            _ => {}
+        }
+        if kbd.kb_buf.len() == 0 {
+            kbd.reader = Some(me);
+            return None;
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match kbd.kb_buf.pop() {
+                Some(ch) => { buf[n] = ch; n += 1; },
+                None => break
+            }
+        }
+        Some(n)
+    });
+
+    // a signal may have ended the wait while we were still recorded
+    {
+        let mut kbd = KBD.lock();
+        if kbd.reader == Some(me) {
+            kbd.reader = None;
+        }
+    }
+    if busy { Err(-EBUSY) } else { Ok(n.unwrap_or(0)) }
 }
 
 //FIXME: I use KBD (spin)lock here, so there might be a deadlock
-pub extern "C" fn keyboard_irq(frame: &mut ExceptionStackFrame) {
+pub fn keyboard_irq(frame: &mut ExceptionStackFrame) {
+    let _latency = ::kern::interrupts::LatencyGuard::new(::kern::interrupts::LatencySource::Keyboard);
     unsafe {
         PIC_CHAIN.lock().eoi(0);
     }
     let mut kbd = KBD.lock();
     let data = kbd.kbe_wait_and_read();
+    let packet = match kbd.decode(data) {
+        Some(packet) => packet,
+        None => return
+    };
 
-    if data == 0xE0 {
-        _is_extended.store(true, Ordering::Relaxed);
+    // releases only update state in decode, they produce no char
+    if packet.status & KB_RELEASE.bits() != 0 {
+        kbd.repeat.release(packet.keycode);
         return;
     }
 
-    let mut packet = KeyPacket {
-        keycode: KeyCode::KEY_UNKNOWN,
-        status: 0
-    };
-
-    let extended = _is_extended.load(Ordering::Relaxed);
-    if data & 0x80 != 0 {
-        packet.status = KB_RELEASE.bits();
-         packet.keycode = match extended {
-            true => get_extend_keycode(data),
-            false if ((data & 0x7f) as usize) < _xtkb_scancode_std.len() =>
-                _xtkb_scancode_std[(data & 0x7f) as usize],
-            _ => {
-                //printk!(Warn, "weird scancode {}", data);
-                KeyCode::KEY_UNKNOWN
+    match packet.keycode {
+        KeyCode::KEY_CAPSLOCK => kbd.sync_leds(),
+        // scheduler single-step: Ctrl+F12 toggles, F12 steps one switch
+        KeyCode::KEY_F12 if kbd.ctrl_down() => { ::kern::task::step_mode_toggle(); },
+        KeyCode::KEY_F12 => ::kern::task::step_once(),
+        // Alt+F1..F4 picks the tty on screen
+        KeyCode::KEY_F1 | KeyCode::KEY_F2 | KeyCode::KEY_F3 | KeyCode::KEY_F4 if kbd.alt_down() => {
+            let n = packet.keycode as usize - KeyCode::KEY_F1 as usize + 1;
+            if ::kern::console::switch(n).is_err() {
+                kprintln!("keyboard: can not switch to tty{}", n);
             }
-        };
+        },
+        _ => {}
+    }
 
-        //Break Code
-        match packet.keycode {
-            KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT => kbd.set_shift_down(false), 
-            KeyCode::KEY_LCTRL | KeyCode::KEY_RCTRL => kbd.set_ctrl_down(false), 
-            KeyCode::KEY_LALT | KeyCode::KEY_RALT => kbd.set_alt_down(false), 
-            _ => {}
-        }
+    if kbd.repeat.press(packet.keycode, timer::ticks()) {
+        kbd.input(packet.keycode);
+    }
+    tty_enqueue();
+}
 
-    } else {
-        packet.status = KB_PRESS.bits();
-        packet.keycode = match extended {
-            true =>  get_extend_keycode(data),
-            false if (data as usize) < _xtkb_scancode_std.len() => 
-                _xtkb_scancode_std[data as usize],
-            _ => {
-                //printk!(Warn, "weird scancode {}", data);
-                KeyCode::KEY_UNKNOWN
-            }
-        };
 
-        //Make Code
-        match packet.keycode {
-            KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT => {
-                kbd.set_shift_down(true);
-                printk!(Warn, "{:#?}\n\r", kbd.status);
-            },
-            KeyCode::KEY_LCTRL | KeyCode::KEY_RCTRL => kbd.set_ctrl_down(true),
-            KeyCode::KEY_LALT | KeyCode::KEY_RALT => kbd.set_alt_down(true),
-            _ => {}
-        }
-    }
+pub fn test_key_repeat() {
+    let mut rep = Repeat::new(500, 10);
+    let (delay, period) = (timer::ms_to_ticks(500), timer::ms_to_ticks(100));
+
+    test_assert!(rep.press(KeyCode::KEY_A, 0));
+    test_assert_eq!(rep.due(delay - 1), None);
+    test_assert_eq!(rep.due(delay), Some(KeyCode::KEY_A));
+    test_assert_eq!(rep.due(delay), None);
+    test_assert_eq!(rep.due(delay + period), Some(KeyCode::KEY_A));
+    // keyboard's own typematic make codes are swallowed
+    test_assert!(!rep.press(KeyCode::KEY_A, delay + period));
+
+    // most recent key takes over, modifiers don't
+    let now = delay + 2 * period;
+    test_assert!(rep.press(KeyCode::KEY_LSHIFT, now));
+    test_assert!(rep.press(KeyCode::KEY_1, now));
+    rep.release(KeyCode::KEY_A);
+    test_assert_eq!(rep.due(now + delay), Some(KeyCode::KEY_1));
+    rep.release(KeyCode::KEY_1);
+    test_assert_eq!(rep.due(now + delay + period), None);
+
+    let mut off = Repeat::new(500, 0);
+    test_assert!(off.press(KeyCode::KEY_BACKSPACE, 0));
+    test_assert_eq!(off.due(delay), None);
+}
 
-    if kbd.shift_down() {
-        match packet.keycode {
-            KeyCode::KEY_0 =>             packet.keycode = KeyCode::KEY_RIGHTPARENTHESIS,
-            KeyCode::KEY_1 =>             packet.keycode = KeyCode::KEY_EXCLAMATION,
-            KeyCode::KEY_2 =>             packet.keycode = KeyCode::KEY_AT,
-            KeyCode::KEY_3 =>             packet.keycode = KeyCode::KEY_HASH,
-            KeyCode::KEY_4 =>             packet.keycode = KeyCode::KEY_DOLLAR,
-            KeyCode::KEY_5 =>             packet.keycode = KeyCode::KEY_PERCENT,
-            KeyCode::KEY_6 =>             packet.keycode = KeyCode::KEY_CARRET,
-            KeyCode::KEY_7 =>             packet.keycode = KeyCode::KEY_AMPERSAND,
-            KeyCode::KEY_8 =>             packet.keycode = KeyCode::KEY_ASTERISK,
-            KeyCode::KEY_9 =>             packet.keycode = KeyCode::KEY_LEFTPARENTHESIS,
-            KeyCode::KEY_UNDERSCORE =>    packet.keycode = KeyCode::KEY_MINUS,
-            KeyCode::KEY_EQUAL =>         packet.keycode = KeyCode::KEY_PLUS,
-            KeyCode::KEY_GRAVE =>         packet.keycode = KeyCode::KEY_TILDE,
-            KeyCode::KEY_COMMA =>         packet.keycode = KeyCode::KEY_LESS,
-            KeyCode::KEY_DOT =>           packet.keycode = KeyCode::KEY_GREATER,
-            KeyCode::KEY_SLASH =>         packet.keycode = KeyCode::KEY_QUESTION,
-            KeyCode::KEY_LEFTBRACKET =>   packet.keycode = KeyCode::KEY_LEFTCURL,
-            KeyCode::KEY_RIGHTBRACKET =>  packet.keycode = KeyCode::KEY_RIGHTCURL,
-            KeyCode::KEY_BACKSLASH =>     packet.keycode = KeyCode::KEY_BAR,
-            KeyCode::KEY_QUOTE =>         packet.keycode = KeyCode::KEY_QUOTEDOUBLE,
-            _ => {}
+pub fn test_key_buffer() {
+    let mut buf = KeyBuffer::new();
+    test_assert_eq!(buf.pop(), None);
+
+    // wrap around a few times
+    for round in 0..3 {
+        for i in 0..KB_BUF_SIZE - 1 {
+            test_assert!(buf.push((round + i) as u8));
+        }
+        for i in 0..KB_BUF_SIZE - 1 {
+            test_assert_eq!(buf.pop(), Some((round + i) as u8));
         }
     }
-    packet.status |= kbd.status.map_or(0, |st| st.bits());
 
-    let st = KeyStatus::from_bits(packet.status);
-    if st.is_some() && st.unwrap().contains(KB_PRESS) && packet.keycode.printable() {
-        print!("{}", packet.keycode as u8 as char);
+    for _ in 0..KB_BUF_SIZE {
+        buf.push(b'a');
+    }
+    test_assert!(!buf.push(b'b'));
+    test_assert_eq!(buf.len(), KB_BUF_SIZE);
+    while let Some(ch) = buf.pop() {
+        test_assert_eq!(ch, b'a');
     }
-    //kbd.kbbuf().write(packet);
-    tty_enqueue();
 
-    if extended { _is_extended.store(false, Ordering::Relaxed); }
+    test_assert_eq!(KeyCode::KEY_EXCLAMATION.ascii(), Some(b'!'));
+    test_assert_eq!(KeyCode::KEY_RETURN.ascii(), Some(b'\n'));
+    test_assert_eq!(KeyCode::KEY_F1.ascii(), None);
 }
 
+pub fn test_key_decode() {
+    use collections::Vec;
+
+    // set 1 make codes, break code is make | 0x80
+    const A: u8 = 0x1e;
+    const C: u8 = 0x2e;
+    const ONE: u8 = 0x02;
+    const LSHIFT: u8 = 0x2a;
+    const RSHIFT: u8 = 0x36;
+    const LCTRL: u8 = 0x1d;
+    const CAPS: u8 = 0x3a;
+
+    let mut kbd = Keyboard::new();
+    let typed = |kbd: &mut Keyboard, seq: &[u8]| -> Vec<u8> {
+        let mut out = Vec::new();
+        for &data in seq {
+            if let Some(packet) = kbd.decode(data) {
+                if packet.status & KB_PRESS.bits() != 0 {
+                    out.extend(kbd.to_ascii(packet.keycode));
+                }
+            }
+        }
+        out
+    };
+
+    test_assert_eq!(typed(&mut kbd, &[A, A | 0x80, ONE, ONE | 0x80]), b"a1".to_vec());
+    test_assert_eq!(typed(&mut kbd, &[LSHIFT, A, ONE, LSHIFT | 0x80, A]), b"A!a".to_vec());
+
+    // one shift released while the other is still down
+    test_assert_eq!(typed(&mut kbd, &[LSHIFT, RSHIFT, LSHIFT | 0x80, A, RSHIFT | 0x80]), b"A".to_vec());
+    test_assert!(!kbd.modifiers().contains(KB_SHIFT_DOWN));
+
+    // caps lock toggles once per press and only affects letters
+    test_assert_eq!(typed(&mut kbd, &[CAPS, CAPS, CAPS | 0x80, A, ONE]), b"A1".to_vec());
+    test_assert!(kbd.modifiers().contains(KB_CAPS_LOCK));
+    test_assert_eq!(typed(&mut kbd, &[LSHIFT, A, LSHIFT | 0x80]), b"a".to_vec());
+    test_assert_eq!(typed(&mut kbd, &[CAPS, CAPS | 0x80, A]), b"a".to_vec());
+    test_assert!(!kbd.modifiers().contains(KB_CAPS_LOCK));
+
+    test_assert_eq!(typed(&mut kbd, &[LCTRL, C, C | 0x80, LCTRL | 0x80, C]), vec![0x03, b'c']);
+    test_assert!(kbd.modifiers().is_empty());
+
+    // extended right ctrl, led ack is no key
+    test_assert_eq!(typed(&mut kbd, &[0xe0, LCTRL, 0xfa, C, 0xe0, LCTRL | 0x80]), vec![0x03]);
+}
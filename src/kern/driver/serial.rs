@@ -1,7 +1,11 @@
 use kern::arch::port::Port;
+use kern::arch::cpu;
+use kern::interrupts::idt::ExceptionStackFrame;
+use kern::interrupts::irq::{PIC_CHAIN, register_irq};
 use spin::Mutex;
 
 const SERIAL_PORT: u16 = 0x3f8;   /* COM1 */
+const COM1_IRQ: u8 = 4;
 
 #[derive(Debug)]
 pub struct Serial {
@@ -35,12 +39,20 @@ impl Serial {
         self.ports[3].write(0x03);    // 8 bits, no parity, one stop bit
         self.ports[2].write(0xC7);    // Enable FIFO, clear them, with 14-byte threshold
         self.ports[4].write(0x0B);    // IRQs enabled, RTS/DSR set
+        self.ports[1].write(0x01);    // Interrupt when data is received
     }
 
     unsafe fn is_transmit_empty(&mut self) -> bool {
         self.ports[5].read() & 0x20 != 0
     }
 
+    /// wait until holding and shift registers are both empty, so the last
+    /// byte is really on the wire
+    pub unsafe fn drain(&mut self) {
+        while self.ports[5].read() & 0x40 == 0 {
+        }
+    }
+
     pub unsafe fn write(&mut self, a: u8) {
         while !self.is_transmit_empty() {
         }
@@ -52,6 +64,15 @@ impl Serial {
         self.ports[5].read() & 0x1 != 0
     }
 
+    /// non-blocking read
+    pub unsafe fn read_byte(&mut self) -> Option<u8> {
+        if self.serial_received() {
+            Some(self.ports[0].read())
+        } else {
+            None
+        }
+    }
+
     pub unsafe fn read(&mut self) -> u8 {
         while !self.serial_received() {
         }
@@ -60,5 +81,95 @@ impl Serial {
     }
 }
 
+impl ::core::fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        for b in s.bytes() {
+            unsafe { self.write(b); }
+        }
+        Ok(())
+    }
+}
 
+/// write to COM1 even if its lock is held, only for panic path where the
+/// holder is never coming back
+pub unsafe fn emergency_write(s: &str) {
+    if let Some(mut com1) = COM1.try_lock() {
+        for b in s.bytes() {
+            com1.write(b);
+        }
+        return;
+    }
+
+    let mut com1 = Serial::new(SERIAL_PORT);
+    for b in s.bytes() {
+        com1.write(b);
+    }
+}
+
+/// fmt::Write over emergency_write, for formatted output in panic path
+pub struct EmergencyWriter;
 
+impl ::core::fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        unsafe { emergency_write(s); }
+        Ok(())
+    }
+}
+
+/// wait for COM1 to send out everything, lock is ignored like emergency_write
+pub unsafe fn drain() {
+    Serial::new(SERIAL_PORT).drain();
+}
+
+const RX_BUF_SIZE: usize = 64;
+
+/// bytes received by serial_irq and not consumed yet, newest are dropped
+/// when it's full
+struct RxBuffer {
+    buf: [u8; RX_BUF_SIZE],
+    head: usize,
+    len: usize,
+}
+
+static RX: Mutex<RxBuffer> = Mutex::new(RxBuffer { buf: [0; RX_BUF_SIZE], head: 0, len: 0 });
+
+/// next byte received on COM1. irq fills the buffer under the same lock,
+/// so take it with interrupts off.
+pub fn read_buffered() -> Option<u8> {
+    let oflags = unsafe { cpu::push_flags() };
+    let byte = {
+        let mut rx = RX.lock();
+        if rx.len == 0 {
+            None
+        } else {
+            let b = rx.buf[rx.head];
+            rx.head = (rx.head + 1) % RX_BUF_SIZE;
+            rx.len -= 1;
+            Some(b)
+        }
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    byte
+}
+
+/// take COM1 line, needs interrupts set up
+pub fn init_irq() {
+    register_irq(COM1_IRQ, serial_irq);
+}
+
+/// COM1 (IRQ4): move everything in the receive fifo into RX
+pub fn serial_irq(_frame: &mut ExceptionStackFrame) {
+    unsafe { PIC_CHAIN.lock().eoi(COM1_IRQ as usize); }
+
+    // only receive buffer and line status are touched, which doesn't get in
+    // the way of a writer holding COM1
+    let mut port = Serial::new(SERIAL_PORT);
+    let mut rx = RX.lock();
+    while let Some(b) = unsafe { port.read_byte() } {
+        if rx.len < RX_BUF_SIZE {
+            let tail = (rx.head + rx.len) % RX_BUF_SIZE;
+            rx.buf[tail] = b;
+            rx.len += 1;
+        }
+    }
+}
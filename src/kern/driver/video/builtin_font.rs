@@ -3,7 +3,23 @@ pub struct FontInfo {
     pub yadvance: u8
 }
 
-pub const BUILTIN_FONTINFO: FontInfo = FontInfo {xadvance: 8, yadvance: 16};
+impl FontInfo {
+    /// width in pixels of text rendered in one line
+    pub fn text_width(&self, len: usize) -> i32 {
+        (len * self.xadvance as usize) as i32
+    }
+
+    /// how many chars fit in width pixels, none in a negative width
+    pub fn chars_fit(&self, width: i32) -> usize {
+        ::core::cmp::max(width, 0) as usize / self.xadvance as usize
+    }
+}
+
+/// glyph cell of builtin font in pixels, as drawn by Framebuffer::draw_char
+pub const GLYPH_WIDTH: i32 = 8;
+pub const GLYPH_HEIGHT: i32 = 16;
+
+pub const BUILTIN_FONTINFO: FontInfo = FontInfo {xadvance: GLYPH_WIDTH as u8, yadvance: GLYPH_HEIGHT as u8};
 
 pub const BUILTIN_FONT: [&'static [u8; 128]; 255] = [
 b"........\
@@ -0,0 +1,208 @@
+//! recorded drawing commands. building a list is cheap and needs no access
+//! to the framebuffer, replaying it draws everything in one pass and tells
+//! which part of the screen changed.
+
+use collections::Vec;
+use core::cmp::{min, max};
+
+use super::framebuffer::{Framebuffer, Point, Rect, Rgba};
+use super::builtin_font::BUILTIN_FONTINFO;
+
+#[derive(Debug, Clone)]
+pub enum DrawCmd {
+    Line(Point, Point, Rgba),
+    Rect(Rect, Rgba),
+    FillRect(Rect, Rgba),
+    /// gradient from first color at top to second at bottom
+    FillRectGrad(Rect, Rgba, Rgba),
+    Circle(Point, i32, Rgba),
+    /// filled by lines radiating from center
+    SpreadCircle(Point, i32, Rgba),
+    /// text, foreground and background
+    Text(Point, Vec<u8>, Rgba, Rgba),
+    /// copy area of (width, height) from src to dst
+    Blit(Point, Point, i32, i32),
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayList {
+    cmds: Vec<DrawCmd>
+}
+
+impl DisplayList {
+    pub fn new() -> DisplayList {
+        DisplayList { cmds: Vec::new() }
+    }
+
+    pub fn push(&mut self, cmd: DrawCmd) -> &mut Self {
+        self.cmds.push(cmd);
+        self
+    }
+
+    pub fn line(&mut self, p1: Point, p2: Point, rgb: Rgba) -> &mut Self {
+        self.push(DrawCmd::Line(p1, p2, rgb))
+    }
+
+    pub fn rect(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) -> &mut Self {
+        self.push(DrawCmd::Rect(Rect { top_left: top_left, width: width, height: height }, rgb))
+    }
+
+    pub fn fill_rect(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) -> &mut Self {
+        self.push(DrawCmd::FillRect(Rect { top_left: top_left, width: width, height: height }, rgb))
+    }
+
+    pub fn fill_rect_grad(&mut self, top_left: Point, width: i32, height: i32,
+                          from: Rgba, to: Rgba) -> &mut Self {
+        self.push(DrawCmd::FillRectGrad(Rect { top_left: top_left, width: width, height: height }, from, to))
+    }
+
+    pub fn circle(&mut self, center: Point, radius: i32, rgb: Rgba) -> &mut Self {
+        self.push(DrawCmd::Circle(center, radius, rgb))
+    }
+
+    pub fn spread_circle(&mut self, center: Point, radius: i32, rgb: Rgba) -> &mut Self {
+        self.push(DrawCmd::SpreadCircle(center, radius, rgb))
+    }
+
+    pub fn text(&mut self, p: Point, text: &[u8], rgb: Rgba, bg: Rgba) -> &mut Self {
+        self.push(DrawCmd::Text(p, text.to_vec(), rgb, bg))
+    }
+
+    pub fn blit(&mut self, dst: Point, src: Point, width: i32, height: i32) -> &mut Self {
+        self.push(DrawCmd::Blit(dst, src, width, height))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.cmds.clear();
+    }
+
+    pub fn iter(&self) -> ::core::slice::Iter<DrawCmd> {
+        self.cmds.iter()
+    }
+}
+
+/// area a command may touch, before clipping to screen
+fn bounds(cmd: &DrawCmd, screen_width: i32) -> Rect {
+    let span = |p1: Point, p2: Point| Rect {
+        top_left: Point::new(min(p1.x, p2.x), min(p1.y, p2.y)),
+        width: (p1.x - p2.x).abs() + 1,
+        height: (p1.y - p2.y).abs() + 1
+    };
+
+    match *cmd {
+        DrawCmd::Line(p1, p2, _) => span(p1, p2),
+        DrawCmd::Rect(r, _) | DrawCmd::FillRect(r, _) | DrawCmd::FillRectGrad(r, _, _) => r,
+        DrawCmd::Circle(c, r, _) | DrawCmd::SpreadCircle(c, r, _) => span(c.offset(-r, -r), c.offset(r, r)),
+        DrawCmd::Text(p, ref text, _, _) => {
+            let info = BUILTIN_FONTINFO;
+            let width = info.text_width(text.len());
+            if p.x + width <= screen_width {
+                Rect { top_left: p, width: width, height: info.yadvance as i32 }
+            } else {
+                // draw_str wraps to column 0 of following rows
+                let rows = (p.x + width + screen_width - 1) / screen_width;
+                Rect { top_left: Point::new(0, p.y), width: screen_width, height: rows * info.yadvance as i32 }
+            }
+        },
+        DrawCmd::Blit(dst, _, width, height) => Rect { top_left: dst, width: width, height: height },
+    }
+}
+
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    /// smallest rect covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() { return *other; }
+        if other.is_empty() { return *self; }
+
+        let (a, b) = (self.top_left, other.top_left);
+        let top_left = Point::new(min(a.x, b.x), min(a.y, b.y));
+        let right = max(a.x + self.width, b.x + other.width);
+        let bottom = max(a.y + self.height, b.y + other.height);
+        Rect { top_left: top_left, width: right - top_left.x, height: bottom - top_left.y }
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let (a, b) = (self.top_left, other.top_left);
+        let top_left = Point::new(max(a.x, b.x), max(a.y, b.y));
+        let right = min(a.x + self.width, b.x + other.width);
+        let bottom = min(a.y + self.height, b.y + other.height);
+        Rect { top_left: top_left, width: max(0, right - top_left.x), height: max(0, bottom - top_left.y) }
+    }
+}
+
+impl Framebuffer {
+    /// replay list in order, return the changed area clipped to screen,
+    /// None if nothing visible was drawn. when double buffered the whole
+    /// list is drawn off screen first and only the changed area is presented
+    pub fn execute(&mut self, list: &DisplayList) -> Option<Rect> {
+        let (w, h) = (self.width, self.height);
+        let screen = Rect { top_left: Point::new(0, 0), width: w, height: h };
+        let mut dirty = Rect { top_left: Point::new(0, 0), width: 0, height: 0 };
+
+        for cmd in list.iter() {
+            match *cmd {
+                DrawCmd::Line(p1, p2, rgb) => self.draw_line(p1, p2, rgb),
+                DrawCmd::Rect(r, rgb) => self.draw_rect(r.top_left, r.width, r.height, rgb),
+                DrawCmd::FillRect(r, rgb) => self.fill_rect(r.top_left, r.width, r.height, rgb),
+                DrawCmd::FillRectGrad(r, from, to) => self.fill_rect_grad(r.top_left, r.width, r.height, from, to),
+                DrawCmd::Circle(c, r, rgb) => self.draw_circle(c, r, rgb),
+                DrawCmd::SpreadCircle(c, r, rgb) => self.spread_circle(c, r, rgb),
+                DrawCmd::Text(p, ref text, rgb, bg) => self.draw_str(p, text, rgb, bg),
+                DrawCmd::Blit(dst, src, width, height) => self.blit_copy(dst, src, width, height),
+            }
+            dirty = dirty.union(&bounds(cmd, w).intersect(&screen));
+        }
+
+        if dirty.is_empty() {
+            return None;
+        }
+        self.present_rect(dirty);
+        Some(dirty)
+    }
+}
+
+pub fn test_present_dirty() {
+    const SENTINEL: u32 = 0x5a5a5a5a;
+    let (width, height, stride) = (8i32, 6i32, 10i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(SENTINEL); (stride * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, stride * 4) };
+    fb.with_double_buffer(true);
+
+    // drawn into the back buffer but outside any list, must stay off screen
+    fb.fill_rect(Point::new(0, 0), width, height, Rgba::new(0x1));
+
+    let mut dl = DisplayList::new();
+    dl.fill_rect(Point::new(2, 1), 3, 2, Rgba::new(0x2))
+      .fill_rect(Point::new(6, 4), 4, 4, Rgba::new(0x3));
+    let dirty = fb.execute(&dl);
+    test_assert!(dirty.is_some());
+    let d = dirty.unwrap();
+    test_assert_eq!((d.top_left.x, d.top_left.y, d.width, d.height), (2, 1, 6, 5));
+
+    for y in 0..height {
+        for x in 0..stride {
+            let inside = x >= 2 && x < 8 && y >= 1 && y < 6;
+            let expected = match (x, y) {
+                _ if !inside => SENTINEL,
+                (2...4, 1...2) => 0x2,
+                (6...7, 4...5) => 0x3,
+                _ => 0x1
+            };
+            test_assert_eq!((x, y, mem[(y * stride + x) as usize].0), (x, y, expected));
+        }
+    }
+
+    // nothing visible drawn, nothing copied
+    dl.clear();
+    dl.fill_rect(Point::new(-5, -5), 2, 2, Rgba::new(0x4));
+    test_assert!(fb.execute(&dl).is_none());
+    test_assert_eq!(mem[0].0, SENTINEL);
+}
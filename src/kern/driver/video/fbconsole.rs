@@ -0,0 +1,160 @@
+//! plain text console drawn with builtin font straight onto a framebuffer.
+//! unlike tty1 it keeps no char cells, only a cursor, so it is cheap to put
+//! on any surface, e.g. as an extra console sink (see console::add_sink).
+
+use core::fmt::{Write, Result};
+use spin::{Mutex, Once};
+
+use super::framebuffer::{Framebuffer, Point, Rgba, colors, GLYPH_WIDTH, GLYPH_HEIGHT};
+
+pub struct FbConsole {
+    fb: Framebuffer,
+    row: usize,
+    col: usize,
+    rows: usize,
+    cols: usize,
+    fg: Rgba,
+    bg: Rgba
+}
+
+impl FbConsole {
+    /// None if framebuffer can not hold a single glyph
+    pub fn new(fb: Framebuffer) -> Option<FbConsole> {
+        let cols = (fb.width / GLYPH_WIDTH) as usize;
+        let rows = (fb.height / GLYPH_HEIGHT) as usize;
+        if cols == 0 || rows == 0 {
+            return None;
+        }
+
+        Some(FbConsole {
+            fb: fb,
+            row: 0,
+            col: 0,
+            rows: rows,
+            cols: cols,
+            fg: colors::LIGHT_GRAY,
+            bg: colors::BLACK
+        })
+    }
+
+    pub fn set_colors(&mut self, fg: Rgba, bg: Rgba) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// (row, col) of cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// (rows, cols) of text area
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn clear(&mut self) {
+        let (w, h, bg) = (self.fb.width, self.fb.height, self.bg);
+        self.fb.fill_rect(Point::new(0, 0), w, h, bg);
+        self.row = 0;
+        self.col = 0;
+    }
+
+    fn cell(&self, row: usize, col: usize) -> Point {
+        Point::new(col as i32 * GLYPH_WIDTH, row as i32 * GLYPH_HEIGHT)
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            let bg = self.bg;
+            self.fb.scroll_up(1, bg);
+        }
+    }
+
+    pub fn putchar(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            0x08 => {
+                if self.col > 0 {
+                    self.col -= 1;
+                    let (p, bg) = (self.cell(self.row, self.col), self.bg);
+                    self.fb.draw_char(p, b' ', bg, bg);
+                }
+            },
+            // font has no glyph for control chars
+            0...0x1f => {},
+            _ => {
+                if self.col == self.cols {
+                    self.newline();
+                }
+                let (p, fg, bg) = (self.cell(self.row, self.col), self.fg, self.bg);
+                self.fb.draw_char(p, byte, fg, bg);
+                self.col += 1;
+            }
+        }
+    }
+}
+
+impl Write for FbConsole {
+    fn write_str(&mut self, s: &str) -> Result {
+        for b in s.bytes() {
+            self.putchar(b);
+        }
+        self.fb.present();
+        Ok(())
+    }
+}
+
+static FBCON: Once<Mutex<FbConsole>> = Once::new();
+
+/// mirror console output onto fb, false if fb is too small, a console is
+/// already registered or there is no free sink
+pub fn register(fb: Framebuffer) -> bool {
+    if FBCON.try().is_some() {
+        return false;
+    }
+
+    match FbConsole::new(fb) {
+        Some(mut con) => {
+            con.clear();
+            let con = FBCON.call_once(|| Mutex::new(con));
+            ::kern::console::add_sink(con)
+        },
+        None => false
+    }
+}
+
+pub fn test_fbconsole() {
+    use collections::Vec;
+
+    // 3 cols x 2 rows
+    let (width, height) = (GLYPH_WIDTH * 3, GLYPH_HEIGHT * 2);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0x5a5a5a5a); (width * height) as usize];
+    let fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, 0) };
+    let mut con = FbConsole::new(fb).expect("fb holds 3x2 glyphs");
+    con.set_colors(Rgba::new(0x1), Rgba::new(0x2));
+    test_assert_eq!(con.size(), (2, 3));
+
+    let _ = con.write_str("ab\x08");
+    test_assert_eq!(con.cursor(), (0, 1));
+    // erased cell is plain background
+    let px = |mem: &Vec<Rgba>, x: i32, y: i32| mem[(y * width + x) as usize].0;
+    test_assert!((0..GLYPH_HEIGHT).all(|y| (GLYPH_WIDTH..GLYPH_WIDTH * 2).all(|x| px(&mem, x, y) == 0x2)));
+
+    let _ = con.write_str("\rxyz");
+    test_assert_eq!(con.cursor(), (0, 3));
+    // wraps at right edge
+    let _ = con.write_str("w");
+    test_assert_eq!(con.cursor(), (1, 1));
+    let _ = con.write_str("\x08\x08\x08\t");
+    test_assert_eq!(con.cursor(), (1, 0));
+
+    // scrolls at the bottom, first row now holds what was second
+    let _ = con.write_str("#\n");
+    test_assert_eq!(con.cursor(), (1, 0));
+    test_assert!((0..GLYPH_HEIGHT).any(|y| (0..GLYPH_WIDTH).any(|x| px(&mem, x, y) == 0x1)));
+    test_assert!((GLYPH_HEIGHT..height).all(|y| (0..width).all(|x| px(&mem, x, y) == 0x2)));
+}
@@ -1,11 +1,14 @@
-use core::ptr::{Unique, copy_nonoverlapping, copy, write_volatile};
+use core::ptr::{Unique, copy_nonoverlapping, copy, read_volatile, write_volatile};
 use core::cmp::min;
+use core::ops::{Add, Sub};
 
 use core::slice::from_raw_parts_mut;
 use core::slice::SliceExt;
 use multiboot2;
+use collections::Vec;
 use ::kern::memory::KERNEL_MAPPING;
 use super::builtin_font::{BUILTIN_FONT, BUILTIN_FONTINFO};
+pub use super::builtin_font::{GLYPH_WIDTH, GLYPH_HEIGHT};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(packed)]
@@ -36,46 +39,281 @@ impl Rgba {
     pub const fn b(&self) -> u8 {
         self.0 as u8
     }
+
+    /// same color with alpha channel replaced
+    pub const fn with_alpha(&self, a: u8) -> Rgba {
+        Rgba((self.0 & 0x00ff_ffff) | (a as u32) << 24)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// src-over compositing of src onto dst, weighted by alpha of src:
+/// 0 keeps dst, 255 replaces it
+pub fn blend(dst: Rgba, src: Rgba) -> Rgba {
+    let a = src.a() as u32;
+    let mix = |d: u8, s: u8| ((s as u32 * a + d as u32 * (255 - a) + 127) / 255) as u8;
+    let out_a = a + (dst.a() as u32 * (255 - a) + 127) / 255;
+
+    Rgba(out_a << 24
+         | (mix(dst.r(), src.r()) as u32) << 16
+         | (mix(dst.g(), src.g()) as u32) << 8
+         | mix(dst.b(), src.b()) as u32)
+}
+
+/// named colors, laid out as 0xAARRGGBB like the 32bpp rgb framebuffer
+pub mod colors {
+    use super::Rgba;
+
+    pub const BLACK: Rgba = Rgba::new(0x000000);
+    pub const WHITE: Rgba = Rgba::new(0xffffff);
+    pub const RED: Rgba = Rgba::new(0xff0000);
+    pub const GREEN: Rgba = Rgba::new(0x00ff00);
+    pub const BLUE: Rgba = Rgba::new(0x0000ff);
+    pub const YELLOW: Rgba = Rgba::new(0xffff00);
+    pub const CYAN: Rgba = Rgba::new(0x00ffff);
+    pub const MAGENTA: Rgba = Rgba::new(0xff00ff);
+    pub const GRAY: Rgba = Rgba::new(0xbebebe);
+    pub const LIGHT_GRAY: Rgba = Rgba::new(0xd3d3d3);
+    pub const DARK_BLUE: Rgba = Rgba::new(0x0000aa);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Point {
     pub x: i32,
     pub y: i32
 }
 
+impl Point {
+    pub const fn new(x: i32, y: i32) -> Point {
+        Point {x: x, y: y}
+    }
+
+    /// point translated by (dx, dy)
+    pub fn offset(&self, dx: i32, dy: i32) -> Point {
+        Point {x: self.x + dx, y: self.y + dy}
+    }
+}
+
+impl Add for Point {
+    type Output = Point;
+    fn add(self, other: Point) -> Point {
+        Point {x: self.x + other.x, y: self.y + other.y}
+    }
+}
+
+impl Sub for Point {
+    type Output = Point;
+    fn sub(self, other: Point) -> Point {
+        Point {x: self.x - other.x, y: self.y - other.y}
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rect {
+    pub top_left: Point,
+    pub width: i32,
+    pub height: i32
+}
+
+/// fill strategies compared by bench_fill
+#[cfg(feature = "prof")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillMethod {
+    /// one volatile store per pixel, the baseline
+    PerPixel,
+    /// fill_rect, block copy of rows
+    RowCopy,
+    /// two pixels per 64-bit store
+    Words,
+    /// `rep stosl` per row, the memset of this kernel. there is no SSE path
+    /// to compare: kernel is built without SSE and never sets CR4.OSFXSR.
+    RepStos,
+}
+
+const BYTES_PER_PIXEL: i32 = 4;
+
+/// rows may be padded, so pitch (bytes per row) can exceed width pixels.
+/// pixel (x, y) lives at byte y * pitch + x * BYTES_PER_PIXEL.
+/// with double buffering on, drawing goes to a heap copy with the same
+/// layout and reaches video memory only at present().
+/// drawing outside clip (whole surface by default) is silently dropped.
 pub struct Framebuffer {
     buf: Unique<Rgba>,
+    back: Option<Vec<Rgba>>,
+    clip: Rect,
     pub width: i32,
     pub height: i32,
     pub pitch: i32
 }
 
+/// (width, pitch) safe to draw with: zero pitch means unpadded rows, and a
+/// pitch too short for width clips width, so drawing never crosses a row
+fn checked_pitch(width: i32, pitch: i32) -> (i32, i32) {
+    use ::kern::console::LogLevel::*;
+
+    if pitch == 0 {
+        printk!(Warn, "framebuffer: zero pitch, assume {} bytes per row\n\r", width * BYTES_PER_PIXEL);
+        (width, width * BYTES_PER_PIXEL)
+    } else if pitch < width * BYTES_PER_PIXEL {
+        printk!(Warn, "framebuffer: pitch {} too short for width {}, clip\n\r", pitch, width);
+        (pitch / BYTES_PER_PIXEL, pitch)
+    } else {
+        (width, pitch)
+    }
+}
+
+fn surface(width: i32, height: i32) -> Rect {
+    Rect { top_left: Point::new(0, 0), width: width, height: height }
+}
+
 impl Framebuffer {
     pub fn new(fb: &multiboot2::FramebufferTag) -> Framebuffer {
         assert!(fb.frame_type == multiboot2::FramebufferType::Rgb);
         assert!(fb.bpp == 32);
 
         let base = fb.addr as usize + KERNEL_MAPPING.KernelMap.start;
+        let (width, pitch) = checked_pitch(fb.width as i32, fb.pitch as i32);
 
         unsafe {
             Framebuffer {
                 buf: Unique::new_unchecked(base as *mut Rgba),
-                width: fb.width as i32,
+                back: None,
+                clip: surface(width, fb.height as i32),
+                width: width,
                 height: fb.height as i32,
-                pitch: fb.pitch as i32
+                pitch: pitch
             }
         }
     }
 
+    /// framebuffer over plain memory of width x height pixels, rows pitch
+    /// bytes apart (0 for unpadded rows), for tests
+    pub unsafe fn from_raw(buf: *mut Rgba, width: i32, height: i32, pitch: i32) -> Framebuffer {
+        let (width, pitch) = checked_pitch(width, pitch);
+        Framebuffer {
+            buf: Unique::new_unchecked(buf),
+            back: None,
+            clip: surface(width, height),
+            width: width,
+            height: height,
+            pitch: pitch
+        }
+    }
+
+    /// another handle to the same video memory, for writers that can not
+    /// wait for the console lock (panic screen). drawing through both races.
+    /// the alias always draws directly, back buffer of self is not shared.
+    pub unsafe fn alias(&self) -> Framebuffer {
+        Framebuffer {
+            buf: Unique::new_unchecked(self.buf.as_ptr()),
+            back: None,
+            clip: surface(self.width, self.height),
+            width: self.width,
+            height: self.height,
+            pitch: self.pitch
+        }
+    }
+
+    /// memory drawing goes to: back buffer if enabled, else video memory
     pub unsafe fn get_mut(&mut self) -> *mut Rgba {
-        self.buf.as_mut() as *mut _
+        match self.back {
+            Some(ref mut back) => back.as_mut_ptr(),
+            None => self.buf.as_mut() as *mut _
+        }
+    }
+
+    /// pixels covered by pitch * height bytes
+    fn size_in_pixels(&self) -> usize {
+        (self.pitch / BYTES_PER_PIXEL) as usize * self.height as usize
+    }
+
+    /// turn drawing into a back buffer on or off. enabling seeds the back
+    /// buffer from video memory, disabling presents what was drawn so far.
+    pub fn with_double_buffer(&mut self, enable: bool) {
+        match (enable, self.back.is_some()) {
+            (true, false) => {
+                let n = self.size_in_pixels();
+                let mut back = Vec::with_capacity(n);
+                unsafe {
+                    copy_nonoverlapping(self.buf.as_ptr() as *const Rgba, back.as_mut_ptr(), n);
+                    back.set_len(n);
+                }
+                self.back = Some(back);
+            },
+            (false, true) => {
+                self.present();
+                self.back = None;
+            },
+            _ => {}
+        }
+    }
+
+    pub fn double_buffered(&self) -> bool {
+        self.back.is_some()
+    }
+
+    /// copy whole back buffer to video memory, no-op when drawing directly
+    pub fn present(&mut self) {
+        let all = surface(self.width, self.height);
+        self.present_rect(all);
+    }
+
+    /// copy only area r of back buffer to video memory, row by row
+    pub fn present_rect(&mut self, r: Rect) {
+        let r = r.intersect(&surface(self.width, self.height));
+        if r.is_empty() {
+            return;
+        }
+
+        let vram = self.buf.as_ptr();
+        let stride = (self.pitch / BYTES_PER_PIXEL) as isize;
+        if let Some(ref back) = self.back {
+            for y in r.top_left.y..r.top_left.y + r.height {
+                let off = y as isize * stride + r.top_left.x as isize;
+                unsafe {
+                    copy_nonoverlapping(back.as_ptr().offset(off), vram.offset(off), r.width as usize);
+                }
+            }
+        }
+    }
+
+    /// confine drawing to clip, cut to the surface
+    pub fn set_clip(&mut self, clip: Rect) {
+        self.clip = clip.intersect(&surface(self.width, self.height));
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// part of rect inside clip, may be empty
+    fn clipped(&self, top_left: Point, width: i32, height: i32) -> Rect {
+        Rect { top_left: top_left, width: width, height: height }.intersect(&self.clip)
+    }
+
+    fn in_clip(&self, p: Point) -> bool {
+        let c = self.clip;
+        p.x >= c.top_left.x && p.x < c.top_left.x + c.width &&
+            p.y >= c.top_left.y && p.y < c.top_left.y + c.height
+    }
+
+    /// address of pixel (x, y), no bounds check
+    unsafe fn pixel_ptr(&mut self, x: i32, y: i32) -> *mut Rgba {
+        (self.get_mut() as *mut u8).offset((y * self.pitch + x * BYTES_PER_PIXEL) as isize) as *mut Rgba
     }
 
     //TODO: optimize situation when dy == 0
     //TODO: add anti-aliasing based on xiaolin wu's algorithm
     // based on wikipedia bresenham line algorithm
     pub fn draw_line(&mut self, p1: Point, p2: Point, rgb: Rgba) {
+        self.plot_line(p1, p2, |fb, p| fb.draw_pixel(p, rgb));
+    }
+
+    /// draw_line blending rgb over existing pixels by its alpha
+    pub fn draw_line_alpha(&mut self, p1: Point, p2: Point, rgb: Rgba) {
+        self.plot_line(p1, p2, |fb, p| fb.blend_pixel(p, rgb));
+    }
+
+    fn plot_line<F: FnMut(&mut Framebuffer, Point)>(&mut self, p1: Point, p2: Point, mut plot: F) {
         let dx = (p2.x - p1.x).abs();
         let dy = (p2.y - p1.y).abs();
         let mut e = 0;
@@ -88,7 +326,7 @@ impl Framebuffer {
             let dir = if p2.y >= p1.y {1} else {-1};
 
             for x in p1.x..p2.x+1 {
-                self.draw_pixel(Point{x: x, y: y}, rgb);
+                plot(self, Point::new(x, y));
                 e += 2 * dy;
                 if e > 1 {
                     e -= 2 * dx;
@@ -104,7 +342,7 @@ impl Framebuffer {
             let dir = if p2.x >= p1.x {1} else {-1};
 
             for y in p1.y..p2.y+1 {
-                self.draw_pixel(Point{x: x, y: y}, rgb);
+                plot(self, Point::new(x, y));
                 e += 2 * dx;
                 if e > 1 {
                     e -= 2 * dy;
@@ -115,15 +353,27 @@ impl Framebuffer {
     }
 
     fn draw_pixel(&mut self, p: Point, rgb: Rgba) {
+        if !self.in_clip(p) {
+            return;
+        }
         unsafe {
-            let c = self.get_mut().offset((p.y * self.width as i32 + p.x) as isize);
+            let c = self.pixel_ptr(p.x, p.y);
             write_volatile(c, rgb);
         }
     }
 
+    fn blend_pixel(&mut self, p: Point, rgb: Rgba) {
+        if !self.in_clip(p) {
+            return;
+        }
+        unsafe {
+            let c = self.pixel_ptr(p.x, p.y);
+            write_volatile(c, blend(read_volatile(c), rgb));
+        }
+    }
+
     // based on http://web.engr.oregonstate.edu/~sllu/bcircle.pdf
     pub fn draw_circle(&mut self, center: Point, radius: i32, rgb: Rgba) {
-        let Point {x: x0, y: y0} = center;
         let mut x = radius;
         let mut y = 0;
         let mut err = 0;
@@ -131,14 +381,14 @@ impl Framebuffer {
         let mut ychange = 1;
 
         while x >= y {
-            self.draw_pixel(Point{x: x0 + x, y: y0 + y}, rgb);
-            self.draw_pixel(Point{x: x0 + y, y: y0 + x}, rgb);
-            self.draw_pixel(Point{x: x0 - y, y: y0 + x}, rgb);
-            self.draw_pixel(Point{x: x0 - x, y: y0 + y}, rgb);
-            self.draw_pixel(Point{x: x0 - x, y: y0 - y}, rgb);
-            self.draw_pixel(Point{x: x0 - y, y: y0 - x}, rgb);
-            self.draw_pixel(Point{x: x0 + y, y: y0 - x}, rgb);
-            self.draw_pixel(Point{x: x0 + x, y: y0 - y}, rgb);
+            self.draw_pixel(center.offset(x, y), rgb);
+            self.draw_pixel(center.offset(y, x), rgb);
+            self.draw_pixel(center.offset(-y, x), rgb);
+            self.draw_pixel(center.offset(-x, y), rgb);
+            self.draw_pixel(center.offset(-x, -y), rgb);
+            self.draw_pixel(center.offset(-y, -x), rgb);
+            self.draw_pixel(center.offset(y, -x), rgb);
+            self.draw_pixel(center.offset(x, -y), rgb);
 
             y += 1;
             err += ychange;
@@ -152,7 +402,6 @@ impl Framebuffer {
     }
 
     pub fn spread_circle(&mut self, center: Point, radius: i32, rgb: Rgba) {
-        let Point {x: x0, y: y0} = center;
         let mut x = radius;
         let mut y = 0;
         let mut err = 0;
@@ -160,14 +409,14 @@ impl Framebuffer {
         let mut ychange = 1;
 
         while x >= y {
-            self.draw_line(center, Point{x: x0 + x, y: y0 + y}, rgb);
-            self.draw_line(center, Point{x: x0 + y, y: y0 + x}, rgb);
-            self.draw_line(center, Point{x: x0 - y, y: y0 + x}, rgb);
-            self.draw_line(center, Point{x: x0 - x, y: y0 + y}, rgb);
-            self.draw_line(center, Point{x: x0 - x, y: y0 - y}, rgb);
-            self.draw_line(center, Point{x: x0 - y, y: y0 - x}, rgb);
-            self.draw_line(center, Point{x: x0 + y, y: y0 - x}, rgb);
-            self.draw_line(center, Point{x: x0 + x, y: y0 - y}, rgb);
+            self.draw_line(center, center.offset(x, y), rgb);
+            self.draw_line(center, center.offset(y, x), rgb);
+            self.draw_line(center, center.offset(-y, x), rgb);
+            self.draw_line(center, center.offset(-x, y), rgb);
+            self.draw_line(center, center.offset(-x, -y), rgb);
+            self.draw_line(center, center.offset(-y, -x), rgb);
+            self.draw_line(center, center.offset(y, -x), rgb);
+            self.draw_line(center, center.offset(x, -y), rgb);
 
             y += 1;
             err += ychange;
@@ -181,15 +430,13 @@ impl Framebuffer {
     }
 
     pub fn draw_rect(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) {
-        use core::cmp::min;
-        let width = min(self.width - top_left.x, width);
-        let height = min(self.height - top_left.y, height);
-
-        let (l, r, t, b) = (top_left.x, top_left.x + width - 1, top_left.y, top_left.y + height - 1);
-        self.draw_line(Point{x: l, y: t}, Point{x: r, y: t}, rgb);
-        self.draw_line(Point{x: l, y: t}, Point{x: l, y: b}, rgb);
-        self.draw_line(Point{x: r, y: t}, Point{x: r, y: b}, rgb);
-        self.draw_line(Point{x: l, y: b}, Point{x: r, y: b}, rgb);
+        let top_right = top_left.offset(width - 1, 0);
+        let bottom_left = top_left.offset(0, height - 1);
+        let bottom_right = top_left.offset(width - 1, height - 1);
+        self.draw_line(top_left, top_right, rgb);
+        self.draw_line(top_left, bottom_left, rgb);
+        self.draw_line(top_right, bottom_right, rgb);
+        self.draw_line(bottom_left, bottom_right, rgb);
     }
 
     pub fn fill_rect_grad(&mut self, top_left: Point, width: i32, height: i32,
@@ -213,23 +460,21 @@ impl Framebuffer {
         }
 
         use core::ptr::copy_nonoverlapping;
-        use core::cmp::min;
 
-        let width = min(self.width - top_left.x, width);
-        let height = min(self.height - top_left.y, height);
+        // colors follow rows of the whole rect, only clipped rows are drawn
+        let r = self.clipped(top_left, width, height);
+        let width = r.width;
 
-        let mut clr = from;
-
-        let base = (top_left.y * self.width) as isize;
         // our kernel stack is big enough for this whole block of data
-        for i in 0..height {
+        for y in r.top_left.y..r.top_left.y + r.height {
+            let clr = interpolate_color(y - top_left.y, from, to, height);
             let data = &[clr; 64];
-            let mut off = base + (i * self.width) as isize + top_left.x as isize;
+            let row = unsafe { self.pixel_ptr(r.top_left.x, y) };
             let mut w = width;
             while w >= 64 {
                 unsafe {
                     copy_nonoverlapping(data,
-                        self.get_mut().offset(off + (width - w) as isize) as *mut _,
+                        row.offset((width - w) as isize) as *mut _,
                         1);
                 }
                 w -= 64;
@@ -237,54 +482,60 @@ impl Framebuffer {
             if w > 0 {
                 unsafe {
                     copy_nonoverlapping(data.as_ptr(),
-                        self.get_mut().offset(off + (width - w) as isize) as *mut Rgba,
+                        row.offset((width - w) as isize) as *mut Rgba,
                         w as usize);
                 }
             }
-
-            clr = interpolate_color(i, from, to, height);
         }
     }
 
-    // should do sanity check
+    /// copy area from src to dst. dst is clipped and src is cut to the
+    /// surface, the matching part of the other side is dropped with it.
     pub fn blit_copy(&mut self, dst: Point, src: Point, width: i32, height: i32) {
-        let width = min(self.width - src.x, width);
-        let height = min(self.height - src.y, height);
-
-        assert!((src.y + height - 1) < self.height);
-        assert!((dst.y + height - 1) < self.height);
+        let d = self.clipped(dst, width, height);
+        let src = src + (d.top_left - dst);
+        let s = Rect { top_left: src, width: d.width, height: d.height }
+            .intersect(&surface(self.width, self.height));
+        let dst = d.top_left + (s.top_left - src);
+        let (src, width, height) = (s.top_left, s.width, s.height);
+
+        if s.is_empty() {
+            return;
+        }
 
-        let (dir, mut base, mut dst_base) = match src.y > dst.y {
-            true => (1, src.y * self.width + src.x, dst.y * self.width + dst.x),
-            false => (-1, (src.y + height - 1) * self.width + src.x,
-                (dst.y + height - 1) * self.width + dst.x),
+        // rows must not be overwritten before they are read
+        let (dir, mut sy, mut dy) = match src.y > dst.y {
+            true => (1, src.y, dst.y),
+            false => (-1, src.y + height - 1, dst.y + height - 1),
         };
 
-        for i in 0..height {
+        for _ in 0..height {
             unsafe {
-                copy_nonoverlapping(self.get_mut().offset(base  as isize),
-                    self.get_mut().offset(dst_base as isize),
+                copy_nonoverlapping(self.pixel_ptr(src.x, sy),
+                    self.pixel_ptr(dst.x, dy),
                     width as usize);
-                base += self.width * dir;
-                dst_base += self.width * dir;
             }
+            sy += dir;
+            dy += dir;
         }
     }
 
     pub fn fill_rect(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) {
-        let width = min(self.width - top_left.x, width);
-        let height = min(self.height - top_left.y, height);
+        let r = self.clipped(top_left, width, height);
+        if r.is_empty() {
+            return;
+        }
+        let (top_left, width, height) = (r.top_left, r.width, r.height);
 
-        let base = (top_left.y * self.width) as isize;
         // our kernel stack is big enough for this whole block of data
         let data = &[rgb; 256];
         {
-            let mut off = base + top_left.x as isize;
+            let row = unsafe { self.pixel_ptr(top_left.x, top_left.y) };
             let mut w = width;
             while w >= 256 {
                 unsafe {
                     copy_nonoverlapping(data,
-                        self.get_mut().offset(off + (width - w) as isize) as *mut _,
+                        row.offset((width - w) as isize) as *mut _,
                         1);
                 }
                 w -= 256;
@@ -293,33 +544,131 @@ impl Framebuffer {
             if w > 0 {
                 unsafe {
                     copy_nonoverlapping(data.as_ptr(),
-                        self.get_mut().offset(off + (width - w) as isize) as *mut Rgba,
+                        row.offset((width - w) as isize) as *mut Rgba,
                         w as usize);
                 }
             }
         }
 
         for i in 1..height {
-            let mut off = base + ((i-1) * self.width) as isize + top_left.x as isize;
             unsafe {
                 copy_nonoverlapping(
-                    self.get_mut().offset(off) as *mut Rgba,
-                    self.get_mut().offset(off + self.width as isize) as *mut Rgba,
+                    self.pixel_ptr(top_left.x, top_left.y + i - 1),
+                    self.pixel_ptr(top_left.x, top_left.y + i),
                     width as usize);
             }
         }
     }
 
-    pub fn draw_char(&mut self, p: Point, c: u8, rgb: Rgba, bg: Rgba) {
-        let base = (p.y * self.width + p.x) as isize;
+    /// move whole surface up by lines text lines of GLYPH_HEIGHT rows and
+    /// fill the rows exposed at the bottom with bg. clip is not applied,
+    /// use blit_copy to scroll a region.
+    pub fn scroll_up(&mut self, lines: usize, bg: Rgba) {
+        let rows = min(lines.saturating_mul(GLYPH_HEIGHT as usize), self.height as usize) as i32;
+        if rows == 0 {
+            return;
+        }
+
+        let kept = self.height - rows;
+        unsafe {
+            // one overlapping move of all kept rows, padding included
+            copy(self.pixel_ptr(0, rows) as *const u8, self.pixel_ptr(0, 0) as *mut u8,
+                 (kept * self.pitch) as usize);
+        }
+
+        let clip = self.clip;
+        self.clip = surface(self.width, self.height);
+        self.fill_rect(Point::new(0, kept), self.width, rows, bg);
+        self.clip = clip;
+    }
+
+    /// fill_rect blending rgb over existing pixels by its alpha. every pixel
+    /// is read back, so this is much slower than fill_rect.
+    pub fn fill_rect_alpha(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) {
+        let r = self.clipped(top_left, width, height);
+
+        for y in r.top_left.y..r.top_left.y + r.height {
+            for x in r.top_left.x..r.top_left.x + r.width {
+                self.blend_pixel(Point::new(x, y), rgb);
+            }
+        }
+    }
+
+    /// run `iterations` fills of rect (clipped), return elapsed tsc cycles
+    #[cfg(feature = "prof")]
+    pub fn bench_fill(&mut self, rect: Rect, rgb: Rgba, iterations: usize, method: FillMethod) -> u64 {
+        use ::kern::arch::cpu::rdtsc;
+
+        // clip never reaches out of surface, so a negative top_left or a
+        // rect beyond the edges is cut here and no pixel_ptr below is off
+        let r = self.clipped(rect.top_left, rect.width, rect.height);
+        if r.is_empty() {
+            return 0;
+        }
+        let (x, y, width, height) = (r.top_left.x, r.top_left.y, r.width, r.height);
+
+        let start = rdtsc();
+        for _ in 0..iterations {
+            match method {
+                FillMethod::PerPixel => {
+                    for i in y..y+height {
+                        for j in x..x+width {
+                            unsafe {
+                                write_volatile(self.pixel_ptr(j, i), rgb);
+                            }
+                        }
+                    }
+                },
+                FillMethod::RowCopy => self.fill_rect(Point::new(x, y), width, height, rgb),
+                FillMethod::Words => {
+                    let pair = (rgb.0 as u64) << 32 | rgb.0 as u64;
+                    for i in y..y+height {
+                        unsafe {
+                            let mut p = self.pixel_ptr(x, i);
+                            let mut n = width as isize;
+                            // an odd first pixel would leave every word unaligned
+                            if (p as usize) % 8 != 0 {
+                                write_volatile(p, rgb);
+                                p = p.offset(1);
+                                n -= 1;
+                            }
+                            let words = p as *mut u64;
+                            for k in 0..n / 2 {
+                                write_volatile(words.offset(k), pair);
+                            }
+                            if n % 2 == 1 {
+                                write_volatile(p.offset(n - 1), rgb);
+                            }
+                        }
+                    }
+                },
+                FillMethod::RepStos => {
+                    for i in y..y+height {
+                        unsafe {
+                            let p = self.pixel_ptr(x, i);
+                            let (_dst, _count): (usize, usize);
+                            asm!("rep stosl"
+                                 : "={rdi}"(_dst), "={rcx}"(_count)
+                                 : "0"(p as usize), "1"(width as usize), "{eax}"(rgb.0)
+                                 : "memory"
+                                 : "volatile");
+                        }
+                    }
+                },
+            }
+        }
+        rdtsc() - start
+    }
 
+    pub fn draw_char(&mut self, p: Point, c: u8, rgb: Rgba, bg: Rgba) {
         let glyph = BUILTIN_FONT[c as usize - 1];
-        for i in 0..16 {
-            let off = base + (i * self.width) as isize;
-            for j in 0..8 {
+        let r = self.clipped(p, GLYPH_WIDTH, GLYPH_HEIGHT);
+        for i in r.top_left.y - p.y..r.top_left.y - p.y + r.height {
+            let row = unsafe { self.pixel_ptr(p.x, p.y + i) };
+            for j in r.top_left.x - p.x..r.top_left.x - p.x + r.width {
                 unsafe {
-                    let idx = (i*8+j) as usize;
-                    *self.get_mut().offset(off+j as isize) = match glyph[idx] {
+                    let idx = (i*GLYPH_WIDTH+j) as usize;
+                    *row.offset(j as isize) = match glyph[idx] {
                         b'*' => rgb,
                         _ => bg,
                     };
@@ -340,5 +689,179 @@ impl Framebuffer {
             }
         }
     }
+
+    /// draw one line of text horizontally centered at row y,
+    /// text gets truncated if wider than framebuffer
+    pub fn draw_str_centered(&mut self, y: i32, text: &[u8], rgb: Rgba, bg: Rgba) {
+        let info = BUILTIN_FONTINFO;
+        let text = &text[..min(text.len(), info.chars_fit(self.width))];
+        let x = (self.width - info.text_width(text.len())) / 2;
+        self.draw_str(Point::new(x, y), text, rgb, bg);
+    }
+
+    /// draw one line of text with its right edge at x_right (exclusive),
+    /// leading chars are truncated if there is no room on the left
+    pub fn draw_str_right(&mut self, x_right: i32, y: i32, text: &[u8], rgb: Rgba, bg: Rgba) {
+        let info = BUILTIN_FONTINFO;
+        let x_right = min(x_right, self.width);
+        if x_right <= 0 {
+            return;
+        }
+
+        let n = min(text.len(), info.chars_fit(x_right));
+        let text = &text[text.len() - n..];
+        let x = x_right - info.text_width(n);
+        self.draw_str(Point::new(x, y), text, rgb, bg);
+    }
 }
 
+pub fn test_framebuffer_pitch() {
+    use collections::Vec;
+
+    const SENTINEL: u32 = 0x5a5a5a5a;
+    // each row padded by 6 pixels
+    let (width, height, stride) = (10i32, 4i32, 16i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(SENTINEL); (stride * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, stride * BYTES_PER_PIXEL) };
+
+    fb.fill_rect(Point::new(0, 0), width, height, Rgba::new(0x1));
+    fb.draw_pixel(Point::new(2, 3), Rgba::new(0x2));
+    fb.fill_rect(Point::new(0, 1), width, 1, Rgba::new(0x3));
+    fb.blit_copy(Point::new(0, 2), Point::new(0, 1), width, 1);
+
+    for y in 0..height {
+        for x in 0..stride {
+            let px = mem[(y * stride + x) as usize].0;
+            let expected = match (x, y) {
+                (x, _) if x >= width => SENTINEL,
+                (2, 3) => 0x2,
+                (_, 1) | (_, 2) => 0x3,
+                _ => 0x1
+            };
+            test_assert_eq!((x, y, px), (x, y, expected));
+        }
+    }
+
+    // zero pitch falls back to unpadded rows
+    let fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, 0) };
+    test_assert_eq!(fb.pitch, width * BYTES_PER_PIXEL);
+}
+
+pub fn test_double_buffer() {
+    const SENTINEL: u32 = 0x5a5a5a5a;
+    let (width, height, stride) = (6i32, 3i32, 8i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(SENTINEL); (stride * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, stride * BYTES_PER_PIXEL) };
+
+    fb.fill_rect(Point::new(0, 0), width, height, Rgba::new(0x1));
+    fb.with_double_buffer(true);
+    test_assert!(fb.double_buffered());
+    fb.fill_rect(Point::new(0, 1), width, 1, Rgba::new(0x2));
+    // nothing reaches video memory before present
+    test_assert!(mem.iter().all(|px| px.0 != 0x2));
+
+    fb.present();
+    for y in 0..height {
+        for x in 0..stride {
+            let px = mem[(y * stride + x) as usize].0;
+            let expected = match (x, y) {
+                (x, _) if x >= width => SENTINEL,
+                (_, 1) => 0x2,
+                _ => 0x1
+            };
+            test_assert_eq!((x, y, px), (x, y, expected));
+        }
+    }
+
+    // turning it off flushes pending drawing, then draws directly again
+    fb.draw_pixel(Point::new(0, 0), Rgba::new(0x3));
+    fb.with_double_buffer(false);
+    test_assert_eq!(mem[0].0, 0x3);
+    fb.draw_pixel(Point::new(1, 0), Rgba::new(0x4));
+    test_assert_eq!(mem[1].0, 0x4);
+}
+
+pub fn test_alpha_blend() {
+    let dst = Rgba::new(0xff10_2030);
+    let src = Rgba::new(0x00f0_e0d0);
+
+    // transparent source keeps dst, opaque one replaces it
+    test_assert_eq!(blend(dst, src.with_alpha(0)).0, dst.0);
+    test_assert_eq!(blend(dst, src.with_alpha(255)).0, src.with_alpha(255).0);
+    // half way, rounded to nearest
+    test_assert_eq!(blend(Rgba::new(0xff00_0000), Rgba::new(0x80ff_80ff)).0, 0xff80_4080);
+    test_assert_eq!(blend(Rgba::new(0x0000_0000), Rgba::new(0x80ff_ffff)).0, 0x8080_8080);
+
+    let (width, height) = (4i32, 2i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0xff00_0000); (width * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, 0) };
+    fb.fill_rect_alpha(Point::new(0, 0), width, 1, Rgba::new(0x80ff_ffff));
+    fb.draw_line_alpha(Point::new(0, 1), Point::new(1, 1), Rgba::new(0x0000_ff00));
+    test_assert!(mem[..4].iter().all(|px| px.0 == 0xff80_8080));
+    test_assert!(mem[4..].iter().all(|px| px.0 == 0xff00_0000));
+}
+
+pub fn test_framebuffer_clip() {
+    let (width, height) = (8i32, 8i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0); (width * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, 0) };
+    fb.with_double_buffer(true);
+    fb.set_clip(Rect { top_left: Point::new(2, 2), width: 3, height: 3 });
+
+    let drawn = |fb: &Framebuffer| {
+        fb.back.as_ref().unwrap().iter().filter(|px| px.0 != 0).count()
+    };
+
+    fb.fill_rect(Point::new(5, 0), 3, 8, Rgba::new(0x1));
+    fb.fill_rect(Point::new(-4, -4), 6, 6, Rgba::new(0x1));
+    fb.draw_line(Point::new(0, 7), Point::new(530, 7), Rgba::new(0x1));
+    fb.blit_copy(Point::new(5, 5), Point::new(2, 2), 3, 3);
+    test_assert_eq!(drawn(&fb), 0);
+
+    // partly covered rect is cut to the clip
+    fb.fill_rect(Point::new(0, 0), 4, 4, Rgba::new(0x2));
+    test_assert_eq!(drawn(&fb), 4);
+    fb.draw_char(Point::new(0, 0), b'#', Rgba::new(0x3), Rgba::new(0x3));
+    test_assert_eq!(drawn(&fb), 9);
+
+    // clip never reaches beyond the surface
+    fb.set_clip(Rect { top_left: Point::new(-10, -10), width: 100, height: 100 });
+    test_assert_eq!((fb.clip().width, fb.clip().height), (width, height));
+    fb.draw_line(Point::new(-5, 0), Point::new(530, 0), Rgba::new(0x4));
+    test_assert_eq!(fb.back.as_ref().unwrap()[..width as usize].iter().filter(|px| px.0 == 0x4).count(),
+                    width as usize);
+}
+
+pub fn test_chars_fit() {
+    let info = BUILTIN_FONTINFO;
+    test_assert_eq!(info.chars_fit(-1), 0);
+    test_assert_eq!(info.chars_fit(::core::i32::MIN), 0);
+    test_assert_eq!(info.chars_fit(info.xadvance as i32 - 1), 0);
+    test_assert_eq!(info.chars_fit(info.xadvance as i32 * 3), 3);
+}
+
+pub fn test_scroll_up() {
+    let (width, height, stride) = (3i32, GLYPH_HEIGHT * 2 + 4, 4i32);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(0); (stride * height) as usize];
+    let mut fb = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), width, height, stride * BYTES_PER_PIXEL) };
+    for y in 0..height {
+        fb.fill_rect(Point::new(0, y), width, 1, Rgba::new(y as u32 + 1));
+    }
+    // scrolling ignores clip
+    fb.set_clip(Rect { top_left: Point::new(0, 0), width: 1, height: 1 });
+
+    fb.scroll_up(1, Rgba::new(0xff));
+    for y in 0..height {
+        for x in 0..width {
+            let expected = match y + GLYPH_HEIGHT {
+                src if src < height => src as u32 + 1,
+                _ => 0xff
+            };
+            test_assert_eq!((x, y, mem[(y * stride + x) as usize].0), (x, y, expected));
+        }
+    }
+    test_assert_eq!(fb.clip().width, 1);
+
+    fb.scroll_up(10, Rgba::new(0xee));
+    test_assert!((0..height).all(|y| (0..width).all(|x| mem[(y * stride + x) as usize].0 == 0xee)));
+}
@@ -1,4 +1,8 @@
 pub mod framebuffer;
 pub mod builtin_font;
 pub mod terminal;
-pub use self::framebuffer::{Framebuffer, Point, Rgba};
+pub mod panic_screen;
+pub mod display_list;
+pub mod fbconsole;
+pub use self::framebuffer::{Framebuffer, Point, Rect, Rgba, colors};
+pub use self::display_list::{DisplayList, DrawCmd};
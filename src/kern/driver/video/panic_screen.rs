@@ -0,0 +1,149 @@
+//! full screen failure report painted straight into framebuffer, it never
+//! takes the console lock so it works even if the panic happened inside it.
+//! everything drawn is mirrored to serial.
+
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, Point, Rgba, colors};
+use super::builtin_font::{BUILTIN_FONTINFO, FontInfo};
+use ::kern::driver::serial;
+use ::kern::interrupts::idt::ExceptionStackFrame;
+use ::kern::arch::cpu;
+use ::kern::memory::paging::ActivePML4Table;
+use ::kern::task;
+
+const BG: Rgba = colors::DARK_BLUE;
+const FG: Rgba = colors::WHITE;
+const MARGIN: i32 = 16;
+const MAX_FRAMES: usize = 16;
+
+static EMERGENCY_FB: Mutex<Option<Framebuffer>> = Mutex::new(None);
+
+/// remember framebuffer for panic screen, before it's handed to console
+pub unsafe fn register(fb: &Framebuffer) {
+    *EMERGENCY_FB.lock() = Some(fb.alias());
+}
+
+struct FbWriter<'a> {
+    fb: &'a mut Framebuffer,
+    pos: Point,
+}
+
+impl<'a> FbWriter<'a> {
+    fn newline(&mut self) {
+        self.pos.x = MARGIN;
+        self.pos.y += BUILTIN_FONTINFO.yadvance as i32;
+    }
+
+    fn putc(&mut self, b: u8) {
+        let FontInfo {xadvance: fw, yadvance: fh} = BUILTIN_FONTINFO;
+        let (fw, fh) = (fw as i32, fh as i32);
+
+        match b {
+            b'\n' => self.newline(),
+            b'\r' => {},
+            _ => {
+                if self.pos.x + fw > self.fb.width - MARGIN {
+                    self.newline();
+                }
+                // out of screen, serial still gets it
+                if self.pos.y + fh > self.fb.height {
+                    return;
+                }
+                let c = if b >= 0x20 && b < 0x7f { b } else { b'?' };
+                let p = self.pos;
+                self.fb.draw_char(p, c, FG, BG);
+                self.pos.x += fw;
+            }
+        }
+    }
+}
+
+impl<'a> Write for FbWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { serial::emergency_write(s); }
+        for b in s.bytes() {
+            self.putc(b);
+        }
+        Ok(())
+    }
+}
+
+/// paint the panic screen, return false if there is no framebuffer (or it
+/// is being painted already), caller should fall back to console then.
+pub fn show(title: &str, msg: fmt::Arguments, location: Option<(&str, u32)>,
+            frame: Option<&ExceptionStackFrame>) -> bool {
+    let mut guard = match EMERGENCY_FB.try_lock() {
+        Some(guard) => guard,
+        None => return false
+    };
+    let fb = match *guard {
+        Some(ref mut fb) => fb,
+        None => return false
+    };
+
+    let (width, height) = (fb.width, fb.height);
+    fb.fill_rect(Point::new(0, 0), width, height, BG);
+    fb.draw_str_centered(MARGIN, title.as_bytes(), FG, BG);
+    unsafe { serial::emergency_write("\n\r"); serial::emergency_write(title); }
+
+    let mut w = FbWriter {
+        fb: fb,
+        pos: Point::new(MARGIN, MARGIN + 2 * BUILTIN_FONTINFO.yadvance as i32)
+    };
+    let _ = write!(w, "\n\r{}\n\r", msg);
+    if let Some((file, line)) = location {
+        let _ = write!(w, "at {}:{}\n\r", file, line);
+    }
+    let _ = write!(w, "tid: {}\n\r\n\r", task::current_id());
+
+    dump_registers(&mut w, frame);
+    let _ = write!(w, "\n\r");
+    unsafe { backtrace(&mut w); }
+    true
+}
+
+fn dump_registers(w: &mut FbWriter, frame: Option<&ExceptionStackFrame>) {
+    let (rsp, rbp, rflags): (usize, usize, usize);
+    unsafe {
+        asm!("movq %rsp, $0" : "=r"(rsp) ::: "volatile");
+        asm!("movq %rbp, $0" : "=r"(rbp) ::: "volatile");
+        asm!("pushfq; popq $0" : "=r"(rflags) :: "memory" : "volatile");
+    }
+
+    if let Some(f) = frame {
+        let _ = write!(w, "rip: {:#018x}  cs: {:#06x}  rflags: {:#018x}\n\r", f.rip, f.cs, f.rflags);
+        let _ = write!(w, "rsp: {:#018x}  ss: {:#06x}\n\r", f.old_rsp, f.old_ss);
+    }
+    let _ = write!(w, "cur rsp: {:#018x}  rbp: {:#018x}  rflags: {:#018x}\n\r", rsp, rbp, rflags);
+    let _ = write!(w, "cr2: {:#018x}  cr3: {:#018x}  cr0: {:#018x}\n\r", cpu::cr2(), cpu::cr3(), cpu::cr0());
+}
+
+/// symbols come from kernel elf module, raw addresses otherwise
+unsafe fn backtrace(w: &mut FbWriter) {
+    let mut rbp: usize;
+    asm!("" : "={rbp}"(rbp) : : : "volatile");
+
+    let _ = write!(w, "backtrace:\n\r");
+    let active_table = ActivePML4Table::new();
+    for _ in 0..MAX_FRAMES {
+        let rip_rbp = match rbp.checked_add(::core::mem::size_of::<usize>()) {
+            Some(rip_rbp) => rip_rbp,
+            None => break
+        };
+        if active_table.translate(rbp).is_none() || active_table.translate(rip_rbp).is_none() {
+            break;
+        }
+
+        let rip = *(rip_rbp as *const usize);
+        if rip == 0 {
+            break;
+        }
+        let _ = match ::kern::kdbg::resolve(rip) {
+            Some((name, off)) => write!(w, "  {:#018x} {}+{:#x}\n\r", rip, name, off),
+            None => write!(w, "  {:#018x}\n\r", rip),
+        };
+        rbp = *(rbp as *const usize);
+    }
+}
@@ -10,6 +10,8 @@ use spin::Once;
 
 pub struct FramebufferDriver {
     fb: Framebuffer,
+    // pixel position of text region, whole screen by default
+    origin: Point,
     // used cols & rows
     width: usize,
     height: usize,
@@ -45,6 +47,7 @@ impl FramebufferDriver {
         let h = fb.height / BUILTIN_FONTINFO.yadvance as i32;
         FramebufferDriver {
             fb: fb,
+            origin: Point::new(0, 0),
             max_cols: w as usize,
             max_rows: h as usize,
 
@@ -54,21 +57,44 @@ impl FramebufferDriver {
     }
 }
 
+impl FramebufferDriver {
+    /// confine text to cols x rows chars at pixel (x, y), clipped by screen.
+    /// return the actual (cols, rows) of region, None if screen is smaller
+    /// than a single char.
+    pub fn set_region(&mut self, x: usize, y: usize, cols: usize, rows: usize) -> Option<(usize, usize)> {
+        use core::cmp::{min, max};
+        let FontInfo {xadvance: fw, yadvance: fh} = BUILTIN_FONTINFO;
+
+        let (max_x, max_y) = match ((self.fb.width as usize).checked_sub(fw as usize),
+                                    (self.fb.height as usize).checked_sub(fh as usize)) {
+            (Some(max_x), Some(max_y)) => (max_x, max_y),
+            _ => return None
+        };
+        let x = min(x, max_x);
+        let y = min(y, max_y);
+        self.origin = Point::new(x as i32, y as i32);
+        // keep at least one cell so cursor math never divides by zero
+        self.width = max(1, min(cols, (self.fb.width as usize - x) / fw as usize));
+        self.height = max(1, min(rows, (self.fb.height as usize - y) / fh as usize));
+        Some((self.width, self.height))
+    }
+}
+
 impl TerminalDriver for FramebufferDriver {
     //TODO: draw cursor
     fn update_cursor(&mut self, row: usize, col: usize) {
     }
 
+    fn show_cursor(&mut self, visible: bool) {
+    }
+
     fn draw_byte(&mut self, cursor: usize, byte: Char) {
         let (ch, fg, bg) = (byte.ascii, byte.attr.fg(), byte.attr.bg());
 
         let p = {
             let (cy, cx) = (cursor / self.width, cursor % self.width);
             let FontInfo {xadvance: fw, yadvance: fh} = BUILTIN_FONTINFO;
-            Point {
-                x: cx as i32 * fw as i32,
-                y: cy as i32 * fh as i32
-            }
+            self.origin.offset(cx as i32 * fw as i32, cy as i32 * fh as i32)
         };
         self.fb.draw_char(p, ch, COLORMAP[fg as usize], COLORMAP[bg as usize]);
     }
@@ -98,16 +124,56 @@ impl TerminalDriver for FramebufferDriver {
             return;
         }
 
-        let fh = BUILTIN_FONTINFO.yadvance as i32;
-        let (width, height) = (self.fb.width, self.fb.height);
-        self.fb.blit_copy(Point{x: 0, y: 0}, Point{x: 0, y: fh}, width, height - fh);
-        self.fb.fill_rect(Point{x: 0, y: height - fh}, width, fh, Rgba(0));
+        // only text region gets scrolled
+        let FontInfo {xadvance: fw, yadvance: fh} = BUILTIN_FONTINFO;
+        let (fw, fh) = (fw as i32, fh as i32);
+        let (width, height) = (self.width as i32 * fw, self.height as i32 * fh);
+        let origin = self.origin;
+        self.fb.blit_copy(origin, origin.offset(0, fh), width, height - fh);
+        self.fb.fill_rect(origin.offset(0, height - fh), width, fh, colors::BLACK);
     }
 
     fn clear(&mut self) {
-        let (w, h) = (self.fb.width, self.fb.height);
-        self.fb.fill_rect(Point{x: 0, y: 0}, w, h, Rgba(0));
+        let FontInfo {xadvance: fw, yadvance: fh} = BUILTIN_FONTINFO;
+        let (w, h) = (self.width as i32 * fw as i32, self.height as i32 * fh as i32);
+        let origin = self.origin;
+        self.fb.fill_rect(origin, w, h, colors::BLACK);
     }
 }
 
-
+pub fn test_tiny_framebuffer() {
+    use collections::Vec;
+    use spin::Mutex;
+    use ::kern::console::Console;
+
+    const GUARD: usize = 64;
+    const SENTINEL: u32 = 0x5a5a5a5a;
+    // room for 2 cols x 1 row of 8x16 cells
+    let (width, height) = (20usize, 20usize);
+    let mut mem: Vec<Rgba> = vec![Rgba::new(SENTINEL); GUARD + width * height + GUARD];
+
+    let fb = unsafe {
+        Framebuffer::from_raw(mem.as_mut_ptr().offset(GUARD as isize), width as i32, height as i32, 0)
+    };
+    let con = Mutex::new(Console::new_with_fb(fb).expect("20x20 fb holds a cell"));
+    test_assert_eq!(con.lock().size(), (1, 2));
+
+    // positions used by status lines of syscalls and kernel threads
+    for &row in [18usize, 19, 20, 21].iter() {
+        Console::with(&con, row, 0, || {
+            let _ = con.lock().write_str("status line longer than screen\t\n\r");
+        });
+    }
+    con.lock().update_cursor(100, 100);
+    let (row, col) = { let c = con.lock(); c.extract_cursor(c.get_cursor()) };
+    test_assert_eq!((row, col), (0, 1));
+
+    let intact = |px: &Rgba| px.0 == SENTINEL;
+    test_assert!(mem[..GUARD].iter().all(&intact));
+    test_assert!(mem[GUARD + width * height..].iter().all(&intact));
+
+    let too_small = unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), 4, 4, 0) };
+    test_assert!(Console::new_with_fb(too_small).is_none());
+    let mut drv = FramebufferDriver::new(unsafe { Framebuffer::from_raw(mem.as_mut_ptr(), 4, 40, 0) });
+    test_assert_eq!(drv.set_region(0, 0, 1, 1), None);
+}
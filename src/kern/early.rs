@@ -0,0 +1,104 @@
+//! early boot, before memory::init brings up kernel heap. nothing here
+//! allocates, data lives in statics or borrows from multiboot info.
+//!
+//! alloc-free and usable in early boot:
+//!   printk!, kprintln!, serial         console and COM1 writes
+//!   early::cmdline, arg, flag           kernel command line
+//!   early::log                          fixed size boot log, see dmesg in kdbg
+//!   console::set_log_level              picked from `loglevel=` on cmdline
+//! not before memory::init: log!, collections, Box/Arc, vec!, format!,
+//! anything touching TaskList.
+
+use core::fmt::{self, Write};
+use spin::{Mutex, Once};
+use multiboot2::BootInformation;
+
+use ::kern::console::{self, LogLevel};
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// record cmdline and apply options needed from the very start.
+/// first thing kernel_main does after serial is up.
+pub fn init(mbinfo: &'static BootInformation) {
+    let cmdline = CMDLINE.call_once(|| {
+        mbinfo.command_line_tag().map(|tag| tag.command_line().trim()).unwrap_or("")
+    });
+
+    if let Some(level) = arg("loglevel") {
+        match parse_log_level(level) {
+            Some(level) => console::set_log_level(level),
+            None => log(format_args!("early: bad loglevel {}\n", level))
+        }
+    }
+    log(format_args!("cmdline: {}\n", cmdline));
+}
+
+fn parse_log_level(s: &str) -> Option<LogLevel> {
+    match s {
+        "debug" => Some(LogLevel::Debug),
+        "normal" => Some(LogLevel::Normal),
+        "info" => Some(LogLevel::Info),
+        "warn" => Some(LogLevel::Warn),
+        "critical" => Some(LogLevel::Critical),
+        _ => None
+    }
+}
+
+/// whole kernel command line, empty before init
+pub fn cmdline() -> &'static str {
+    CMDLINE.try().map(|s| *s).unwrap_or("")
+}
+
+/// value of last `key=value` on cmdline
+pub fn arg(key: &str) -> Option<&'static str> {
+    cmdline().split_whitespace()
+        .filter_map(|word| match word.find('=') {
+            Some(i) if &word[..i] == key => Some(&word[i+1..]),
+            _ => None
+        })
+        .last()
+}
+
+/// whether a bare word is on cmdline
+pub fn flag(name: &str) -> bool {
+    cmdline().split_whitespace().any(|word| word == name)
+}
+
+const EARLY_LOG_SIZE: usize = 4096;
+
+/// boot messages kept for later inspection, the oldest are overwritten
+struct EarlyLog {
+    buf: [u8; EARLY_LOG_SIZE],
+    // total bytes ever written
+    written: usize,
+}
+
+impl Write for EarlyLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.buf[self.written % EARLY_LOG_SIZE] = b;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static EARLY_LOG: Mutex<EarlyLog> = Mutex::new(EarlyLog { buf: [0; EARLY_LOG_SIZE], written: 0 });
+
+/// append to boot log, never blocks (drops the message if log is busy)
+pub fn log(args: fmt::Arguments) {
+    if let Some(mut log) = EARLY_LOG.try_lock() {
+        let _ = log.write_fmt(args);
+    }
+}
+
+/// call f with the boot log in order, as up to two slices
+pub fn with_log<F>(f: F) where F: FnOnce(&[u8], &[u8]) {
+    let log = EARLY_LOG.lock();
+    if log.written <= EARLY_LOG_SIZE {
+        f(&log.buf[..log.written], &[]);
+    } else {
+        let head = log.written % EARLY_LOG_SIZE;
+        f(&log.buf[head..], &log.buf[..head]);
+    }
+}
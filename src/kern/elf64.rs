@@ -1,6 +1,11 @@
 use core::fmt;
 use core::iter::Iterator;
+use core::mem::align_of;
 use ::kern::console::LogLevel::*;
+use ::kern::memory::{KERNEL_MAPPING, PAGE_SIZE};
+use ::kern::memory::paging::{self, EntryFlags, Page, PageRange, ActivePML4Table};
+use ::kern::memory::inactive::{InactivePML4Table, TemporaryPage};
+use ::kern::memory::uaccess;
 
 pub const SIZEOF_IDENT: usize = 16;
 pub const SIZEOF_EHDR: usize = 64;
@@ -91,6 +96,14 @@ pub const ELFCLASS64: u8 = 2;
 /// ELF class number.
 pub const ELFCLASSNUM: u8 = 3;
 
+/// Data encoding byte index.
+pub const EI_DATA: usize = 5;
+/// 2's complement, little endian.
+pub const ELFDATA2LSB: u8 = 1;
+
+/// AMD x86-64 architecture.
+pub const EM_X86_64: u16 = 62;
+
 
 /// Convert an ET value to their associated string.
 #[inline]
@@ -180,18 +193,131 @@ pub const PF_W: u32 = 1 << 1;
 /// Segment is readable
 pub const PF_R: u32 = 1 << 2;
 
+impl ProgramHeader {
+    /// page range covering [p_vaddr, p_vaddr + p_memsz)
+    pub fn pages(&self) -> PageRange {
+        let start = self.p_vaddr as usize & !(PAGE_SIZE - 1);
+        PageRange::new(start, (self.p_vaddr + self.p_memsz) as usize)
+    }
+
+    /// user page flags for segment, pages are never readable only since x86
+    /// has no such bit
+    pub fn page_flags(&self) -> EntryFlags {
+        let mut flags = paging::USER;
+        if self.p_flags & PF_W != 0 {
+            flags.insert(paging::WRITABLE);
+        }
+        if self.p_flags & PF_X == 0 {
+            flags.insert(paging::NO_EXECUTE);
+        }
+        flags
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct SectionHeader {
+    /// Section name (string tbl index)
+    pub sh_name: u32,
+    /// Section type
+    pub sh_type: u32,
+    /// Section flags
+    pub sh_flags: u64,
+    /// Section virtual addr at execution
+    pub sh_addr: u64,
+    /// Section file offset
+    pub sh_offset: u64,
+    /// Section size in bytes
+    pub sh_size: u64,
+    /// Link to another section
+    pub sh_link: u32,
+    /// Additional section information
+    pub sh_info: u32,
+    /// Section alignment
+    pub sh_addralign: u64,
+    /// Entry size if section holds table
+    pub sh_entsize: u64,
+}
+
+pub const SIZEOF_SHDR: usize = 64;
+
+/// Section header table entry unused
+pub const SHT_NULL: u32 = 0;
+/// Program data
+pub const SHT_PROGBITS: u32 = 1;
+/// Symbol table
+pub const SHT_SYMTAB: u32 = 2;
+/// String table
+pub const SHT_STRTAB: u32 = 3;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Symbol {
+    /// Symbol name (string tbl index)
+    pub st_name: u32,
+    /// Symbol type and binding
+    pub st_info: u8,
+    /// Symbol visibility
+    pub st_other: u8,
+    /// Section index
+    pub st_shndx: u16,
+    /// Symbol value
+    pub st_value: u64,
+    /// Symbol size
+    pub st_size: u64,
+}
+
+pub const SIZEOF_SYM: usize = 24;
+
+/// Symbol is a code object
+pub const STT_FUNC: u8 = 2;
+
+impl Symbol {
+    pub fn sym_type(&self) -> u8 {
+        self.st_info & 0xf
+    }
+}
+
 pub struct ProgramHeaderIter<'a> {
     data: &'a [u8],
     header: &'a Header,
     next: usize
 }
 
+/// PT_LOAD entries of program header table
+pub struct LoadSegmentIter<'a> {
+    inner: ProgramHeaderIter<'a>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// image is shorter than the tables it declares
+    Truncated,
+    /// image is not aligned for in place header access
+    Misaligned,
+    BadMagic,
+    /// not a 64-bit little endian image
+    BadClass,
+    /// not built for x86_64
+    BadMachine,
+    /// not an executable
+    BadType,
+    /// program header table is malformed
+    BadProgramHeaders,
+    /// PT_LOAD segment is out of file or user space, or overlaps another one
+    BadSegment,
+    /// no free pid for the task to run the image
+    TooManyTasks,
+}
+
 pub struct Elf64<'a> {
     pub header: &'a Header,
     pub data: &'a [u8]
 }
 
 impl<'a> Elf64<'a> {
+    /// unchecked view of bytes, only for images known to be sane (e.g the
+    /// kernel itself). use parse() for anything that is going to be loaded.
     pub unsafe fn from(bytes: &'a [u8]) -> Elf64<'a> {
         let h = &*(bytes.as_ptr() as *const Header);
 
@@ -201,6 +327,66 @@ impl<'a> Elf64<'a> {
         }
     }
 
+    /// validate bytes as an x86_64 executable whose program headers and
+    /// PT_LOAD segments all lie inside of bytes
+    pub fn parse(bytes: &'a [u8]) -> Result<Elf64<'a>, ElfError> {
+        if bytes.len() < SIZEOF_EHDR {
+            return Err(ElfError::Truncated);
+        }
+        if bytes.as_ptr() as usize % align_of::<Header>() != 0 {
+            return Err(ElfError::Misaligned);
+        }
+
+        let elf = unsafe { Elf64::from(bytes) };
+        let h = elf.header;
+        if &h.e_ident[..SELFMAG] != &ELFMAG[..] {
+            return Err(ElfError::BadMagic);
+        }
+        if h.e_ident[EI_CLASS] != ELFCLASS64 || h.e_ident[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfError::BadClass);
+        }
+        if h.e_machine != EM_X86_64 {
+            return Err(ElfError::BadMachine);
+        }
+        if h.e_type != ET_EXEC {
+            return Err(ElfError::BadType);
+        }
+
+        if h.e_phnum > 0 {
+            if h.e_phentsize as usize != SIZEOF_PHDR || h.e_phoff % align_of::<ProgramHeader>() as u64 != 0 {
+                return Err(ElfError::BadProgramHeaders);
+            }
+            let table = (h.e_phnum as u64 * SIZEOF_PHDR as u64).checked_add(h.e_phoff);
+            match table {
+                Some(end) if end <= bytes.len() as u64 => {},
+                _ => return Err(ElfError::Truncated)
+            }
+        }
+
+        for (i, ph) in elf.load_segments().enumerate() {
+            let in_file = ph.p_offset.checked_add(ph.p_filesz).map_or(false, |end| end <= bytes.len() as u64);
+            let end = ph.p_vaddr.checked_add(ph.p_memsz);
+            if !in_file || ph.p_filesz > ph.p_memsz || ph.p_memsz == 0 || end.is_none() {
+                return Err(ElfError::BadSegment);
+            }
+
+            // each page belongs to a single segment, so it gets one set of flags
+            let overlaps = elf.load_segments().take(i).any(|other| {
+                let (a, b) = (ph.pages(), other.pages());
+                a.start < b.end && b.start < a.end
+            });
+            if overlaps {
+                return Err(ElfError::BadSegment);
+            }
+        }
+
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
     pub fn program_headers(&self) -> ProgramHeaderIter<'a> {
         ProgramHeaderIter {
             data: self.data,
@@ -208,6 +394,113 @@ impl<'a> Elf64<'a> {
             next: 0
         }
     }
+
+    pub fn load_segments(&self) -> LoadSegmentIter<'a> {
+        LoadSegmentIter { inner: self.program_headers() }
+    }
+
+    /// map each PT_LOAD segment into address space inactive with flags from
+    /// its p_flags, fill it with file content and zero the rest of its pages
+    /// (bss included). nothing is mapped if a segment is outside UserCode,
+    /// the rest of user space belongs to stack, shm and framebuffer.
+    /// elf should come from parse().
+    pub fn load_into(&self, inactive: &mut InactivePML4Table) -> Result<(), ElfError> {
+        let user = &KERNEL_MAPPING.UserCode;
+        for ph in self.load_segments() {
+            let end = ph.p_vaddr + ph.p_memsz;
+            if (ph.p_vaddr as usize) < user.start || end as usize > user.end + 1 {
+                return Err(ElfError::BadSegment);
+            }
+        }
+
+        for ph in self.load_segments() {
+            printk!(Debug, "elf: load {:?} {:?}\n\r", ph.pages(), ph.page_flags());
+            let mut active = ActivePML4Table::new();
+            let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+            active.with(inactive, &mut temp_page, |mapper| {
+                for page in ph.pages() {
+                    mapper.map(page, ph.page_flags());
+                }
+            });
+
+            // frames come dirty, clear what file content does not cover.
+            // copies go through temporary mappings, so read only pages are fine
+            let (vaddr, filesz) = (ph.p_vaddr as usize, ph.p_filesz as usize);
+            let (start, end) = (vaddr & !(PAGE_SIZE - 1), ph.pages().end.start_address());
+            let data = &self.data[ph.p_offset as usize..ph.p_offset as usize + filesz];
+            uaccess::zero_space(inactive, start, vaddr - start).expect("elf: zero segment head");
+            uaccess::copy_to_space(inactive, vaddr, data).expect("elf: copy segment");
+            uaccess::zero_space(inactive, vaddr + filesz, end - vaddr - filesz).expect("elf: zero bss");
+        }
+
+        Ok(())
+    }
+
+    pub fn section_headers(&self) -> &'a [SectionHeader] {
+        let (off, num) = (self.header.e_shoff as usize, self.header.e_shnum as usize);
+        match off.checked_add(num * SIZEOF_SHDR) {
+            Some(end) if num != 0 && end <= self.data.len() => {},
+            _ => return &[]
+        }
+
+        unsafe {
+            ::core::slice::from_raw_parts(self.data.as_ptr().offset(off as isize)
+                as *const SectionHeader, num)
+        }
+    }
+
+    /// content of section in file, empty if out of file
+    pub fn section_data(&self, sh: &SectionHeader) -> &'a [u8] {
+        let start = sh.sh_offset as usize;
+        match start.checked_add(sh.sh_size as usize) {
+            Some(end) if end <= self.data.len() => &self.data[start..end],
+            _ => &[]
+        }
+    }
+
+    /// symbol table and its associated string table
+    pub fn symbols(&self) -> Option<(&'a [Symbol], &'a [u8])> {
+        let shdrs = self.section_headers();
+        let symtab = match shdrs.iter().find(|sh| sh.sh_type == SHT_SYMTAB) {
+            Some(sh) => sh,
+            None => return None
+        };
+
+        let strtab = match shdrs.get(symtab.sh_link as usize) {
+            Some(sh) if sh.sh_type == SHT_STRTAB => self.section_data(sh),
+            _ => return None
+        };
+
+        let data = self.section_data(symtab);
+        let syms = unsafe {
+            ::core::slice::from_raw_parts(data.as_ptr() as *const Symbol, data.len() / SIZEOF_SYM)
+        };
+        Some((syms, strtab))
+    }
+
+    /// find function symbol covering addr, return its name and offset of addr in it
+    pub fn resolve(&self, addr: usize) -> Option<(&'a str, usize)> {
+        let (syms, strtab) = match self.symbols() {
+            Some(v) => v,
+            None => return None
+        };
+
+        let addr = addr as u64;
+        syms.iter()
+            .filter(|sym| sym.sym_type() == STT_FUNC)
+            .find(|sym| addr >= sym.st_value && addr < sym.st_value + sym.st_size)
+            .map(|sym| (symbol_name(strtab, sym.st_name as usize), (addr - sym.st_value) as usize))
+    }
+}
+
+/// nul terminated string in strtab starting at off
+fn symbol_name(strtab: &[u8], off: usize) -> &str {
+    if off >= strtab.len() {
+        return "";
+    }
+    let s = &strtab[off..];
+    let len = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+    ::core::str::from_utf8(&s[..len]).unwrap_or("")
 }
 
 impl<'a> Iterator for ProgramHeaderIter<'a> {
@@ -229,3 +522,99 @@ impl<'a> Iterator for ProgramHeaderIter<'a> {
     }
 }
 
+impl<'a> Iterator for LoadSegmentIter<'a> {
+    type Item = &'a ProgramHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.find(|ph| ph.p_type == PT_LOAD)
+    }
+}
+
+
+pub fn test_elf_parse() {
+    use core::ptr::write;
+    use core::slice::from_raw_parts;
+
+    // header, a PT_NOTE and a PT_LOAD with 16 bytes of file content
+    const PHOFF: usize = SIZEOF_EHDR;
+    const DATA: usize = PHOFF + 2 * SIZEOF_PHDR;
+    let build = |mem: &mut [u64; 32], h: Header, load: ProgramHeader| unsafe {
+        let base = mem.as_mut_ptr() as *mut u8;
+        write(base as *mut Header, h);
+        write(base.offset(PHOFF as isize) as *mut ProgramHeader,
+              ProgramHeader { p_type: PT_NOTE, ..Default::default() });
+        write(base.offset((PHOFF + SIZEOF_PHDR) as isize) as *mut ProgramHeader, load);
+    };
+
+    let mut h = Header::default();
+    h.e_ident[..SELFMAG].copy_from_slice(&ELFMAG[..]);
+    h.e_ident[EI_CLASS] = ELFCLASS64;
+    h.e_ident[EI_DATA] = ELFDATA2LSB;
+    h.e_type = ET_EXEC;
+    h.e_machine = EM_X86_64;
+    h.e_entry = 0x0400_0010;
+    h.e_phoff = PHOFF as u64;
+    h.e_phentsize = SIZEOF_PHDR as u16;
+    h.e_phnum = 2;
+    let load = ProgramHeader {
+        p_type: PT_LOAD, p_flags: PF_R | PF_W, p_offset: DATA as u64, p_vaddr: 0x0400_0000,
+        p_filesz: 16, p_memsz: 0x1800, ..Default::default()
+    };
+
+    let mut mem = [0u64; 32];
+    let len = DATA + 16;
+    let check = |mem: &[u64; 32]| {
+        let bytes = unsafe { from_raw_parts(mem.as_ptr() as *const u8, len) };
+        Elf64::parse(bytes).map(|elf| elf.entry())
+    };
+
+    build(&mut mem, h, load);
+    test_assert_eq!(check(&mem), Ok(0x0400_0010));
+    {
+        let bytes = unsafe { from_raw_parts(mem.as_ptr() as *const u8, len) };
+        let elf = Elf64::parse(bytes).unwrap();
+        test_assert_eq!(elf.load_segments().count(), 1);
+        let ph = elf.load_segments().next().unwrap();
+        test_assert_eq!((ph.p_vaddr, ph.p_filesz, ph.p_memsz), (0x0400_0000, 16, 0x1800));
+        test_assert_eq!(ph.page_flags(), paging::USER | paging::WRITABLE | paging::NO_EXECUTE);
+        test_assert_eq!(ph.pages().count(), 2);
+
+        test_assert_eq!(Elf64::parse(&bytes[..SIZEOF_EHDR - 1]).map(|_| ()), Err(ElfError::Truncated));
+        test_assert_eq!(Elf64::parse(&bytes[..DATA - 1]).map(|_| ()), Err(ElfError::Truncated));
+        test_assert_eq!(Elf64::parse(&bytes[1..]).map(|_| ()), Err(ElfError::Misaligned));
+
+        // offset + size wraps around, must not pass the bounds check
+        let sh = SectionHeader { sh_offset: !0 - 7, sh_size: 16, ..Default::default() };
+        test_assert!(elf.section_data(&sh).is_empty());
+        let sh = SectionHeader { sh_offset: SIZEOF_EHDR as u64, sh_size: 8, ..Default::default() };
+        test_assert_eq!(elf.section_data(&sh).len(), 8);
+    }
+
+    let mut bad = h;
+    bad.e_ident[0] = 0;
+    build(&mut mem, bad, load);
+    test_assert_eq!(check(&mem), Err(ElfError::BadMagic));
+
+    let mut bad = h;
+    bad.e_ident[EI_CLASS] = ELFCLASS32;
+    build(&mut mem, bad, load);
+    test_assert_eq!(check(&mem), Err(ElfError::BadClass));
+
+    let mut bad = h;
+    bad.e_machine = 3;
+    build(&mut mem, bad, load);
+    test_assert_eq!(check(&mem), Err(ElfError::BadMachine));
+
+    // file content past end of image
+    build(&mut mem, h, ProgramHeader { p_filesz: 17, ..load });
+    test_assert_eq!(check(&mem), Err(ElfError::BadSegment));
+
+    build(&mut mem, h, ProgramHeader { p_memsz: 8, ..load });
+    test_assert_eq!(check(&mem), Err(ElfError::BadSegment));
+
+    // code segment is read only and executable
+    build(&mut mem, h, ProgramHeader { p_flags: PF_R | PF_X, ..load });
+    let bytes = unsafe { from_raw_parts(mem.as_ptr() as *const u8, len) };
+    let ph = Elf64::parse(bytes).unwrap().load_segments().next().unwrap();
+    test_assert_eq!(ph.page_flags(), paging::USER);
+}
@@ -0,0 +1,111 @@
+//! framebuffer handed out to userland. one task at a time may map the
+//! video memory into its address space and draw directly, the mapping goes
+//! away when the task unmaps it or exits.
+
+use spin::{Mutex, Once};
+use multiboot2;
+
+use ::kern::memory::frame::FrameRange;
+use ::kern::memory::paging::{self, Page, ActivePML4Table, VirtualAddress};
+use ::kern::memory::{PAGE_SIZE, KERNEL_MAPPING};
+use ::kern::task::{Task, ProcId, VirtualMemoryArea};
+use ::kern::syscall::{EBUSY, EINVAL, ENODEV, ENOMEM};
+
+/// layout shared with userland by sys_fbinfo
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// bytes per row
+    pub pitch: u32,
+    pub bpp: u32,
+    /// bytes of the mapping sys_fbmap gives
+    pub size: u64,
+}
+
+static INFO: Once<(FbInfo, usize)> = Once::new();
+
+/// pid of the task which has framebuffer mapped, and where
+static OWNER: Mutex<Option<(ProcId, VirtualAddress)>> = Mutex::new(None);
+
+/// record rgb framebuffer from bootloader, text mode is not exported
+pub fn init(fb: &multiboot2::FramebufferTag) {
+    if fb.frame_type != multiboot2::FramebufferType::Rgb {
+        return;
+    }
+
+    INFO.call_once(|| {
+        let size = fb.pitch as usize * fb.height as usize;
+        let info = FbInfo {
+            width: fb.width as u32,
+            height: fb.height as u32,
+            pitch: fb.pitch as u32,
+            bpp: fb.bpp as u32,
+            size: ((size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) as u64,
+        };
+        (info, fb.addr as usize)
+    });
+}
+
+pub fn info() -> Option<FbInfo> {
+    INFO.try().map(|&(info, _)| info)
+}
+
+/// map framebuffer into task, task must be the running one.
+/// return user address of mapping.
+pub fn map(task: &mut Task) -> Result<VirtualAddress, isize> {
+    let (info, paddr) = match INFO.try() {
+        Some(&v) => v,
+        None => return Err(-ENODEV)
+    };
+
+    let mut owner = OWNER.lock();
+    if owner.is_some() {
+        return Err(-EBUSY);
+    }
+
+    let (start, size) = (KERNEL_MAPPING.UserFb.start, info.size as usize);
+    if size > ::kern::memory::region_size(&KERNEL_MAPPING.UserFb) {
+        return Err(-ENOMEM);
+    }
+
+    let flags = paging::USER | paging::WRITABLE | paging::NO_EXECUTE;
+    let mut vma = VirtualMemoryArea::new(start, size, flags);
+    vma.mapped = true;
+    if task.add_vma(vma).is_err() {
+        return Err(-EINVAL);
+    }
+
+    // video memory belongs to no allocator, so frames are not refcounted
+    let mut active = ActivePML4Table::new();
+    for (i, f) in FrameRange::new(paddr, paddr + size).enumerate() {
+        active.map_to(Page::from_vaddress(start) + i, f, flags);
+    }
+
+    *owner = Some((task.pid, start));
+    Ok(start)
+}
+
+/// drop mapping of task, task must be the running one
+pub fn unmap(task: &mut Task) -> Result<(), isize> {
+    let mut owner = OWNER.lock();
+    let start = match *owner {
+        Some((pid, start)) if pid == task.pid => start,
+        _ => return Err(-EINVAL)
+    };
+
+    let vma = task.remove_vma(start).expect("fbdev: vma of mapping missing");
+    let mut active = ActivePML4Table::new();
+    for i in 0..vma.size / PAGE_SIZE {
+        active.unmap(Page::from_vaddress(start) + i);
+    }
+
+    *owner = None;
+    Ok(())
+}
+
+/// release framebuffer if task holds it, used when task exits
+pub fn release(task: &mut Task) {
+    let _ = unmap(task);
+}
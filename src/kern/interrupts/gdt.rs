@@ -4,6 +4,7 @@ use x86_64::instructions::tables::{lgdt, DescriptorTablePointer};
 use x86_64::PrivilegeLevel;
 use core::mem::size_of;
 use bit_field::BitField;
+use ::kern::console::LogLevel::*;
 
 /// the new GDT after remap kernel and setup paging,
 /// some entry is 64bit long, some is 128bit long
@@ -41,6 +42,15 @@ impl GlobalDescriptorTable {
         SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
     }
 
+    /// descriptor privilege level of the segment sel refers to
+    pub fn dpl(&self, sel: SegmentSelector) -> Option<u8> {
+        let index = (sel.0 >> 3) as usize;
+        if index == 0 || index >= self.next {
+            return None;
+        }
+        Some(self.table[index].get_bits(45..47) as u8)
+    }
+
     pub fn load(&'static self) {
         let dtp = DescriptorTablePointer {
             base: self.table.as_ptr() as u64,
@@ -50,6 +60,48 @@ impl GlobalDescriptorTable {
     }
 }
 
+/// print the GDT the cpu actually uses (from sgdt), not what we meant to load
+pub fn dump_loaded() {
+    let mut dtp = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt ($0)" :: "r"(&mut dtp) : "memory" : "volatile"); }
+
+    let (base, limit) = (dtp.base, dtp.limit);
+    let entries = unsafe {
+        ::core::slice::from_raw_parts(base as *const u64, (limit as usize + 1) / size_of::<u64>())
+    };
+    printk!(Normal, "gdt at {:#x}, limit {:#x}\n\r", base, limit);
+
+    let mut i = 1;
+    while i < entries.len() {
+        let v = entries[i];
+        if !v.get_bit(47) {
+            i += 1;
+            continue;
+        }
+
+        let mut seg_base = v.get_bits(16..40) | v.get_bits(56..64) << 24;
+        let seg_limit = v.get_bits(0..16) | v.get_bits(48..52) << 16;
+        let ty = v.get_bits(40..44);
+        let dpl = v.get_bits(45..47);
+        // system segments (tss) take two slots in long mode
+        let system = !v.get_bit(44);
+        if system && i + 1 < entries.len() {
+            seg_base |= entries[i+1].get_bits(0..32) << 32;
+        }
+
+        let kind = match (system, ty) {
+            (true, 0b1001) => "tss (available)",
+            (true, 0b1011) => "tss (busy)",
+            (true, _) => "system",
+            (false, ty) if ty & 0b1000 != 0 => if v.get_bit(53) { "code64" } else { "code" },
+            (false, _) => "data",
+        };
+        printk!(Normal, "  [{}] sel {:#x}: base {:#x} limit {:#x} type {:#b} dpl {} {}\n\r",
+                i, i << 3, seg_base, seg_limit, ty, dpl, kind);
+        i += if system { 2 } else { 1 };
+    }
+}
+
 bitflags! {
     flags DescriptorFlags: u64 {
         const CONFORMING        = 1 << 42,
@@ -93,6 +93,50 @@ impl Entry {
     pub fn options(&mut self) -> &mut EntryOptions {
         &mut self.options
     }
+
+    pub fn handler_address(&self) -> u64 {
+        self.pointer_low as u64 | (self.pointer_middle as u64) << 16 | (self.pointer_high as u64) << 32
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.options.0.get_bit(15)
+    }
+
+    /// 0 means no IST switch, otherwise IST1-IST7
+    pub fn ist(&self) -> u16 {
+        self.options.0.get_bits(0..3)
+    }
+
+    pub fn dpl(&self) -> u16 {
+        self.options.0.get_bits(13..15)
+    }
+
+    pub fn is_trap_gate(&self) -> bool {
+        self.options.0.get_bit(8)
+    }
+}
+
+/// print present entries of the IDT the cpu actually uses (from sidt)
+pub fn dump_loaded() {
+    use ::kern::console::LogLevel::*;
+
+    let mut dtp = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sidt ($0)" :: "r"(&mut dtp) : "memory" : "volatile"); }
+
+    let (base, limit) = (dtp.base, dtp.limit);
+    let entries = unsafe {
+        ::core::slice::from_raw_parts(base as *const Entry, (limit as usize + 1) / size_of::<Entry>())
+    };
+    printk!(Normal, "idt at {:#x}, limit {:#x}\n\r", base, limit);
+
+    for (vector, e) in entries.iter().enumerate().filter(|&(_, e)| e.is_present()) {
+        let selector = e.gdt_selector;
+        let handler = e.handler_address() as usize;
+        let name = ::kern::kdbg::resolve(handler).map(|(name, _)| name).unwrap_or("?");
+        printk!(Normal, "  [{:3}] {:#x}:{:#x} {} ist {} dpl {} {}\n\r",
+                vector, selector, handler, if e.is_trap_gate() { "trap" } else { "intr" },
+                e.ist(), e.dpl(), name);
+    }
 }
 
 macro_rules! define_handler {
@@ -1,5 +1,6 @@
 use ::kern::arch::port::{UnsafePort, Port};
-use spin::Mutex;
+use spin::{Mutex, RwLock};
+use super::idt::ExceptionStackFrame;
 
 /**
  * ref: http://wiki.osdev.org/8259_PIC
@@ -50,6 +51,12 @@ impl Pic8259A {
     pub unsafe fn eoi(&mut self) {
         self.command.write(0x20);
     }
+
+    /// in-service register, lines being handled right now
+    pub unsafe fn isr(&mut self) -> u8 {
+        self.command.write(OCW3_READ_ISR);
+        self.command.read()
+    }
 }
 
 /// represent two cascaded pic chips
@@ -73,6 +80,8 @@ const ICW1_LEVEL: u8 =	0x08;		/* Level triggered (edge) mode */
 #[allow(dead_code)]
 const ICW1_INIT: u8 =	0x10;		/* Initialization - required! */
 
+const OCW3_READ_ISR: u8 = 0x0b;
+
 const ICW4_8086: u8 =	0x01;		/* 8086/88 (MCS-80/85) mode */
 #[allow(dead_code)]
 const ICW4_AUTO: u8 =	0x02;		/* Auto (normal) EOI */
@@ -133,6 +142,23 @@ impl PicChain {
         self.pics[0].eoi();
     }
 
+    /// tell if irq is a spurious one raised on the lowest line (7 or 15) of
+    /// a pic with nothing in service. a spurious irq15 still got the master
+    /// to ack the cascade line, so the master gets its eoi here.
+    pub unsafe fn spurious(&mut self, irq: usize) -> bool {
+        match irq {
+            7 => self.pics[0].isr() & 0x80 == 0,
+            15 => {
+                if self.pics[1].isr() & 0x80 == 0 {
+                    self.pics[0].eoi();
+                    true
+                } else {
+                    false
+                }
+            },
+            _ => false
+        }
+    }
 
     unsafe fn setmask(&mut self, mask: u16) {
         self.irqmask = mask;
@@ -147,3 +173,53 @@ impl PicChain {
         self.setmask(mask);
     }
 }
+
+/// driver side of a PIC irq line, must send eoi itself
+pub type IrqHandler = fn(&mut ExceptionStackFrame);
+
+pub const NR_IRQS: usize = 16;
+// slave pic is chained on this line
+const CASCADE_IRQ: usize = 2;
+
+/// only register_irq takes the write lock, with interrupts off, so dispatch
+/// on the same cpu never waits for it
+static IRQ_HANDLERS: RwLock<[Option<IrqHandler>; NR_IRQS]> = RwLock::new([None; NR_IRQS]);
+
+/// route irq line (0-15) to handler and unmask it. a line has one owner,
+/// claiming a taken one is a driver bug.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    use ::kern::arch::cpu;
+
+    let irq = irq as usize;
+    assert!(irq < NR_IRQS && irq != CASCADE_IRQ, "irq: line {} can not be registered", irq);
+
+    let oflags = unsafe { cpu::push_flags() };
+    {
+        let mut handlers = IRQ_HANDLERS.write();
+        assert!(handlers[irq].is_none(), "irq: line {} is already taken", irq);
+        handlers[irq] = Some(handler);
+    }
+    unsafe {
+        PIC_CHAIN.lock().enable(irq + 0x20);
+        cpu::pop_flags(oflags);
+    }
+}
+
+/// common path of all PIC vectors, see IDT
+pub fn dispatch(irq: usize, frame: &mut ExceptionStackFrame) {
+    // no eoi for a line that never was in service
+    if unsafe { PIC_CHAIN.lock().spurious(irq) } {
+        kprintln!("irq: spurious on line {}", irq);
+        return;
+    }
+
+    // guard is gone before handler runs, timer handler may switch away
+    let handler = IRQ_HANDLERS.read()[irq];
+    match handler {
+        Some(handler) => handler(frame),
+        None => {
+            kprintln!("irq: nobody handles line {}", irq);
+            unsafe { PIC_CHAIN.lock().eoi(irq); }
+        }
+    }
+}
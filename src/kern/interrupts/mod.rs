@@ -8,7 +8,7 @@ pub use self::irq::{PIC_CHAIN, Irqs};
 
 use self::gdt::{GlobalDescriptorTable, Descriptor};
 use self::timer::{PIT, timer_handler};
-use ::kern::driver::keyboard::{KBD, keyboard_irq};
+use ::kern::driver::keyboard::KBD;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::SegmentSelector;
@@ -19,20 +19,48 @@ use ::kern::console::LogLevel::*;
 use ::kern::arch::cpu::cr2;
 use ::kern::memory::MemoryManager;
 use spin::{Once, Mutex};
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use core::fmt;
+
+macro_rules! irq_stubs {
+    ($($stub:ident: $irq:expr),*) => {
+        $(extern "C" fn $stub(frame: &mut ExceptionStackFrame) { irq::dispatch($irq, frame); })*
+    }
+}
+
+// one entry per PIC line, handlers are looked up at irq time
+irq_stubs!(irq0: 0, irq1: 1, irq2: 2, irq3: 3, irq4: 4, irq5: 5, irq6: 6, irq7: 7,
+           irq8: 8, irq9: 9, irq10: 10, irq11: 11, irq12: 12, irq13: 13, irq14: 14, irq15: 15);
 
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.page_fault = Entry::new(cs().0, define_handler_with_errno!(page_fault_handler) as u64);
         idt.general_protection_fault = 
-            Entry::new(cs().0, define_handler_with_errno!(general_protection_fault) as u64);
+            Entry::new(cs().0, define_handler_with_errno!(general_protection_fault_handler) as u64);
+        idt.invalid_opcode = Entry::new(cs().0, define_handler!(invalid_opcode_handler) as u64);
+        idt.debug = Entry::new(cs().0, define_handler!(debug_handler) as u64);
         idt.breakpoint = Entry::new(cs().0, define_handler!(int3_handler) as u64);
         idt.double_fault = Entry::new(cs().0, define_handler_with_errno!(double_fault_handler) as u64);
         idt.double_fault.options().set_ist_index(IST_INDEX_DBL_FAULT as u16);
+        idt.stack_segment_fault =
+            Entry::new(cs().0, define_handler_with_errno!(stack_segment_fault_handler) as u64);
+        idt.stack_segment_fault.options().set_ist_index(IST_INDEX_STACK_FAULT as u16);
         idt.divide_by_zero = Entry::new(cs().0, define_handler!(divide_by_zero_handler) as u64);
 
-        idt.irqs[Irqs::TIMER as usize-32] = Entry::new(cs().0, define_handler!(timer_handler) as u64);
-        idt.irqs[Irqs::KBD as usize-32] = Entry::new(cs().0, define_handler!(keyboard_irq) as u64);
+        let stubs = [
+            define_handler!(irq0) as u64, define_handler!(irq1) as u64,
+            define_handler!(irq2) as u64, define_handler!(irq3) as u64,
+            define_handler!(irq4) as u64, define_handler!(irq5) as u64,
+            define_handler!(irq6) as u64, define_handler!(irq7) as u64,
+            define_handler!(irq8) as u64, define_handler!(irq9) as u64,
+            define_handler!(irq10) as u64, define_handler!(irq11) as u64,
+            define_handler!(irq12) as u64, define_handler!(irq13) as u64,
+            define_handler!(irq14) as u64, define_handler!(irq15) as u64,
+        ];
+        for (entry, &stub) in idt.irqs.iter_mut().zip(stubs.iter()) {
+            *entry = Entry::new(cs().0, stub);
+        }
 
         idt
     };
@@ -49,43 +77,267 @@ bitflags! {
     }
 }
 
-extern "C" fn double_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
-    printk!(Debug, "double fault\n\r{:#?}\n\r", frame);
+/// unrecoverable exception, report it on panic screen if possible and stop
+fn fatal(title: &str, frame: &ExceptionStackFrame, args: fmt::Arguments) -> ! {
+    use ::kern::driver::video::panic_screen;
+
+    if !panic_screen::show(title, args, None, Some(frame)) {
+        printk!(Critical, "{}: {}\n\r{:#?}\n\r", title, args, frame);
+    }
     loop {
         unsafe { asm!("hlt"); }
     }
 }
 
-extern "C" fn general_protection_fault(frame: &mut ExceptionStackFrame, err_code: u64) {
-    printk!(Debug, "GPE err code: {:#?}\n\r", err_code);
+/// addr lies in the guard page below kernel stack of current task. a page
+/// fault there can not push its frame, so overflow shows up as double fault.
+fn kern_stack_overflow(addr: usize) -> bool {
+    use ::kern::task::{TaskList, in_stack_guard};
 
-    loop {
-        unsafe { asm!("hlt"); }
+    let tasks = match TaskList::try_get() {
+        Some(tasks) => tasks,
+        None => return false
+    };
+    let task = match tasks.current().and_then(|t| t.try_read()) {
+        Some(task) => task,
+        None => return false
+    };
+    let hit = task.kern_stack.as_ref().map_or(false, |st| in_stack_guard(st, addr));
+    hit
+}
+
+extern "C" fn double_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
+    let addr = cr2();
+    if kern_stack_overflow(addr) {
+        fatal("KERNEL STACK OVERFLOW", frame, format_args!("double fault on stack guard, cr2 {:#x}", addr));
+    }
+    fatal("DOUBLE FAULT", frame, format_args!("double fault, err code: {:#x}, cr2 {:#x}", err_code, addr));
+}
+
+extern "C" fn stack_segment_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
+    fatal("STACK SEGMENT FAULT", frame, format_args!("#SS err code: {:#x}, {}, cr2 {:#x}",
+                                                     err_code, SelectorErrorCode(err_code), cr2()));
+}
+
+/// error code of faults caused by a segment selector (#GP, #TS, #NP, #SS)
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let err = self.0;
+        if err == 0 {
+            return write!(f, "no selector");
+        }
+
+        let table = match (err >> 1) & 0x3 {
+            0 => "gdt",
+            2 => "ldt",
+            _ => "idt"
+        };
+        write!(f, "{}selector {:#x} ({}[{}])", if err & 1 != 0 { "external " } else { "" },
+               err & 0xfff8, table, (err >> 3) & 0x1fff)
+    }
+}
+
+extern "C" fn general_protection_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
+    fatal("GENERAL PROTECTION FAULT", frame, format_args!("GPE err code: {:#x}, {}",
+                                                          err_code, SelectorErrorCode(err_code)));
+}
+
+extern "C" fn invalid_opcode_handler(frame: &mut ExceptionStackFrame) {
+    let rip = frame.rip as usize;
+    match ::kern::kdbg::resolve(rip) {
+        Some((name, off)) => fatal("INVALID OPCODE", frame,
+                                   format_args!("invalid opcode at {:#x} {}+{:#x}", rip, name, off)),
+        None => fatal("INVALID OPCODE", frame, format_args!("invalid opcode at {:#x}", rip)),
+    }
+}
+
+/// resume address for a page fault that a self-test provoked on purpose,
+/// 0 means no fault is expected. it is consumed by the first page fault.
+pub static PAGE_FAULT_FIXUP: AtomicUsize = AtomicUsize::new(0);
+static PAGE_FAULT_HIT: AtomicBool = AtomicBool::new(false);
+
+/// return whether an expected page fault has been taken since last call
+pub fn take_page_fault_hit() -> bool {
+    PAGE_FAULT_HIT.swap(false, Ordering::SeqCst)
+}
+
+/// back the faulting page if it lies in a vma of current task which is not
+/// populated yet. false if the fault is not ours to resolve.
+fn demand_page(addr: usize, err: PageFaultErrorCode) -> bool {
+    // present page with wrong permission is a real fault
+    if err.contains(PROTECTION_VIOLATION) {
+        return false;
     }
+    ::kern::task::populate_user_page(addr)
 }
 
 extern "C" fn page_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
-    use ::kern::task::CURRENT_ID;
-    use core::sync::atomic::Ordering;
+    use ::kern::task;
 
     let err = PageFaultErrorCode::from_bits(err_code).unwrap();
-    printk!(Debug, "page fault! {:#?}\n\rerr code: {:#?}, cr2: {:#x} tid: {:#x}\n\r",
-            frame, err, cr2(), CURRENT_ID.load(Ordering::SeqCst));
-    loop {
-        unsafe { asm!("hlt"); }
+    if demand_page(cr2(), err) {
+        return;
+    }
+    ::kern::trace::record(::kern::trace::EventKind::PageFault, cr2(), task::current_id() as usize);
+
+    let fixup = PAGE_FAULT_FIXUP.swap(0, Ordering::SeqCst);
+    if fixup != 0 {
+        printk!(Debug, "expected page fault at {:#x}, cr2: {:#x}, resume at {:#x}\n\r",
+                frame.rip, cr2(), fixup);
+        PAGE_FAULT_HIT.store(true, Ordering::SeqCst);
+        frame.rip = fixup as u64;
+        return;
     }
+
+    fatal("PAGE FAULT", frame, format_args!("page fault! err code: {:?}, cr2: {:#x} tid: {:#x}",
+            err, cr2(), task::current_id()));
 }
 
 extern "C" fn int3_handler(frame: &mut ExceptionStackFrame) {
     printk!(Debug, "int3!! {:#?}\n\r", frame);
 }
 
+static WATCHPOINT_HITS: AtomicUsize = AtomicUsize::new(0);
+
+/// hardware watchpoints are traps: rip already points past the instruction
+/// which touched the watched address
+extern "C" fn debug_handler(frame: &mut ExceptionStackFrame) {
+    use ::kern::arch::cpu;
+
+    let dr6 = cpu::take_dr6();
+    for slot in (0..cpu::NR_WATCHPOINTS).filter(|&i| dr6 & cpu::DR6_HIT_MASK & (1 << i) != 0) {
+        WATCHPOINT_HITS.fetch_add(1, Ordering::SeqCst);
+        let rip = frame.rip as usize;
+        match ::kern::kdbg::resolve(rip) {
+            Some((name, off)) => printk!(Warn, "watchpoint {} hit: addr {:#x}, rip {:#x} {}+{:#x}\n\r",
+                                         slot, cpu::watchpoint(slot), rip, name, off),
+            None => printk!(Warn, "watchpoint {} hit: addr {:#x}, rip {:#x}\n\r",
+                            slot, cpu::watchpoint(slot), rip),
+        }
+    }
+}
+
+pub fn test_watchpoint() {
+    use ::kern::arch::cpu;
+    use core::ptr;
+
+    let mut watched: u64 = 0;
+    let addr = &mut watched as *mut u64;
+    let slot = cpu::set_watchpoint(addr as usize, 8, true).expect("no free watchpoint");
+    let before = WATCHPOINT_HITS.load(Ordering::SeqCst);
+    unsafe {
+        ptr::read_volatile(addr);
+        test_assert_eq!(WATCHPOINT_HITS.load(Ordering::SeqCst), before);
+        ptr::write_volatile(addr, 0xdead);
+    }
+    test_assert_eq!(WATCHPOINT_HITS.load(Ordering::SeqCst), before + 1);
+
+    cpu::clear_watchpoint(slot);
+    unsafe { ptr::write_volatile(addr, 0xbeef); }
+    test_assert_eq!(WATCHPOINT_HITS.load(Ordering::SeqCst), before + 1);
+}
+
 extern "C" fn divide_by_zero_handler(frame: &mut ExceptionStackFrame) {
-    printk!(Debug, "divide_by_zero!! {:#?}\n\r", frame);
-    loop {}
+    fatal("DIVIDE BY ZERO", frame, format_args!("divide_by_zero!!"));
+}
+
+/// handlers whose latency is measured under prof feature
+#[derive(Debug, Clone, Copy)]
+pub enum LatencySource {
+    Timer = 0,
+    Keyboard = 1,
+}
+
+const NR_LATENCY_SOURCES: usize = 2;
+
+/// cycles spent in a handler, in rdtsc units
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyStats {
+    pub min: usize,
+    pub max: usize,
+    pub avg: usize,
+    pub count: usize,
+}
+
+struct Latency {
+    min: AtomicUsize,
+    max: AtomicUsize,
+    total: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Latency {
+    const fn new() -> Latency {
+        Latency {
+            min: AtomicUsize::new(::core::usize::MAX),
+            max: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    // handlers run with IF cleared on a single cpu, plain load/store is enough
+    fn record(&self, cycles: usize) {
+        if cycles < self.min.load(Ordering::Relaxed) {
+            self.min.store(cycles, Ordering::Relaxed);
+        }
+        if cycles > self.max.load(Ordering::Relaxed) {
+            self.max.store(cycles, Ordering::Relaxed);
+        }
+        self.total.fetch_add(cycles, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> LatencyStats {
+        let count = self.count.load(Ordering::Relaxed);
+        LatencyStats {
+            min: if count == 0 { 0 } else { self.min.load(Ordering::Relaxed) },
+            max: self.max.load(Ordering::Relaxed),
+            avg: if count == 0 { 0 } else { self.total.load(Ordering::Relaxed) / count },
+            count: count,
+        }
+    }
+}
+
+static LATENCIES: [Latency; NR_LATENCY_SOURCES] = [Latency::new(), Latency::new()];
+
+/// measures from creation to drop, free when prof feature is off
+pub struct LatencyGuard {
+    source: LatencySource,
+    start: u64,
+}
+
+impl LatencyGuard {
+    #[inline(always)]
+    pub fn new(source: LatencySource) -> LatencyGuard {
+        let start = if cfg!(feature = "prof") { ::kern::arch::cpu::rdtsc() } else { 0 };
+        LatencyGuard { source: source, start: start }
+    }
+}
+
+impl Drop for LatencyGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if cfg!(feature = "prof") {
+            let cycles = ::kern::arch::cpu::rdtsc() - self.start;
+            LATENCIES[self.source as usize].record(cycles as usize);
+        }
+    }
+}
+
+/// handler latencies so far, all zero unless built with prof
+pub fn latency_stats() -> [(LatencySource, LatencyStats); NR_LATENCY_SOURCES] {
+    [
+        (LatencySource::Timer, LATENCIES[LatencySource::Timer as usize].stats()),
+        (LatencySource::Keyboard, LATENCIES[LatencySource::Keyboard as usize].stats()),
+    ]
 }
 
 const IST_INDEX_DBL_FAULT: usize = 0;
+const IST_INDEX_STACK_FAULT: usize = 1;
 // single tss
 pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
 static GDT: Once<GlobalDescriptorTable> = Once::new();
@@ -96,6 +348,49 @@ pub const USER_DS_SEL: SegmentSelector = SegmentSelector((3<<3) | 3);
 pub const USER_CS_SEL: SegmentSelector = SegmentSelector((4<<3) | 3);
 pub const TSS_SEL: SegmentSelector = SegmentSelector(5<<3);
 
+/// user half of the canonical address space
+pub fn is_user_canonical(addr: usize) -> bool {
+    addr < 0x0000_8000_0000_0000
+}
+
+/// sysret raises #GP in ring 0 when its target is bad, which is miserable to
+/// track down. rip and rsp come from user, so a bad one is the task's fault
+/// and the caller kills it instead of returning.
+pub fn sysret_target_ok(rip: usize, rsp: usize) -> bool {
+    is_user_canonical(rip) && is_user_canonical(rsp)
+}
+
+/// check the selectors sysret derives from STAR, they are fixed once the
+/// gdt and STAR are loaded
+fn check_sysret_selectors() {
+    // STAR[63:48] is KERN_DS_SEL, sysret loads ss from +8 and cs from +16
+    assert_eq!(USER_DS_SEL.0 & !3, KERN_DS_SEL.0 + 8, "sysret: USER_DS_SEL mismatches STAR");
+    assert_eq!(USER_CS_SEL.0 & !3, KERN_DS_SEL.0 + 16, "sysret: USER_CS_SEL mismatches STAR");
+    for &(name, sel) in [("USER_CS_SEL", USER_CS_SEL), ("USER_DS_SEL", USER_DS_SEL)].iter() {
+        let dpl = GDT.try().and_then(|gdt| gdt.dpl(sel));
+        assert!(dpl == Some(3), "sysret: {} {:#x} has dpl {:?}, expects 3", name, sel.0, dpl);
+    }
+}
+
+/// rsp0 and ist stacks of the single tss
+pub fn dump_tss() {
+    let tss = unsafe { &TSS };
+    printk!(Normal, "tss at {:#x}\n\r", tss as *const _ as usize);
+    for (i, rsp) in tss.privilege_stack_table.iter().enumerate() {
+        printk!(Normal, "  rsp{}: {:#x}\n\r", i, rsp.0);
+    }
+    for (i, ist) in tss.interrupt_stack_table.iter().enumerate().filter(|&(_, ist)| ist.0 != 0) {
+        printk!(Normal, "  ist{}: {:#x}\n\r", i + 1, ist.0);
+    }
+}
+
+/// dump the loaded descriptor tables, for chasing setup bugs
+pub fn dump_tables() {
+    gdt::dump_loaded();
+    idt::dump_loaded();
+    dump_tss();
+}
+
 pub fn init(mm: &mut MemoryManager) {
     use x86_64;
     use x86_64::instructions::tables::load_tss;
@@ -107,6 +402,12 @@ pub fn init(mm: &mut MemoryManager) {
         unsafe {
             TSS.interrupt_stack_table[IST_INDEX_DBL_FAULT] = x86_64::VirtualAddress(dbl_fault_stack.top());
         }
+
+        let stack_fault_stack = mm.alloc_stack(1).expect("alloc stack_fault stack failed\n\r");
+        printk!(Info, "alloc stack_fault_stack {:#x}\n\r", stack_fault_stack.bottom());
+        unsafe {
+            TSS.interrupt_stack_table[IST_INDEX_STACK_FAULT] = x86_64::VirtualAddress(stack_fault_stack.top());
+        }
     }
 
     let mut tss_sel = SegmentSelector(0);
@@ -138,6 +439,7 @@ pub fn init(mm: &mut MemoryManager) {
         msr::wrmsr(msr::IA32_FMASK, 0x0200); // disable interrupt right now
         ::kern::arch::cpu::enable_sce_bit();
     }
+    check_sysret_selectors();
 
     unsafe {
         load_ds(KERN_DS_SEL);
@@ -145,17 +447,22 @@ pub fn init(mm: &mut MemoryManager) {
         set_cs(KERN_CS_SEL);
         load_tss(TSS_SEL);
     }
+    ::kern::percpu::init_boot_cpu();
 
     IDT.load();
+    if cfg!(feature = "kdebug") {
+        dump_tables();
+    }
 
     unsafe {
-        PIT.lock().init();
-        KBD.lock().init();
-
         PIC_CHAIN.lock().init();
         PIC_CHAIN.lock().enable(Irqs::IRQ2 as usize);
-        PIC_CHAIN.lock().enable(Irqs::TIMER as usize);
-        PIC_CHAIN.lock().enable(Irqs::KBD as usize);
+
+        // drivers claim their lines with irq::register_irq
+        PIT.lock().init();
+        irq::register_irq(timer::TIMER_IRQ, timer_handler);
+        KBD.lock().init();
+        ::kern::driver::serial::init_irq();
         let mut oflags = ::kern::arch::cpu::push_flags();
         printk!(Debug, "oflags {:#?}\n\r", oflags);
         interrupts::enable();
@@ -183,3 +490,18 @@ pub fn test_idt() {
         //busy_wait();
     }
 }
+
+pub fn test_selector_error_code() {
+    use collections::String;
+    use core::fmt::Write;
+
+    let text = |err: u64| {
+        let mut s = String::new();
+        let _ = write!(s, "{}", SelectorErrorCode(err));
+        s
+    };
+    test_assert_eq!(text(0), "no selector");
+    test_assert_eq!(text(0x10), "selector 0x10 (gdt[2])");
+    test_assert_eq!(text(0x2b), "external selector 0x28 (idt[5])");
+    test_assert_eq!(text(0x1c), "selector 0x18 (ldt[3])");
+}
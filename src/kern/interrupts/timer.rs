@@ -4,12 +4,13 @@ use super::idt::*;
 use super::irq::PIC_CHAIN;
 use spin::Mutex;
 use ::kern::console::LogLevel::*;
-use ::kern::console::{Console, tty1};
 
 use ::kern::task::*;
 
 const FREQ: u32 = 1193180;
-const HZ: u32 = 100;
+pub const HZ: u32 = 100;
+/// PIC line of PIT
+pub const TIMER_IRQ: u8 = 0;
 
 static TIMER_TICKS: AtomicUsize = AtomicUsize::new(0);
 pub static PIT: Mutex<Timer> = Mutex::new(Timer::new());
@@ -46,19 +47,45 @@ impl Timer {
 
 }
 
-pub extern "C" fn timer_handler(frame: &mut ExceptionStackFrame) {
-    use ::kern::console::tty1;
+/// ticks since timer init
+pub fn ticks() -> usize {
+    TIMER_TICKS.load(Ordering::SeqCst)
+}
+
+/// ticks covering at least ms milliseconds, saturates for huge ms from
+/// userspace
+pub fn ms_to_ticks(ms: usize) -> usize {
+    ms.saturating_mul(HZ as usize).saturating_add(999) / 1000
+}
 
+pub fn timer_handler(frame: &mut ExceptionStackFrame) {
+    let latency = super::LatencyGuard::new(super::LatencySource::Timer);
     unsafe { PIC_CHAIN.lock().eoi(0); }
-    //printk!(Critical, "{}\n", TIMER_TICKS.load(Ordering::Acquire));
-    
+
     let old = TIMER_TICKS.fetch_add(1, Ordering::SeqCst);
-    //if (old + 1) % HZ as usize == 0 {
-        //Console::with(&tty1, 0, 60, || {
-            //printk!(Critical, "{}", TIMER_TICKS.load(Ordering::SeqCst));
-        //});
-    //}
+    if cfg!(feature = "kdebug") && (old + 1) % (HZ as usize * 10) == 0 {
+        kprintln!("ticks: {}", old + 1);
+    }
 
-    unsafe { sched(); }
+    // never wait for the list inside irq, sleepers are woken at next tick
+    if let Some(mut tasks) = TaskList::try_get_mut() {
+        tasks.account_tick(current_id());
+        tasks.wake_sleepers(old + 1);
+    }
+    ::kern::driver::keyboard::timer_tick(old + 1);
+
+    // sched may switch away and come back much later, count it out
+    drop(latency);
+    if preemptive() {
+        unsafe { sched(); }
+    }
 }
 
+pub fn test_ms_to_ticks() {
+    use core::usize;
+
+    test_assert_eq!(ms_to_ticks(0), 0);
+    test_assert_eq!(ms_to_ticks(1), 1);
+    test_assert_eq!(ms_to_ticks(1000), HZ as usize);
+    test_assert_eq!(ms_to_ticks(usize::MAX), usize::MAX / 1000);
+}
@@ -0,0 +1,104 @@
+//! tiny kernel debugger which reads commands from COM1.
+//! symbols come from the kernel elf loaded as boot module "kernel".
+//!
+//! commands:
+//!   sym <hexaddr>   resolve address into function name + offset
+//!   ps              list tasks
+//!   cr3             show active pml4 and the one current task expects
+//!   tables          dump loaded gdt, idt and tss
+//!   irqlat          irq handler latency (needs prof feature)
+//!   dmesg           boot log kept by kern::early
+//!   switches        toggle dumping contexts of every task switch
+
+use collections::String;
+use ::kern::driver::serial;
+use ::kern::elf64::Elf64;
+use ::kern::modules;
+use ::kern::arch::cpu;
+use ::kern::task;
+use ::kern::memory::paging;
+use ::kern::console::LogLevel::*;
+
+/// resolve addr with kernel symbols, None if no symbol covers it
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    modules::find("kernel").and_then(|m| {
+        let elf = unsafe { Elf64::from(m.bytes()) };
+        elf.resolve(addr)
+    })
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    let s = s.trim_left_matches("0x").trim_left_matches("0X");
+    usize::from_str_radix(s, 16).ok()
+}
+
+fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        Some("sym") => match args.next().and_then(parse_hex) {
+            Some(addr) => match resolve(addr) {
+                Some((name, off)) => printk!(Normal, "{:#x}: {}+{:#x}\n\r", addr, name, off),
+                None => printk!(Normal, "{:#x}: unknown\n\r", addr)
+            },
+            None => printk!(Warn, "usage: sym <hexaddr>\n\r")
+        },
+        Some("ps") => task::list(),
+        Some("cr3") => {
+            let expected = task::TaskList::get().current()
+                .and_then(|t| t.try_read().map(|t| t.ctx.cr3));
+            printk!(Normal, "cr3: {:#x}, current task: {:?}\n\r",
+                paging::current_pml4_frame(), expected);
+        },
+        Some("tables") => ::kern::interrupts::dump_tables(),
+        Some("switches") => { task::dump_switches_toggle(); },
+        Some("dmesg") => ::kern::early::with_log(|older, newer| {
+            for &b in older.iter().chain(newer.iter()) {
+                if b == b'\n' {
+                    echo(b'\r');
+                }
+                echo(b);
+            }
+        }),
+        Some("irqlat") => {
+            for &(source, st) in ::kern::interrupts::latency_stats().iter() {
+                printk!(Normal, "{:?}: min {} max {} avg {} cycles, {} irqs\n\r",
+                    source, st.min, st.max, st.avg, st.count);
+            }
+        },
+        Some(cmd) => printk!(Warn, "kdbg: unknown command {}\n\r", cmd),
+        None => {}
+    }
+}
+
+fn echo(b: u8) {
+    let oflags = unsafe { cpu::push_flags() };
+    unsafe { serial::COM1.lock().write(b); }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// kernel thread polling serial for commands
+pub fn kdbg_thread() {
+    let mut line = String::new();
+    loop {
+        // serial_irq moves input off the uart, take it from there
+        match serial::read_buffered() {
+            Some(b'\r') | Some(b'\n') => {
+                echo(b'\r');
+                echo(b'\n');
+                execute(&line);
+                line.clear();
+            },
+            Some(0x08) | Some(0x7f) => {
+                if line.pop().is_some() {
+                    echo(0x08); echo(b' '); echo(0x08);
+                }
+            },
+            Some(b) if b >= 0x20 && b < 0x7f => {
+                echo(b);
+                line.push(b as char);
+            },
+            Some(_) => {},
+            None => unsafe { asm!("hlt":::: "volatile"); }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! buffered logger, fragments are formatted into a heap String and only
+//! complete lines are written to console, one lock acquisition per batch.
+//! can only be used after kernel heap is ready.
+
+use collections::String;
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+use ::kern::console::tty1;
+use ::kern::arch::cpu;
+
+static LOG_BUF: Mutex<Option<String>> = Mutex::new(None);
+
+macro_rules! log {
+    ($($arg:tt)*) => ({
+        $crate::kern::logger::_log(format_args!($($arg)*));
+    });
+}
+
+fn emit(s: &str) {
+    let mut con = tty1.lock();
+    con.write_str(s).unwrap();
+}
+
+pub fn _log(args: fmt::Arguments) {
+    let oflags = unsafe { cpu::push_flags() };
+    {
+        let mut guard = LOG_BUF.lock();
+        if guard.is_none() {
+            *guard = Some(String::new());
+        }
+
+        let buf = guard.as_mut().unwrap();
+        buf.write_fmt(args).unwrap();
+        if let Some(pos) = buf.rfind('\n') {
+            let rest = buf.split_off(pos + 1);
+            emit(buf);
+            *buf = rest;
+        }
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// write out pending fragments even if no newline seen
+pub fn flush() {
+    let oflags = unsafe { cpu::push_flags() };
+    if let Some(ref mut buf) = *LOG_BUF.lock() {
+        emit(buf);
+        buf.clear();
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// called by panic handler, the buffer may be locked by the panicking
+/// context, so never wait for it
+pub fn flush_on_panic() {
+    if let Some(mut guard) = LOG_BUF.try_lock() {
+        if let Some(ref mut buf) = *guard {
+            if !buf.is_empty() {
+                emit(buf);
+                emit("\n\r");
+                buf.clear();
+            }
+        }
+    }
+}
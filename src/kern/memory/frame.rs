@@ -4,7 +4,9 @@ use multiboot2::*;
 
 use super::PAGE_SIZE;
 use super::KERNEL_MAPPING;
-use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use collections::{BTreeMap, Vec};
 use super::frame_allocator::BuddyAllocator;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -68,22 +70,131 @@ impl Frame {
     }
 }
 
+/// kind of a memory map entry, by multiboot2 type number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// 1, free ram
+    Usable,
+    /// 2 and anything unknown
+    Reserved,
+    /// 3, holds acpi tables, ram once they are no longer needed
+    AcpiReclaimable,
+    /// 4, must be preserved across sleep
+    AcpiNvs,
+    /// 5, defective ram
+    BadRam,
+}
+
+impl RegionKind {
+    pub fn from_type(typ: u32) -> RegionKind {
+        match typ {
+            1 => RegionKind::Usable,
+            3 => RegionKind::AcpiReclaimable,
+            4 => RegionKind::AcpiNvs,
+            5 => RegionKind::BadRam,
+            _ => RegionKind::Reserved,
+        }
+    }
+}
+
+/// physical range [start, end) of one kind
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+const MAX_REGIONS: usize = 32;
+
+/// memory map with entry types kept, fixed size since it's needed
+/// before kernel heap exists
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegions {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize
+}
+
+impl MemoryRegions {
+    pub const fn empty() -> MemoryRegions {
+        MemoryRegions {
+            regions: [MemoryRegion { start: 0, end: 0, kind: RegionKind::Reserved }; MAX_REGIONS],
+            len: 0
+        }
+    }
+
+    /// extra entries are dropped with a warning
+    pub fn push(&mut self, region: MemoryRegion) {
+        use ::kern::console as con;
+        use con::LogLevel::*;
+
+        if self.len == MAX_REGIONS {
+            printk!(Warn, "memory map: too many regions, drop {:?}\n\r", region);
+            return;
+        }
+        self.regions[self.len] = region;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    pub fn usable(&self) -> ::core::iter::Filter<::core::slice::Iter<MemoryRegion>, fn(&&MemoryRegion) -> bool> {
+        fn is_usable(r: &&MemoryRegion) -> bool { r.kind == RegionKind::Usable }
+        self.as_slice().iter().filter(is_usable as fn(&&MemoryRegion) -> bool)
+    }
+
+    /// every entry of the multiboot2 memory map. memory_areas() of multiboot2
+    /// only yields usable ones and hides the type, so the tag is read by its
+    /// layout in the spec: u32 type, size, entry_size, entry_version, then
+    /// entries of u64 base, u64 length, u32 type, u32 reserved.
+    pub fn from_multiboot(mbinfo: &BootInformation) -> MemoryRegions {
+        let mmap = mbinfo.memory_map_tag().expect("memory map is unavailable");
+        let tag = mmap as *const _ as usize;
+        let (size, entry_size) = unsafe {
+            (*((tag + 4) as *const u32) as usize, *((tag + 8) as *const u32) as usize)
+        };
+
+        let mut regions = MemoryRegions::empty();
+        let mut entry = tag + 16;
+        while entry + entry_size <= tag + size {
+            let (base, len, typ) = unsafe {
+                (*(entry as *const u64) as usize, *((entry + 8) as *const u64) as usize,
+                 *((entry + 16) as *const u32))
+            };
+            if len > 0 {
+                regions.push(MemoryRegion { start: base, end: base + len, kind: RegionKind::from_type(typ) });
+            }
+            entry += entry_size;
+        }
+        regions
+    }
+}
+
 pub trait FrameAllocator {
     fn alloc_frame(&mut self) -> Option<Frame>;
     fn dealloc_frame(&mut self, frame: Frame);
 }
 
-/// early stage fast frame allocator, dealloc_frame is not implemented,
-/// since there is no need to free. after paging system being setuped, 
-/// a new frame allocator needed.
+/// frames AreaFrameAllocator can take back, there is no heap for more
+const EARLY_FREED_MAX: usize = 32;
+
+/// early stage fast bump allocator. freed frames are kept in a small stack
+/// which is checked before bumping. after paging system being setuped, 
+/// a new frame allocator needed. only Usable regions are handed out.
 #[derive(Debug)]
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
     current_area: Option<Range<Frame>>,
-    areas: MemoryAreaIter,
+    regions: MemoryRegions,
+    // index of region after current_area
+    next_region: usize,
     kernel: Range<Frame>,
     multiboot: Range<Frame>,
-    used: usize
+    used: usize,
+    freed: [Frame; EARLY_FREED_MAX],
+    nr_freed: usize
 }
 
 impl FrameAllocator for AreaFrameAllocator {
@@ -91,6 +202,12 @@ impl FrameAllocator for AreaFrameAllocator {
         use ::kern::console as con;
         use con::LogLevel::*;
 
+        if self.nr_freed > 0 {
+            self.nr_freed -= 1;
+            self.used += 1;
+            return Some(self.freed[self.nr_freed]);
+        }
+
         let frame = self.next_free_frame;
 
         if self.current_area.is_some() {
@@ -118,19 +235,31 @@ impl FrameAllocator for AreaFrameAllocator {
     }
 
     fn dealloc_frame(&mut self, frame: Frame) {
-        unimplemented!()
+        use ::kern::console as con;
+        use con::LogLevel::*;
+
+        if self.nr_freed == EARLY_FREED_MAX {
+            printk!(Warn, "early frame allocator: free list is full, leak {:#x}\n\r", frame.start_address());
+            return;
+        }
+        self.freed[self.nr_freed] = frame;
+        self.nr_freed += 1;
+        self.used = self.used.saturating_sub(1);
     }
 }
 
 impl AreaFrameAllocator {
-    pub fn new(areas: MemoryAreaIter, kernel: Range<Frame>, mb: Range<Frame>) -> AreaFrameAllocator {
+    pub fn new(regions: MemoryRegions, kernel: Range<Frame>, mb: Range<Frame>) -> AreaFrameAllocator {
         let mut afa = AreaFrameAllocator {
             next_free_frame: Frame::from_paddress(0),
             current_area: None,
-            areas: areas,
+            regions: regions,
+            next_region: 0,
             kernel: kernel,
             multiboot: mb,
-            used: 0
+            used: 0,
+            freed: [Frame::from_paddress(0); EARLY_FREED_MAX],
+            nr_freed: 0
         };
 
         afa.next_area();
@@ -140,13 +269,19 @@ impl AreaFrameAllocator {
 
     //NOTE: I assume areas are already sorted by base addr
     pub fn next_area(&mut self) {
-        if let Some(area) = self.areas.next() {
+        let regions = self.regions;
+        let next = regions.as_slice().iter().enumerate().skip(self.next_region)
+            .find(|&(_, r)| r.kind == RegionKind::Usable);
+
+        if let Some((i, area)) = next {
+            self.next_region = i + 1;
             self.current_area = Some(Range {
-                start: Frame::from_paddress(area.base_addr as usize),
-                end: Frame::from_paddress((area.base_addr + area.length - 1) as usize) + 1,
+                start: Frame::from_paddress(area.start),
+                end: Frame::from_paddress(area.end - 1) + 1,
             });
             self.next_free_frame = self.current_area.as_ref().unwrap().start;
         } else {
+            self.next_region = regions.as_slice().len();
             self.current_area = None;
         }
     }
@@ -187,16 +322,145 @@ impl<T: FrameAllocator, U: FrameAllocator> FrameAllocatorProxy<T, U> {
     }
 }
 
+/// who requested a frame, only recorded for leak detection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameOwner {
+    Unknown,
+    PageTable,
+    Page,
+}
+
+/// handle to the global frame allocator, used where a `FrameAllocator`
+/// is expected and no special allocator is required.
+pub struct GlobalFrameAllocator(pub FrameOwner);
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn alloc_frame(&mut self) -> Option<Frame> {
+        alloc_frame_for(self.0)
+    }
+
+    fn dealloc_frame(&mut self, frame: Frame) {
+        dealloc_frame(frame)
+    }
+}
+
+/// per frame reference counts, a frame goes back to allocator only when the
+/// last reference dropped. frames allocated before counts set up have count 0
+/// and are freed directly.
+static FRAME_REFS: Once<Vec<AtomicUsize>> = Once::new();
+
+/// set up reference counts for all physical frames, needs kernel heap
+pub fn init_refcounts(mbinfo: &BootInformation) {
+    let regions = MemoryRegions::from_multiboot(mbinfo);
+    let end = regions.usable().map(|r| r.end).max().unwrap();
+    let nr_frames = Frame::from_paddress(end - 1).number + 1;
+
+    FRAME_REFS.call_once(|| {
+        (0..nr_frames).map(|_| AtomicUsize::new(0)).collect()
+    });
+}
+
+fn refcount(frame: Frame) -> Option<&'static AtomicUsize> {
+    FRAME_REFS.try().and_then(|refs| refs.get(frame.number))
+}
+
+/// add a reference to an allocated frame, return new count
+pub fn frame_ref(frame: Frame) -> usize {
+    match refcount(frame) {
+        Some(r) => r.fetch_add(1, Ordering::SeqCst) + 1,
+        None => 0
+    }
+}
+
+/// decrement r unless it is zero, return new value
+fn saturating_dec(r: &AtomicUsize) -> usize {
+    let mut count = r.load(Ordering::SeqCst);
+    while count > 0 {
+        let old = r.compare_and_swap(count, count - 1, Ordering::SeqCst);
+        if old == count {
+            return count - 1;
+        }
+        count = old;
+    }
+    0
+}
+
+/// drop a reference, return new count. never goes below zero.
+pub fn frame_unref(frame: Frame) -> usize {
+    match refcount(frame) {
+        Some(r) => saturating_dec(r),
+        None => 0
+    }
+}
+
+/// usable frames reported by bootloader
+static FRAMES_TOTAL: AtomicUsize = AtomicUsize::new(0);
+/// frames taken by kernel image and boot modules plus the ones handed out
+static FRAMES_USED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// frame usage in frames. free counts every usable frame not in use, some of
+/// them may be out of reach of current allocator.
+pub fn stats() -> FrameStats {
+    let total = FRAMES_TOTAL.load(Ordering::SeqCst);
+    let used = FRAMES_USED.load(Ordering::SeqCst);
+    FrameStats {
+        total: total,
+        used: used,
+        free: total.saturating_sub(used)
+    }
+}
+
+/// frames allocated since leak_mark() and not freed yet, only under test feature
+static FRAME_TRACKER: Mutex<Option<BTreeMap<usize, FrameOwner>>> = Mutex::new(None);
+
 pub fn alloc_frame() -> Option<Frame> {
-    if let Some(ref mut proxy) = *FRAME_ALLOCATOR.lock() {
+    alloc_frame_for(FrameOwner::Unknown)
+}
+
+pub fn alloc_frame_for(owner: FrameOwner) -> Option<Frame> {
+    let frame = if let Some(ref mut proxy) = *FRAME_ALLOCATOR.lock() {
         proxy.alloc_frame()
     } else {
         panic!("FRAME_ALLOCATOR is not initialized\n");
+    };
+
+    if let Some(f) = frame {
+        FRAMES_USED.fetch_add(1, Ordering::SeqCst);
+        if let Some(r) = refcount(f) {
+            r.store(1, Ordering::SeqCst);
+        }
     }
 
+    if cfg!(feature = "test") {
+        if let Some(f) = frame {
+            if let Some(ref mut tracker) = *FRAME_TRACKER.lock() {
+                tracker.insert(f.number, owner);
+            }
+        }
+    }
+    frame
 }
 
+/// drop one reference of frame, frame is freed when no one refers it
 pub fn dealloc_frame(frame: Frame) {
+    if frame_unref(frame) > 0 {
+        return;
+    }
+
+    if cfg!(feature = "test") {
+        if let Some(ref mut tracker) = *FRAME_TRACKER.lock() {
+            tracker.remove(&frame.number);
+        }
+    }
+
+    saturating_dec(&FRAMES_USED);
     if let Some(ref mut proxy) = *FRAME_ALLOCATOR.lock() {
         proxy.dealloc_frame(frame)
     } else {
@@ -204,6 +468,53 @@ pub fn dealloc_frame(frame: Frame) {
     }
 }
 
+/// start tracking frame allocations, previous records are dropped.
+/// kernel heap is required.
+pub fn leak_mark() {
+    if cfg!(feature = "test") {
+        *FRAME_TRACKER.lock() = Some(BTreeMap::new());
+    }
+}
+
+/// report frames allocated since leak_mark() but never freed,
+/// return number of leaked frames and stop tracking.
+pub fn leak_report() -> usize {
+    use ::kern::console as con;
+    use con::LogLevel::*;
+
+    let tracker = match FRAME_TRACKER.lock().take() {
+        Some(tracker) => tracker,
+        None => return 0
+    };
+
+    let mut by_owner: BTreeMap<FrameOwner, usize> = BTreeMap::new();
+    for (&number, &owner) in tracker.iter() {
+        printk!(Debug, "leaked frame {:#x} ({:?})\n\r", number * PAGE_SIZE, owner);
+        *by_owner.entry(owner).or_insert(0) += 1;
+    }
+    for (owner, count) in by_owner.iter() {
+        printk!(Warn, "leaked {} frames owned by {:?}\n\r", count, owner);
+    }
+
+    tracker.len()
+}
+
+/// largest physically contiguous free block in bytes, for DMA buffers and
+/// huge pages. 0 before buddy allocator takes over.
+pub fn largest_free_contiguous() -> usize {
+    match *FRAME_ALLOCATOR.lock() {
+        Some(ref proxy) if !proxy.initial => {
+            proxy.alternative.as_ref().map(|buddy| buddy.largest_free()).unwrap_or(0)
+        },
+        _ => 0
+    }
+}
+
+/// total size of usable memory reported by bootloader
+pub fn total_memory(mbinfo: &BootInformation) -> usize {
+    MemoryRegions::from_multiboot(mbinfo).usable().map(|r| r.end - r.start).sum()
+}
+
 pub fn upgrade_allocator(mbinfo: &'static BootInformation) {
     use ::kern::console as con;
     use con::LogLevel::*;
@@ -213,9 +524,9 @@ pub fn upgrade_allocator(mbinfo: &'static BootInformation) {
         //FIXME: exclude heap area
         let area = {
             let area = {
-                let mmap = mbinfo.memory_map_tag().expect("memory map is unavailable");
-                let max = mmap.memory_areas().max_by_key(|a| a.base_addr).unwrap();
-                (max.base_addr as usize, (max.base_addr + max.length) as usize)
+                let regions = MemoryRegions::from_multiboot(mbinfo);
+                let max = regions.usable().max_by_key(|r| r.start).unwrap();
+                (max.start, max.end)
             };
             let current = proxy.allocator.next_free_frame.start_address();
             let v = [
@@ -240,11 +551,19 @@ pub fn init(mbinfo: &'static BootInformation) {
     use con::LogLevel::*;
 
     let kernel_base = KERNEL_MAPPING.KernelMap.start;
-    let mmap = mbinfo.memory_map_tag().expect("memory map is unavailable");
+    let regions = MemoryRegions::from_multiboot(mbinfo);
     {
-        let start = mmap.memory_areas().map(|a| a.base_addr).min().unwrap();
-        let end = mmap.memory_areas().map(|a| a.base_addr + a.length).max().unwrap();
+        let start = regions.usable().map(|r| r.start).min().unwrap();
+        let end = regions.usable().map(|r| r.end).max().unwrap();
         printk!(Info, "mmap start: {:#x}, end: {:#x}\n\r", start ,end);
+        // acpi tables stay mapped and referenced after parsing, so
+        // AcpiReclaimable is never given back
+        for r in regions.as_slice().iter().filter(|r| r.kind != RegionKind::Usable) {
+            printk!(Info, "  skip [{:#x}, {:#x}) {:?}\n\r", r.start, r.end, r.kind);
+        }
+        for r in regions.as_slice() {
+            ::kern::early::log(format_args!("mem: [{:#x}, {:#x}) {:?}\n", r.start, r.end, r.kind));
+        }
     }
 
 
@@ -269,13 +588,14 @@ pub fn init(mbinfo: &'static BootInformation) {
     let mr = {
         use ::core::cmp::{min, max};
 
-        let mods_start = mbinfo.module_tags().map(|a| a.start_address()).min().unwrap() as usize;
-        let mods_end = mbinfo.module_tags().map(|a| a.end_address()).max().unwrap() as usize;
-
-        let (mb_start, mb_end) = (
-            min(mods_start, mbinfo.start_address() - kernel_base),
-            max(mods_end, mbinfo.end_address() - kernel_base)
+        let (mut mb_start, mut mb_end) = (
+            mbinfo.start_address() - kernel_base,
+            mbinfo.end_address() - kernel_base
             );
+        if let Some((mods_start, mods_end)) = ::kern::modules::physical_range(mbinfo) {
+            mb_start = min(mods_start, mb_start);
+            mb_end = max(mods_end, mb_end);
+        }
         printk!(Info, "mboot2(include modules) start: {:#x}, end: {:#x}\n\r", mb_start, mb_end);
 
         Range {
@@ -284,8 +604,11 @@ pub fn init(mbinfo: &'static BootInformation) {
         }
     };
     
+    FRAMES_TOTAL.store(total_memory(mbinfo) / PAGE_SIZE, Ordering::SeqCst);
+    FRAMES_USED.store((kr.end.number - kr.start.number) + (mr.end.number - mr.start.number), Ordering::SeqCst);
+
     //FIXME: exclude region used by kernel heap
-    let afa = AreaFrameAllocator::new(mmap.memory_areas(), kr, mr);
+    let afa = AreaFrameAllocator::new(regions, kr, mr);
     let mut guard = FRAME_ALLOCATOR.lock();
     *guard = Some(FrameAllocatorProxy::new(afa));
 }
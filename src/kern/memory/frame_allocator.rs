@@ -78,6 +78,13 @@ impl BuddyAllocator {
         self.address_of(n)
     }
 
+    /// size in bytes of the largest block alloc() can still hand out
+    pub fn largest_free(&self) -> usize {
+        self.tree[1] * UNIT
+    }
+
+    /// freeing merges buddies back up the tree whenever both halves are free,
+    /// so contiguous blocks come back as soon as all their pieces do
     pub fn dealloc(&mut self, addr: usize) {
         if addr < self.start { return; }
 
@@ -30,10 +30,11 @@ impl FrameAllocator for TinyAllocator {
 
 impl TinyAllocator {
     pub fn new() -> TinyAllocator {
+        use super::frame::{alloc_frame_for, FrameOwner};
         let data = [
-            super::frame::alloc_frame(),
-            super::frame::alloc_frame(),
-            super::frame::alloc_frame(),
+            alloc_frame_for(FrameOwner::PageTable),
+            alloc_frame_for(FrameOwner::PageTable),
+            alloc_frame_for(FrameOwner::PageTable),
         ];
         TinyAllocator {
             frames: data
@@ -41,23 +42,49 @@ impl TinyAllocator {
     }
 }
 
+/// reserved frames not taken for page tables go back to the frame allocator,
+/// TemporaryPage is built on every user copy and would leak them otherwise
+impl Drop for TinyAllocator {
+    fn drop(&mut self) {
+        for p in self.frames.iter_mut() {
+            if let Some(frame) = p.take() {
+                super::frame::dealloc_frame(frame);
+            }
+        }
+    }
+}
+
+/// catch temporary page clobbering an existing mapping (e.g. nested with()
+/// using the same fixed address), which otherwise corrupts page tables silently.
+/// cheap enough to keep on, turn off only when profiling page table code.
+const CHECK_OVERLAP: bool = true;
+
 pub struct TemporaryPage {
     page: Page,
-    allocator:  TinyAllocator
+    allocator:  TinyAllocator,
+    mapped: bool
 }
 
 impl TemporaryPage {
     pub fn new(page: Page) -> TemporaryPage {
         TemporaryPage {
             page: page,
-            allocator: TinyAllocator::new()
+            allocator: TinyAllocator::new(),
+            mapped: false
         }
     }
 
     pub fn map(&mut self, frame: Frame, activePML4Table: &mut ActivePML4Table) -> VirtualAddress {
-        assert!(activePML4Table.translate(self.page.start_address()).is_none(),
-            "temporary page should not be mapped");
-        activePML4Table.map_to(self.page, frame, WRITABLE|PRESENT);
+        if CHECK_OVERLAP {
+            let addr = self.page.start_address();
+            assert!(!self.mapped, "temporary page {:#x} is mapped twice", addr);
+            if let Some(paddr) = activePML4Table.translate(addr) {
+                panic!("temporary page {:#x} overlaps existing mapping to {:#x}", addr, paddr);
+            }
+        }
+        self.mapped = true;
+        // page tables for temporary page come from its own reserved frames
+        activePML4Table.map_to_with(self.page, frame, WRITABLE|PRESENT, &mut self.allocator);
         printk!(Debug, "TemporaryPage::map {:x} to {:x}\n\r", frame.start_address(), 
                 self.page.start_address());
         self.page.start_address() as VirtualAddress
@@ -65,6 +92,7 @@ impl TemporaryPage {
 
     pub fn unmap(&mut self, activePML4Table: &mut ActivePML4Table) {
         printk!(Debug, "TemporaryPage::unmap\n\r");
+        self.mapped = false;
         activePML4Table.unmap(self.page)
     }
 
@@ -98,4 +126,34 @@ impl InactivePML4Table {
         
         inactive
     }
+
+    /// free the pml4 frame and every page table of this space, except the
+    /// kernel slots shared with all other spaces. frames of pages still
+    /// mapped are left alone, vmas owning them unmap them first. space must
+    /// not be the active one.
+    pub fn destroy(mut self, activePML4Table: &mut ActivePML4Table) {
+        use collections::Vec;
+        use super::frame::dealloc_frame;
+
+        assert!(current_pml4_frame() != self.pml4_frame.start_address(),
+            "destroy address space {:?} in use", self);
+
+        let mut tables = Vec::new();
+        let mut mapped = 0;
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        activePML4Table.with(&mut self, &mut temp_page, |mapper| {
+            let shared = kernel_slots();
+            // last slot is the recursive one, pointing back to pml4 itself
+            for slot in (0..ENTRY_COUNT - 1).filter(|&slot| slot < shared.start || slot >= shared.end) {
+                mapped += tables_below(mapper, slot, &mut tables);
+            }
+        });
+
+        printk!(Debug, "destroy {:?}: {} tables, {} pages still mapped\n\r",
+            self, tables.len(), mapped);
+        for f in tables {
+            dealloc_frame(f);
+        }
+        dealloc_frame(self.pml4_frame);
+    }
 }
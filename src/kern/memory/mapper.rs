@@ -1,5 +1,5 @@
 use core::ptr::Unique;
-use super::frame::{Frame, alloc_frame, dealloc_frame};
+use super::frame::{Frame, FrameAllocator, FrameOwner, GlobalFrameAllocator, alloc_frame_for};
 use super::paging::*;
 
 pub struct Mapper {
@@ -79,53 +79,127 @@ impl Mapper {
             .map(|frame| frame.start_address() + offset)
     }
 
+    /// frame mapped at page, None if it is not present at any level.
+    /// for a page inside of a huge page, the 4K frame backing it.
+    pub fn translate_page(&self, page: Page) -> Option<Frame> {
+        self.translate(page.start_address()).map(Frame::from_paddress)
+    }
+
+    /// flags of the 4K page entry mapping vaddr, None if unmapped or huge
+    pub fn entry_flags(&self, vaddr: VirtualAddress) -> Option<EntryFlags> {
+        vaddr.validate();
+
+        self.next_level_table(vaddr.pml4t_index())
+            .and_then(|p3| p3.next_level_table(vaddr.pdpt_index()))
+            .and_then(|p2| p2.next_level_table(vaddr.pdt_index()))
+            .map(|p1| p1[vaddr.pt_index()].flags())
+            .and_then(|flags| if flags.contains(PRESENT) { Some(flags) } else { None })
+    }
+
+    /// current flags of 4K page, None if it is not mapped
+    pub fn flags_of(&self, page: Page) -> Option<EntryFlags> {
+        self.entry_flags(page.start_address())
+    }
+
+    /// replace flags of mapped 4K page with new_flags, frame stays. for
+    /// copy-on-write and W^X changes.
+    pub fn protect(&mut self, page: Page, new_flags: EntryFlags) {
+        let vaddr = page.start_address() as VirtualAddress;
+        vaddr.validate();
+
+        {
+            let p1 = self.next_level_table_mut(vaddr.pml4t_index())
+                .and_then(|p3| p3.next_level_table_mut(vaddr.pdpt_index()))
+                .and_then(|p2| p2.next_level_table_mut(vaddr.pdt_index()))
+                .expect("protect: page is not mapped by a 4K entry");
+            let frame = p1[vaddr.pt_index()].pointed_frame()
+                .expect("protect: page is not present");
+            p1[vaddr.pt_index()].set(frame, new_flags | PRESENT);
+        }
+        flush(vaddr);
+    }
+
     //FIXME: need to check if frame has been used
     pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) {
+        self.map_to_with(page, frame, flags, &mut GlobalFrameAllocator(FrameOwner::PageTable))
+    }
+
+    /// like map_to, but intermediate tables are allocated from `allocator`
+    pub fn map_to_with<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator {
         let vaddr = page.start_address() as VirtualAddress;
 
-        let pdpt = self.next_level_table_or_create(vaddr.pml4t_index());
-        let pdt = pdpt.next_level_table_or_create(vaddr.pdpt_index());
-        let pt = pdt.next_level_table_or_create(vaddr.pdt_index());
+        let pdpt = self.next_level_table_or_create_with(vaddr.pml4t_index(), allocator);
+        let pdt = pdpt.next_level_table_or_create_with(vaddr.pdpt_index(), allocator);
+        let pt = pdt.next_level_table_or_create_with(vaddr.pdt_index(), allocator);
 
         assert!(pt[vaddr.pt_index()].is_unused(),
             "pt[vaddr.pt_index()] used: vaddr {:#x} -> {:#x}\n\r",
             vaddr, pt[vaddr.pt_index()].pointed_frame().as_ref().unwrap().start_address());
         pt[vaddr.pt_index()].set(frame, flags | PRESENT);
+        flush(vaddr);
     }
 
 
+    /// map 2MiB page starting at page to 2MiB of frames starting at frame,
+    /// both must be 2MiB aligned. the PDT entry is set directly, no PT is
+    /// used. unmap() takes the whole 2MiB back.
+    pub fn map_huge_2mib(&mut self, page: Page, frame: Frame, flags: EntryFlags) {
+        let vaddr = page.start_address() as VirtualAddress;
+        assert!(vaddr % HUGE_PAGE_2MIB == 0 && frame.start_address() % HUGE_PAGE_2MIB == 0,
+            "huge page {:#x} -> {:#x} is not 2MiB aligned", vaddr, frame.start_address());
+
+        let mut allocator = GlobalFrameAllocator(FrameOwner::PageTable);
+        let pdpt = self.next_level_table_or_create_with(vaddr.pml4t_index(), &mut allocator);
+        let pdt = pdpt.next_level_table_or_create_with(vaddr.pdpt_index(), &mut allocator);
+
+        assert!(pdt[vaddr.pdt_index()].is_unused(), "pdt[vaddr.pdt_index()] used: vaddr {:#x}", vaddr);
+        pdt[vaddr.pdt_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+        flush(vaddr);
+    }
+
     pub fn map(&mut self, page: Page, flags: EntryFlags) {
-        let frame = alloc_frame().expect("no more free frame available");
+        let frame = alloc_frame_for(FrameOwner::Page).expect("no more free frame available");
         self.map_to(page, frame, flags)
     }
 
+    pub fn map_with<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator {
+        let frame = allocator.alloc_frame().expect("no more free frame available");
+        self.map_to_with(page, frame, flags, allocator)
+    }
+
     pub fn identity_map(&mut self, frame: Frame, flags: EntryFlags) {
         let page = Page::from_vaddress(frame.start_address());
         self.map_to(page, frame, flags)
     }
 
-    //TODO: support huge page
+    /// unmap 4K page, or the whole 2MiB page if page starts one.
+    //TODO: support 1G huge page
     pub fn unmap(&mut self, page: Page) {
         let vaddr = page.start_address() as VirtualAddress;
         assert!(self.translate(vaddr).is_some(), "vaddr {:#x} doest exist in mapping", vaddr);
 
-        let p3 = self.next_level_table_mut(vaddr.pml4t_index());
+        let p2 = match self.next_level_table_mut(vaddr.pml4t_index())
+            .and_then(|p3| p3.next_level_table_mut(vaddr.pdpt_index())) {
+            Some(p2) => p2,
+            None => return
+        };
 
-        let huge_page = || {None};
+        if p2[vaddr.pdt_index()].flags().contains(HUGE_PAGE) {
+            assert!(vaddr % HUGE_PAGE_2MIB == 0, "vaddr {:#x} is inside of a 2MiB page", vaddr);
+            p2[vaddr.pdt_index()].set_unused();
+            // one invlpg drops the whole 2MiB entry
+            flush(vaddr);
+            return;
+        }
 
-        p3.and_then(|p3| p3.next_level_table_mut(vaddr.pdpt_index()))
-            .and_then(|p2| p2.next_level_table_mut(vaddr.pdt_index()))
-            .and_then(|p1| {
-                assert!(!p1[vaddr.pt_index()].is_unused());
-                let frame = p1[vaddr.pt_index()].pointed_frame().unwrap();
-                p1[vaddr.pt_index()].set_unused();
+        let p1 = p2.next_level_table_mut(vaddr.pdt_index()).unwrap();
+        assert!(!p1[vaddr.pt_index()].is_unused());
+        p1[vaddr.pt_index()].set_unused();
 
-                ::kern::arch::cpu::tlb_flush(vaddr);
-                //TODO: free pdpt, pdt, pt tables when empty
-                //dealloc_frame(frame);
-                Some(())
-            })
-            .or_else(huge_page);
+        flush(vaddr);
+        //TODO: free pdpt, pdt, pt tables when empty
     }
 }
 
@@ -4,8 +4,10 @@ pub mod inactive;
 pub mod mapper;
 pub mod stack_allocator;
 pub mod frame_allocator;
+pub mod uaccess;
 
 pub use self::stack_allocator::Stack;
+pub use self::frame::{leak_mark, leak_report, largest_free_contiguous};
 
 use self::paging::*;
 use core::ops::Range;
@@ -23,6 +25,9 @@ pub const PAGE_SIZE: usize = 4096;
 /// concrete page mapping schema of memory areas, inspired from linux x86_64
 /// ref: https://www.kernel.org/doc/Documentation/x86/x86_64/mm.txt
 /// 0000000000000000 - 00007fffffffffff (=47 bits) user space, different per mm
+///     00000000_04000000 - 00005fff_ffffffff elf segments
+///     00006000_00000000 - 00006fff_ffffffff shared memory attachments
+///     00007000_00000000 - 00007000_3fffffff framebuffer of its owner
 /// hole caused by [48:63] sign extension
 /// ffff800000000000 - ffff8007ffffffff (=32G) direct mapping of all phys. memory
 /// ffff800800000000 - ffff87ffffffffff (=43bits) reserved now
@@ -33,6 +38,8 @@ pub struct MemorySchema {
     pub UserMap: Range<usize>,
     pub UserCode: Range<usize>,
     pub UserStack: Range<usize>,
+    pub UserShm: Range<usize>,
+    pub UserFb: Range<usize>,
     pub Invalid: Range<usize>, // hardware hole
     pub PhysicalDirectMap: Range<usize>,
     pub KernelMap: Range<usize>,
@@ -43,8 +50,10 @@ pub struct MemorySchema {
 pub const KERNEL_MAPPING: MemorySchema = MemorySchema {
     UserMap: Range {start: 0, end: 0x7fff_ff7fffff},
 
-    UserCode: Range {start: 0x04000000, end: 0x7fff_ff7fffff},
+    UserCode: Range {start: 0x04000000, end: 0x5fff_ffffffff},
     UserStack: Range {start: 0x7fff_ff800000, end: 0x7fff_ffffefff},
+    UserShm: Range {start: 0x6000_00000000, end: 0x6fff_ffffffff},
+    UserFb: Range {start: 0x7000_00000000, end: 0x7000_3fffffff},
 
     Invalid: Range {start: 0x8000_00000000, end: 0xffff7fff_ffffffff},
     PhysicalDirectMap: Range {start: 0xffff8000_00000000, end: 0xffff8007_ffffffff},
@@ -54,6 +63,12 @@ pub const KERNEL_MAPPING: MemorySchema = MemorySchema {
     KernelStack: Range {start: 0xffff8802_00000000, end: 0xffff8802_07ffffff}, // 128MB
 };
 
+/// bytes covered by region r of MemorySchema, whose end is inclusive
+pub fn region_size(r: &Range<usize>) -> usize {
+    r.end.checked_sub(r.start).and_then(|n| n.checked_add(1))
+        .expect("memory schema: region ends before it starts or spans all addresses")
+}
+
 #[allow(non_snake_case)]
 pub struct MemoryManager<'a> {
     pub activePML4Table: ActivePML4Table,
@@ -67,6 +82,22 @@ impl<'a> MemoryManager<'a> {
         self.stackAllocator.alloc_stack(&mut self.activePML4Table, size_in_pages)
     }
 
+    /// drop a reference of frame, it goes back to frame allocator with the
+    /// last one, see frame::dealloc_frame
+    pub fn free_frame(&mut self, frame: frame::Frame) {
+        frame::dealloc_frame(frame)
+    }
+
+    pub fn stats(&self) -> frame::FrameStats {
+        frame::stats()
+    }
+}
+
+/// print frame usage at level
+pub fn log_stats(level: con::LogLevel) {
+    let st = frame::stats();
+    printk!(level, "frames: {} used, {} free of {} ({}MB free)\n\r",
+            st.used, st.free, st.total, st.free * PAGE_SIZE >> 20);
 }
 
 pub static MM: Once<Mutex<MemoryManager<'static>>> = Once::new();
@@ -89,6 +120,7 @@ pub fn init(mbinfo: &'static BootInformation) -> &'static Mutex<MemoryManager<'s
     ::kern::arch::cpu::enable_write_protect_bit();
     remap_the_kernel(&mbinfo);
     frame::upgrade_allocator(&mbinfo);
+    frame::init_refcounts(&mbinfo);
     if cfg!(feature = "test") {
         test_frame_allocator_upgraded();
         test_paging_after_remap();
@@ -103,6 +135,7 @@ pub fn init(mbinfo: &'static BootInformation) -> &'static Mutex<MemoryManager<'s
         StackAllocator::new(start, end)
     };
 
+    log_stats(Info);
     MM.call_once(|| {
         Mutex::new(MemoryManager {
             activePML4Table: ActivePML4Table::new(),
@@ -117,12 +150,173 @@ pub fn init(mbinfo: &'static BootInformation) -> &'static Mutex<MemoryManager<'s
 
 fn test_frame_allocator() {
     let mut i = 0;
-    while let Some(_) = frame::alloc_frame() {
+    let mut last = None;
+    while let Some(f) = frame::alloc_frame() {
         //printk!(Warn, "{:#x}({:#x})  ", f.number, f.start_address());
+        last = Some(f);
         i += 1;
         if i == 20 { break }
     }
     printk!(Warn, "allocated #{} frames\n\r", i);
+
+    // freed frame is handed out again before bumping
+    let last = last.expect("no frame allocated");
+    let used = frame::stats().used;
+    frame::dealloc_frame(last);
+    assert_eq!(frame::stats().used, used - 1);
+    assert_eq!(frame::alloc_frame(), Some(last));
+    assert_eq!(frame::stats().used, used);
+}
+
+pub fn test_frame_stats() {
+    let before = frame::stats();
+    test_assert_eq!(before.used + before.free, before.total);
+
+    let f = frame::alloc_frame().expect("no more mem");
+    let st = frame::stats();
+    test_assert_eq!((st.used, st.free), (before.used + 1, before.free - 1));
+
+    // still referenced, stays in use
+    frame::frame_ref(f);
+    frame::dealloc_frame(f);
+    test_assert_eq!(frame::stats(), st);
+    frame::dealloc_frame(f);
+    test_assert_eq!(frame::stats(), before);
+}
+
+pub fn test_frame_leak_detector() {
+    use collections::Vec;
+    use self::frame::FrameOwner;
+
+    leak_mark();
+    let mut v = Vec::new();
+    for _ in 0..8 {
+        v.push(frame::alloc_frame_for(FrameOwner::Page).expect("no more mem"));
+    }
+    let kept = v.pop().unwrap();
+    for f in v {
+        frame::dealloc_frame(f);
+    }
+    test_assert_eq!(leak_report(), 1);
+
+    leak_mark();
+    frame::dealloc_frame(kept);
+    test_assert_eq!(leak_report(), 0);
+    printk!(Warn, "frame leak detector ok\n\r");
+}
+
+pub fn test_temporary_page_frames() {
+    use self::inactive::TemporaryPage;
+
+    let mut active = ActivePML4Table::new();
+    let frame = frame::alloc_frame().expect("no more mem");
+    {
+        // first map may build page tables for the page, they are kept
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        temp_page.map(frame, &mut active);
+        temp_page.unmap(&mut active);
+    }
+
+    leak_mark();
+    for _ in 0..8 {
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        temp_page.map(frame, &mut active);
+        temp_page.unmap(&mut active);
+    }
+    test_assert_eq!(leak_report(), 0);
+    frame::dealloc_frame(frame);
+}
+
+pub fn test_frame_refcount() {
+    let f = frame::alloc_frame().expect("no more mem");
+    test_assert_eq!(frame::frame_ref(f), 2);
+    test_assert_eq!(frame::frame_ref(f), 3);
+
+    // still referenced, so stays allocated
+    frame::dealloc_frame(f);
+    frame::dealloc_frame(f);
+    test_assert_eq!(frame::frame_ref(f), 2);
+    test_assert_eq!(frame::frame_unref(f), 1);
+
+    frame::dealloc_frame(f);
+    test_assert_eq!(frame::frame_unref(f), 0);
+    printk!(Warn, "frame refcount ok\n\r");
+}
+
+/// free in a fragmenting pattern (every other block first), the whole
+/// range must coalesce back once everything is freed
+pub fn test_frame_coalescing() {
+    use collections::Vec;
+    use self::frame_allocator::BuddyAllocator;
+
+    // buddy allocator only does bookkeeping, the range is never touched
+    let nr = 64;
+    let mut buddy = BuddyAllocator::new(0x1000_0000, nr * PAGE_SIZE);
+    let blocks: Vec<usize> = (0..nr).map(|_| buddy.alloc(PAGE_SIZE).expect("buddy: no more mem")).collect();
+    test_assert_eq!(buddy.largest_free(), 0);
+
+    for (_, b) in blocks.iter().enumerate().filter(|&(i, _)| i % 2 == 0) {
+        buddy.dealloc(*b);
+    }
+    test_assert_eq!(buddy.largest_free(), PAGE_SIZE);
+    test_assert!(buddy.alloc(2 * PAGE_SIZE).is_none(), "fragmented buddy gives out 2 pages");
+
+    for (_, b) in blocks.iter().enumerate().filter(|&(i, _)| i % 2 == 1) {
+        buddy.dealloc(*b);
+    }
+    test_assert_eq!(buddy.largest_free(), nr * PAGE_SIZE);
+    test_assert!(buddy.alloc(nr * PAGE_SIZE).is_some(), "coalesced buddy fails whole range");
+
+    // same for the global allocator, it comes back to where it was
+    let before = largest_free_contiguous();
+    let frames: Vec<frame::Frame> = (0..32).map(|_| frame::alloc_frame().expect("no more mem")).collect();
+    let (even, odd): (Vec<_>, Vec<_>) = frames.iter().enumerate().partition(|&(i, _)| i % 2 == 0);
+    for (_, f) in even.into_iter().chain(odd.into_iter()) {
+        frame::dealloc_frame(*f);
+    }
+    test_assert_eq!(largest_free_contiguous(), before);
+    printk!(Warn, "frame coalescing ok, largest free block {:#x}\n\r", before);
+}
+
+/// early allocator over a synthetic memory map with every entry type,
+/// only frames of Usable regions may come out
+pub fn test_memory_regions() {
+    use self::frame::{AreaFrameAllocator, FrameAllocator, Frame, MemoryRegions, MemoryRegion, RegionKind};
+
+    let types = [1, 2, 3, 4, 5, 1];
+    let mut regions = MemoryRegions::empty();
+    for (i, &typ) in types.iter().enumerate() {
+        let start = 0x100_0000 + i * 0x10000;
+        regions.push(MemoryRegion { start: start, end: start + 4 * PAGE_SIZE, kind: RegionKind::from_type(typ) });
+    }
+
+    // allocator only does bookkeeping, the frames are never touched
+    let none = Frame::from_paddress(0)..Frame::from_paddress(0);
+    let mut afa = AreaFrameAllocator::new(regions, none.clone(), none);
+    let mut count = 0;
+    while let Some(f) = afa.alloc_frame() {
+        let paddr = f.start_address();
+        let kind = regions.as_slice().iter()
+            .find(|r| paddr >= r.start && paddr < r.end)
+            .map(|r| r.kind);
+        test_assert!(kind == Some(RegionKind::Usable), "frame {:#x} from {:?} region", paddr, kind);
+        count += 1;
+    }
+    test_assert_eq!(count, 8);
+}
+
+/// user regions with a fixed purpose must not overlap, each of them assumes
+/// its addresses are its own
+pub fn test_memory_schema() {
+    let m = &KERNEL_MAPPING;
+    let user = [("UserCode", &m.UserCode), ("UserShm", &m.UserShm),
+                ("UserFb", &m.UserFb), ("UserStack", &m.UserStack)];
+    for (i, &(name, r)) in user.iter().enumerate() {
+        test_assert!(r.start >= m.UserMap.start && r.end <= m.UserMap.end, "{} is outside UserMap", name);
+        for &(other, o) in user[i + 1..].iter() {
+            test_assert!(r.end < o.start || o.end < r.start, "{} overlaps {}", name, other);
+        }
+    }
 }
 
 fn test_frame_allocator_upgraded() {
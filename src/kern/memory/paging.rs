@@ -1,5 +1,7 @@
 use core::ops::{Range, Add, AddAssign};
-use super::frame::{Frame, FrameRange, alloc_frame};
+use core::sync::atomic::{AtomicBool, Ordering};
+use collections::Vec;
+use super::frame::{Frame, FrameRange, FrameAllocator, FrameOwner, GlobalFrameAllocator, alloc_frame, alloc_frame_for, dealloc_frame};
 use super::mapper::Mapper;
 use super::{PAGE_SIZE, KERNEL_MAPPING};
 use super::inactive::{InactivePML4Table, TemporaryPage};
@@ -62,12 +64,23 @@ impl Iterator for PageRange {
 }
 
 impl PageRange {
+    /// pages covering [start, end), empty if end == start
     pub fn new(start: VirtualAddress, end: VirtualAddress) -> PageRange {
+        assert!(start <= end, "PageRange: end {:#x} is below start {:#x}", end, start);
+        let first = Page::from_vaddress(start);
+        if end == start {
+            return PageRange { start: first, end: first };
+        }
         PageRange {
-            start: Page::from_vaddress(start),
-            end: Page::from_vaddress(end-1) + 1
+            start: first,
+            end: Page::from_vaddress(end - 1) + 1
         }
     }
+
+    /// pages covering [start, start + size), None if it wraps around
+    pub fn from_size(start: VirtualAddress, size: usize) -> Option<PageRange> {
+        start.checked_add(size).map(|end| PageRange::new(start, end))
+    }
 }
 
 bitflags! {
@@ -91,6 +104,8 @@ bitflags! {
 
 const AddressBitsMask: usize = 0x000fffff_fffff000;
 pub const ENTRY_COUNT: usize = 512;
+/// size of a page mapped by a PDT entry with HUGE_PAGE set
+pub const HUGE_PAGE_2MIB: usize = PAGE_SIZE * ENTRY_COUNT;
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
@@ -248,8 +263,15 @@ impl<L> Table<L> where L: HierarchyTableLevel {
 
     pub fn next_level_table_or_create(&mut self, index: usize) 
         -> &mut Table<L::NextLevel> {
+        self.next_level_table_or_create_with(index, &mut GlobalFrameAllocator(FrameOwner::PageTable))
+    }
+
+    /// table frame is allocated from `allocator` if needed
+    pub fn next_level_table_or_create_with<A>(&mut self, index: usize, allocator: &mut A)
+        -> &mut Table<L::NextLevel> where A: FrameAllocator {
+        assert!(!self.entries[index].flags().contains(HUGE_PAGE), "entry {} maps a huge page", index);
         if self.next_level_table(index).is_none() {
-            let frame = alloc_frame().expect("no more free frame available");
+            let frame = allocator.alloc_frame().expect("no more free frame available");
             //FIXME: mark mid level tables as USER to make them accessable
             self.entries[index].set(frame, WRITABLE | PRESENT | USER);
             self.next_level_table_mut(index).unwrap().zero()
@@ -295,7 +317,7 @@ impl ActivePML4Table {
             let old_pml4 = tempPage.map_table_frame(backup, self);
 
             self.entries[511].set(inactive.pml4_frame, backup2.flags());
-            ::kern::arch::cpu::tlb_flush_all();
+            flush_all();
 
             f(self);
 
@@ -303,13 +325,35 @@ impl ActivePML4Table {
             /// active pml4's top now is not recursive-mapped anymore, that's why we temp-mapped it 
             /// to old_pml4
             old_pml4[511].set(backup, backup2.flags());
-            ::kern::arch::cpu::tlb_flush_all();
+            flush_all();
         }
 
         tempPage.unmap(self);
     }
 }
 
+/// drop TLB entry of the page holding vaddr (invlpg), enough after a single
+/// page entry changed, a huge one included
+pub fn flush(vaddr: VirtualAddress) {
+    ::kern::arch::cpu::tlb_flush(vaddr)
+}
+
+/// drop every non GLOBAL TLB entry (cr3 reload), for changes to a whole
+/// address space, like swapping recursive slot in ActivePML4Table::with
+pub fn flush_all() {
+    ::kern::arch::cpu::tlb_flush_all()
+}
+
+/// pml4 slots of kernel mapping and kernel heap
+pub fn kernel_slots() -> Range<usize> {
+    KERNEL_MAPPING.KernelMap.start.pml4t_index()..KERNEL_MAPPING.KernelHeap.end.pml4t_index() + 1
+}
+
+/// set once the boot address space is in use. spaces created after that
+/// point their kernel slots to its tables, so kernel mappings done later in
+/// any space (stack guard pages, lapic, acpi tables) show up in all of them.
+static KERNEL_SLOTS_SHARED: AtomicBool = AtomicBool::new(false);
+
 pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
     let kernel_base = KERNEL_MAPPING.KernelMap.start;
     let mut active = ActivePML4Table::new();
@@ -319,10 +363,24 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
     let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
 
     let mut new_map = {
-        let frame = alloc_frame().expect("no more memory");
+        let frame = alloc_frame_for(FrameOwner::PageTable).expect("no more memory");
         InactivePML4Table::new(frame, &mut active, &mut temp_page)
     };
 
+    if KERNEL_SLOTS_SHARED.load(Ordering::SeqCst) {
+        // entries of active are out of reach inside with(), copy them first
+        let shared: Vec<(usize, PageEntry)> = kernel_slots().map(|i| (i, active[i])).collect();
+        active.with(&mut new_map, &mut temp_page, |mapper| {
+            for &(i, entry) in shared.iter() {
+                assert!(!entry.is_unused(), "kernel pml4 slot {} is not populated", i);
+                mapper[i] = entry;
+            }
+        });
+
+        printk!(Debug, "create_address_space {:?} shares kernel slots\n\r", new_map);
+        return new_map;
+    }
+
     //TODO: need to move kernel stack into high address area
     active.with(&mut new_map, &mut temp_page, |mapper| {
         let elf = mbinfo.elf_sections_tag().expect("elf sections is unavailable");
@@ -331,7 +389,9 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
                 continue;
             }
 
-            let mut flags = PRESENT;
+            // kernel image is the same in every address space, keep it
+            // in TLB across task switches
+            let mut flags = PRESENT | GLOBAL;
             if !sect.flags().contains(ELF_SECTION_EXECUTABLE) {
                 flags |= NO_EXECUTE;
             }
@@ -359,7 +419,7 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
                 //make the whole area (including booting code) for stack
                 let mut r = FrameRange::new(sect.start_address(), sect.end_address());
 
-                flags = PRESENT | NO_EXECUTE | WRITABLE;
+                flags = PRESENT | NO_EXECUTE | WRITABLE | GLOBAL;
                 printk!(Info, "map boot section [{:#x}, {:#x}) -> [{:#x}, {:#x}), flags: {:?} as stack\n\r",
                     r.start.start_address(), r.end.start_address(),
                     r.start.start_address() + kernel_base, r.end.start_address() + kernel_base,
@@ -409,13 +469,14 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
             use ::core::cmp::{min, max};
             // map mbinfo and modules.
             // NOTE: modules appears in front or end of mbinfo structure
-            let mods_start = mbinfo.module_tags().map(|a| a.start_address()).min().unwrap() as usize;
-            let mods_end = mbinfo.module_tags().map(|a| a.end_address()).max().unwrap() as usize;
-
-            let (mb_start, mb_end) = (
-                min(mods_start, mbinfo.start_address() - kernel_base),
-                max(mods_end, mbinfo.end_address() - kernel_base)
+            let (mut mb_start, mut mb_end) = (
+                mbinfo.start_address() - kernel_base,
+                mbinfo.end_address() - kernel_base
                 );
+            if let Some((mods_start, mods_end)) = ::kern::modules::physical_range(mbinfo) {
+                mb_start = min(mods_start, mb_start);
+                mb_end = max(mods_end, mb_end);
+            }
 
             let r = FrameRange::new(mb_start, mb_end);
             printk!(Info, "map mbinfo(include modules) [{:#x} -> {:#x})\n\r", 
@@ -431,13 +492,13 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
             //map kheap area to high end of physical area
             //TODO: should be lazily mapped after page fault sets up
             let start_address = KERNEL_MAPPING.KernelHeap.start;
-            let alloc_size = KERNEL_MAPPING.KernelHeap.end - KERNEL_MAPPING.KernelHeap.start + 1;
+            let alloc_size = super::region_size(&KERNEL_MAPPING.KernelHeap);
 
             //FIXME: so FrameAllocator should not override this region
             //heap occupies HEAP_RANGE of the end of physical area
             let mut r = {
-                let mmap = mbinfo.memory_map_tag().unwrap();
-                let end = mmap.memory_areas().map(|a| a.base_addr + a.length).max().unwrap() as usize;
+                let regions = ::kern::memory::frame::MemoryRegions::from_multiboot(mbinfo);
+                let end = regions.usable().map(|r| r.end).max().unwrap();
                 FrameRange::new(end - alloc_size, end)
             };
 
@@ -461,15 +522,22 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
 pub fn remap_the_kernel(mbinfo: &BootInformation) {
     let mut new_map = create_address_space(mbinfo);
     switch(new_map);
+    KERNEL_SLOTS_SHARED.store(true, Ordering::SeqCst);
+    ::kern::arch::cpu::enable_global_pages();
 
     let start_address = KERNEL_MAPPING.KernelHeap.start;
-    let alloc_size = KERNEL_MAPPING.KernelHeap.end - KERNEL_MAPPING.KernelHeap.start + 1;
+    let alloc_size = super::region_size(&KERNEL_MAPPING.KernelHeap);
     use kheap_allocator;
     kheap_allocator::init(start_address, alloc_size);
 }
 
+/// physical address of the pml4 table in use, cr3 flag bits stripped
+pub fn current_pml4_frame() -> PhysicalAddress {
+    ::kern::arch::cpu::cr3() & !0xfff
+}
+
 pub fn switch(new_map: InactivePML4Table) -> InactivePML4Table {
-    let old = Frame::from_paddress(::kern::arch::cpu::cr3());
+    let old = Frame::from_paddress(current_pml4_frame());
 
     unsafe {
         ::kern::arch::cpu::cr3_set(new_map.pml4_frame.start_address());
@@ -580,3 +648,370 @@ pub fn test_paging_after_remap() {
         //for p in v2.iter_mut() { *p = 3; }
     }
 }
+
+/// frame allocator which records frames handed out, so mapping code can be
+/// checked for how many table frames it consumes
+struct CountingAllocator {
+    allocated: usize,
+    deallocated: usize,
+    limit: usize,
+}
+
+impl FrameAllocator for CountingAllocator {
+    fn alloc_frame(&mut self) -> Option<Frame> {
+        if self.allocated - self.deallocated >= self.limit {
+            return None;
+        }
+        self.allocated += 1;
+        alloc_frame()
+    }
+
+    fn dealloc_frame(&mut self, frame: Frame) {
+        self.deallocated += 1;
+        super::frame::dealloc_frame(frame)
+    }
+}
+
+/// pml4 slot the mapping selftests share, unused in the boot address space
+const SCRATCH_VADDR: VirtualAddress = 0x7f00_0000_0000;
+
+/// run f on the first page of the scratch pml4 slot, then free the tables
+/// its mappings left below the slot (unmap keeps them). f must unmap every
+/// page it mapped there.
+fn with_scratch_page<F>(f: F) where F: FnOnce(&mut ActivePML4Table, Page) {
+    let mut pml4 = ActivePML4Table::new();
+    let slot = SCRATCH_VADDR.pml4t_index();
+    assert!(pml4.next_level_table(slot).is_none(), "scratch pml4 slot already used");
+
+    f(&mut pml4, Page::from_vaddress(SCRATCH_VADDR));
+    free_tables_below(&mut pml4, slot);
+}
+
+/// push frames of the page tables under pml4 entry slot to frames, return
+/// how many pages (huge ones included) are still mapped there
+pub fn tables_below(pml4: &Table<PML4T>, slot: usize, frames: &mut Vec<Frame>) -> usize {
+    let mut mapped = 0;
+    if let Some(p3) = pml4.next_level_table(slot) {
+        for i in 0..ENTRY_COUNT {
+            if p3[i].flags().contains(HUGE_PAGE) {
+                mapped += 1;
+            }
+            if let Some(p2) = p3.next_level_table(i) {
+                for j in 0..ENTRY_COUNT {
+                    if p2[j].flags().contains(HUGE_PAGE) {
+                        mapped += 1;
+                    }
+                    if let Some(p1) = p2.next_level_table(j) {
+                        mapped += (0..ENTRY_COUNT).filter(|&k| !p1[k].is_unused()).count();
+                        frames.push(p2[j].pointed_frame().unwrap());
+                    }
+                }
+                frames.push(p3[i].pointed_frame().unwrap());
+            }
+        }
+        frames.push(pml4[slot].pointed_frame().unwrap());
+    }
+    mapped
+}
+
+/// give back all tables under pml4 entry slot and clear it, no page may be
+/// mapped there anymore
+fn free_tables_below(pml4: &mut ActivePML4Table, slot: usize) {
+    let mut frames = Vec::new();
+    let mapped = tables_below(pml4, slot, &mut frames);
+    assert!(mapped == 0, "{} pages left in pml4 slot {}", mapped, slot);
+
+    pml4[slot].set_unused();
+    // recursive mappings of the freed tables go as well
+    flush_all();
+    for f in frames {
+        dealloc_frame(f);
+    }
+}
+
+/// map a page in a untouched pml4 slot through a mock allocator, all three
+/// intermediate tables should come from it, then page itself.
+pub fn test_mapper_with_allocator() {
+    printk!(Debug, "test_mapper_with_allocator\n\r");
+
+    with_scratch_page(|pml4, page| {
+        let vaddr = page.start_address();
+        let mut allocator = CountingAllocator { allocated: 0, deallocated: 0, limit: 4 };
+        pml4.map_with(page, WRITABLE, &mut allocator);
+        test_assert_eq!(allocator.allocated, 4);
+
+        // tables exist now, no more frames needed for the neighbour page
+        let frame = alloc_frame().expect("no more mem");
+        pml4.map_to_with(page + 1, frame, WRITABLE, &mut allocator);
+        test_assert_eq!(allocator.allocated, 4);
+
+        let mapped = pml4.translate_page(page);
+        test_assert!(mapped.is_some());
+        test_assert_eq!(pml4.translate((page + 1).start_address() + 0x10), Some(frame.start_address() + 0x10));
+
+        pml4.unmap(page);
+        pml4.unmap(page + 1);
+        test_assert!(pml4.translate(vaddr).is_none());
+        if let Some(f) = mapped {
+            dealloc_frame(f);
+        }
+        dealloc_frame(frame);
+    });
+}
+
+/// walk of a fresh mapping gives back the frame mapper was handed, holes at
+/// any level translate to None
+pub fn test_translate() {
+    with_scratch_page(|pml4, page| {
+        let vaddr = page.start_address();
+        test_assert!(pml4.translate(vaddr).is_none());
+        test_assert!(pml4.translate_page(page).is_none());
+
+        let frame = alloc_frame().expect("no more mem");
+        pml4.map_to(page, frame, WRITABLE | NO_EXECUTE);
+        test_assert_eq!(pml4.translate_page(page), Some(frame));
+        test_assert_eq!(pml4.translate(vaddr + 0x123), Some(frame.start_address() + 0x123));
+        test_assert_eq!(pml4.translate(vaddr + PAGE_SIZE - 1), Some(frame.start_address() + PAGE_SIZE - 1));
+        // tables are there, entry is not
+        test_assert!(pml4.translate_page(page + 1).is_none());
+
+        pml4.unmap(page);
+        test_assert!(pml4.translate_page(page).is_none());
+        dealloc_frame(frame);
+    });
+}
+
+/// a 2MiB page takes a single PDT entry, every 4K page inside of it
+/// translates to the matching 4K frame of the huge one
+pub fn test_huge_page() {
+    with_scratch_page(|pml4, page| {
+        let vaddr = page.start_address();
+        // only translated, never accessed, so any 2MiB aligned frame does
+        let frame = Frame::from_paddress(HUGE_PAGE_2MIB);
+        pml4.map_huge_2mib(page, frame, NO_EXECUTE);
+        test_assert_eq!(pml4.translate(vaddr), Some(frame.start_address()));
+        test_assert_eq!(pml4.translate(vaddr + 0x12_3456), Some(frame.start_address() + 0x12_3456));
+        test_assert_eq!(pml4.translate_page(page + 511), Some(frame + 511));
+        test_assert!(pml4.translate(vaddr + HUGE_PAGE_2MIB).is_none());
+        // no PT below it
+        let has_pt = pml4.next_level_table(vaddr.pml4t_index())
+            .and_then(|p3| p3.next_level_table(vaddr.pdpt_index()))
+            .map_or(false, |p2| p2.next_level_table(vaddr.pdt_index()).is_some());
+        test_assert!(!has_pt);
+
+        pml4.unmap(page);
+        test_assert!(pml4.translate(vaddr).is_none());
+        test_assert!(pml4.translate(vaddr + 0x12_3456).is_none());
+    });
+}
+
+/// with CR0.WP set, even ring 0 must fault when writing a read-only page.
+/// write the first byte of this function (.text is mapped read-only) back
+/// to itself and expect the page fault handler to skip the store.
+pub fn test_write_protect() {
+    use ::kern::arch::cpu;
+    use ::kern::interrupts;
+
+    printk!(Debug, "test_write_protect\n\r");
+    assert!(cpu::cr0() & cpu::CR0_WRITE_PROTECT != 0, "CR0.WP is not set");
+
+    let target = test_write_protect as usize;
+    let fixup = &interrupts::PAGE_FAULT_FIXUP as *const _ as usize;
+    unsafe {
+        asm!("
+             leaq 1f(%rip), %rax
+             movq %rax, ($1)
+             movb ($0), %al
+             movb %al, ($0)
+             1:
+             movq $$0, ($1)"
+             :
+             :"r"(target), "r"(fixup)
+             :"rax", "memory"
+             :"volatile");
+    }
+
+    test_assert!(interrupts::take_page_fault_hit(),
+        "write to read-only page {:#x} did not fault, W^X is not enforced", target);
+}
+
+/// W^X style flip of a data page: write access goes away at once and
+/// comes back, frame stays the same
+pub fn test_protect() {
+    use ::kern::interrupts;
+
+    with_scratch_page(|pml4, page| {
+        let vaddr = page.start_address();
+        test_assert!(pml4.flags_of(page).is_none());
+
+        let frame = alloc_frame().expect("no more mem");
+        pml4.map_to(page, frame, WRITABLE | NO_EXECUTE);
+        test_assert!(pml4.flags_of(page).map_or(false, |f| f.contains(WRITABLE | PRESENT)));
+        // a writable TLB entry is cached now
+        unsafe { ::core::ptr::write_volatile(vaddr as *mut u8, 0x5a); }
+
+        pml4.protect(page, NO_EXECUTE);
+        test_assert!(pml4.flags_of(page).map_or(false, |f| f.contains(PRESENT) && !f.contains(WRITABLE)));
+        test_assert_eq!(pml4.translate_page(page), Some(frame));
+
+        let fixup = &interrupts::PAGE_FAULT_FIXUP as *const _ as usize;
+        unsafe {
+            asm!("
+                 leaq 1f(%rip), %rax
+                 movq %rax, ($1)
+                 movb $$0xa5, ($0)
+                 1:
+                 movq $$0, ($1)"
+                 :
+                 :"r"(vaddr), "r"(fixup)
+                 :"rax", "memory"
+                 :"volatile");
+        }
+        test_assert!(interrupts::take_page_fault_hit(), "write to protected page {:#x} did not fault", vaddr);
+        test_assert_eq!(unsafe { ::core::ptr::read_volatile(vaddr as *const u8) }, 0x5a);
+
+        pml4.protect(page, WRITABLE | NO_EXECUTE);
+        unsafe { ::core::ptr::write_volatile(vaddr as *mut u8, 0xa5); }
+        test_assert_eq!(unsafe { ::core::ptr::read_volatile(vaddr as *const u8) }, 0xa5);
+
+        pml4.unmap(page);
+        dealloc_frame(frame);
+    });
+}
+
+/// cycles to touch every page of kernel image once
+fn touch_kernel_image() -> u64 {
+    use ::kern::arch::cpu;
+    extern {
+        static _start: u64;
+        static _end: u64;
+    }
+
+    let (start, end) = unsafe { (&_start as *const _ as usize, &_end as *const _ as usize) };
+    let begin = cpu::rdtsc();
+    let mut addr = start & !(PAGE_SIZE - 1);
+    while addr < end {
+        unsafe { ::core::ptr::read_volatile(addr as *const u8); }
+        addr += PAGE_SIZE;
+    }
+    cpu::rdtsc() - begin
+}
+
+/// cycles to touch every page of [start, start + nr * PAGE_SIZE) once
+fn touch_pages(start: VirtualAddress, nr: usize) -> u64 {
+    use ::kern::arch::cpu;
+
+    let begin = cpu::rdtsc();
+    for i in 0..nr {
+        unsafe { ::core::ptr::read_volatile((start + i * PAGE_SIZE) as *const u8); }
+    }
+    cpu::rdtsc() - begin
+}
+
+/// remapping a page is seen right away, so map/unmap flush it. a single page
+/// invlpg leaves TLB entries of its neighbours alone, a full flush does not:
+/// touching them after repeated single page unmaps should cost about as much
+/// as when warm, and much more after flush_all. timings are only reported.
+pub fn test_tlb_flush() {
+    use ::kern::arch::cpu;
+
+    const NR: usize = 64;
+    with_scratch_page(|pml4, page| {
+        let vaddr = page.start_address();
+
+        // stale entries would show the old frame
+        let (f1, f2) = (alloc_frame().expect("no more mem"), alloc_frame().expect("no more mem"));
+        let p = vaddr as *mut u8;
+        pml4.map_to(page, f1, WRITABLE | NO_EXECUTE);
+        unsafe { ::core::ptr::write_volatile(p, 0xaa); }
+        pml4.unmap(page);
+        pml4.map_to(page, f2, WRITABLE | NO_EXECUTE);
+        unsafe { ::core::ptr::write_volatile(p, 0xbb); }
+        pml4.unmap(page);
+        pml4.map_to(page, f1, WRITABLE | NO_EXECUTE);
+        test_assert_eq!(unsafe { ::core::ptr::read_volatile(p) }, 0xaa);
+        pml4.unmap(page);
+        dealloc_frame(f1);
+        dealloc_frame(f2);
+
+        // page NR is the one churned, 0..NR are its neighbours
+        let frames: Vec<Frame> = (0..NR + 1).map(|_| alloc_frame().expect("no more mem")).collect();
+        for (i, &f) in frames.iter().enumerate() {
+            pml4.map_to(page + i, f, WRITABLE | NO_EXECUTE);
+        }
+
+        let oflags = unsafe { cpu::push_flags() };
+        touch_pages(vaddr, NR);
+        let warm = touch_pages(vaddr, NR);
+        for _ in 0..NR {
+            pml4.unmap(page + NR);
+            pml4.map_to(page + NR, frames[NR], WRITABLE | NO_EXECUTE);
+        }
+        let after_invlpg = touch_pages(vaddr, NR);
+        flush_all();
+        let after_flush_all = touch_pages(vaddr, NR);
+        unsafe { cpu::pop_flags(oflags); }
+
+        printk!(Info, "touch {} pages: warm {}, after {} invlpg {}, after full flush {} cycles\n\r",
+            NR, warm, NR, after_invlpg, after_flush_all);
+
+        for (i, &f) in frames.iter().enumerate() {
+            pml4.unmap(page + i);
+            dealloc_frame(f);
+        }
+    });
+}
+
+/// tables a space built below user and temporary page slots all go back,
+/// shared kernel tables stay
+pub fn test_destroy_address_space() {
+    use super::{leak_mark, leak_report, MM};
+
+    leak_mark();
+    let mut space = {
+        let mm = MM.try().unwrap().lock();
+        create_address_space(mm.mbinfo)
+    };
+    let page = Page::from_vaddress(KERNEL_MAPPING.UserCode.start);
+    let frame = alloc_frame().expect("no more mem");
+
+    let mut active = ActivePML4Table::new();
+    {
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        active.with(&mut space, &mut temp_page, |mapper| {
+            mapper.map_to(page, frame, USER | WRITABLE);
+            mapper.unmap(page);
+        });
+    }
+    dealloc_frame(frame);
+
+    space.destroy(&mut active);
+    test_assert_eq!(leak_report(), 0);
+    test_assert!(!active[kernel_slots().start].is_unused());
+}
+
+/// kernel image is GLOBAL, so a cr3 reload (task switch) should leave its
+/// TLB entries alone while a global flush drops them. the timings are only
+/// reported, emulators may not model global pages at all.
+pub fn test_global_kernel_pages() {
+    use ::kern::arch::cpu;
+
+    test_assert!(cpu::cr4() & cpu::CR4_PAGE_GLOBAL != 0, "CR4.PGE is not set");
+
+    let active = ActivePML4Table::new();
+    let text = test_global_kernel_pages as usize;
+    test_assert!(active.translate(text).is_some());
+    test_assert!(active.entry_flags(text).map(|f| f.contains(GLOBAL)).unwrap_or(false),
+        "kernel text {:#x} is not GLOBAL", text);
+
+    let oflags = unsafe { cpu::push_flags() };
+    let warm = touch_kernel_image();
+    cpu::tlb_flush_all();
+    let after_cr3 = touch_kernel_image();
+    cpu::tlb_flush_global();
+    let after_global = touch_kernel_image();
+    unsafe { cpu::pop_flags(oflags); }
+
+    printk!(Info, "touch kernel image: warm {}, after cr3 reload {}, after global flush {} cycles\n\r",
+        warm, after_cr3, after_global);
+}
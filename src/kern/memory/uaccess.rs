@@ -0,0 +1,133 @@
+//! bounds checked copy between kernel and user address spaces.
+//! `*_space` variants work on an address space which is not active (e.g a
+//! task being created), its frames are reached by temporary mapping, so cr3
+//! is never switched.
+
+use core::cmp::min;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+use collections::Vec;
+
+use super::paging::{Page, ActivePML4Table, VirtualAddress, PhysicalAddress, USER, WRITABLE};
+use super::inactive::{InactivePML4Table, TemporaryPage};
+use super::frame::Frame;
+use super::{PAGE_SIZE, KERNEL_MAPPING};
+
+pub type UaccessResult = Result<(), &'static str>;
+
+/// [addr, addr + len) should be inside of user space
+fn check_user_range(addr: VirtualAddress, len: usize) -> UaccessResult {
+    match addr.checked_add(len) {
+        Some(end) if addr >= KERNEL_MAPPING.UserMap.start && end <= KERNEL_MAPPING.UserMap.end + 1 => Ok(()),
+        _ => Err("bad user address")
+    }
+}
+
+/// physical address of each page covering [addr, addr + len) in inactive
+fn translate_range(inactive: &mut InactivePML4Table, temp_page: &mut TemporaryPage,
+                   addr: VirtualAddress, len: usize) -> Result<Vec<PhysicalAddress>, &'static str> {
+    let mut active = ActivePML4Table::new();
+    let mut frames = Vec::new();
+    let mut ok = true;
+
+    active.with(inactive, temp_page, |mapper| {
+        let mut vaddr = addr & !(PAGE_SIZE - 1);
+        while vaddr < addr + len {
+            match mapper.translate(vaddr) {
+                Some(paddr) => frames.push(paddr),
+                None => { ok = false; break; }
+            }
+            vaddr += PAGE_SIZE;
+        }
+    });
+
+    if ok { Ok(frames) } else { Err("page not mapped") }
+}
+
+/// call f(offset in range, kernel visible address, chunk len) for each page
+/// sized chunk of [addr, addr + len) in address space inactive
+fn for_each_chunk<F>(inactive: &mut InactivePML4Table, addr: VirtualAddress, len: usize, mut f: F)
+    -> UaccessResult where F: FnMut(usize, VirtualAddress, usize) {
+    check_user_range(addr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+    let frames = translate_range(inactive, &mut temp_page, addr, len)?;
+
+    let mut active = ActivePML4Table::new();
+    let mut done = 0;
+    for paddr in frames {
+        let page_off = (addr + done) & (PAGE_SIZE - 1);
+        let n = min(PAGE_SIZE - page_off, len - done);
+
+        let base = temp_page.map(Frame::from_paddress(paddr), &mut active);
+        f(done, base + page_off, n);
+        temp_page.unmap(&mut active);
+
+        done += n;
+    }
+    Ok(())
+}
+
+/// copy src into dst of address space inactive
+pub fn copy_to_space(inactive: &mut InactivePML4Table, dst: VirtualAddress, src: &[u8]) -> UaccessResult {
+    for_each_chunk(inactive, dst, src.len(), |off, kaddr, n| unsafe {
+        copy_nonoverlapping(src.as_ptr().offset(off as isize), kaddr as *mut u8, n);
+    })
+}
+
+/// zero [dst, dst + len) of address space inactive
+pub fn zero_space(inactive: &mut InactivePML4Table, dst: VirtualAddress, len: usize) -> UaccessResult {
+    for_each_chunk(inactive, dst, len, |_, kaddr, n| unsafe {
+        write_bytes(kaddr as *mut u8, 0, n);
+    })
+}
+
+/// copy src of address space inactive into dst
+pub fn copy_from_space(inactive: &mut InactivePML4Table, src: VirtualAddress, dst: &mut [u8]) -> UaccessResult {
+    let len = dst.len();
+    let ptr = dst.as_mut_ptr();
+    for_each_chunk(inactive, src, len, |off, kaddr, n| unsafe {
+        copy_nonoverlapping(kaddr as *const u8, ptr.offset(off as isize), n);
+    })
+}
+
+/// all pages of [addr, addr + len) should be user accessible in active
+/// address space, and writable if kernel is going to write them. pages of a
+/// vma of current task not populated yet are mapped now, as a user access
+/// would fault them in. callers must not hold the current task's lock.
+fn check_user_mapped(addr: VirtualAddress, len: usize, write: bool) -> UaccessResult {
+    check_user_range(addr, len)?;
+
+    let active = ActivePML4Table::new();
+    let mut vaddr = addr & !(PAGE_SIZE - 1);
+    while vaddr < addr + len {
+        if active.translate(vaddr).is_none() && !::kern::task::populate_user_page(vaddr) {
+            return Err("page not mapped");
+        }
+        let ok = match active.entry_flags(vaddr) {
+            Some(flags) => flags.contains(USER) && (!write || flags.contains(WRITABLE)),
+            None => false
+        };
+        if !ok {
+            return Err("page not accessible");
+        }
+        vaddr += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// copy src into dst of current task
+pub fn copy_to_user(dst: VirtualAddress, src: &[u8]) -> UaccessResult {
+    check_user_mapped(dst, src.len(), true)?;
+    unsafe { copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()); }
+    Ok(())
+}
+
+/// copy src of current task into dst
+pub fn copy_from_user(src: VirtualAddress, dst: &mut [u8]) -> UaccessResult {
+    check_user_mapped(src, dst.len(), false)?;
+    unsafe { copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()); }
+    Ok(())
+}
@@ -5,6 +5,11 @@ pub mod arch;
 
 #[macro_use]
 pub mod console;
+#[macro_use]
+pub mod logger;
+#[macro_use]
+pub mod selftest;
+pub mod early;
 pub mod util;
 pub mod driver;
 pub mod memory;
@@ -13,6 +18,17 @@ pub mod task;
 pub mod syscall;
 pub mod vfs;
 pub mod elf64;
+pub mod version;
+pub mod modules;
+pub mod kdbg;
+pub mod shm;
+pub mod fbdev;
+pub mod acpi;
+pub mod apic;
+pub mod percpu;
+pub mod tls;
+pub mod trace;
+pub mod signal;
 
 
 pub use self::syscall::syscall_dispatch;
@@ -0,0 +1,92 @@
+//! registry of multiboot2 boot modules.
+//! each module is identified by the first word of its cmdline (label),
+//! e.g `module2 /init init` registers a module labeled "init".
+
+use multiboot2::BootInformation;
+use collections::Vec;
+use spin::Once;
+
+use ::kern::memory::KERNEL_MAPPING;
+use ::kern::console as con;
+use con::LogLevel::*;
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub label: &'static str,
+    /// rest of cmdline after label
+    pub args: &'static str,
+    /// physical range of module, end is exclusive
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Module {
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// module content, mapped by kernel mapping
+    pub fn bytes(&self) -> &'static [u8] {
+        let kernel_base = KERNEL_MAPPING.KernelMap.start;
+        unsafe {
+            ::core::slice::from_raw_parts((self.start + kernel_base) as *const u8, self.size())
+        }
+    }
+}
+
+/// all module tags provided by bootloader, may be empty
+pub fn multiboot_modules(mbinfo: &'static BootInformation) -> Vec<Module> {
+    mbinfo.module_tags().map(|m| {
+        let cmdline: &'static str = m.name().trim();
+        let (label, args) = match cmdline.find(' ') {
+            Some(i) => (&cmdline[..i], cmdline[i+1..].trim()),
+            None => (cmdline, "")
+        };
+
+        Module {
+            label: label,
+            args: args,
+            start: m.start_address() as usize,
+            end: m.end_address() as usize
+        }
+    }).collect()
+}
+
+/// physical range covered by all modules, None if no module loaded.
+/// usable before kernel heap is ready.
+pub fn physical_range(mbinfo: &BootInformation) -> Option<(usize, usize)> {
+    let start = mbinfo.module_tags().map(|a| a.start_address()).min();
+    let end = mbinfo.module_tags().map(|a| a.end_address()).max();
+    match (start, end) {
+        (Some(s), Some(e)) => Some((s as usize, e as usize)),
+        _ => None
+    }
+}
+
+static MODULES: Once<Vec<Module>> = Once::new();
+
+/// build registry, needs kernel heap
+pub fn init(mbinfo: &'static BootInformation) {
+    MODULES.call_once(|| {
+        let mods = multiboot_modules(mbinfo);
+        if mods.is_empty() {
+            printk!(Warn, "no boot modules found\n\r");
+        }
+        for m in &mods {
+            printk!(Info, "module {} [{:#x}, {:#x}) {} bytes {}\n\r", m.label, m.start, m.end, m.size(), m.args);
+        }
+        mods
+    });
+}
+
+pub fn all() -> &'static [Module] {
+    match MODULES.try() {
+        Some(mods) => mods.as_slice(),
+        None => &[]
+    }
+}
+
+/// look up module by its cmdline label
+pub fn find(label: &str) -> Option<&'static Module> {
+    all().iter().find(|m| m.label == label)
+}
@@ -0,0 +1,79 @@
+//! per cpu state, reached through gs. both gs base msrs point to the block
+//! of the cpu (userland doesn't use gs), so swapgs is harmless and the block
+//! is reachable from any context: irq, syscall or kernel thread.
+//! only the boot cpu exists now, APs would get their own blocks later.
+
+use core::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use x86_64::registers::msr;
+
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// layout is used by context.asm and syscall.rs, keep offsets in sync:
+///   [gs:0]  scratch, user rsp during syscall entry
+///   [gs:8]  kern_rsp, TLSSegment of current task at top of its kernel stack,
+///           see tls.rs for its layout
+///   [gs:16] self pointer
+///   [gs:24] cpu id
+///   [gs:32] id of the task running on this cpu
+#[repr(C)]
+pub struct PerCpu {
+    pub scratch: usize,
+    pub kern_rsp: usize,
+    self_ptr: usize,
+    pub id: usize,
+    pub current: AtomicIsize,
+}
+
+impl PerCpu {
+    pub const fn new(id: usize) -> PerCpu {
+        PerCpu {
+            scratch: 0,
+            kern_rsp: 0,
+            self_ptr: 0,
+            id: id,
+            current: AtomicIsize::new(0),
+        }
+    }
+}
+
+static mut BOOT_CPU: PerCpu = PerCpu::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// point gs at boot cpu block. loading a gs selector clears gs base, so
+/// this must come after segments are reloaded.
+pub fn init_boot_cpu() {
+    unsafe {
+        let base = &BOOT_CPU as *const PerCpu as usize;
+        BOOT_CPU.self_ptr = base;
+        msr::wrmsr(msr::IA32_GS_BASE, base as u64);
+        msr::wrmsr(IA32_KERNEL_GS_BASE, base as u64);
+    }
+    READY.store(true, Ordering::SeqCst);
+}
+
+/// block of the running cpu, None before init_boot_cpu()
+pub fn this() -> Option<&'static mut PerCpu> {
+    if !READY.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    unsafe {
+        let p: usize;
+        asm!("movq %gs:16, $0" : "=r"(p) ::: "volatile");
+        Some(&mut *(p as *mut PerCpu))
+    }
+}
+
+/// id of the task running on this cpu, 0 before tasking starts
+pub fn current_task() -> isize {
+    this().map(|cpu| cpu.current.load(Ordering::SeqCst)).unwrap_or(0)
+}
+
+pub fn set_current_task(pid: isize) {
+    this().expect("percpu: not initialized").current.store(pid, Ordering::SeqCst);
+}
+
+/// kernel stack (TLSSegment) syscalls of the current task land on
+pub fn set_kern_rsp(kern_rsp: usize) {
+    this().expect("percpu: not initialized").kern_rsp = kern_rsp;
+}
@@ -0,0 +1,292 @@
+//! in-kernel self tests which should not depend on each other. they are run
+//! in random order to shake out hidden dependencies, the seed is printed so
+//! a failing order can be replayed by building with SOS2_TEST_SEED=<seed>.
+//! a single test can be picked with `test=<name>` on kernel cmdline, qemu
+//! then exits with its result, which is handy for bisecting and CI.
+
+use collections::{Vec, VecDeque};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use ::kern::util::Rng;
+use ::kern::memory;
+use ::kern::task::{self, TaskList, TaskState, ProcId};
+use ::kern::arch::cpu;
+use ::kern::console::LogLevel::*;
+
+/// like assert!, but a failure is recorded for the running test and the
+/// test goes on, so one run reports every broken check
+macro_rules! test_assert {
+    ($cond:expr) => ({
+        if !$cond {
+            $crate::kern::selftest::record_failure(file!(), line!(),
+                format_args!("{}", stringify!($cond)));
+        }
+    });
+    ($cond:expr, $($arg:tt)+) => ({
+        if !$cond {
+            $crate::kern::selftest::record_failure(file!(), line!(), format_args!($($arg)+));
+        }
+    });
+}
+
+macro_rules! test_assert_eq {
+    ($left:expr, $right:expr) => ({
+        match (&$left, &$right) {
+            (left_val, right_val) => {
+                if !(*left_val == *right_val) {
+                    $crate::kern::selftest::record_failure(file!(), line!(),
+                        format_args!("{} == {} ({:?} vs {:?})",
+                            stringify!($left), stringify!($right), left_val, right_val));
+                }
+            }
+        }
+    });
+}
+
+macro_rules! test_assert_ne {
+    ($left:expr, $right:expr) => ({
+        match (&$left, &$right) {
+            (left_val, right_val) => {
+                if *left_val == *right_val {
+                    $crate::kern::selftest::record_failure(file!(), line!(),
+                        format_args!("{} != {} (both {:?})",
+                            stringify!($left), stringify!($right), left_val));
+                }
+            }
+        }
+    });
+}
+
+struct SelfTest {
+    name: &'static str,
+    func: fn(),
+}
+
+/// need memory and interrupts ready
+const TESTS: &'static [SelfTest] = &[
+    SelfTest { name: "mapper_with_allocator", func: memory::paging::test_mapper_with_allocator },
+    SelfTest { name: "translate", func: memory::paging::test_translate },
+    SelfTest { name: "huge_page", func: memory::paging::test_huge_page },
+    SelfTest { name: "tlb_flush", func: memory::paging::test_tlb_flush },
+    SelfTest { name: "protect", func: memory::paging::test_protect },
+    SelfTest { name: "destroy_address_space", func: memory::paging::test_destroy_address_space },
+    SelfTest { name: "write_protect", func: memory::paging::test_write_protect },
+    SelfTest { name: "global_kernel_pages", func: memory::paging::test_global_kernel_pages },
+    SelfTest { name: "frame_leak_detector", func: memory::test_frame_leak_detector },
+    SelfTest { name: "temporary_page_frames", func: memory::test_temporary_page_frames },
+    SelfTest { name: "frame_refcount", func: memory::test_frame_refcount },
+    SelfTest { name: "frame_stats", func: memory::test_frame_stats },
+    SelfTest { name: "frame_coalescing", func: memory::test_frame_coalescing },
+    SelfTest { name: "memory_regions", func: memory::test_memory_regions },
+    SelfTest { name: "memory_schema", func: memory::test_memory_schema },
+    SelfTest { name: "kheap_allocator", func: ::test_kheap_allocator },
+    SelfTest { name: "acpi_bounds", func: ::kern::acpi::test_acpi_bounds },
+    SelfTest { name: "lapic_other_space", func: ::kern::apic::test_lapic_other_space },
+    SelfTest { name: "ms_to_ticks", func: ::kern::interrupts::timer::test_ms_to_ticks },
+    SelfTest { name: "watchpoint", func: ::kern::interrupts::test_watchpoint },
+    SelfTest { name: "selector_error_code", func: ::kern::interrupts::test_selector_error_code },
+    SelfTest { name: "context_switch", func: task::test_context_switch },
+    SelfTest { name: "vma_bounds", func: task::test_vma_bounds },
+    SelfTest { name: "signal_permission", func: ::kern::signal::test_signal_permission },
+    SelfTest { name: "exit_notify", func: ::kern::task::test_exit_notify },
+    SelfTest { name: "priority_permission", func: task::test_priority_permission },
+    SelfTest { name: "stack_guard_shared", func: task::test_stack_guard_shared },
+    SelfTest { name: "stack_canary", func: task::test_stack_canary },
+    SelfTest { name: "tls_layout", func: ::kern::tls::test_tls_layout },
+    SelfTest { name: "trace_format", func: ::kern::trace::test_trace_format },
+    SelfTest { name: "shm_release", func: ::kern::shm::test_shm_release },
+    SelfTest { name: "elf_parse", func: ::kern::elf64::test_elf_parse },
+    SelfTest { name: "whole_chars", func: ::kern::syscall::test_whole_chars },
+    SelfTest { name: "ramfs", func: ::kern::vfs::ramfs::test_ramfs },
+    SelfTest { name: "vfs_fds", func: ::kern::vfs::test_vfs_fds },
+    SelfTest { name: "framebuffer_pitch", func: ::kern::driver::video::framebuffer::test_framebuffer_pitch },
+    SelfTest { name: "double_buffer", func: ::kern::driver::video::framebuffer::test_double_buffer },
+    SelfTest { name: "present_dirty", func: ::kern::driver::video::display_list::test_present_dirty },
+    SelfTest { name: "alpha_blend", func: ::kern::driver::video::framebuffer::test_alpha_blend },
+    SelfTest { name: "framebuffer_clip", func: ::kern::driver::video::framebuffer::test_framebuffer_clip },
+    SelfTest { name: "chars_fit", func: ::kern::driver::video::framebuffer::test_chars_fit },
+    SelfTest { name: "scroll_up", func: ::kern::driver::video::framebuffer::test_scroll_up },
+    SelfTest { name: "tiny_framebuffer", func: ::kern::driver::video::terminal::test_tiny_framebuffer },
+    SelfTest { name: "fbconsole", func: ::kern::driver::video::fbconsole::test_fbconsole },
+    SelfTest { name: "ansi_sgr", func: ::kern::console::test_ansi_sgr },
+    SelfTest { name: "console_backspace", func: ::kern::console::test_console_backspace },
+    SelfTest { name: "console_switch", func: ::kern::console::test_console_switch },
+    SelfTest { name: "console_sinks", func: ::kern::console::test_console_sinks },
+    SelfTest { name: "key_repeat", func: ::kern::driver::keyboard::test_key_repeat },
+    SelfTest { name: "key_buffer", func: ::kern::driver::keyboard::test_key_buffer },
+    SelfTest { name: "key_decode", func: ::kern::driver::keyboard::test_key_decode },
+];
+
+/// seed from build environment if given, otherwise from tsc
+pub fn seed() -> u64 {
+    match option_env!("SOS2_TEST_SEED").and_then(|s| s.parse::<u64>().ok()) {
+        Some(seed) => seed,
+        None => ::kern::arch::cpu::rdtsc()
+    }
+}
+
+/// failed test_assert*! of the running test
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// called by test_assert*! macros, the test keeps going after a failure
+pub fn record_failure(file: &'static str, line: u32, what: fmt::Arguments) {
+    FAILURES.fetch_add(1, Ordering::SeqCst);
+    printk!(Critical, "selftest: FAIL {}:{}: {}\n\r", file, line, what);
+}
+
+pub fn run(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let mut order: Vec<&SelfTest> = TESTS.iter().collect();
+    rng.shuffle(&mut order);
+
+    printk!(Info, "selftest: running {} tests, seed {}\n\r", order.len(), seed);
+    let mut failed = 0;
+    for t in order.iter() {
+        printk!(Info, "selftest: {}\n\r", t.name);
+        FAILURES.store(0, Ordering::SeqCst);
+        (t.func)();
+
+        let n = FAILURES.load(Ordering::SeqCst);
+        if n > 0 {
+            printk!(Critical, "selftest: {} failed with {} failures\n\r", t.name, n);
+            failed += 1;
+        }
+    }
+    printk!(Info, "selftest: {} passed, {} failed, {} total (seed {})\n\r",
+        order.len() - failed, failed, order.len(), seed);
+}
+
+/// qemu_exit codes of run_one(), and of a panic when panic_exits()
+const EXIT_PASS: u32 = 0;
+const EXIT_FAIL: u32 = 1;
+const EXIT_NOT_FOUND: u32 = 2;
+pub const EXIT_PANIC: u32 = 3;
+
+/// whether a panic should leave qemu instead of halting, so CI fails fast.
+/// always in test builds, otherwise with `panic=exit` in kernel cmdline.
+pub fn panic_exits() -> bool {
+    cfg!(feature = "test") || ::kern::early::arg("panic") == Some("exit")
+}
+
+/// test named by `test=<name>` in kernel cmdline, if any
+pub fn selected(cmdline: &str) -> Option<&str> {
+    cmdline.split_whitespace()
+        .filter(|arg| arg.starts_with("test="))
+        .map(|arg| &arg["test=".len()..])
+        .last()
+}
+
+/// run exactly one test and leave qemu with its result
+pub fn run_one(name: &str) -> ! {
+    let t = match TESTS.iter().find(|t| t.name == name) {
+        Some(t) => t,
+        None => {
+            printk!(Critical, "selftest: test {} not found, available:\n\r", name);
+            for t in TESTS.iter() {
+                printk!(Critical, "  {}\n\r", t.name);
+            }
+            cpu::qemu_exit(EXIT_NOT_FOUND);
+        }
+    };
+
+    printk!(Info, "selftest: {}\n\r", t.name);
+    FAILURES.store(0, Ordering::SeqCst);
+    (t.func)();
+
+    let n = FAILURES.load(Ordering::SeqCst);
+    if n > 0 {
+        printk!(Critical, "selftest: {} failed with {} failures\n\r", t.name, n);
+        cpu::qemu_exit(EXIT_FAIL);
+    }
+    printk!(Info, "selftest: {} passed\n\r", t.name);
+    cpu::qemu_exit(EXIT_PASS);
+}
+
+/// work lengths (in ticks) handed to stress workers, filled by sched_stress
+static STRESS_WORK: Mutex<Option<VecDeque<u64>>> = Mutex::new(None);
+static STRESS_ALIVE: AtomicUsize = AtomicUsize::new(0);
+
+const STRESS_ROUNDS: usize = 20;
+const STRESS_MAX_WORKERS: u64 = 8;
+const STRESS_MAX_WORK: u64 = 16;
+
+fn stress_worker() {
+    let ticks = STRESS_WORK.lock().as_mut()
+        .and_then(|work| work.pop_front())
+        .expect("sched_stress: no work for worker");
+    for _ in 0..ticks {
+        unsafe { asm!("hlt":::: "volatile"); }
+    }
+    STRESS_ALIVE.fetch_sub(1, Ordering::SeqCst);
+    task::exit(0);
+}
+
+/// tasks alive and Running ones, busy tasks are counted as alive only
+fn stress_census() -> (usize, usize) {
+    let tasks = TaskList::get();
+    let running = tasks.tasks.values()
+        .filter(|t| t.try_read().map(|t| t.state == TaskState::Running).unwrap_or(false))
+        .count();
+    (tasks.tasks.len(), running)
+}
+
+fn stress_all_exited(pids: &[ProcId]) -> bool {
+    let tasks = TaskList::get();
+    pids.iter().all(|pid| match tasks.get_task(*pid) {
+        Some(t) => t.try_read().map(|t| t.state == TaskState::Zombie).unwrap_or(false),
+        None => true
+    })
+}
+
+/// kernel thread: repeatedly spawn a random number of workers doing random
+/// length work, then check that pids, frames and task count all come back.
+/// the spawn pattern only depends on seed(), so a failure can be replayed.
+pub fn sched_stress() {
+    let seed = seed();
+    let mut rng = Rng::new(seed);
+    let (baseline, _) = stress_census();
+    *STRESS_WORK.lock() = Some(VecDeque::new());
+    let mut spawned = 0;
+
+    printk!(Info, "sched_stress: {} rounds, seed {}\n\r", STRESS_ROUNDS, seed);
+    for round in 0..STRESS_ROUNDS {
+        memory::leak_mark();
+
+        let nr = rng.range(1, STRESS_MAX_WORKERS) as usize;
+        let mut pids = Vec::with_capacity(nr);
+        {
+            let oflags = unsafe { cpu::push_flags() };
+            let mut tasks = TaskList::get_mut();
+            for _ in 0..nr {
+                STRESS_WORK.lock().as_mut().unwrap().push_back(rng.range(0, STRESS_MAX_WORK));
+                STRESS_ALIVE.fetch_add(1, Ordering::SeqCst);
+                pids.push(tasks.alloc_kernel_task("stress-worker", stress_worker as usize));
+            }
+            unsafe { cpu::pop_flags(oflags); }
+        }
+        spawned += nr;
+
+        while STRESS_ALIVE.load(Ordering::SeqCst) > 0 || !stress_all_exited(&pids) {
+            let (_, running) = stress_census();
+            assert!(running <= 1, "sched_stress: {} tasks Running at once", running);
+            unsafe { asm!("hlt":::: "volatile"); }
+        }
+
+        let reaped = {
+            let oflags = unsafe { cpu::push_flags() };
+            let n = TaskList::get_mut().reap_zombies();
+            unsafe { cpu::pop_flags(oflags); }
+            n
+        };
+        assert_eq!(reaped, nr, "sched_stress: round {} reaped {} of {}", round, reaped, nr);
+
+        let (count, running) = stress_census();
+        assert_eq!(count, baseline, "sched_stress: round {} task count {} != {}", round, count, baseline);
+        assert!(running <= 1, "sched_stress: {} tasks Running at once", running);
+        assert_eq!(memory::leak_report(), 0, "sched_stress: round {} leaked frames", round);
+    }
+
+    printk!(Info, "sched_stress: passed, {} rounds, {} tasks spawned\n\r", STRESS_ROUNDS, spawned);
+    task::exit(0);
+}
@@ -0,0 +1,210 @@
+//! shared memory regions between tasks.
+//! a region holds one reference of each of its frames, and every attachment
+//! holds another one. only the creator and its children may attach it.
+//! region is destroyed once its creator has exited and the last attachment
+//! is gone.
+
+use collections::{BTreeMap, Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use ::kern::memory::frame::{self, Frame, FrameOwner};
+use ::kern::memory::paging::{self, Page, ActivePML4Table, VirtualAddress};
+use ::kern::memory::{self, PAGE_SIZE, KERNEL_MAPPING};
+use ::kern::task::{Task, ProcId, VirtualMemoryArea};
+use ::kern::syscall::{EINVAL, ENOMEM, EPERM};
+
+struct ShmRegion {
+    frames: Vec<Frame>,
+    attached: usize,
+    creator: Option<ProcId>, // None after it exited
+}
+
+impl ShmRegion {
+    fn free(self) {
+        for f in self.frames {
+            frame::dealloc_frame(f);
+        }
+    }
+}
+
+lazy_static! {
+    static ref REGIONS: Mutex<BTreeMap<usize, ShmRegion>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// max size of a single region
+const SHM_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// create a region of at least size bytes owned by task creator, return its id
+pub fn create(creator: ProcId, size: usize) -> Result<usize, isize> {
+    if size == 0 || size > SHM_MAX_SIZE {
+        return Err(-EINVAL);
+    }
+
+    let nr_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut frames = Vec::with_capacity(nr_pages);
+    for _ in 0..nr_pages {
+        match frame::alloc_frame_for(FrameOwner::Page) {
+            Some(f) => frames.push(f),
+            None => {
+                for f in frames {
+                    frame::dealloc_frame(f);
+                }
+                return Err(-ENOMEM);
+            }
+        }
+    }
+
+    // content of shared memory should not leak from previous owners
+    {
+        let mut active = ActivePML4Table::new();
+        let mut temp_page = ::kern::memory::inactive::TemporaryPage::new(
+            Page::from_vaddress(0xfffff_cafe_beef_000));
+        for &f in frames.iter() {
+            let vaddr = temp_page.map(f, &mut active);
+            unsafe { ::core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE); }
+            temp_page.unmap(&mut active);
+        }
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    REGIONS.lock().insert(id, ShmRegion { frames: frames, attached: 0, creator: Some(creator) });
+    Ok(id)
+}
+
+/// find a free range of size in shm area of task
+fn find_free_range(task: &Task, size: usize) -> Option<VirtualAddress> {
+    let area = &KERNEL_MAPPING.UserShm;
+    let mut start = area.start;
+    for vma in task.vmas.iter() {
+        if vma.end() <= start { continue; }
+        match start.checked_add(size) {
+            Some(end) if vma.start >= end => break,
+            Some(_) => start = vma.end(),
+            None => return None
+        }
+    }
+
+    match start.checked_add(size) {
+        Some(end) if end <= area.end + 1 => Some(start),
+        _ => None
+    }
+}
+
+/// map region id into task, task must be the running one.
+/// return address of mapping.
+pub fn attach(task: &mut Task, id: usize) -> Result<VirtualAddress, isize> {
+    let mut regions = REGIONS.lock();
+    let region = match regions.get_mut(&id) {
+        Some(region) => region,
+        None => return Err(-EINVAL)
+    };
+    match region.creator {
+        Some(pid) if pid == task.pid || pid == task.ppid => {},
+        _ => return Err(-EPERM)
+    }
+
+    let size = region.frames.len() * PAGE_SIZE;
+    let start = match find_free_range(task, size) {
+        Some(start) => start,
+        None => return Err(-ENOMEM)
+    };
+
+    let flags = paging::USER | paging::WRITABLE | paging::NO_EXECUTE;
+    let mut vma = VirtualMemoryArea::new(start, size, flags);
+    vma.mapped = true;
+    if task.add_vma(vma).is_err() {
+        return Err(-ENOMEM);
+    }
+
+    let mut active = ActivePML4Table::new();
+    for (i, &f) in region.frames.iter().enumerate() {
+        frame::frame_ref(f);
+        active.map_to(Page::from_vaddress(start) + i, f, flags);
+    }
+
+    region.attached += 1;
+    task.shm.push((start, id));
+    Ok(start)
+}
+
+/// unmap region attached at addr from task, task must be the running one.
+pub fn detach(task: &mut Task, addr: VirtualAddress) -> Result<(), isize> {
+    let idx = match task.shm.iter().position(|&(start, _)| start == addr) {
+        Some(idx) => idx,
+        None => return Err(-EINVAL)
+    };
+    let (start, id) = task.shm.remove(idx);
+    task.remove_vma(start);
+
+    let mut regions = REGIONS.lock();
+    let destroy = {
+        let region = regions.get_mut(&id).expect("shm: attached region missing");
+        let mut active = ActivePML4Table::new();
+        for (i, &f) in region.frames.iter().enumerate() {
+            active.unmap(Page::from_vaddress(start) + i);
+            frame::dealloc_frame(f);
+        }
+
+        region.attached -= 1;
+        region.attached == 0 && region.creator.is_none()
+    };
+
+    if destroy {
+        regions.remove(&id).unwrap().free();
+    }
+    Ok(())
+}
+
+/// task pid exits: free its regions nobody has attached, the others go
+/// with their last detach
+pub fn release(pid: ProcId) {
+    let mut regions = REGIONS.lock();
+    let owned: Vec<usize> = regions.iter()
+        .filter(|&(_, region)| region.creator == Some(pid))
+        .map(|(&id, _)| id)
+        .collect();
+
+    for id in owned {
+        let unused = {
+            let region = regions.get_mut(&id).unwrap();
+            region.creator = None;
+            region.attached == 0
+        };
+        if unused {
+            regions.remove(&id).unwrap().free();
+        }
+    }
+}
+
+/// drop all attachments of task, used when task exits
+pub fn detach_all(task: &mut Task) {
+    loop {
+        let start = match task.shm.last() {
+            Some(&(start, _)) => start,
+            None => break
+        };
+        detach(task, start).expect("shm: detach failed");
+    }
+}
+
+pub fn test_shm_release() {
+    // no task has this pid
+    let creator = 0xdead;
+
+    // page tables of the temporary page used for zeroing stay around
+    let id = create(creator, PAGE_SIZE).expect("shm create");
+    release(creator);
+    test_assert!(!REGIONS.lock().contains_key(&id));
+
+    memory::leak_mark();
+    let id = create(creator, 2 * PAGE_SIZE).expect("shm create");
+    test_assert!(REGIONS.lock().contains_key(&id));
+    release(0xbeef);
+    test_assert!(REGIONS.lock().contains_key(&id));
+    release(creator);
+    test_assert!(!REGIONS.lock().contains_key(&id));
+    test_assert_eq!(memory::leak_report(), 0);
+}
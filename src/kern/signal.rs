@@ -0,0 +1,142 @@
+//! minimal signals: a pending set and a mask per task, checked on every
+//! return to userspace. only the default action exists for now, which
+//! terminates the task with exit code 128 + signal.
+
+use ::kern::task::{self, Task, TaskKind, TaskList, ProcId};
+use ::kern::syscall::{EINVAL, ENOENT, EPERM};
+
+pub const SIGINT: usize = 2;
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+pub const SIGTERM: usize = 15;
+pub const NSIG: usize = 32;
+
+/// how of sigprocmask
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+const fn bit(sig: usize) -> usize {
+    1 << sig
+}
+
+// SIGKILL can never be blocked
+const UNBLOCKABLE: usize = 1 << SIGKILL;
+
+fn valid(sig: usize) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+/// sender may signal only itself and its children. kernel threads never
+/// return to user to take a signal, a pending one would just cut every
+/// wait of theirs short.
+fn may_send(sender: ProcId, target: &Task) -> bool {
+    target.kind == TaskKind::User && (target.pid == sender || target.ppid == sender)
+}
+
+/// mark sig from task sender pending on task pid, delivered on its next
+/// return to user
+pub fn send(sender: ProcId, pid: ProcId, sig: usize) -> Result<(), isize> {
+    if !valid(sig) {
+        return Err(-EINVAL);
+    }
+
+    let target = match TaskList::get().get_task(pid) {
+        Some(target) => target.clone(),
+        None => return Err(-ENOENT)
+    };
+    let deliverable = {
+        let mut target = target.write();
+        if !may_send(sender, &target) {
+            return Err(-EPERM);
+        }
+        target.sig_pending |= bit(sig);
+        target.sig_pending & !target.sig_mask != 0
+    };
+
+    // every signal is fatal for now, a sleeping target must not wait for
+    // its sleep to end on its own. it sees interrupted() and gives up.
+    if deliverable {
+        TaskList::get_mut().wake(pid);
+    }
+    Ok(())
+}
+
+/// whether current task has a signal to take on its way back to user.
+/// blocking waits give up early on it.
+pub fn interrupted() -> bool {
+    let tasks = TaskList::get();
+    let pending = match tasks.current() {
+        Some(current) => {
+            let task = current.read();
+            task.sig_pending & !task.sig_mask != 0
+        },
+        None => false
+    };
+    pending
+}
+
+/// change mask of task, return the old one
+pub fn sigprocmask(task: &mut Task, how: usize, set: usize) -> Result<usize, isize> {
+    let old = task.sig_mask;
+    task.sig_mask = match how {
+        SIG_BLOCK => old | set,
+        SIG_UNBLOCK => old & !set,
+        SIG_SETMASK => set,
+        _ => return Err(-EINVAL)
+    } & !UNBLOCKABLE;
+    Ok(old)
+}
+
+/// take lowest pending signal not blocked by mask
+pub fn take_deliverable(task: &mut Task) -> Option<usize> {
+    let ready = task.sig_pending & !task.sig_mask;
+    if ready == 0 {
+        return None;
+    }
+
+    let sig = ready.trailing_zeros() as usize;
+    task.sig_pending &= !bit(sig);
+    Some(sig)
+}
+
+/// run default action of sig for current task, never returns
+pub fn deliver(sig: usize) -> ! {
+    task::exit(128 + sig as isize)
+}
+
+/// called with IF off on the way back to userspace, and IF stays off until
+/// user code runs: a signal sent by an irq or by a task sched switches to
+/// can not slip in between the check and sysret.
+pub fn check_pending() {
+    let sig = {
+        let tasks = TaskList::get();
+        match tasks.current() {
+            Some(current) => take_deliverable(&mut current.write()),
+            None => None
+        }
+    };
+    if let Some(sig) = sig {
+        deliver(sig);
+    }
+}
+
+pub fn test_signal_permission() {
+    let mut me = Task::empty();
+    me.pid = 5;
+    me.kind = TaskKind::User;
+    let mut child = me.clone();
+    child.pid = 6;
+    child.ppid = 5;
+    let mut other = me.clone();
+    other.pid = 7;
+    other.ppid = 1;
+    let mut kthread = Task::empty();
+    kthread.pid = 2;
+    kthread.ppid = 5;
+
+    test_assert!(may_send(5, &me));
+    test_assert!(may_send(5, &child));
+    test_assert!(!may_send(5, &other));
+    test_assert!(!may_send(5, &kthread));
+}
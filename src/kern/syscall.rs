@@ -1,7 +1,12 @@
-use ::kern::console::LogLevel::*;
 use ::kern::task;
+use ::kern::shm;
+use ::kern::fbdev;
+use ::kern::vfs;
+use ::kern::tls;
+use ::kern::trace;
+use ::kern::memory::uaccess;
+use ::kern::driver::keyboard;
 use ::kern::arch::cpu;
-use ::kern::console::{Console, tty1};
 
 use core::sync::atomic::Ordering;
 use x86_64::instructions::interrupts;
@@ -50,44 +55,476 @@ pub enum Syscall {
     WAITPID       =  38,
     FCHDIR        =  39,
     GETCWD        =  40,
+    SHM_CREATE    =  41,
+    SHM_ATTACH    =  42,
+    SHM_DETACH    =  43,
+    ERRNO         =  44,
+    FBINFO        =  45,
+    FBMAP         =  46,
+    FBUNMAP       =  47,
+    YIELD         =  48,
+    KLOG          =  49,
+    SETPRIORITY   =  50,
+    TIMES         =  51,
 
-    NR_SYSCALL    =  41
+    NR_SYSCALL    =  52
 }
 
+/// syscall returns -errno on failure
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const EBADF: isize = 9;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const EBUSY: isize = 16;
+pub const EEXIST: isize = 17;
+pub const ENODEV: isize = 19;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EMFILE: isize = 24;
+pub const EFBIG: isize = 27;
+pub const ENOSYS: isize = 38;
+
+/// entry point from syscall_entry (context.asm). abi: number in rax, args in
+/// rdi, rsi, rdx, r8, r9, r10 (args[0..6]), result or -errno back in rax.
+/// return value is written back into rax of user
 #[no_mangle]
-pub unsafe extern "C" fn syscall_dispatch(id: usize, args: *const usize) 
+pub unsafe extern "C" fn syscall_dispatch(id: usize, args: *const usize) -> isize
 {
     let args = ::core::slice::from_raw_parts(args, 6);
-    let tid = task::CURRENT_ID.load(Ordering::SeqCst);
-    Console::with(&tty1, 19, 0, || {
-        printk!(Info, "syscall({}) tid {}: {:#x} {:#x} {:#x} {:#x} {:#x} {:#x}\n\r", id, tid, 
-                args[0], args[1], args[2], args[3], args[4], args[5]);
-    });
+    let tid = task::current_id();
+    assert!(task::current_stack_canary_ok(), "syscall: kernel stack of tid {} overflowed", tid);
+    assert!(tls::canary_ok(), "syscall: tls of tid {} is corrupted", tid);
+    debug_assert_eq!(tls::pid(), tid);
+    trace::record(trace::EventKind::Syscall, id, tid as usize);
+    kprintln!("syscall({}) tid {}: {:#x} {:#x} {:#x} {:#x} {:#x} {:#x}", id, tid,
+              args[0], args[1], args[2], args[3], args[4], args[5]);
 
-    if id == Syscall::NONE as usize || id > Syscall::NR_SYSCALL as usize {
-        panic!("invalid syscall id {}", id);
+    let ret = if id == Syscall::NONE as usize || id >= Syscall::NR_SYSCALL as usize {
+        kprintln!("syscall: unknown number {} from tid {}", id, tid);
+        -ENOSYS
+    } else {
+        dispatch(::core::intrinsics::transmute(id), args)
+    };
+
+    // errno is sticky like libc, successful calls leave it alone
+    if ret < 0 {
+        tls::set_errno(-ret);
     }
 
-    let nr: Syscall = ::core::intrinsics::transmute(id);
-    match nr {
-        Syscall::WRITE => {
-            let buf = ::core::slice::from_raw_parts(args[1] as *const u8, args[2]);
-            sys_write(args[0] as isize, buf);
-        },
-        _ => {
-            unimplemented!()
-        }
+    // syscall_entry would cli right after we return anyway, do it before
+    // the check so sched can not run between it and sysret
+    interrupts::disable();
+    ::kern::signal::check_pending();
+
+    // syscall_entry saved user rip (rcx) and rsp above the args
+    let (user_rip, user_rsp) = (*args.as_ptr().offset(7), *args.as_ptr().offset(10));
+    if !::kern::interrupts::sysret_target_ok(user_rip, user_rsp) {
+        kprintln!("syscall: tid {} returns to bad rip {:#x} rsp {:#x}, killed",
+                  tid, user_rip, user_rsp);
+        ::kern::signal::deliver(::kern::signal::SIGSEGV);
     }
+    ret
 }
 
+/// syscall table: route nr to its handler, -ENOSYS for numbers without one
+fn dispatch(nr: Syscall, args: &[usize]) -> isize {
+    match nr {
+        Syscall::FORK => sys_fork(),
+        Syscall::EXIT => sys_exit(args[0] as isize),
+        Syscall::GETPID => sys_getpid(),
+        Syscall::GETPPID => sys_getppid(),
+        Syscall::WAITPID => sys_waitpid(args[0]),
+        Syscall::KILL => sys_kill(args[0], args[1]),
+        Syscall::SIGPROCMASK => sys_sigprocmask(args[0], args[1]),
+        Syscall::READ => sys_read(args[0], args[1], args[2]),
+        Syscall::WRITE => sys_write(args[0], args[1], args[2]),
+        Syscall::OPEN => sys_open(args[0], args[1]),
+        Syscall::CLOSE => sys_close(args[0]),
+        Syscall::SHM_CREATE => sys_shm_create(args[0]),
+        Syscall::SHM_ATTACH => sys_shm_attach(args[0]),
+        Syscall::SHM_DETACH => sys_shm_detach(args[0]),
+        Syscall::ERRNO => sys_errno(),
+        Syscall::FBINFO => sys_fbinfo(args[0]),
+        Syscall::FBMAP => sys_fbmap(),
+        Syscall::FBUNMAP => sys_fbunmap(),
+        Syscall::YIELD => sys_yield(),
+        Syscall::SLEEP => sys_nanosleep(args[0]),
+        Syscall::KLOG => sys_klog(args[0], args[1]),
+        Syscall::SETPRIORITY => sys_setpriority(args[0], args[1]),
+        Syscall::TIMES => sys_times(),
+        _ => -ENOSYS
+    }
+}
 
 pub fn init()
 {
     
 }
 
-pub fn sys_write(fd: isize, buf: &[u8]) {
-    let msg = ::core::str::from_utf8(buf).unwrap();
-    Console::with(&tty1, 18, 0, || { printk!(Debug, "sys_write {}\n\r", msg); });
+/// largest read or write in one call
+const READ_MAX: usize = 4096;
+/// longest path sys_open accepts, including terminating nul
+const PATH_MAX: usize = 256;
+
+/// [addr, addr + len) should lie in a single vma of task, which must be
+/// writable if kernel is going to write into it
+fn user_buf_ok(task: &task::Task, addr: usize, len: usize, write: bool) -> bool {
+    match task.find_vma(addr) {
+        Some(vma) => len <= vma.end() - addr && (!write || vma.flags.contains(::kern::memory::paging::WRITABLE)),
+        None => false
+    }
+}
+
+/// user_buf_ok for the calling task, which is only locked while checking
+fn current_buf_ok(addr: usize, len: usize, write: bool) -> bool {
+    match task::TaskList::get().current() {
+        Some(current) => user_buf_ok(&current.read(), addr, len, write),
+        None => false
+    }
+}
+
+/// open file at nul terminated path for access mode in flags (see
+/// vfs::O_RDONLY), return the new fd
+pub fn sys_open(path: usize, flags: usize) -> isize {
+    use collections::Vec;
+
+    // path may end anywhere in its vma, copy no further than that
+    let len = match task::TaskList::get().current() {
+        Some(current) => current.read().find_vma(path).map(|vma| ::core::cmp::min(PATH_MAX, vma.end() - path)),
+        None => return -ENOENT
+    };
+    let len = match len {
+        Some(len) => len,
+        None => return -EFAULT
+    };
+    let mut buf: Vec<u8> = vec![0; len];
+    if uaccess::copy_from_user(path, &mut buf).is_err() {
+        return -EFAULT;
+    }
+    let path = match buf.iter().position(|&b| b == 0) {
+        Some(n) => &buf[..n],
+        None => return -EINVAL
+    };
+    let path = match ::core::str::from_utf8(path) {
+        Ok(path) => path,
+        Err(_) => return -EINVAL
+    };
+
+    with_current(|task| match vfs::open(task, path, flags) {
+        Ok(fd) => fd as isize,
+        Err(e) => e
+    })
+}
+
+pub fn sys_close(fd: usize) -> isize {
+    with_current(|task| match vfs::close(task, fd) {
+        Ok(()) => 0,
+        Err(e) => e
+    })
+}
+
+/// read up to len bytes into user buf. stdin (fd 0) reads keyboard: buf must
+/// lie in caller's user stack and it blocks until at least one byte is
+/// there, -EBUSY if another task is blocked on it already. other fds read
+/// files opened by sys_open, 0 means end of file.
+/// return how many bytes were read.
+pub fn sys_read(fd: usize, buf: usize, len: usize) -> isize {
+    use collections::Vec;
+
+    if fd == 0 {
+        return sys_read_stdin(buf, len);
+    }
+    if fd < vfs::FIRST_FD {
+        return -EBADF;
+    }
+
+    if !current_buf_ok(buf, len, true) {
+        return -EFAULT;
+    }
+
+    // task stays locked only for the file access, copy_to_user may have to
+    // fault in pages of its vmas
+    let mut data: Vec<u8> = vec![0; ::core::cmp::min(len, READ_MAX)];
+    let n = with_current(|task| match vfs::read(task, fd, &mut data) {
+        Ok(n) => n as isize,
+        Err(e) => e
+    });
+    if n < 0 {
+        return n;
+    }
+    match uaccess::copy_to_user(buf, &data[..n as usize]) {
+        Ok(()) => n,
+        Err(_) => -EFAULT
+    }
+}
+
+fn sys_read_stdin(buf: usize, len: usize) -> isize {
+    use collections::Vec;
+
+    let in_stack = match task::TaskList::get().current() {
+        Some(current) => current.read().user_stack.as_ref()
+            .map_or(false, |vma| vma.contains(buf) && len <= vma.end() - buf),
+        None => return -ENOENT
+    };
+    if !in_stack {
+        return -EFAULT;
+    }
+
+    let mut data: Vec<u8> = vec![0; ::core::cmp::min(len, READ_MAX)];
+    let n = match keyboard::read(&mut data) {
+        Ok(n) => n,
+        Err(e) => return e
+    };
+    match uaccess::copy_to_user(buf, &data[..n]) {
+        Ok(()) => n as isize,
+        Err(_) => -EFAULT
+    }
+}
+
+/// write up to READ_MAX bytes of user buf. fd 0, 1 and 2 go to console as
+/// text, cut back to a whole char when buf is longer. other fds write files
+/// opened by sys_open. return how many bytes were written.
+pub fn sys_write(fd: usize, buf: usize, len: usize) -> isize {
+    use collections::Vec;
+
+    if !current_buf_ok(buf, len, false) {
+        return -EFAULT;
+    }
+    let mut data: Vec<u8> = vec![0; ::core::cmp::min(len, READ_MAX)];
+    if uaccess::copy_from_user(buf, &mut data).is_err() {
+        return -EFAULT;
+    }
+
+    if fd < vfs::FIRST_FD {
+        // the cut may split a char, its start is written by the next call
+        let text = if len > READ_MAX { whole_chars(&data) } else { &data[..] };
+        return sys_write_console(text);
+    }
+    with_current(|task| match vfs::write(task, fd, &data) {
+        Ok(n) => n as isize,
+        Err(e) => e
+    })
+}
+
+/// longest prefix of buf which does not end inside a utf-8 sequence
+fn whole_chars(buf: &[u8]) -> &[u8] {
+    // a sequence is at most 4 bytes, its first byte is in the last 3 unless
+    // it is complete
+    for back in 1..::core::cmp::min(4, buf.len() + 1) {
+        let b = buf[buf.len() - back];
+        if b & 0xc0 == 0x80 {
+            continue;
+        }
+        let need = if b & 0x80 == 0 { 1 } else if b & 0xe0 == 0xc0 { 2 } else if b & 0xf0 == 0xe0 { 3 } else { 4 };
+        return if need > back { &buf[..buf.len() - back] } else { buf };
+    }
+    buf
+}
+
+fn sys_write_console(buf: &[u8]) -> isize {
+    let msg = match ::core::str::from_utf8(buf) {
+        Ok(msg) => msg,
+        Err(_) => return -EINVAL
+    };
+    kprintln!("sys_write {}", msg);
+    buf.len() as isize
+}
+
+/// terminate calling task, its exit code is kept until it's reaped
+pub fn sys_exit(code: isize) -> ! {
+    task::exit(code)
 }
 
+/// duplicate caller, return child pid to parent and 0 to child
+pub fn sys_fork() -> isize {
+    let mut tasks = task::TaskList::get_mut();
+    let parent = match tasks.current() {
+        Some(parent) => parent.clone(),
+        None => return -ENOENT
+    };
+    let parent = parent.read();
+    let frame = unsafe { task::SyscallFrame::current() };
+    match tasks.fork(&parent, frame) {
+        Ok(pid) => pid as isize,
+        Err(e) => e
+    }
+}
+
+/// set priority of task pid (0 for caller), 0 is the highest. the lowest
+/// one is kept for idle. only the caller and its children can be changed,
+/// and only to a lower priority, -EPERM otherwise.
+pub fn sys_setpriority(pid: usize, prio: usize) -> isize {
+    let me = task::current_id();
+    let pid = if pid == 0 { me } else { pid as task::ProcId };
+    if prio >= task::MAX_PRIORITY as usize {
+        return -EINVAL;
+    }
+    match task::TaskList::get_mut().set_priority_by(me, pid, prio as u8) {
+        Ok(()) => 0,
+        Err(e) => e
+    }
+}
+
+/// give cpu to next ready task, return when scheduled again.
+/// sched() asserts IF is off: the timer calls it with interrupts masked by
+/// the gate, here yield_now masks them, so the two never run sched at the
+/// same time and fight over TaskList locks. a tick arriving right after
+/// the yield just finds a fresh task and switches as usual.
+pub fn sys_yield() -> isize {
+    task::yield_now();
+    0
+}
+
+/// sleep for at least ms milliseconds, rounded up to timer ticks. a signal
+/// cuts it short.
+pub fn sys_nanosleep(ms: usize) -> isize {
+    use ::kern::interrupts::timer;
+    task::sleep_ticks(timer::ms_to_ticks(ms));
+    0
+}
+
+pub fn sys_getpid() -> isize {
+    task::current_id() as isize
+}
+
+/// block until child pid exits and return its exit code, -1 if pid is not
+/// a child of caller or a signal arrived first. the child is reaped, a second wait on it fails.
+pub fn sys_waitpid(pid: usize) -> isize {
+    match task::waitpid(pid as task::ProcId) {
+        Some(code) => code,
+        None => -1
+    }
+}
+
+/// make signal sig pending on task pid, a sleeping target is woken to take it.
+/// only the caller and its children can be signaled, -EPERM otherwise.
+pub fn sys_kill(pid: usize, sig: usize) -> isize {
+    match ::kern::signal::send(task::current_id(), pid as task::ProcId, sig) {
+        Ok(()) => 0,
+        Err(e) => e
+    }
+}
+
+/// block (how 0), unblock (1) or set (2) signal mask of caller,
+/// return the old mask. SIGKILL stays unblocked.
+pub fn sys_sigprocmask(how: usize, set: usize) -> isize {
+    with_current(|task| match ::kern::signal::sigprocmask(task, how, set) {
+        Ok(old) => old as isize,
+        Err(e) => e
+    })
+}
+
+pub fn sys_getppid() -> isize {
+    match task::TaskList::get().current() {
+        Some(current) => current.read().ppid as isize,
+        None => -ENOENT
+    }
+}
+
+/// timer ticks caller has spent running
+pub fn sys_times() -> isize {
+    match task::TaskList::get().current() {
+        Some(current) => current.read().ticks as isize,
+        None => -ENOENT
+    }
+}
+
+/// run f with the calling task locked. f must not touch user memory, which
+/// may need the lock to fault pages in.
+fn with_current<F>(f: F) -> isize where F: FnOnce(&mut task::Task) -> isize {
+    let current = match task::TaskList::get().current() {
+        Some(current) => current.clone(),
+        None => return -ENOENT
+    };
+    let mut current = current.write();
+    f(&mut current)
+}
+
+pub fn sys_shm_create(size: usize) -> isize {
+    match shm::create(task::current_id(), size) {
+        Ok(id) => id as isize,
+        Err(e) => e
+    }
+}
+
+pub fn sys_shm_attach(id: usize) -> isize {
+    with_current(|task| match shm::attach(task, id) {
+        Ok(addr) => addr as isize,
+        Err(e) => e
+    })
+}
+
+pub fn sys_shm_detach(addr: usize) -> isize {
+    with_current(|task| match shm::detach(task, addr) {
+        Ok(()) => 0,
+        Err(e) => e
+    })
+}
+
+
+/// last error number of calling task, never fails itself
+pub fn sys_errno() -> isize {
+    tls::errno()
+}
+
+/// largest text sys_klog hands out in one call
+const KLOG_MAX: usize = 4096;
+
+/// copy recent kernel events as text lines into user buf, newest events are
+/// kept when len is short. return number of bytes written.
+pub fn sys_klog(buf: usize, len: usize) -> isize {
+    use collections::Vec;
+
+    let mut text: Vec<u8> = vec![0; ::core::cmp::min(len, KLOG_MAX)];
+    let n = trace::format(&mut text);
+    match uaccess::copy_to_user(buf, &text[..n]) {
+        Ok(()) => n as isize,
+        Err(_) => -EFAULT
+    }
+}
+
+/// fill FbInfo at user address info
+pub fn sys_fbinfo(info: usize) -> isize {
+    use core::mem::size_of;
+    use core::slice;
+
+    let fb = match fbdev::info() {
+        Some(fb) => fb,
+        None => return -ENODEV
+    };
+    let bytes = unsafe { slice::from_raw_parts(&fb as *const _ as *const u8, size_of::<fbdev::FbInfo>()) };
+    match uaccess::copy_to_user(info, bytes) {
+        Ok(()) => 0,
+        Err(_) => -EFAULT
+    }
+}
+
+/// map framebuffer into caller, only one task can hold it
+pub fn sys_fbmap() -> isize {
+    with_current(|task| match fbdev::map(task) {
+        Ok(addr) => addr as isize,
+        Err(e) => e
+    })
+}
+
+pub fn sys_fbunmap() -> isize {
+    with_current(|task| match fbdev::unmap(task) {
+        Ok(()) => 0,
+        Err(e) => e
+    })
+}
+
+pub fn test_whole_chars() {
+    // sequences of 2, 3 and 4 bytes, whole and cut
+    test_assert_eq!(whole_chars(b""), b"");
+    test_assert_eq!(whole_chars(b"ab"), b"ab");
+    test_assert_eq!(whole_chars(b"a\xc3\xa9"), b"a\xc3\xa9");
+    test_assert_eq!(whole_chars(b"a\xc3"), b"a");
+    test_assert_eq!(whole_chars(b"a\xe2\x82"), b"a");
+    test_assert_eq!(whole_chars(b"a\xe2\x82\xac"), b"a\xe2\x82\xac");
+    test_assert_eq!(whole_chars(b"a\xf0\x9f\x98"), b"a");
+    test_assert_eq!(whole_chars(b"a\xf0\x9f\x98\x80"), b"a\xf0\x9f\x98\x80");
+}
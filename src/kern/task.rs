@@ -1,15 +1,17 @@
 use ::kern::memory::inactive::{TemporaryPage, InactivePML4Table};
 use ::kern::memory::stack_allocator::{Stack, StackAllocator};
-use ::kern::memory::{MemoryManager, MM, KERNEL_MAPPING};
+use ::kern::memory::{MemoryManager, MM, KERNEL_MAPPING, PAGE_SIZE};
 use ::kern::memory::paging;
+use ::kern::memory::uaccess;
 use ::kern::console::LogLevel::*;
-use ::kern::console::{Console, tty1};
 use ::kern::arch::cpu;
 use ::kern::interrupts::{self, idt};
+use ::kern::percpu;
+pub use ::kern::tls::TLSSegment;
 
-use core::sync::atomic::{AtomicIsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use collections::string::{String, ToString};
-use collections::{BTreeMap, Vec};
+use collections::{BTreeMap, Vec, VecDeque};
 use alloc::arc::Arc;
 use alloc::heap::Heap;
 use alloc::allocator::{Alloc, Layout};
@@ -21,7 +23,7 @@ use x86_64;
 
 pub type ProcId = isize;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
     Unused,
     Created,
@@ -31,9 +33,20 @@ pub enum TaskState {
     Zombie
 }
 
+/// how a task was built, which decides how it is switched to: user tasks
+/// own an address space and need TSS.rsp0 for traps from ring 3, kernel
+/// threads run in whatever address space is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    Kernel,
+    User
+}
+
 
-/// context for kernel side task scheduler
+/// context for kernel side task scheduler.
+/// field offsets are used by context_switch, keep them in sync.
 #[derive(Debug, Clone)]
+#[repr(C)]
 pub struct Context {
     pub rflags: usize,
     pub cr3: usize, // phyiscal address
@@ -60,6 +73,35 @@ impl Context {
             r15: 0, 
         }
     }
+
+    /// sanity check of saved context against task's kernel stack
+    pub fn validate(&self, kern_stack: Option<&Stack>) -> Result<(), &'static str> {
+        // bit 1 of rflags is reserved and always set
+        if self.rflags & 0x2 == 0 {
+            return Err("rflags reserved bit 1 not set");
+        }
+
+        if self.cr3 == 0 {
+            return Err("cr3 is zero");
+        }
+        if self.cr3 & 0xfff != 0 {
+            return Err("cr3 is not page aligned");
+        }
+
+        match kern_stack {
+            Some(st) if self.rsp > st.bottom() && self.rsp <= st.top() => Ok(()),
+            Some(_) => Err("rsp out of kernel stack"),
+            None => Err("task has no kernel stack")
+        }
+    }
+
+    /// safe to call from irq context
+    pub fn dump(&self) {
+        kprintln!("rflags: {:#018x}  cr3: {:#018x}", self.rflags, self.cr3);
+        kprintln!("rsp: {:#018x}  rbp: {:#018x}  rbx: {:#018x}", self.rsp, self.rbp, self.rbx);
+        kprintln!("r12: {:#018x}  r13: {:#018x}  r14: {:#018x}  r15: {:#018x}",
+            self.r12, self.r13, self.r14, self.r15);
+    }
 }
 
 /// for task 
@@ -74,6 +116,7 @@ pub struct VirtualMemoryArea {
 impl VirtualMemoryArea {
     pub fn new(start: usize, size: usize, flags: paging::EntryFlags) -> VirtualMemoryArea {
         assert!(!flags.contains(paging::PRESENT));
+        assert!(start.checked_add(size).is_some(), "vma [{:#x}, +{:#x}) wraps around", start, size);
 
         VirtualMemoryArea {
             start: start,
@@ -93,13 +136,8 @@ impl VirtualMemoryArea {
             }
         });
 
-        // switching pml4 is heavy
-        let cur_pml4 = paging::switch(inactive.clone());
-        unsafe {
-            ::core::ptr::copy_nonoverlapping(data.as_ptr() as *mut u8,
-                self.start as *mut u8, data.len());
-        }
-        paging::switch(cur_pml4);
+        // copy through temporary mappings, active cr3 stays untouched
+        uaccess::copy_to_space(inactive, self.start, data).expect("copy data into vma");
     }
 
     pub fn map(&self, inactive: &mut InactivePML4Table) {
@@ -113,86 +151,365 @@ impl VirtualMemoryArea {
         });
     }
 
+    /// unmap populated pages of vma and drop our reference of their frames,
+    /// inactive must not be the active address space
     pub fn unmap(&mut self, inactive: &mut InactivePML4Table) {
-        unimplemented!()
+        use ::kern::memory::frame::{Frame, dealloc_frame};
+
+        let mut active = paging::ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(paging::Page::from_vaddress(0xfffff_cafe_beef_000));
+        printk!(Debug, "unmapping VirtualMemoryArea {:?}\n\r", self.get_pages());
+        active.with(inactive, &mut temp_page, |mapper| {
+            for page in self.get_pages() {
+                // demand paged vmas may have holes
+                if let Some(paddr) = mapper.translate(page.start_address()) {
+                    mapper.unmap(page);
+                    dealloc_frame(Frame::from_paddress(paddr));
+                }
+            }
+        });
+        self.mapped = false;
+    }
+
+    /// map pages of vma populated in active space into child as well, and
+    /// copy their contents. holes stay for demand paging.
+    pub fn fork_into(&self, child: &mut InactivePML4Table) {
+        let present: Vec<paging::Page> = {
+            let active = paging::ActivePML4Table::new();
+            self.get_pages().filter(|page| active.translate(page.start_address()).is_some()).collect()
+        };
+
+        let mut active = paging::ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(paging::Page::from_vaddress(0xfffff_cafe_beef_000));
+        active.with(child, &mut temp_page, |mapper| {
+            for page in present.iter() {
+                mapper.map(*page, self.flags);
+            }
+        });
+
+        for page in present.iter() {
+            let addr = page.start_address();
+            let src = unsafe { ::core::slice::from_raw_parts(addr as *const u8, PAGE_SIZE) };
+            uaccess::copy_to_space(child, addr, src).expect("fork: copy page");
+        }
     }
 
     pub fn get_pages(&self) -> paging::PageRange {
-        paging::PageRange::new(self.start, self.start + self.size)
+        paging::PageRange::new(self.start, self.end())
     }
-}
 
-#[derive(Debug, Clone)]
-#[repr(C, packed)]
-pub struct TLSSegment {
-    pub user_rsp: usize,
-    pub kern_rsp: usize
-}
+    /// exclusive end address, None if it wraps around
+    pub fn checked_end(&self) -> Option<usize> {
+        self.start.checked_add(self.size)
+    }
 
-impl TLSSegment {
-    pub const fn empty() -> TLSSegment {
-        TLSSegment {
-            user_rsp: 0,
-            kern_rsp: 0
-        }
+    /// exclusive end address, vma must not wrap around
+    pub fn end(&self) -> usize {
+        self.checked_end().expect("vma wraps around")
     }
 
-    pub const fn new(kern_rsp: usize, user_rsp: usize) -> TLSSegment {
-        TLSSegment {
-            user_rsp,
-            kern_rsp
-        }
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    pub fn overlaps(&self, other: &VirtualMemoryArea) -> bool {
+        self.start < other.end() && other.start < self.end()
     }
 }
 
+/// what syscall_entry (context.asm) leaves right below TLSSegment of
+/// caller, lowest address first. dispatch args start at rdi.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbx: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub r10: usize,
+    pub rax: usize,
+    pub rcx: usize, // user rip
+    pub r11: usize, // user rflags
+    pub rbp: usize,
+    pub user_rsp: usize,
+}
+
+impl SyscallFrame {
+    /// frame of the syscall current task is in
+    pub unsafe fn current() -> &'static SyscallFrame {
+        use core::mem::size_of;
+        let kern_rsp = percpu::this().expect("syscall before percpu init").kern_rsp;
+        &*((kern_rsp - size_of::<SyscallFrame>()) as *const SyscallFrame)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Task {
     pub pid: ProcId,
     pub ppid: ProcId,
+    pub kind: TaskKind,
     pub name: Option<String>,
     pub cr3: Option<InactivePML4Table>,
     pub kern_stack: Option<Stack>,
     pub user_stack: Option<VirtualMemoryArea>,
     pub code: Option<VirtualMemoryArea>,
     pub data: Option<VirtualMemoryArea>, //including data and bss
+    pub vmas: Vec<VirtualMemoryArea>, // sorted by start address
+    pub shm: Vec<(usize, usize)>, // attached shared memory (address, id)
+    pub files: Vec<Option<::kern::vfs::OpenFile>>, // indexed by fd, see vfs::FIRST_FD
     pub exec_entry: usize,
     pub ctx: Context,
     pub state: TaskState,
+    pub exit_code: isize,
+    pub waiting_on: Option<ProcId>, // child pid this task sleeps in waitpid for
+    pub sig_pending: usize, // bit n set when signal n is pending
+    pub sig_mask: usize, // blocked signals, see signal.rs
+    pub wake_at: Option<usize>, // timer tick a sleeping task is woken at
+    pub priority: u8, // 0 is the highest
+    pub ticks: usize, // timer ticks spent as the running task
 }
 
 impl Task {
-    pub const fn empty() -> Task {
+    pub fn empty() -> Task {
         Task {
             pid: 0,
             ppid: 0,
+            kind: TaskKind::Kernel,
             name: None,
             cr3: None,
             kern_stack: None,
             user_stack: None,
             code: None,
             data: None,
+            vmas: Vec::new(),
+            shm: Vec::new(),
+            files: Vec::new(),
             exec_entry: 0,
             state: TaskState::Unused,
             ctx: Context::new(),
+            exit_code: 0,
+            waiting_on: None,
+            sig_pending: 0,
+            sig_mask: 0,
+            wake_at: None,
+            priority: DEFAULT_PRIORITY,
+            ticks: 0,
         }
     }
+
+    /// check the task is built the way its kind says, return what is off
+    pub fn check_kind(&self) -> Result<(), &'static str> {
+        match self.kind {
+            TaskKind::User if self.user_stack.is_none() => Err("user task without user stack"),
+            TaskKind::User if self.cr3.is_none() => Err("user task without address space"),
+            TaskKind::Kernel if self.user_stack.is_some() || self.code.is_some() =>
+                Err("kernel thread with user mappings"),
+            _ => Ok(())
+        }
+    }
+
+    /// change state, illegal transitions panic in debug builds
+    pub fn set_state(&mut self, new: TaskState) {
+        use self::TaskState::*;
+
+        let legal = match (self.state, new) {
+            (Unused, Created) => true,
+            (Created, Ready) => true,
+            (Ready, Running) => true,
+            (Running, Ready) | (Running, Sleep) | (Running, Zombie) => true,
+            (Sleep, Ready) => true,
+            (Zombie, Unused) => true,
+            _ => false
+        };
+
+        if !legal {
+            if cfg!(debug_assertions) {
+                panic!("task {}: illegal state transition {:?} -> {:?}", self.pid, self.state, new);
+            }
+            kprintln!("task {}: illegal state transition {:?} -> {:?}", self.pid, self.state, new);
+        }
+        self.state = new;
+    }
+
+    /// register vma into the sorted vma list, reject it if overlaps with
+    /// any registered one.
+    pub fn add_vma(&mut self, vma: VirtualMemoryArea) -> Result<(), &'static str> {
+        if vma.size == 0 {
+            return Err("empty vma");
+        }
+        // user space ends where non-canonical hole begins
+        match vma.checked_end() {
+            Some(end) if end <= KERNEL_MAPPING.Invalid.start => {},
+            _ => return Err("vma is beyond user space")
+        }
+
+        let idx = match self.vmas.binary_search_by_key(&vma.start, |v| v.start) {
+            Ok(_) => return Err("vma overlaps"),
+            Err(i) => i
+        };
+
+        // list is sorted, so only neighbours can overlap
+        if idx > 0 && self.vmas[idx-1].overlaps(&vma) {
+            return Err("vma overlaps");
+        }
+        if idx < self.vmas.len() && self.vmas[idx].overlaps(&vma) {
+            return Err("vma overlaps");
+        }
+
+        self.vmas.insert(idx, vma);
+        if cfg!(feature = "test") { check_vma_invariants(self); }
+        Ok(())
+    }
+
+    /// unregister vma starts at start
+    pub fn remove_vma(&mut self, start: usize) -> Option<VirtualMemoryArea> {
+        match self.vmas.binary_search_by_key(&start, |v| v.start) {
+            Ok(i) => Some(self.vmas.remove(i)),
+            Err(_) => None
+        }
+    }
+
+    /// find the vma which covers addr, used by page fault handler
+    pub fn find_vma(&self, addr: usize) -> Option<&VirtualMemoryArea> {
+        let idx = match self.vmas.binary_search_by_key(&addr, |v| v.start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+
+        let vma = &self.vmas[idx];
+        if vma.contains(addr) { Some(vma) } else { None }
+    }
+}
+
+/// vmas of task should be sorted by start address and never overlap
+pub fn check_vma_invariants(task: &Task) {
+    for w in task.vmas.windows(2) {
+        assert!(w[0].start < w[1].start, "task {}: vmas unsorted {:#x} {:#x}",
+            task.pid, w[0].start, w[1].start);
+        assert!(!w[0].overlaps(&w[1]), "task {}: vma [{:#x}, {:#x}) overlaps [{:#x}, {:#x})",
+            task.pid, w[0].start, w[0].end(), w[1].start, w[1].end());
+    }
 }
 
 pub const MAX_TASK: isize = 64;
 
+/// user task caller may only change priority of itself or its children,
+/// never of kernel threads (idle included), and may not raise it
+pub fn may_set_priority(caller: ProcId, target: &Task, prio: u8) -> Result<(), isize> {
+    use ::kern::syscall::EPERM;
+    if target.kind != TaskKind::User || (target.pid != caller && target.ppid != caller) {
+        return Err(-EPERM);
+    }
+    if prio < target.priority {
+        return Err(-EPERM);
+    }
+    Ok(())
+}
+
+/// priority of new tasks, 0 is the highest
+pub const DEFAULT_PRIORITY: u8 = 20;
+/// lowest priority, only idle has it so it runs when nothing else is ready
+pub const MAX_PRIORITY: u8 = 255;
+
+/// bytes of kernel stack of each task, guard page not counted
+const KERN_STACK_SIZE: usize = 8192;
+
+/// kernel stack from heap with an unmapped guard page right below it, so an
+/// overflow faults instead of trashing the heap block in front. heap tables
+/// are shared by all address spaces, so the guard is gone in every one.
+fn alloc_kern_stack() -> Stack {
+    use ::kern::memory::paging::{ActivePML4Table, Page};
+
+    let base = unsafe {
+        Heap.alloc(Layout::from_size_align_unchecked(PAGE_SIZE + KERN_STACK_SIZE, PAGE_SIZE))
+            .expect("alloc kernel stack") as usize
+    };
+    ActivePML4Table::new().unmap(Page::from_vaddress(base));
+
+    let bottom = base + PAGE_SIZE;
+    unsafe { *(bottom as *mut usize) = STACK_CANARY; }
+    printk!(Debug, "kernel stack [{:#x}, {:#x}), guard {:#x}\n\r", bottom, bottom + KERN_STACK_SIZE, base);
+    Stack::new(bottom + KERN_STACK_SIZE, bottom)
+}
+
+/// lowest word of each kernel stack, right above its guard page. it is gone
+/// once the stack has grown all the way down, even if no write touched the
+/// guard page yet.
+const STACK_CANARY: usize = 0x57ac_b077_57ac_b077;
+
+/// false if kernel stack st has overflowed down to its canary
+pub fn stack_canary_ok(st: &Stack) -> bool {
+    unsafe { *(st.bottom() as *const usize) == STACK_CANARY }
+}
+
+/// stack_canary_ok for the kernel stack syscalls of running task land on,
+/// true before tasking starts
+pub fn current_stack_canary_ok() -> bool {
+    match percpu::this().map(|cpu| cpu.kern_rsp) {
+        // kern_rsp is the tls block at the top of the stack
+        Some(rsp) if rsp != 0 => {
+            let bottom = rsp + ::kern::tls::TLS_SIZE - KERN_STACK_SIZE;
+            unsafe { *(bottom as *const usize) == STACK_CANARY }
+        },
+        _ => true
+    }
+}
+
+/// give stack of alloc_kern_stack back to heap. heap is mapped onto
+/// contiguous frames (see create_address_space), so the guard page gets
+/// back the frame right below that of stack bottom.
+fn free_kern_stack(st: Stack) {
+    use ::kern::memory::paging::{ActivePML4Table, Page, WRITABLE};
+    use ::kern::memory::frame::Frame;
+
+    let base = st.bottom() - PAGE_SIZE;
+    let mut active = ActivePML4Table::new();
+    let paddr = active.translate(st.bottom()).expect("kernel stack is not mapped") - PAGE_SIZE;
+    active.map_to(Page::from_vaddress(base), Frame::from_paddress(paddr), WRITABLE);
+    unsafe {
+        Heap.dealloc(base as *mut u8, Layout::from_size_align_unchecked(PAGE_SIZE + KERN_STACK_SIZE, PAGE_SIZE));
+    }
+}
+
+/// whether addr lies in the guard page of kernel stack st
+pub fn in_stack_guard(st: &Stack, addr: usize) -> bool {
+    addr < st.bottom() && addr >= st.bottom() - PAGE_SIZE
+}
+
 type TaskMap = BTreeMap<ProcId, Arc<RwLock<Task>>>;
 
+/// work exit could not finish because a task lock was busy, sched retries it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Deferred {
+    /// wake parent if it still waits in waitpid for child
+    Wake(ProcId, ProcId),
+    /// hand child to init if its parent is still the exited task
+    Reparent(ProcId, ProcId),
+}
+
 pub struct TaskList {
     pub tasks: TaskMap,
-    pub next_id: ProcId,
+    /// round-robin queue of Ready tasks
+    pub ready: VecDeque<ProcId>,
+    /// adopts children of exited tasks, None until init is loaded
+    pub init: Option<ProcId>,
+    deferred: Vec<Deferred>,
 }
 
 impl TaskList {
     pub fn new() -> TaskList {
         TaskList {
             tasks: BTreeMap::new(),
-            next_id: 1
+            ready: VecDeque::new(),
+            init: None,
+            deferred: Vec::new(),
         }
     }
 
@@ -204,38 +521,316 @@ impl TaskList {
         TASKS.call_once(init_tasks).write()
     }
 
+    /// for exception handlers, which may have interrupted a list writer
+    pub fn try_get() -> Option<RwLockReadGuard<'static, TaskList>> {
+        TASKS.call_once(init_tasks).try_read()
+    }
+
+    /// used by sched, which must never spin on the list inside irq
+    pub fn try_get_mut() -> Option<RwLockWriteGuard<'static, TaskList>> {
+        TASKS.call_once(init_tasks).try_write()
+    }
+
     pub fn get_task(&self, id: ProcId) -> Option<&Arc<RwLock<Task>>> {
         self.tasks.get(&id)
     }
 
     pub fn current(&self) -> Option<&Arc<RwLock<Task>>> {
-        self.get_task(CURRENT_ID.load(Ordering::SeqCst))
+        self.get_task(current_id())
+    }
+
+    /// smallest pid not in use, pids of reaped tasks get reused
+    fn try_alloc_pid(&self) -> Option<ProcId> {
+        (1..MAX_TASK).find(|pid| !self.tasks.contains_key(pid))
+    }
+
+    fn alloc_pid(&self) -> ProcId {
+        self.try_alloc_pid().expect("task id exceeds maximum boundary")
+    }
+
+    /// (priority, queue index) of the best Ready task in ready queue. busy
+    /// tasks are taken as is, sched handles them when it gets there.
+    fn best_ready(&self) -> Option<(u8, usize)> {
+        self.ready.iter().enumerate()
+            .filter_map(|(i, pid)| self.tasks.get(pid).map(|t| match t.try_read() {
+                Some(t) if t.state != TaskState::Ready => (MAX_PRIORITY, i),
+                Some(t) => (t.priority, i),
+                None => (MAX_PRIORITY, i)
+            }))
+            .min()
+    }
+
+    /// set_priority asked by user task caller, see may_set_priority
+    pub fn set_priority_by(&mut self, caller: ProcId, pid: ProcId, prio: u8) -> Result<(), isize> {
+        use ::kern::syscall::ENOENT;
+        match self.get_task(pid) {
+            Some(task) => {
+                let mut task = task.write();
+                may_set_priority(caller, &task, prio)?;
+                task.priority = prio;
+                Ok(())
+            },
+            None => Err(-ENOENT)
+        }
+    }
+
+    pub fn set_priority(&mut self, pid: ProcId, prio: u8) -> Result<(), isize> {
+        use ::kern::syscall::ENOENT;
+        match self.get_task(pid) {
+            Some(task) => {
+                task.write().priority = prio;
+                Ok(())
+            },
+            None => Err(-ENOENT)
+        }
+    }
+
+    /// make sleepers whose wake_at has come Ready, called by timer irq.
+    /// a task whose lock is busy is not asleep, or is woken at next tick.
+    pub fn wake_sleepers(&mut self, now: usize) {
+        let ready = &mut self.ready;
+        for (&pid, task_lock) in self.tasks.iter() {
+            if let Some(mut task) = task_lock.try_write() {
+                match task.wake_at {
+                    Some(tick) if tick <= now && task.state == TaskState::Sleep => {
+                        task.set_state(TaskState::Ready);
+                        ready.push_back(pid);
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// make task pid Ready if it sleeps. return false if it could not be
+    /// locked, irq callers should try again later.
+    pub fn wake(&mut self, pid: ProcId) -> bool {
+        let task_lock = match self.tasks.get(&pid) {
+            Some(task_lock) => task_lock.clone(),
+            None => return true
+        };
+        let result = match task_lock.try_write() {
+            Some(mut task) => {
+                if task.state == TaskState::Sleep {
+                    task.set_state(TaskState::Ready);
+                    self.ready.push_back(pid);
+                }
+                true
+            },
+            None => false
+        };
+        result
+    }
+
+    /// charge one timer tick to task pid. called from timer irq, so a task
+    /// locked by someone else just misses this tick.
+    pub fn account_tick(&self, pid: ProcId) {
+        if let Some(mut task) = self.get_task(pid).and_then(|t| t.try_write()) {
+            task.ticks += 1;
+        }
+    }
+
+    /// duplicate user task parent, which is inside a syscall with frame on
+    /// its kernel stack. populated pages of user stack, code and data are
+    /// copied; shared memory and framebuffer mappings are not inherited.
+    /// child returns 0 from the same syscall.
+    pub fn fork(&mut self, parent: &Task, frame: &SyscallFrame) -> Result<ProcId, isize> {
+        use core::mem::size_of;
+        use ::kern::syscall::{EAGAIN, EINVAL};
+
+        if parent.kind != TaskKind::User {
+            return Err(-EINVAL);
+        }
+        let pid = match self.try_alloc_pid() {
+            Some(pid) => pid,
+            None => return Err(-EAGAIN)
+        };
+
+        let mut task = Task::empty();
+        task.pid = pid;
+        task.kind = TaskKind::User;
+        task.ppid = parent.pid;
+        task.name = parent.name.clone();
+        task.exec_entry = parent.exec_entry;
+        task.sig_mask = parent.sig_mask;
+        task.priority = parent.priority;
+        task.files = parent.files.clone();
+        task.set_state(TaskState::Created);
+
+        let mut cr3 = {
+            let mm = MM.try().unwrap().lock();
+            paging::create_address_space(mm.mbinfo)
+        };
+        for vma in parent.user_stack.iter().chain(parent.code.iter()).chain(parent.data.iter()) {
+            vma.fork_into(&mut cr3);
+            task.add_vma(vma.clone()).expect("fork: add vma");
+        }
+        task.user_stack = parent.user_stack.clone();
+        task.code = parent.code.clone();
+        task.data = parent.data.clone();
+        task.ctx.cr3 = cr3.pml4_frame.start_address();
+        task.cr3 = Some(cr3);
+
+        task.kern_stack = Some(alloc_kern_stack());
+
+        // context_switch retq's into syscall_return, which pops the copied
+        // frame and sysrets with IF restored from user r11
+        extern { fn syscall_return(); }
+        let kern_rsp = task.kern_stack.as_ref().map(|st| st.top()).unwrap();
+        unsafe {
+            let tlsbase = kern_rsp - size_of::<TLSSegment>();
+            ::core::ptr::write(tlsbase as *mut TLSSegment, TLSSegment::new(tlsbase, frame.user_rsp, pid));
+
+            let fp = (tlsbase - size_of::<SyscallFrame>()) as *mut SyscallFrame;
+            ::core::ptr::write(fp, *frame);
+            (*fp).rax = 0;
+
+            let ret = (fp as usize - size_of::<usize>()) as *mut usize;
+            *ret = syscall_return as usize;
+            task.ctx.rsp = ret as usize;
+        }
+        // IF stays off until sysret
+        task.ctx.rflags = 0x0002;
+
+        task.set_state(TaskState::Ready);
+        self.tasks.insert(pid, Arc::new(RwLock::new(task)));
+        self.ready.push_back(pid);
+        Ok(pid)
+    }
+
+    /// remove zombie pid and free what it still holds: user vmas, page tables
+    /// and kernel stack. return its exit code, None if there is no such task.
+    /// should be called with interrupts disabled.
+    pub fn reap(&mut self, pid: ProcId) -> Option<isize> {
+        let task_lock = match self.tasks.remove(&pid) {
+            Some(task_lock) => task_lock,
+            None => return None
+        };
+        let mut guard = task_lock.write();
+        let task = &mut *guard;
+        assert!(task.state == TaskState::Zombie, "reap: task {} is {:?}, not Zombie", pid, task.state);
+        assert!(pid != current_id(), "reap: task {} reaps itself", pid);
+
+        if let Some(mut cr3) = task.cr3.take() {
+            let kernel_pml4 = MM.try().unwrap().lock().kernelPML4Table;
+            if cr3 != kernel_pml4 {
+                // kernel threads keep running in the address space of whichever
+                // user task was before them, it may be this one
+                if paging::current_pml4_frame() == cr3.pml4_frame.start_address() {
+                    paging::switch(kernel_pml4);
+                }
+                for vma in task.user_stack.iter_mut().chain(task.code.iter_mut()).chain(task.data.iter_mut()) {
+                    if vma.mapped {
+                        vma.unmap(&mut cr3);
+                    }
+                }
+                cr3.destroy(&mut paging::ActivePML4Table::new());
+            }
+        }
+
+        if let Some(st) = task.kern_stack.take() {
+            free_kern_stack(st);
+        }
+        task.set_state(TaskState::Unused);
+        self.ready.retain(|&p| p != pid);
+        Some(task.exit_code)
+    }
+
+    /// pid is exiting and the caller holds its lock: hand its children to
+    /// init and wake its parent if it waits for pid. what finds a busy lock
+    /// is queued and done by flush_deferred.
+    pub fn notify_exit(&mut self, pid: ProcId, ppid: ProcId) {
+        let others: Vec<ProcId> = self.tasks.keys().cloned().filter(|&p| p != pid).collect();
+        for &child in others.iter() {
+            self.defer(Deferred::Reparent(pid, child));
+        }
+        self.defer(Deferred::Wake(ppid, pid));
+    }
+
+    /// retry what notify_exit had to queue
+    pub fn flush_deferred(&mut self) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        let pending = ::core::mem::replace(&mut self.deferred, Vec::new());
+        for &d in pending.iter() {
+            self.defer(d);
+        }
+    }
+
+    fn defer(&mut self, d: Deferred) {
+        if !self.try_apply(d) {
+            self.deferred.push(d);
+        }
+    }
+
+    /// false if the lock d needs is busy. a task gone in between needs nothing
+    fn try_apply(&mut self, d: Deferred) -> bool {
+        let pid = match d {
+            Deferred::Wake(parent, _) => parent,
+            Deferred::Reparent(_, child) => child
+        };
+        let task_lock = match self.get_task(pid) {
+            Some(task_lock) => task_lock.clone(),
+            None => return true
+        };
+        let mut task = match task_lock.try_write() {
+            Some(task) => task,
+            None => return false
+        };
+
+        match d {
+            Deferred::Wake(parent, child) => {
+                if task.state == TaskState::Sleep && task.waiting_on == Some(child) {
+                    task.set_state(TaskState::Ready);
+                    self.ready.push_back(parent);
+                }
+            },
+            Deferred::Reparent(from, _) => {
+                if let Some(init) = self.init {
+                    if task.ppid == from {
+                        task.ppid = init;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// reap every zombie but the current task, return number reaped.
+    /// should be called with interrupts disabled.
+    pub fn reap_zombies(&mut self) -> usize {
+        let id = current_id();
+        let zombies: Vec<ProcId> = self.tasks.iter()
+            .filter(|&(&pid, task_lock)| {
+                // a busy task is not a zombie, nobody touches those
+                pid != id && task_lock.try_read().map(|t| t.state == TaskState::Zombie).unwrap_or(false)
+            })
+            .map(|(&pid, _)| pid)
+            .collect();
+
+        for &pid in zombies.iter() {
+            self.reap(pid);
+        }
+        zombies.len()
     }
 
     // kernel thread
-    pub fn alloc_kernel_task(&mut self, name: &str, rip: usize) {
+    pub fn alloc_kernel_task(&mut self, name: &str, rip: usize) -> ProcId {
         use core::mem::size_of;
 
 
-        let pid = self.next_id;
-        assert!(self.next_id < MAX_TASK, "task id exceeds maximum boundary");
+        let pid = self.alloc_pid();
 
         let mut task = Task::empty();
         task.pid = pid as isize;
         task.ppid = 0;
+        task.kind = TaskKind::Kernel;
         task.name = Some(name.to_string());
-        task.state = TaskState::Created;
+        task.set_state(TaskState::Created);
         task.exec_entry = rip;
 
-        task.kern_stack = Some({
-            let mem = unsafe {
-                &*(Heap.alloc(Layout::from_size_align_unchecked(8192, 1)).unwrap() as *mut [u8; 8192])
-            };
-
-            printk!(Debug, "boxed slice [{:#x}, {:#x})\n\r", mem.as_ptr() as usize, mem.len());
-            let top = mem.as_ptr() as usize;
-            Stack::new(top + mem.len(), top)
-        });
+        task.kern_stack = Some(alloc_kern_stack());
         task.cr3 = Some({
             let mut mm = MM.try().unwrap().lock();
             mm.kernelPML4Table
@@ -249,10 +844,7 @@ impl TaskList {
         unsafe {
             let tlsbase = kern_rsp - size_of::<TLSSegment>();
             let tls = tlsbase as *mut TLSSegment;
-            ::core::ptr::write(tls, TLSSegment {
-                user_rsp: 0,
-                kern_rsp: tlsbase
-            });
+            ::core::ptr::write(tls, TLSSegment::new(tlsbase, 0, task.pid));
 
             let fp = tlsbase as *mut usize;
             *fp.offset(-1) = interrupts::KERN_DS_SEL.0 as usize;
@@ -262,91 +854,112 @@ impl TaskList {
             *fp.offset(-5) = task.exec_entry;
             *fp.offset(-6) = start_task as usize;
         }
+        if cfg!(debug_assertions) {
+            check_initial_frame(&task);
+        }
         task.ctx.cr3 = task.cr3.as_ref().unwrap().pml4_frame.start_address();
 
+        task.set_state(TaskState::Ready);
         self.entry(pid).or_insert(Arc::new(RwLock::new(task)));
-        self.next_id += 1;
+        self.ready.push_back(pid);
+        pid
     }
 
-    // user task
-    pub fn load_task(&mut self, name: &str, elf: &Elf64, parent: ProcId) {
+    // user task running statically linked elf image, left Created and out of
+    // ready queue: caller decides whether it runs right away or gets queued.
+    // image may have one executable and one writable (data and bss) segment.
+    pub fn alloc_elf_task(&mut self, name: &str, parent: ProcId, image: &[u8]) -> Result<ProcId, ElfError> {
         use core::mem::size_of;
 
-        let pid = self.next_id;
-        assert!(self.next_id < MAX_TASK, "task id exceeds maximum boundary");
+        let elf = Elf64::parse(image)?;
+        let (code_segs, data_segs) = elf.load_segments().fold((0, 0), |(c, d), ph| {
+            if ph.p_flags & PF_X != 0 { (c + 1, d) } else { (c, d + 1) }
+        });
+        if code_segs != 1 || data_segs > 1 {
+            return Err(ElfError::BadSegment);
+        }
+
+        // image is untrusted: its segments must not overlap each other or
+        // the user stack, or booking vmas and mapping the stack would fail
+        let stack_vma = VirtualMemoryArea::new(KERNEL_MAPPING.UserStack.start,
+                                               ::kern::memory::region_size(&KERNEL_MAPPING.UserStack),
+                                               paging::USER | paging::WRITABLE | paging::NO_EXECUTE);
+        let mut seg_vmas: Vec<(VirtualMemoryArea, bool)> = Vec::new();
+        for ph in elf.load_segments() {
+            let pages = ph.pages();
+            let (start, end) = (pages.start.start_address(), pages.end.start_address());
+            let mut vma = VirtualMemoryArea::new(start, end - start, ph.page_flags());
+            // segments are mapped by load_into
+            vma.mapped = true;
+
+            if vma.overlaps(&stack_vma) || seg_vmas.iter().any(|seg| seg.0.overlaps(&vma)) {
+                return Err(ElfError::BadSegment);
+            }
+            seg_vmas.push((vma, ph.p_flags & PF_X != 0));
+        }
+
+        let pid = match self.try_alloc_pid() {
+            Some(pid) => pid,
+            None => return Err(ElfError::TooManyTasks)
+        };
+
+        let mut cr3 = {
+            let mut mm = MM.try().unwrap().lock();
+            paging::create_address_space(mm.mbinfo)
+        };
+        if let Err(e) = elf.load_into(&mut cr3) {
+            // segments loaded before the bad one own their frames
+            for seg in seg_vmas.iter_mut() {
+                seg.0.unmap(&mut cr3);
+            }
+            cr3.destroy(&mut paging::ActivePML4Table::new());
+            return Err(e);
+        }
 
         let mut task = Task::empty();
         task.pid = pid as isize;
         task.ppid = parent; 
+        task.kind = TaskKind::User;
         task.name = Some(name.to_string());
-        task.state = TaskState::Created;
-
-        task.cr3 = Some({
-            let mut mm = MM.try().unwrap().lock();
-            paging::create_address_space(mm.mbinfo)
-        });
+        task.exec_entry = elf.entry();
+        task.set_state(TaskState::Created);
+        task.cr3 = Some(cr3);
 
         task.user_stack = Some({
-            let mut vma = VirtualMemoryArea {
-                start: KERNEL_MAPPING.UserStack.start,
-                size: KERNEL_MAPPING.UserStack.end - KERNEL_MAPPING.UserStack.start + 1,
-                mapped: false,
-                flags: paging::USER | paging::WRITABLE | paging::NO_EXECUTE
-            };
-
+            let mut vma = stack_vma;
             vma.map(task.cr3.as_mut().unwrap());
             vma.mapped = true;
 
             vma
         });
 
-        {
-            printk!(Debug, "load program_headers\n\r");
-            task.exec_entry = elf.header.e_entry as usize;
-
-            for ph in elf.program_headers() {
-                printk!(Debug, "{:?}\n\r", ph);
-                if ph.p_type != PT_LOAD { continue; }
-
-                let sz = ph.p_memsz as usize;
-                let data = unsafe { elf.data.as_ptr().offset(ph.p_offset as isize) };
-                match (ph.p_flags & PF_X) != 0 {
-                    false => {
-                        printk!(Debug, "load data/bss segment\n\r");
-                    }, 
-
-                    true => {
-                        //let code: &[u8; 20] = &*(data as *const [u8; 20]);
-                        //printk!(Debug, "load code segment {:?}\n\r", code);
-                        task.code = Some({
-                            let mut vma = VirtualMemoryArea {
-                                start: KERNEL_MAPPING.UserCode.start,
-                                size: sz,
-                                mapped: false,
-                                flags: paging::USER | paging::WRITABLE
-                            };
-
-                            let data = unsafe { ::core::slice::from_raw_parts(data, sz) };
-                            vma.map_with_data(task.cr3.as_mut().unwrap(), data);
-                            vma.mapped = true;
-
-                            vma
-                        });
-
-                    }
-                }
+        let stack_vma = task.user_stack.clone().unwrap();
+        let mut booked = task.add_vma(stack_vma);
+        for seg in seg_vmas.iter() {
+            if booked.is_err() {
+                break;
             }
+            booked = task.add_vma(seg.0.clone());
+        }
+        if let Err(e) = booked {
+            printk!(Warn, "{}: {}, drop its address space\n\r", name, e);
+            let mut cr3 = task.cr3.take().unwrap();
+            for vma in task.user_stack.iter_mut().chain(seg_vmas.iter_mut().map(|seg| &mut seg.0)) {
+                vma.unmap(&mut cr3);
+            }
+            cr3.destroy(&mut paging::ActivePML4Table::new());
+            return Err(ElfError::BadSegment);
         }
 
+        for (vma, exec) in seg_vmas {
+            if exec {
+                task.code = Some(vma);
+            } else {
+                task.data = Some(vma);
+            }
+        }
 
-        task.kern_stack = Some({
-            let mem = unsafe {
-                &*(Heap.alloc(Layout::from_size_align_unchecked(8192, 1)).unwrap() as *mut [u8; 8192])
-            };
-            printk!(Debug, "boxed slice [{:#x}, {:#x})\n\r", mem.as_ptr() as usize, mem.len());
-            let top = mem.as_ptr() as usize;
-            Stack::new(top + mem.len(), top)
-        });
+        task.kern_stack = Some(alloc_kern_stack());
         task.ctx = Context::new();
         let kern_rsp = task.kern_stack.as_ref().map(|st| st.top()).unwrap();
         task.ctx.rflags = 0x0202;
@@ -354,17 +967,14 @@ impl TaskList {
         unsafe { 
             let mut tlsbase = kern_rsp - size_of::<TLSSegment>();
             let tls = tlsbase as *mut TLSSegment;
-            ::core::ptr::write(tls, TLSSegment {
-                user_rsp: (KERNEL_MAPPING.UserStack.end+1),
-                kern_rsp: tlsbase
-            });
+            ::core::ptr::write(tls, TLSSegment::new(tlsbase, KERNEL_MAPPING.UserStack.end+1, task.pid));
         }
         
         task.ctx.cr3 = task.cr3.as_ref().unwrap().pml4_frame.start_address();
         printk!(Debug, "init cr3 {:?} {}\n\r", task.cr3, task.ctx.cr3);
 
         self.entry(pid).or_insert(Arc::new(RwLock::new(task)));
-        self.next_id += 1;
+        Ok(pid)
     }
 }
 
@@ -382,10 +992,203 @@ impl DerefMut for TaskList {
 }
 
 static TASKS: Once<RwLock<TaskList>> = Once::new();
-pub static CURRENT_ID: AtomicIsize = AtomicIsize::new(0);
+
+/// nesting count of non-preemptible regions, sched won't switch when non-zero
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// mark the beginning of a region that should not be preempted, can be nested
+pub fn preempt_disable() {
+    PREEMPT_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn preempt_enable() {
+    let old = PREEMPT_COUNT.fetch_sub(1, Ordering::SeqCst);
+    assert!(old > 0, "preempt_enable: unbalanced with preempt_disable");
+}
+
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.load(Ordering::SeqCst) == 0
+}
+
+/// when off, timer does not call sched and tasks only switch at yield_now(),
+/// which makes bugs reproducible regardless of preemption timing
+static PREEMPTIVE: AtomicBool = AtomicBool::new(true);
+
+pub fn set_preemptive(on: bool) {
+    PREEMPTIVE.store(on, Ordering::SeqCst);
+    kprintln!("sched: {} mode", if on { "preemptive" } else { "cooperative" });
+}
+
+pub fn preemptive() -> bool {
+    PREEMPTIVE.load(Ordering::SeqCst)
+}
+
+/// give up cpu to next ready task, the only switch point in cooperative mode
+pub fn yield_now() {
+    unsafe {
+        let oflags = cpu::push_flags();
+        sched();
+        cpu::pop_flags(oflags);
+    }
+}
+
+/// in step mode, sched only switches once per step_once(), and dumps
+/// both contexts of each switch
+static STEP_MODE: AtomicBool = AtomicBool::new(false);
+/// dump every switch without stepping, kdbg `switches` toggles it
+static DUMP_SWITCHES: AtomicBool = AtomicBool::new(false);
+static STEP_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// toggle step mode, return if it's on now
+pub fn step_mode_toggle() -> bool {
+    let on = !STEP_MODE.load(Ordering::SeqCst);
+    STEP_PENDING.store(0, Ordering::SeqCst);
+    STEP_MODE.store(on, Ordering::SeqCst);
+    kprintln!("sched: step mode {}", if on { "on" } else { "off" });
+    on
+}
+
+/// toggle dumping of every context switch, return if it's on now
+pub fn dump_switches_toggle() -> bool {
+    let on = !DUMP_SWITCHES.load(Ordering::SeqCst);
+    DUMP_SWITCHES.store(on, Ordering::SeqCst);
+    kprintln!("sched: dump switches {}", if on { "on" } else { "off" });
+    on
+}
+
+/// print saved contexts of from and to side by side, fields that differ are
+/// marked with '*'. safe to call from irq context. called before switch_to,
+/// so from's column is still what was saved when it was last switched out.
+pub fn dump_switch(from: &Task, to: &Task) {
+    let (a, b) = (&from.ctx, &to.ctx);
+    let rows = [
+        ("rsp", a.rsp, b.rsp),
+        ("rbp", a.rbp, b.rbp),
+        ("cr3", a.cr3, b.cr3),
+        ("rflags", a.rflags, b.rflags),
+        ("rbx", a.rbx, b.rbx),
+        ("r12", a.r12, b.r12),
+        ("r13", a.r13, b.r13),
+        ("r14", a.r14, b.r14),
+        ("r15", a.r15, b.r15),
+    ];
+
+    kprintln!("sched: switch {} -> {} ({} stale)", from.pid, to.pid, from.pid);
+    for &(name, x, y) in rows.iter() {
+        kprintln!("{} {:<6} {:#018x}  {:#018x}", if x != y { '*' } else { ' ' }, name, x, y);
+    }
+}
+
+/// allow one more context switch in step mode
+pub fn step_once() {
+    if STEP_MODE.load(Ordering::SeqCst) {
+        STEP_PENDING.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// whether sched may switch at this tick, consumes one pending step
+fn step_allowed() -> bool {
+    if !STEP_MODE.load(Ordering::SeqCst) {
+        return true;
+    }
+
+    let mut pending = STEP_PENDING.load(Ordering::SeqCst);
+    while pending > 0 {
+        let old = STEP_PENDING.compare_and_swap(pending, pending - 1, Ordering::SeqCst);
+        if old == pending {
+            return true;
+        }
+        pending = old;
+    }
+    false
+}
+
+/// id of the task running on this cpu, 0 before tasking starts
+pub fn current_id() -> ProcId {
+    percpu::current_task()
+}
+
+/// map a zeroed page at addr if addr lies in a vma of current task but is
+/// not populated yet. shared by page fault handler and uaccess, so it never
+/// blocks: false as well when task list or current task is locked.
+pub fn populate_user_page(addr: usize) -> bool {
+    use ::kern::memory::paging::{ActivePML4Table, Page, WRITABLE};
+
+    if current_id() == 0 {
+        return false;
+    }
+
+    let tasks = match TaskList::try_get() {
+        Some(tasks) => tasks,
+        None => return false
+    };
+    let task = match tasks.current().and_then(|t| t.try_read()) {
+        Some(task) => task,
+        None => return false
+    };
+
+    let vma = [task.find_vma(addr), task.user_stack.as_ref(), task.code.as_ref()].iter()
+        .filter_map(|v| *v)
+        .find(|v| v.contains(addr))
+        .cloned();
+    let vma = match vma {
+        Some(vma) => vma,
+        None => return false
+    };
+
+    // addr is in task's own address space, so it is the active one
+    let page = Page::from_vaddress(addr);
+    let mut active = ActivePML4Table::new();
+    if active.translate(page.start_address()).is_some() {
+        return false;
+    }
+    // frame may still hold data of its previous owner, clear it before
+    // user can see it. read-only vmas get their final flags afterwards.
+    active.map(page, vma.flags | WRITABLE);
+    unsafe { ::core::ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE); }
+    if !vma.flags.contains(WRITABLE) {
+        active.protect(page, vma.flags);
+    }
+    ::kern::trace::record(::kern::trace::EventKind::DemandPage, addr, task.pid as usize);
+    printk!(Debug, "demand paging: map {:#x} for task {}\n\r", page.start_address(), task.pid);
+    true
+}
+
+fn set_current_id(pid: ProcId) {
+    percpu::set_current_task(pid);
+}
 
 fn init_tasks() -> RwLock<TaskList> { RwLock::new(TaskList::new()) }
 
+/// rename task pid, return false if no such task
+pub fn set_name(pid: ProcId, name: &str) -> bool {
+    let tasks = TaskList::get();
+    match tasks.get_task(pid) {
+        Some(task_lock) => {
+            task_lock.write().name = Some(name.to_string());
+            true
+        },
+        None => false
+    }
+}
+
+/// print a ps like table of all tasks
+pub fn list() {
+    let tasks = TaskList::get();
+    printk!(Normal, "{:>4} {:>4} {:<16} {}\n\r", "PID", "PPID", "NAME", "STATE");
+    for (pid, task_lock) in tasks.tasks.iter() {
+        // don't wait for a task being modified
+        match task_lock.try_read() {
+            Some(task) => {
+                let name = task.name.as_ref().map(|s| s.as_str()).unwrap_or("-");
+                // names longer than the column get truncated
+                printk!(Normal, "{:>4} {:>4} {:<16.16} {:?}\n\r", task.pid, task.ppid, name, task.state);
+            },
+            None => printk!(Normal, "{:>4} {:>4} {:<16} {}\n\r", pid, "?", "?", "busy")
+        }
+    }
+}
+
 pub fn init() {
     printk!(Info, "tasks init\n\r");
 
@@ -396,18 +1199,26 @@ pub fn init() {
             idle as usize,
             test_thread as usize,
             test_thread2 as usize,
+            ::kern::kdbg::kdbg_thread as usize,
         ];
         let names = [
             &"idle",
             &"kthread1",
             &"kthread2",
+            &"kdbg",
         ];
 
         let mut tasks = TaskList::get_mut();
         for (id, &rip) in rips.iter().enumerate() {
-            tasks.alloc_kernel_task(names[id], rip);
+            let pid = tasks.alloc_kernel_task(names[id], rip);
+            if rip == idle as usize {
+                tasks.set_priority(pid, MAX_PRIORITY).unwrap();
+            }
             //printk!(Info, "{:?}\n\r", task);
         }
+        if cfg!(feature = "test") {
+            tasks.alloc_kernel_task("stress", ::kern::selftest::sched_stress as usize);
+        }
 
         unsafe { cpu::pop_flags(oflags); }
     }
@@ -415,36 +1226,51 @@ pub fn init() {
 
     { 
         unsafe { x86_64::instructions::interrupts::disable(); }
+        let init_pid;
 
         {
             printk!(Debug, "load init from module\n\r");
 
-            let elf = unsafe {
-                let kernel_base = KERNEL_MAPPING.KernelMap.start;
-                let mut mm = MM.try().unwrap().lock();
-                let init_mod = mm.mbinfo.module_tags().next().unwrap();
-                assert!(init_mod.name() == "init");
-
-                let (init_start, init_end) = (
-                    init_mod.start_address() as usize + kernel_base,
-                    init_mod.end_address() as usize + kernel_base
-                );
-                let bytes = ::core::slice::from_raw_parts(init_start as *const u8, 
-                    (init_end - init_start) as usize);
-                Elf64::from(bytes)
+            // a boot config without labels still gets its first module run
+            let init_mod = match ::kern::modules::find("init").or(::kern::modules::all().first()) {
+                Some(init_mod) => init_mod,
+                None => {
+                    printk!(Warn, "no init module, stay in kernel\n\r");
+                    idle();
+                    unreachable!();
+                }
             };
-            printk!(Debug, "{:?}\n\r", elf.header);
-
             let mut tasks = TaskList::get_mut();
-            tasks.load_task(&"init", &elf, 1);
+            // task is named after the program it runs. it is the first
+            // task to enter user space, whatever pid it gets
+            let loaded = tasks.alloc_elf_task(init_mod.label, 1, init_mod.bytes());
+            init_pid = match loaded {
+                Ok(pid) => {
+                    tasks.init = Some(pid);
+                    pid
+                },
+                Err(e) => {
+                    printk!(Warn, "init module is not a loadable elf: {:?}, stay in kernel\n\r", e);
+                    drop(tasks);
+                    idle();
+                    unreachable!();
+                }
+            };
         }
 
+        if cfg!(feature = "test") { list(); }
+
         let init: *mut Task;
         {
             let tasks = TaskList::get();
-            let task_lock = tasks.get_task(4).expect("task 4");
+            // init runs right away instead of waiting in the queue
+            let task_lock = tasks.get_task(init_pid).expect("init task is gone").clone();
             let mut task = task_lock.write();
-            CURRENT_ID.store(task.pid, Ordering::SeqCst);
+            assert!(task.state == TaskState::Created, "init task {} is {:?}, not Created",
+                    init_pid, task.state);
+            task.set_state(TaskState::Ready);
+            task.set_state(TaskState::Running);
+            set_current_id(task.pid);
             init = task.deref_mut() as *mut Task;
         }
 
@@ -456,6 +1282,152 @@ pub fn init() {
     panic!("task done");
 }
 
+/// terminate current task, it stays Zombie until reaped
+pub fn exit(code: isize) -> ! {
+    unsafe { x86_64::instructions::interrupts::disable(); }
+    {
+        let mut tasks = TaskList::get_mut();
+        let task_lock = tasks.current().expect("exit: no current task").clone();
+        let mut task = task_lock.write();
+        task.exit_code = code;
+        ::kern::shm::detach_all(&mut task);
+        ::kern::shm::release(task.pid);
+        ::kern::fbdev::release(&mut task);
+        task.set_state(TaskState::Zombie);
+
+        // children go to init, parent sleeping in waitpid for us goes back
+        // to ready queue. a busy lock can not be waited for with IF off, so
+        // that part is queued and finished by sched.
+        tasks.notify_exit(task.pid, task.ppid);
+    }
+
+    // sched never picks a Zombie, so we never come back after switching
+    // away. switch right now, which also works in cooperative mode; if
+    // nothing else is ready (idle is busy), wait for next tick.
+    loop {
+        unsafe {
+            sched();
+            asm!("sti; hlt; cli":::: "volatile");
+        }
+    }
+}
+
+/// wait until child pid exits, reap it and return its exit code.
+/// None if pid is not a child of current task, or a signal came first.
+pub fn waitpid(pid: ProcId) -> Option<isize> {
+    loop {
+        let oflags = unsafe { cpu::push_flags() };
+        if ::kern::signal::interrupted() {
+            unsafe { cpu::pop_flags(oflags); }
+            return None;
+        }
+        // check and fall asleep with IF off, so the child can not exit in
+        // between and miss us
+        let me = {
+            let mut tasks = TaskList::get_mut();
+            let me = tasks.current().expect("waitpid: no current task").clone();
+            let child_state = match tasks.get_task(pid) {
+                Some(child) => {
+                    let child = child.read();
+                    if child.ppid != current_id() {
+                        None
+                    } else {
+                        Some(child.state)
+                    }
+                },
+                None => None
+            };
+
+            match child_state {
+                None => {
+                    unsafe { cpu::pop_flags(oflags); }
+                    return None;
+                },
+                Some(TaskState::Zombie) => {
+                    let code = tasks.reap(pid);
+                    unsafe { cpu::pop_flags(oflags); }
+                    return code;
+                },
+                Some(_) => {
+                    let mut task = me.write();
+                    task.waiting_on = Some(pid);
+                    task.set_state(TaskState::Sleep);
+                }
+            }
+            me
+        };
+
+        unsafe { block(&me); }
+        me.write().waiting_on = None;
+        unsafe { cpu::pop_flags(oflags); }
+    }
+}
+
+/// switch away from current task me, which has just been put to Sleep with
+/// IF off. if nothing else is ready, sched keeps us running while asleep:
+/// run again and wait for next irq instead, caller re-checks its condition.
+unsafe fn block(me: &Arc<RwLock<Task>>) {
+    sched();
+
+    let mut task = me.write();
+    if task.state == TaskState::Sleep {
+        task.set_state(TaskState::Ready);
+        task.set_state(TaskState::Running);
+        drop(task);
+        asm!("sti; hlt; cli":::: "volatile");
+    }
+}
+
+/// sleep until poll returns Some. poll runs with IF off, so whatever it
+/// checks can not change before current task is asleep; it should record
+/// current_id() somewhere an irq will TaskList::wake it from.
+/// None if a signal is pending for current task.
+pub fn wait_for<T, F>(mut poll: F) -> Option<T> where F: FnMut() -> Option<T> {
+    loop {
+        let oflags = unsafe { cpu::push_flags() };
+        if ::kern::signal::interrupted() {
+            unsafe { cpu::pop_flags(oflags); }
+            return None;
+        }
+        if let Some(v) = poll() {
+            unsafe { cpu::pop_flags(oflags); }
+            return Some(v);
+        }
+
+        let me = TaskList::get().current().expect("wait_for: no current task").clone();
+        me.write().set_state(TaskState::Sleep);
+        unsafe { block(&me); }
+        unsafe { cpu::pop_flags(oflags); }
+    }
+}
+
+/// put current task to sleep for at least ticks timer ticks, or until a
+/// signal is pending for it
+pub fn sleep_ticks(ticks: usize) {
+    use ::kern::interrupts::timer;
+
+    // huge ticks from sys_nanosleep just means forever
+    let wake_at = timer::ticks().saturating_add(ticks);
+    loop {
+        let oflags = unsafe { cpu::push_flags() };
+        // with IF off no tick can pass between the check and falling asleep
+        if timer::ticks() >= wake_at || ::kern::signal::interrupted() {
+            unsafe { cpu::pop_flags(oflags); }
+            return;
+        }
+
+        let me = TaskList::get().current().expect("sleep: no current task").clone();
+        {
+            let mut task = me.write();
+            task.wake_at = Some(wake_at);
+            task.set_state(TaskState::Sleep);
+        }
+        unsafe { block(&me); }
+        me.write().wake_at = None;
+        unsafe { cpu::pop_flags(oflags); }
+    }
+}
+
 pub fn idle() {
     loop {
         unsafe { asm!("sti; hlt":::: "volatile"); }
@@ -471,9 +1443,7 @@ pub fn test_thread2() {
     };
 
     loop {
-        Console::with(&tty1, 21, 0, || {
-            printk!(Debug, "kernel thread 2: {}\n\r", count);
-        });
+        kprintln!("kernel thread 2: {}", count);
         count += 1;
         busy_wait();
     }
@@ -488,50 +1458,82 @@ pub fn test_thread() {
     };
 
     loop {
-        Console::with(&tty1, 20, 0, || {
-            printk!(Debug, "kernel thread 1: {}\n\r", count);
-        });
+        let ticks = TaskList::get().current().map_or(0, |t| t.read().ticks);
+        kprintln!("kernel thread 1: {} ({} ticks)", count, ticks);
         count += 1;
         busy_wait();
     }
 }
 
 
+/// switch kernel context from current to next, return when current gets
+/// scheduled again. interrupts must be off: next's rflags is restored last.
+pub unsafe fn switch_to(current: &mut Task, next: &mut Task) {
+    use ::kern::arch::cpu::flags;
+
+    assert!(!flags::flags().contains(flags::Flags::IF), "switch_to: IF should be disabled");
+    assert!(current as *const Task != next as *const Task, "switch_to: switch task {} to itself", next.pid);
+    context_switch(&mut current.ctx, &next.ctx);
+}
+
+/// save callee-saved registers, rsp and rflags of caller into prev and load
+/// them from next, then return on next's stack (sysv: rdi = prev, rsi = next).
+/// a fresh context has the address to start at (start_task) at top of its
+/// stack. rflags goes last since popfq may enable interrupts.
+///   Context offsets: 0 rflags, 8 cr3, 16 rbp, 24 rbx, 32 rsp, 40 r12, 48 r13, 56 r14, 64 r15
 #[inline(never)]
 #[naked]
-pub unsafe extern "C" fn switch_to(current: &mut Task, next: &mut Task) {
-    // save context
-    asm!("pushfq; popq $0" : "=r"(current.ctx.rflags) ::"memory": "volatile");
-    asm!("movq %rbp, $0"   : "=r"(current.ctx.rbp) ::"memory": "volatile");
-    asm!("movq %rbx, $0"   : "=r"(current.ctx.rbx) ::"memory": "volatile");
-    asm!("movq %r12, $0"   : "=r"(current.ctx.r12) ::"memory": "volatile");
-    asm!("movq %r13, $0"   : "=r"(current.ctx.r13) ::"memory": "volatile");
-    asm!("movq %r14, $0"   : "=r"(current.ctx.r14) ::"memory": "volatile");
-    asm!("movq %r15, $0"   : "=r"(current.ctx.r15) ::"memory": "volatile");
-
-    asm!("movq %rsp, $0"   : "=r"(current.ctx.rsp) ::"memory": "volatile");
-
-    // load context
-    asm!("movq $0, %rbx"  :: "r"(next.ctx.rbx) :"memory": "volatile");
-    asm!("movq $0, %r12"  :: "r"(next.ctx.r12) :"memory": "volatile");
-    asm!("movq $0, %r13"  :: "r"(next.ctx.r13) :"memory": "volatile");
-    asm!("movq $0, %r14"  :: "r"(next.ctx.r14) :"memory": "volatile");
-    asm!("movq $0, %r15"  :: "r"(next.ctx.r15) :"memory": "volatile");
-
-    asm!("movq $0, %rsp"  :: "r"(next.ctx.rsp) :"memory": "volatile");
-    
-    //CAUTION: popfq causes IF enabled
-    //NOTE: rbp is used by switch_to, to override rbp at the end
+unsafe extern "C" fn context_switch(prev: *mut Context, next: *const Context) {
     asm!("
-         pushq $0
-         movq $1, %rbp
+         pushfq
+         popq (%rdi)
+         movq %rbp, 16(%rdi)
+         movq %rbx, 24(%rdi)
+         movq %rsp, 32(%rdi)
+         movq %r12, 40(%rdi)
+         movq %r13, 48(%rdi)
+         movq %r14, 56(%rdi)
+         movq %r15, 64(%rdi)
+
+         movq 16(%rsi), %rbp
+         movq 24(%rsi), %rbx
+         movq 32(%rsi), %rsp
+         movq 40(%rsi), %r12
+         movq 48(%rsi), %r13
+         movq 56(%rsi), %r14
+         movq 64(%rsi), %r15
+         pushq (%rsi)
          popfq
+         retq
          "
-         :
-         :"r"(next.ctx.rflags),
-          "r"(next.ctx.rbp)
-         :"memory"
-         :"volatile");
+         ::: "memory" : "volatile");
+    ::core::intrinsics::unreachable()
+}
+
+/// read back the frame a new kernel thread starts from: context_switch
+/// returns into start_task, whose iretq pops an exception frame ending
+/// right below TLSSegment. offsets written by hand that drift from those
+/// layouts are caught here instead of faulting on first schedule.
+fn check_initial_frame(task: &Task) {
+    use core::mem::size_of;
+
+    let top = task.kern_stack.as_ref().map(|st| st.top()).expect("kernel thread without stack");
+    let tlsbase = top - size_of::<TLSSegment>();
+    let pid = task.pid;
+
+    let tls_ok = unsafe { TLSSegment::at_stack_top(top).intact() };
+    assert!(tls_ok, "task {}: tls overwritten by initial frame", pid);
+    assert_eq!(task.ctx.rsp + size_of::<usize>() + size_of::<idt::ExceptionStackFrame>(), tlsbase,
+               "task {}: initial frame does not end at tls", pid);
+
+    let ret = unsafe { *(task.ctx.rsp as *const usize) };
+    let frame = unsafe { &*((task.ctx.rsp + size_of::<usize>()) as *const idt::ExceptionStackFrame) };
+    assert_eq!(ret, start_task as usize, "task {}: context_switch does not return to start_task", pid);
+    assert_eq!(frame.rip as usize, task.exec_entry, "task {}: bad initial rip", pid);
+    assert_eq!(frame.cs, interrupts::KERN_CS_SEL.0 as u64, "task {}: bad initial cs", pid);
+    assert_eq!(frame.old_ss, interrupts::KERN_DS_SEL.0 as u64, "task {}: bad initial ss", pid);
+    assert!(frame.rflags & 0x2 != 0, "task {}: reserved bit 1 of initial rflags is clear", pid);
+    assert_eq!(frame.old_rsp as usize, tlsbase, "task {}: bad initial rsp", pid);
 }
 
 #[inline(never)]
@@ -545,6 +1547,11 @@ unsafe fn ret_to_userspace(init: &mut Task) -> ! {
     use ::kern::interrupts::{self, idt};
     use ::kern::syscall;
 
+    assert_eq!(init.kind, TaskKind::User, "ret_to_userspace: task {} is a kernel thread", init.pid);
+    if let Err(e) = init.check_kind() {
+        panic!("ret_to_userspace: task {}: {}", init.pid, e);
+    }
+
     let frame = idt::ExceptionStackFrame {
         rip: init.exec_entry as u64,
         cs: interrupts::USER_CS_SEL.0 as u64,
@@ -554,15 +1561,8 @@ unsafe fn ret_to_userspace(init: &mut Task) -> ! {
     };
 
     {
-        use x86_64::registers::msr;
-
-        let tlsbase = init.kern_stack.as_ref().map(|st| st.top()).unwrap()
-            - ::core::mem::size_of::<TLSSegment>();
-        let tls = &*(tlsbase as *const TLSSegment);
-        if tls.kern_rsp != 0 {
-            msr::wrmsr(msr::IA32_GS_BASE, tls.kern_rsp as u64);
-        }
-
+        let tls = TLSSegment::at_stack_top(init.kern_stack.as_ref().map(|st| st.top()).unwrap());
+        percpu::set_kern_rsp(tls.kern_rsp);
         interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(tls.kern_rsp);
 
         // alternate way to write rsp0
@@ -574,6 +1574,15 @@ unsafe fn ret_to_userspace(init: &mut Task) -> ! {
 
 
     paging::switch(init.cr3.clone().unwrap());
+    if !interrupts::sysret_target_ok(frame.rip as usize, frame.old_rsp as usize) {
+        printk!(Warn, "task {}: entry {:#x} is not a user address, killed\n\r", init.pid, frame.rip);
+        ::kern::signal::deliver(::kern::signal::SIGSEGV);
+    }
+
+    // IF is off from here to sysret
+    if let Some(sig) = ::kern::signal::take_deliverable(init) {
+        ::kern::signal::deliver(sig);
+    }
 
 
     asm!("
@@ -598,63 +1607,357 @@ pub unsafe fn sched() {
     let oflags = flags::flags();
     assert!(!oflags.contains(flags::Flags::IF), "sched: should disable IF\n");
 
-    let id = CURRENT_ID.load(Ordering::SeqCst);
+    let id = current_id();
     if id == 0 { return  }
+    if !preemptible() { return }
+    if !step_allowed() { return }
 
     let nid;
     let current: *mut Task;
     let mut next: *mut Task = 0 as *mut Task;
 
     {
-        let tasks = TaskList::get();
-        nid = if id + 1 >= tasks.next_id as ProcId { 1 } else { id + 1 };
-        CURRENT_ID.store(nid, Ordering::Release);
-
-        assert_ne!(id, nid, "sched: id should not be equal to nid");
+        let mut tasks = match TaskList::try_get_mut() {
+            Some(tasks) => tasks,
+            None => return
+        };
+        tasks.flush_deferred();
 
         {
             let current_lock = tasks.get_task(id as ProcId).expect("sched: get current task error");
-            let mut guard = current_lock.try_read().expect("sched: current lock failed");
-            current = guard.deref() as *const Task as *mut Task;
-            assert!((*current).pid == id);
+            // current task may be preempted while holding its own lock (e.g inside a 
+            // syscall), skip this tick and retry at next one.
+            match current_lock.try_read() {
+                Some(guard) => {
+                    current = guard.deref() as *const Task as *mut Task;
+                    assert!((*current).pid == id);
+                },
+                None => return
+            }
         }
 
-        {
-            let next_lock = tasks.get_task(nid as ProcId).expect("sched: get next task error");
+        // highest priority wins, queue order breaks ties so equal
+        // priorities round-robin. tasks which are not Ready anymore are
+        // dropped from queue lazily.
+        loop {
+            let (prio, idx) = match tasks.best_ready() {
+                Some(best) => best,
+                None => return // nothing else to run, keep current
+            };
+            if (*current).state == TaskState::Running && (*current).priority < prio {
+                return;
+            }
+            let pid = tasks.ready.remove(idx).unwrap();
+            assert_ne!(id, pid, "sched: running task should not be in ready queue");
+
+            let next_lock = match tasks.get_task(pid) {
+                Some(next_lock) => next_lock.clone(),
+                None => continue
+            };
             match next_lock.try_write() {
                 Some(mut guard) => {
+                    if guard.state != TaskState::Ready {
+                        continue;
+                    }
                     next = guard.deref_mut() as *mut Task;
-                    assert!((*next).pid == nid);
+                    nid = pid;
+                    break;
                 },
                 None => {
-                    printk!(Critical, "sched: next({}) lock failed\n\r", nid);
+                    kprintln!("sched: next({}) lock failed", pid);
+                    tasks.ready.push_front(pid);
+                    return;
                 }
             };
         }
+
+        if (*current).state == TaskState::Running {
+            tasks.ready.push_back(id);
+        }
+        // only commit the switch after both tasks are reachable
+        set_current_id(nid);
+        ::kern::trace::switch(id, nid);
         //now tasklist lock released
     }
 
-    //printk!(Debug, "switch {} {:#x} to {} {:#x}\n", id, (&*current).ctx.rsp, nid, (&*next).ctx.rsp);
-    //printk!(Debug, "switch {:?} \n-> {:?}\n", (&*current).ctx, (&*next).ctx);
-
     if next as usize != 0 {
-        use x86_64::registers::msr;
-        
         let next = &mut *next;
-        if next.user_stack.is_some() { // which means it's a user task
-            let tlsbase = next.kern_stack.as_ref().map(|st| st.top()).unwrap()
-                - ::core::mem::size_of::<TLSSegment>();
-            let tls = &*(tlsbase as *const TLSSegment);
-            if tls.kern_rsp != 0 {
-                msr::wrmsr(msr::IA32_GS_BASE, tls.kern_rsp as u64);
+        // every task owns a tls block, so tls:: accessors always see the
+        // running task, kernel threads included
+        if let Some(st) = next.kern_stack.as_ref() {
+            assert!(stack_canary_ok(st), "sched: kernel stack of task {} overflowed", nid);
+            let tls = TLSSegment::at_stack_top(st.top());
+            assert!(tls.intact(), "sched: tls of task {} is corrupted", nid);
+            debug_assert_eq!(tls.pid, nid);
+            percpu::set_kern_rsp(tls.kern_rsp);
+        }
+        if let Err(e) = next.check_kind() {
+            panic!("sched: task {} is not a proper {:?} task: {}", nid, next.kind, e);
+        }
+        match next.kind {
+            TaskKind::User => {
+                interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(percpu::this().unwrap().kern_rsp);
+
+                // compare with hardware, a kernel thread may run in the address
+                // space of whichever user task was there before it. kernel threads
+                // never reload cr3, and when a reload is needed kernel image is
+                // GLOBAL so only user entries get flushed.
+                if paging::current_pml4_frame() != next.ctx.cr3 {
+                    paging::switch(next.cr3.clone().unwrap());
+                }
+            },
+            TaskKind::Kernel => {}
+        }
+        if cfg!(debug_assertions) {
+            let cr3 = paging::current_pml4_frame();
+            if (*current).kind == TaskKind::User && cr3 != (*current).ctx.cr3 {
+                kprintln!("sched: task {} runs with cr3 {:#x}, expects {:#x}",
+                    (*current).pid, cr3, (*current).ctx.cr3);
             }
-            interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(tls.kern_rsp);
-
-            if (*current).ctx.cr3 != next.ctx.cr3 {
-                paging::switch(next.cr3.clone().unwrap());
+            if let Err(e) = next.ctx.validate(next.kern_stack.as_ref()) {
+                printk!(Critical, "sched: task {} has bad context: {}\n\r", next.pid, e);
+                next.ctx.dump();
+                panic!("sched: invalid context");
             }
         }
-        switch_to(&mut *current, &mut *next); 
+        if STEP_MODE.load(Ordering::SeqCst) || DUMP_SWITCHES.load(Ordering::SeqCst) {
+            dump_switch(&*current, next);
+        }
+
+        // an exiting task switches away as Zombie and stays so
+        if (*current).state == TaskState::Running {
+            (*current).set_state(TaskState::Ready);
+        }
+        next.set_state(TaskState::Running);
+        switch_to(&mut *current, &mut *next);
     }
 }
 
+static mut SWITCH_TEST_A: Context = Context::new();
+static mut SWITCH_TEST_B: Context = Context::new();
+static SWITCH_TEST_B_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// B side of test_context_switch, entered by retq of context_switch
+extern "C" fn switch_test_b() -> ! {
+    unsafe {
+        asm!("movq $$0, %r12; movq $$0, %r13; movq $$0, %r14; movq $$0, %r15"
+             ::: "r12", "r13", "r14", "r15" : "volatile");
+        SWITCH_TEST_B_RUNS.fetch_add(1, Ordering::SeqCst);
+        context_switch(&mut SWITCH_TEST_B, &SWITCH_TEST_A);
+    }
+    unreachable!("switch_test_b resumed");
+}
+
+/// switch A -> B -> A on a private stack, callee-saved registers of A must
+/// come back untouched although B scribbles on them
+pub fn test_context_switch() {
+    const PATTERNS: [usize; 6] = [
+        0x1111_2222_3333_4444, 0x5555_6666_7777_8888, 0x0123_4567_89ab_cdef,
+        0x1357_9bdf_2468_ace0, 0xfeed_face_dead_beef, 0x0bad_cafe_f00d_d00d,
+    ];
+
+    let stack = vec![0usize; 512];
+    #[repr(C)]
+    struct Args { prev: usize, next: usize, func: usize, out: usize }
+    let mut out = [0usize; 6];
+
+    unsafe {
+        let oflags = cpu::push_flags();
+        let top = (stack.as_ptr() as usize + stack.len() * 8) & !0xf;
+        let entry = (top - 16) as *mut usize;
+        *entry = switch_test_b as usize;
+        SWITCH_TEST_B = Context::new();
+        SWITCH_TEST_B.rsp = entry as usize;
+        SWITCH_TEST_B.rflags = 0x2;
+
+        let args = Args {
+            prev: &mut SWITCH_TEST_A as *mut _ as usize,
+            next: &SWITCH_TEST_B as *const _ as usize,
+            func: context_switch as usize,
+            out: out.as_mut_ptr() as usize,
+        };
+        let before = SWITCH_TEST_B_RUNS.load(Ordering::SeqCst);
+
+        // llvm reserves rbx and rbp, save them by hand
+        asm!("
+             pushq %rbx
+             pushq %rbp
+             pushq $0
+             movq 0($0), %rdi
+             movq 8($0), %rsi
+             movq 16($0), %rax
+             movabsq $$0x1111222233334444, %rbx
+             movabsq $$0x5555666677778888, %rbp
+             movabsq $$0x0123456789abcdef, %r12
+             movabsq $$0x13579bdf2468ace0, %r13
+             movabsq $$0xfeedfacedeadbeef, %r14
+             movabsq $$0x0badcafef00dd00d, %r15
+             call *%rax
+             popq %rcx
+             movq 24(%rcx), %rcx
+             movq %rbx, 0(%rcx)
+             movq %rbp, 8(%rcx)
+             movq %r12, 16(%rcx)
+             movq %r13, 24(%rcx)
+             movq %r14, 32(%rcx)
+             movq %r15, 40(%rcx)
+             popq %rbp
+             popq %rbx
+             "
+             :: "r"(&args)
+             : "rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11",
+               "r12", "r13", "r14", "r15", "memory"
+             : "volatile");
+
+        test_assert_eq!(SWITCH_TEST_B_RUNS.load(Ordering::SeqCst), before + 1);
+        cpu::pop_flags(oflags);
+    }
+
+    for (i, (&got, &want)) in out.iter().zip(PATTERNS.iter()).enumerate() {
+        test_assert!(got == want, "register {} is {:#x} after switch, expects {:#x}", i, got, want);
+    }
+    drop(stack);
+}
+
+pub fn test_vma_bounds() {
+    use ::kern::memory::paging::PageRange;
+
+    let flags = paging::USER | paging::WRITABLE;
+    test_assert_eq!(PageRange::new(0x1000, 0x1000).count(), 0);
+    test_assert_eq!(PageRange::new(0x1001, 0x1001).count(), 0);
+    test_assert_eq!(PageRange::new(0x1fff, 0x2001).count(), 2);
+    test_assert!(PageRange::from_size(!0 - PAGE_SIZE + 1, PAGE_SIZE).is_none());
+    test_assert_eq!(PageRange::from_size(0x2000, PAGE_SIZE).map(|r| r.count()), Some(1));
+
+    let mut task = Task::empty();
+    let top = KERNEL_MAPPING.Invalid.start;
+    // last page of user space is fine, crossing into the hole is not
+    test_assert!(task.add_vma(VirtualMemoryArea::new(top - 2 * PAGE_SIZE, 4 * PAGE_SIZE, flags)).is_err());
+    test_assert!(task.add_vma(VirtualMemoryArea::new(top, PAGE_SIZE, flags)).is_err());
+    test_assert!(task.add_vma(VirtualMemoryArea::new(top - PAGE_SIZE, PAGE_SIZE, flags)).is_ok());
+
+    // built by hand, so new() can't catch the wrap
+    let wrapping = VirtualMemoryArea { start: !0 - PAGE_SIZE + 1, size: 2 * PAGE_SIZE, mapped: false, flags: flags };
+    test_assert!(wrapping.checked_end().is_none());
+    test_assert!(task.add_vma(wrapping).is_err());
+    test_assert_eq!(task.vmas.len(), 1);
+}
+
+/// canary sits at the lowest word, the deepest point a stack can reach
+/// before its guard page
+pub fn test_priority_permission() {
+    use ::kern::syscall::EPERM;
+
+    let mut me = Task::empty();
+    me.pid = 5;
+    me.kind = TaskKind::User;
+    me.priority = DEFAULT_PRIORITY;
+    let mut child = me.clone();
+    child.pid = 6;
+    child.ppid = 5;
+    let mut other = me.clone();
+    other.pid = 7;
+    other.ppid = 1;
+    let mut kthread = Task::empty();
+    kthread.pid = 2;
+    kthread.priority = DEFAULT_PRIORITY;
+    let mut idle = Task::empty();
+    idle.priority = MAX_PRIORITY;
+
+    test_assert_eq!(may_set_priority(5, &me, DEFAULT_PRIORITY + 1), Ok(()));
+    test_assert_eq!(may_set_priority(5, &me, DEFAULT_PRIORITY), Ok(()));
+    test_assert_eq!(may_set_priority(5, &child, DEFAULT_PRIORITY + 1), Ok(()));
+    test_assert_eq!(may_set_priority(5, &me, 0), Err(-EPERM));
+    test_assert_eq!(may_set_priority(5, &other, DEFAULT_PRIORITY + 1), Err(-EPERM));
+    test_assert_eq!(may_set_priority(5, &kthread, DEFAULT_PRIORITY + 1), Err(-EPERM));
+    test_assert_eq!(may_set_priority(5, &idle, 0), Err(-EPERM));
+}
+
+pub fn test_exit_notify() {
+    let mut tl = TaskList::new();
+    tl.init = Some(1);
+    let add = |tl: &mut TaskList, pid: ProcId, ppid: ProcId, state: TaskState| {
+        let mut task = Task::empty();
+        task.pid = pid;
+        task.ppid = ppid;
+        task.state = state;
+        tl.tasks.insert(pid, Arc::new(RwLock::new(task)));
+    };
+    add(&mut tl, 1, 0, TaskState::Sleep);
+    add(&mut tl, 2, 1, TaskState::Sleep);
+    add(&mut tl, 3, 2, TaskState::Running);
+    add(&mut tl, 4, 3, TaskState::Zombie);
+    add(&mut tl, 5, 3, TaskState::Ready);
+    add(&mut tl, 6, 2, TaskState::Ready);
+    tl.get_task(2).unwrap().write().waiting_on = Some(3);
+
+    // 3 exits while its parent and one child are busy
+    let exiting = tl.get_task(3).unwrap().clone();
+    let parent = tl.get_task(2).unwrap().clone();
+    let busy_child = tl.get_task(5).unwrap().clone();
+    {
+        let _me = exiting.write();
+        let _p = parent.write();
+        let _c = busy_child.write();
+        tl.notify_exit(3, 2);
+        // reparent of 2 and 5, wake of 2
+        test_assert_eq!(tl.deferred.len(), 3);
+        tl.flush_deferred();
+        test_assert_eq!(tl.deferred.len(), 3);
+    }
+    test_assert_eq!(tl.get_task(4).unwrap().read().ppid, 1);
+    test_assert_eq!(tl.get_task(5).unwrap().read().ppid, 3);
+    test_assert_eq!(tl.get_task(6).unwrap().read().ppid, 2);
+    test_assert!(tl.ready.is_empty());
+
+    // queued part is done once the locks are free
+    tl.flush_deferred();
+    test_assert_eq!(tl.deferred.len(), 0);
+    test_assert_eq!(tl.get_task(5).unwrap().read().ppid, 1);
+    test_assert_eq!(tl.get_task(2).unwrap().read().state, TaskState::Ready);
+    test_assert_eq!(tl.ready.iter().cloned().collect::<Vec<_>>(), vec![2]);
+    // init only waits for what it was told about, it stays asleep
+    test_assert_eq!(tl.get_task(1).unwrap().read().state, TaskState::Sleep);
+}
+
+/// a space created before the stack must miss its guard page too
+pub fn test_stack_guard_shared() {
+    use ::kern::memory::paging::ActivePML4Table;
+
+    let mut space = {
+        let mm = MM.try().unwrap().lock();
+        paging::create_address_space(mm.mbinfo)
+    };
+    let st = alloc_kern_stack();
+    let guard = st.bottom() - PAGE_SIZE;
+
+    let mut active = ActivePML4Table::new();
+    let mut temp_page = TemporaryPage::new(paging::Page::from_vaddress(0xfffff_cafe_beef_000));
+    let mut mapped = (true, false);
+    active.with(&mut space, &mut temp_page, |mapper| {
+        mapped = (mapper.translate(guard).is_some(), mapper.translate(st.bottom()).is_some());
+    });
+    test_assert_eq!(mapped, (false, true));
+
+    // giving it back remaps the guard in every space as well
+    free_kern_stack(st);
+    active.with(&mut space, &mut temp_page, |mapper| {
+        mapped.0 = mapper.translate(guard).is_some();
+    });
+    test_assert!(mapped.0);
+
+    space.destroy(&mut active);
+}
+
+pub fn test_stack_canary() {
+    let st = alloc_kern_stack();
+    test_assert!(stack_canary_ok(&st));
+    unsafe {
+        // top of stack is not where overflows end up
+        *((st.top() - ::core::mem::size_of::<usize>()) as *mut usize) = 0;
+        test_assert!(stack_canary_ok(&st));
+        *(st.bottom() as *mut usize) = 0;
+        test_assert!(!stack_canary_ok(&st));
+    }
+    free_kern_stack(st);
+}
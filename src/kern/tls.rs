@@ -0,0 +1,141 @@
+//! per task block at the top of kernel stack. PerCpu.kern_rsp ([gs:8])
+//! points to the one of running task, so it is always two loads away:
+//! read [gs:8], then the field at its offset. syscalls start their kernel
+//! stack right below the block.
+//!
+//! offsets are shared with context.asm, keep TLS_* in sync with TLSSegment.
+
+use ::kern::percpu;
+use ::kern::task::ProcId;
+
+/// user rsp at last syscall entry, initial user stack before the first one
+pub const TLS_USER_RSP: usize = 0;
+/// address of this block, also the initial kernel rsp of syscalls
+pub const TLS_KERN_RSP: usize = 8;
+/// last error of failed syscall
+pub const TLS_ERRNO: usize = 16;
+/// pid of the owning task
+pub const TLS_PID: usize = 24;
+/// free for use by syscall and irq paths, not preserved across a switch
+pub const TLS_SCRATCH: usize = 32;
+/// overwritten by stray writes into the block, e.g. a frame built over it.
+/// stack overflows grow away from it, see task::stack_canary_ok for those.
+pub const TLS_CANARY: usize = 40;
+pub const TLS_SIZE: usize = 48;
+
+pub const CANARY: usize = 0x5afe_57ac_5afe_57ac;
+
+#[derive(Debug, Clone)]
+#[repr(C, packed)]
+pub struct TLSSegment {
+    pub user_rsp: usize,
+    pub kern_rsp: usize,
+    pub errno: isize,
+    pub pid: ProcId,
+    pub scratch: usize,
+    pub canary: usize,
+}
+
+impl TLSSegment {
+    pub const fn empty() -> TLSSegment {
+        TLSSegment {
+            user_rsp: 0,
+            kern_rsp: 0,
+            errno: 0,
+            pid: 0,
+            scratch: 0,
+            canary: CANARY
+        }
+    }
+
+    pub const fn new(kern_rsp: usize, user_rsp: usize, pid: ProcId) -> TLSSegment {
+        TLSSegment {
+            user_rsp,
+            kern_rsp,
+            errno: 0,
+            pid,
+            scratch: 0,
+            canary: CANARY
+        }
+    }
+
+    /// block at the top of kernel stack whose top is kern_top
+    pub unsafe fn at_stack_top(kern_top: usize) -> &'static mut TLSSegment {
+        &mut *((kern_top - TLS_SIZE) as *mut TLSSegment)
+    }
+
+    pub fn intact(&self) -> bool {
+        self.canary == CANARY
+    }
+}
+
+fn installed() -> bool {
+    percpu::this().map(|cpu| cpu.kern_rsp != 0).unwrap_or(false)
+}
+
+unsafe fn load(off: usize) -> usize {
+    let v: usize;
+    asm!("movq %gs:8, $0
+          movq ($0, $1), $0"
+         : "=&r"(v) : "r"(off) : "memory" : "volatile");
+    v
+}
+
+unsafe fn store(off: usize, v: usize) {
+    let base: usize;
+    asm!("movq %gs:8, $0
+          movq $2, ($0, $1)"
+         : "=&r"(base) : "r"(off), "r"(v) : "memory" : "volatile");
+}
+
+/// last syscall error of running task, 0 before tasking starts
+pub fn errno() -> isize {
+    if !installed() { return 0; }
+    unsafe { load(TLS_ERRNO) as isize }
+}
+
+pub fn set_errno(errno: isize) {
+    assert!(installed(), "tls: no task installed");
+    unsafe { store(TLS_ERRNO, errno as usize); }
+}
+
+/// pid recorded in the block, must agree with percpu::current_task()
+pub fn pid() -> ProcId {
+    if !installed() { return 0; }
+    unsafe { load(TLS_PID) as ProcId }
+}
+
+pub fn user_rsp() -> usize {
+    if !installed() { return 0; }
+    unsafe { load(TLS_USER_RSP) }
+}
+
+pub fn scratch() -> usize {
+    assert!(installed(), "tls: no task installed");
+    unsafe { load(TLS_SCRATCH) }
+}
+
+pub fn set_scratch(v: usize) {
+    assert!(installed(), "tls: no task installed");
+    unsafe { store(TLS_SCRATCH, v); }
+}
+
+/// false if the block of running task has been overwritten
+pub fn canary_ok() -> bool {
+    !installed() || unsafe { load(TLS_CANARY) == CANARY }
+}
+
+pub fn test_tls_layout() {
+    use core::mem::size_of;
+
+    let tls = TLSSegment::new(0x1000, 0x2000, 7);
+    let base = &tls as *const _ as usize;
+    test_assert_eq!(size_of::<TLSSegment>(), TLS_SIZE);
+    test_assert_eq!(&tls.user_rsp as *const _ as usize - base, TLS_USER_RSP);
+    test_assert_eq!(&tls.kern_rsp as *const _ as usize - base, TLS_KERN_RSP);
+    test_assert_eq!(&tls.errno as *const _ as usize - base, TLS_ERRNO);
+    test_assert_eq!(&tls.pid as *const _ as usize - base, TLS_PID);
+    test_assert_eq!(&tls.scratch as *const _ as usize - base, TLS_SCRATCH);
+    test_assert_eq!(&tls.canary as *const _ as usize - base, TLS_CANARY);
+    test_assert!(tls.intact());
+}
@@ -0,0 +1,145 @@
+//! fixed size ring of recent kernel events: context switches, faults and
+//! syscalls. recording never allocates nor waits, so it is safe in irq and
+//! exception handlers; an event is dropped if the ring is busy.
+//! sys_klog formats the ring as text for userland.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use ::kern::arch::cpu;
+use ::kern::task::ProcId;
+
+const NR_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// a: previous pid, b: next pid
+    Switch,
+    /// a: fault address, b: pid
+    PageFault,
+    /// a: fault address, b: pid, resolved by demand paging
+    DemandPage,
+    /// a: syscall number, b: pid
+    Syscall,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub tsc: u64,
+    pub kind: EventKind,
+    pub a: usize,
+    pub b: usize,
+}
+
+const EMPTY: Event = Event { tsc: 0, kind: EventKind::Switch, a: 0, b: 0 };
+
+struct Ring {
+    events: [Event; NR_EVENTS],
+    // total events ever recorded
+    count: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { events: [EMPTY; NR_EVENTS], count: 0 });
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn record(kind: EventKind, a: usize, b: usize) {
+    match RING.try_lock() {
+        Some(mut ring) => {
+            let i = ring.count % NR_EVENTS;
+            ring.events[i] = Event { tsc: cpu::rdtsc(), kind: kind, a: a, b: b };
+            ring.count += 1;
+        },
+        None => { DROPPED.fetch_add(1, Ordering::Relaxed); }
+    }
+}
+
+pub fn switch(prev: ProcId, next: ProcId) {
+    record(EventKind::Switch, prev as usize, next as usize);
+}
+
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            EventKind::Switch => write!(f, "{:>16} switch {} -> {}", self.tsc, self.a as ProcId, self.b as ProcId),
+            EventKind::PageFault => write!(f, "{:>16} fault {:#x} pid {}", self.tsc, self.a, self.b as ProcId),
+            EventKind::DemandPage => write!(f, "{:>16} demand page {:#x} pid {}", self.tsc, self.a, self.b as ProcId),
+            EventKind::Syscall => write!(f, "{:>16} syscall {} pid {}", self.tsc, self.a, self.b as ProcId),
+        }
+    }
+}
+
+/// fmt::Write into a byte slice, whole lines only so output never ends
+/// in the middle of an event
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// format newest events that fit into buf as text lines, oldest first.
+/// return number of bytes written. events recorded meanwhile are dropped,
+/// the ring is too large to snapshot on a kernel stack.
+pub fn format(buf: &mut [u8]) -> usize {
+    let ring = RING.lock();
+    let (events, count) = (&ring.events, ring.count);
+    let n = ::core::cmp::min(count, NR_EVENTS);
+
+    // walk backwards to find how many newest lines fit
+    let mut first = count;
+    let mut total = 0;
+    let mut scratch = [0u8; 128];
+    while first > count - n {
+        let ev = &events[(first - 1) % NR_EVENTS];
+        let mut w = SliceWriter { buf: &mut scratch, len: 0 };
+        if writeln!(w, "{}", ev).is_err() || total + w.len > buf.len() {
+            break;
+        }
+        total += w.len;
+        first -= 1;
+    }
+
+    let mut w = SliceWriter { buf: buf, len: 0 };
+    for seq in first..count {
+        let _ = writeln!(w, "{}", events[seq % NR_EVENTS]);
+    }
+    w.len
+}
+
+pub fn test_trace_format() {
+    // no switch may sneak in as newest event
+    let oflags = unsafe { cpu::push_flags() };
+    for i in 0..NR_EVENTS + 10 {
+        record(EventKind::Syscall, i, 1);
+    }
+
+    let mut small = [0u8; 100];
+    let n = format(&mut small);
+    unsafe { cpu::pop_flags(oflags); }
+    test_assert!(n > 0 && n <= small.len());
+    if n == 0 || n > small.len() {
+        return;
+    }
+    test_assert_eq!(small[n - 1], b'\n');
+    // newest event is kept when space is short
+    let last = ::core::str::from_utf8(&small[..n]).unwrap().lines().last().unwrap();
+    test_assert!(last.ends_with("syscall 265 pid 1"));
+
+    let mut empty = [0u8; 0];
+    test_assert_eq!(format(&mut empty), 0);
+}
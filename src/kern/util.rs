@@ -13,3 +13,45 @@ pub fn cpu_relax() {
 pub fn cpu_relax() {
 }
 
+
+/// xorshift64* pseudo random generator, deterministic for a given seed.
+/// not suitable for anything security related.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // zero state would stay zero forever
+        Rng { state: if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed } }
+    }
+
+    /// seeded from time stamp counter
+    pub fn from_tsc() -> Rng {
+        Rng::new(::kern::arch::cpu::rdtsc())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// uniform-ish value in [min, max)
+    pub fn range(&mut self, min: u64, max: u64) -> u64 {
+        assert!(min < max, "Rng::range: empty range");
+        min + self.next_u64() % (max - min)
+    }
+
+    /// fisher-yates shuffle
+    pub fn shuffle<T>(&mut self, v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            let j = self.range(0, i as u64 + 1) as usize;
+            v.swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use multiboot2::BootInformation;
+use ::kern::arch::cpu;
+use ::kern::memory::frame;
+use ::kern::console as con;
+use con::LogLevel::*;
+
+pub const NAME: &'static str = "SOS2";
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// build timestamp exported by Makefile, not every build goes through it though
+pub fn build_time() -> &'static str {
+    option_env!("SOS2_BUILD_TIME").unwrap_or("unknown")
+}
+
+/// print boot banner with detected capabilities
+pub fn print_banner(mbinfo: &BootInformation) {
+    let vendor = cpu::vendor();
+    let vendor = ::core::str::from_utf8(&vendor).unwrap_or("unknown");
+    let mem = frame::total_memory(mbinfo);
+
+    printk!(Info, "==================================================\n\r");
+    printk!(Info, "  {} v{} (built {})\n\r", NAME, VERSION, build_time());
+    printk!(Info, "  cpu: {}, memory: {}MB ({:#x} bytes)\n\r", vendor, mem >> 20, mem);
+    printk!(Info, "==================================================\n\r");
+}
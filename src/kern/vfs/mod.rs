@@ -1,32 +1,215 @@
-pub type NodeId = usize;
-pub const ROOT_ID: NodeId = 1;
+pub mod ramfs;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum NodeType {
-    Dir,
-    File,
-    SymLink,
+use core::fmt;
+use core::cmp::min;
+use spin::Once;
+
+use ::kern::task::Task;
+use ::kern::syscall::{EBADF, EISDIR, EMFILE, EINVAL, EFBIG};
+use ::kern::console::LogLevel::*;
+use self::ramfs::{Vnode, VnodeRef};
+
+/// Ok or -errno, ready to be returned from syscalls
+pub type Result<T> = ::core::result::Result<T, isize>;
+
+pub type Fd = usize;
+
+/// fd 0, 1 and 2 are served by keyboard and console, never by vfs
+pub const FIRST_FD: Fd = 3;
+/// size limit of fd table of a task
+pub const MAX_FILES: usize = 32;
+/// size limit of a file, ramfs lives on kernel heap
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// access mode of open(), low two bits of flags like posix
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+
+/// file opened by a task, with its own offset
+#[derive(Clone)]
+pub struct OpenFile {
+    node: VnodeRef,
+    offset: usize,
+    readable: bool,
+    writable: bool,
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpenFile {{ offset: {} readable: {} writable: {} }}",
+               self.offset, self.readable, self.writable)
+    }
+}
+
+impl OpenFile {
+    /// read from offset on and advance it, 0 at end of file
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.readable {
+            return Err(-EBADF);
+        }
+        let n = match *self.node.read() {
+            Vnode::File { ref data } => {
+                let start = min(self.offset, data.len());
+                let n = min(buf.len(), data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                n
+            },
+            Vnode::Dir { .. } => return Err(-EISDIR)
+        };
+        self.offset += n;
+        Ok(n)
+    }
+
+    /// write at offset and advance it, file grows as needed and a gap left
+    /// by offset beyond the end reads as zeros. -EFBIG if file would grow
+    /// past MAX_FILE_SIZE.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.writable {
+            return Err(-EBADF);
+        }
+        let end = match self.offset.checked_add(buf.len()) {
+            Some(end) if end <= MAX_FILE_SIZE => end,
+            _ => return Err(-EFBIG)
+        };
+        match *self.node.write() {
+            Vnode::File { ref mut data } => {
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[self.offset..end].copy_from_slice(buf);
+            },
+            Vnode::Dir { .. } => return Err(-EISDIR)
+        }
+        self.offset += buf.len();
+        Ok(buf.len())
+    }
+}
+
+static ROOT: Once<VnodeRef> = Once::new();
+
+/// root of ramfs mounted at /
+pub fn root() -> &'static VnodeRef {
+    ROOT.call_once(|| {
+        let root = Vnode::new_dir();
+        let motd = format!("welcome to {} v{}\n", ::kern::version::NAME, ::kern::version::VERSION);
+        ramfs::insert(&root, "/etc", Vnode::new_dir()).expect("vfs: mkdir /etc");
+        ramfs::insert(&root, "/etc/motd", Vnode::new_file(motd.as_bytes())).expect("vfs: seed /etc/motd");
+        ramfs::insert(&root, "/etc/hostname", Vnode::new_file(b"sos2\n")).expect("vfs: seed /etc/hostname");
+        root
+    })
 }
 
-// mapping from disk file
-pub struct Node {
-    typ: NodeType,
-    ino: NodeId,
-    size: u64
+/// mount ramfs with its seed files, needs kernel heap
+pub fn init() {
+    root();
+    printk!(Info, "vfs: ramfs mounted at /\n\r");
 }
 
-pub enum FileType {
-    Null, // unknown
-    Node,
-    Pipe
+/// open regular file at path with access mode in flags (O_RDONLY, O_WRONLY
+/// or O_RDWR), return lowest free fd of task
+pub fn open(task: &mut Task, path: &str, flags: usize) -> Result<Fd> {
+    let (readable, writable) = match flags {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(-EINVAL)
+    };
+    let node = ramfs::resolve(root(), path)?;
+    if node.read().is_dir() {
+        return Err(-EISDIR);
+    }
+
+    while task.files.len() < FIRST_FD {
+        task.files.push(None);
+    }
+    let free = task.files.iter().skip(FIRST_FD).position(|f| f.is_none());
+    let fd = match free {
+        Some(i) => FIRST_FD + i,
+        None if task.files.len() < MAX_FILES => {
+            task.files.push(None);
+            task.files.len() - 1
+        },
+        None => return Err(-EMFILE)
+    };
+
+    task.files[fd] = Some(OpenFile { node: node, offset: 0, readable: readable, writable: writable });
+    Ok(fd)
+}
+
+fn file_of(task: &mut Task, fd: Fd) -> Result<&mut OpenFile> {
+    if fd < FIRST_FD {
+        return Err(-EBADF);
+    }
+    match task.files.get_mut(fd) {
+        Some(&mut Some(ref mut file)) => Ok(file),
+        _ => Err(-EBADF)
+    }
+}
+
+pub fn read(task: &mut Task, fd: Fd, buf: &mut [u8]) -> Result<usize> {
+    file_of(task, fd)?.read(buf)
+}
+
+pub fn write(task: &mut Task, fd: Fd, buf: &[u8]) -> Result<usize> {
+    file_of(task, fd)?.write(buf)
 }
 
-// opened file 
-pub trait File {
-    fn get_node(&self) -> &Node;
+pub fn close(task: &mut Task, fd: Fd) -> Result<()> {
+    file_of(task, fd)?;
+    task.files[fd] = None;
+    Ok(())
 }
 
-pub trait FileSystem {
+pub fn test_vfs_fds() {
+    use ::kern::syscall::ENOENT;
+
+    let mut task = Task::empty();
+    test_assert_eq!(open(&mut task, "/nope", O_RDONLY), Err(-ENOENT));
+    test_assert_eq!(open(&mut task, "/etc", O_RDONLY), Err(-EISDIR));
+
+    let a = open(&mut task, "/etc/hostname", O_RDONLY);
+    let b = open(&mut task, "/etc/hostname", O_RDONLY);
+    test_assert_eq!((a, b), (Ok(FIRST_FD), Ok(FIRST_FD + 1)));
+
+    // each fd keeps its own offset
+    let mut buf = [0u8; 3];
+    test_assert_eq!(read(&mut task, FIRST_FD, &mut buf), Ok(3));
+    test_assert_eq!(&buf, b"sos");
+    test_assert_eq!(read(&mut task, FIRST_FD, &mut buf), Ok(2));
+    test_assert_eq!(&buf[..2], b"2\n");
+    test_assert_eq!(read(&mut task, FIRST_FD, &mut buf), Ok(0));
+    test_assert_eq!(read(&mut task, FIRST_FD + 1, &mut buf), Ok(3));
+
+    test_assert_eq!(close(&mut task, FIRST_FD), Ok(()));
+    test_assert_eq!(close(&mut task, FIRST_FD), Err(-EBADF));
+    test_assert_eq!(read(&mut task, FIRST_FD, &mut buf), Err(-EBADF));
+    test_assert_eq!(read(&mut task, 1, &mut buf), Err(-EBADF));
+    // lowest free fd is reused
+    test_assert_eq!(open(&mut task, "/etc/motd", O_RDONLY), Ok(FIRST_FD));
+
+    while task.files.len() < MAX_FILES {
+        test_assert!(open(&mut task, "/etc/motd", O_RDONLY).is_ok());
+    }
+    test_assert_eq!(open(&mut task, "/etc/motd", O_RDONLY), Err(-EMFILE));
+    test_assert_eq!(open(&mut task, "/etc/motd", 3), Err(-EINVAL));
+    test_assert_eq!(write(&mut task, FIRST_FD, b"x"), Err(-EBADF));
+
+    // writing past the end leaves a zero filled gap
+    let mut file = OpenFile { node: Vnode::new_file(b"ab"), offset: 3, readable: true, writable: true };
+    test_assert_eq!(file.write(b"cd"), Ok(2));
+    file.offset = 0;
+    let mut buf = [0xffu8; 8];
+    test_assert_eq!(file.read(&mut buf), Ok(5));
+    test_assert_eq!(&buf[..5], b"ab\0cd");
+    file.readable = false;
+    test_assert_eq!(file.read(&mut buf), Err(-EBADF));
 
+    // growing past the size limit fails and leaves the file alone
+    file.offset = MAX_FILE_SIZE - 1;
+    test_assert_eq!(file.write(b"xy"), Err(-EFBIG));
+    test_assert_eq!(file.write(b"x"), Ok(1));
+    test_assert_eq!(file.write(b"y"), Err(-EFBIG));
+    file.offset = !0;
+    test_assert_eq!(file.write(b"x"), Err(-EFBIG));
 }
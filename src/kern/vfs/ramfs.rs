@@ -0,0 +1,106 @@
+//! in-memory filesystem, a tree of vnodes which lives as long as the kernel.
+//! paths are absolute and '/' separated, empty components are skipped.
+
+use collections::{BTreeMap, String, Vec};
+use collections::string::ToString;
+use alloc::arc::Arc;
+use spin::RwLock;
+
+use ::kern::syscall::{ENOENT, ENOTDIR, EEXIST, EINVAL};
+use super::Result;
+
+pub type VnodeRef = Arc<RwLock<Vnode>>;
+
+pub enum Vnode {
+    File { data: Vec<u8> },
+    Dir { children: BTreeMap<String, VnodeRef> },
+}
+
+impl Vnode {
+    pub fn new_dir() -> VnodeRef {
+        Arc::new(RwLock::new(Vnode::Dir { children: BTreeMap::new() }))
+    }
+
+    pub fn new_file(data: &[u8]) -> VnodeRef {
+        let mut v = Vec::with_capacity(data.len());
+        v.extend_from_slice(data);
+        Arc::new(RwLock::new(Vnode::File { data: v }))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        match *self {
+            Vnode::Dir { .. } => true,
+            Vnode::File { .. } => false
+        }
+    }
+}
+
+/// (parent path, last component) of path, None if path names root
+fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_right_matches('/');
+    match path.rfind('/') {
+        Some(i) => Some((&path[..i], &path[i+1..])),
+        None if path.is_empty() => None,
+        None => Some(("", path))
+    }
+}
+
+/// child name of dir
+pub fn lookup(dir: &VnodeRef, name: &str) -> Result<VnodeRef> {
+    match *dir.read() {
+        Vnode::Dir { ref children } => children.get(name).cloned().ok_or(-ENOENT),
+        Vnode::File { .. } => Err(-ENOTDIR)
+    }
+}
+
+/// walk path down from root
+pub fn resolve(root: &VnodeRef, path: &str) -> Result<VnodeRef> {
+    let mut node = root.clone();
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        node = lookup(&node, name)?;
+    }
+    Ok(node)
+}
+
+/// add node as path, parent directory must exist
+pub fn insert(root: &VnodeRef, path: &str, node: VnodeRef) -> Result<()> {
+    let (parent, name) = match split_last(path) {
+        Some(v) => v,
+        None => return Err(-EINVAL)
+    };
+    let parent = resolve(root, parent)?;
+    let mut parent = parent.write();
+    match *parent {
+        Vnode::Dir { ref mut children } => {
+            if children.contains_key(name) {
+                return Err(-EEXIST);
+            }
+            children.insert(name.to_string(), node);
+            Ok(())
+        },
+        Vnode::File { .. } => Err(-ENOTDIR)
+    }
+}
+
+pub fn test_ramfs() {
+    let root = Vnode::new_dir();
+    test_assert!(insert(&root, "/etc", Vnode::new_dir()).is_ok());
+    test_assert!(insert(&root, "/etc/motd", Vnode::new_file(b"hi")).is_ok());
+    test_assert_eq!(insert(&root, "/etc/motd", Vnode::new_file(b"")).err(), Some(-EEXIST));
+    test_assert_eq!(insert(&root, "/etc/motd/x", Vnode::new_file(b"")).err(), Some(-ENOTDIR));
+    test_assert_eq!(insert(&root, "/", Vnode::new_dir()).err(), Some(-EINVAL));
+
+    let is_dir = |path: &str| resolve(&root, path).map(|n| { let d = n.read().is_dir(); d }).unwrap_or(false);
+    test_assert!(is_dir("/"));
+    test_assert!(is_dir("//etc/"));
+    test_assert!(!is_dir("/etc/motd"));
+    test_assert_eq!(resolve(&root, "/etc/nope").err(), Some(-ENOENT));
+    test_assert_eq!(resolve(&root, "/etc/motd/x").err(), Some(-ENOTDIR));
+
+    let motd = resolve(&root, "etc/motd").unwrap();
+    let hit = match *motd.read() {
+        Vnode::File { ref data } => &data[..] == b"hi",
+        Vnode::Dir { .. } => false
+    };
+    test_assert!(hit);
+}
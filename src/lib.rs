@@ -38,7 +38,7 @@ use kern::driver::serial;
 use kern::memory;
 use kern::interrupts;
 use kheap_allocator as kheap;
-use kern::driver::video::{Framebuffer, Point, Rgba};
+use kern::driver::video::{Framebuffer, DisplayList, Point, Rect, Rgba, colors};
 use kern::task;
 use kern::syscall;
 
@@ -56,58 +56,100 @@ fn busy_wait () {
 fn display(fb: &mut Framebuffer) {
     let w = fb.width as i32;
     let h = fb.height as i32;
+    let silver = Rgba::new(0xeeeeee).with_alpha(0xee);
+    fb.with_double_buffer(true);
     for g in 0..1 {
-        fb.fill_rect_grad(Point{x:0, y: 0}, w, h, Rgba(0x0000ff00), Rgba(255<<16));
+        let mut dl = DisplayList::new();
+        dl.fill_rect_grad(Point::new(0, 0), w, h, colors::GREEN, colors::RED);
 
-        fb.draw_line(Point{x: 530, y: 120}, Point{x: 330, y: 10}, Rgba(0xeeeeeeee));
-        fb.draw_line(Point{x: 330, y: 120}, Point{x: 530, y: 10}, Rgba(0xeeeeeeee));
-
-        fb.draw_line(Point{x: 300, y: 10}, Point{x: 500, y: 100}, Rgba(0xeeeeeeee));
-        fb.draw_line(Point{x: 300, y: 10}, Point{x: 400, y: 220}, Rgba(0xeeeeeeee));
-
-        fb.draw_line(Point{x: 100, y: 220}, Point{x: 300, y: 100}, Rgba(0xeeeeeeee));
-        fb.draw_line(Point{x: 100, y: 220}, Point{x: 300, y: 10}, Rgba(0xeeeeeeee));
+        dl.line(Point::new(530, 120), Point::new(330, 10), silver)
+          .line(Point::new(330, 120), Point::new(530, 10), silver)
+          .line(Point::new(300, 10), Point::new(500, 100), silver)
+          .line(Point::new(300, 10), Point::new(400, 220), silver)
+          .line(Point::new(100, 220), Point::new(300, 100), silver)
+          .line(Point::new(100, 220), Point::new(300, 10), silver);
 
         for r in (100..150).filter(|x| x % 5 == 0) {
-            fb.draw_circle(Point{x: 200, y: 200}, r, Rgba::from(0, g as u8, 0xff));
+            dl.circle(Point::new(200, 200), r, Rgba::from(0, g as u8, 0xff));
         }
+        dl.spread_circle(Point::new(400, 100), 90, Rgba::from(0, g as u8, 0xee));
+
+        dl.rect(Point::new(199, 199), 202, 102, Rgba::from(0x00, g as u8, 0xff))
+          .fill_rect(Point::new(200, 200), 200, 100, Rgba::from(0x80, g as u8, 0x80))
+          .rect(Point::new(199, 309), 302, 102, Rgba::from(0x00, g as u8, 0xff))
+          .fill_rect(Point::new(200, 310), 300, 100, Rgba::from(0xa0, g as u8, 0x80))
+          .rect(Point::new(199, 419), 392, 102, Rgba::from(0x00, g as u8, 0xff))
+          .fill_rect(Point::new(200, 420), 390, 100, Rgba::from(0xe0, g as u8, 0x80));
+
+        dl.text(Point::new(300, 550), b"A", colors::BLUE, colors::RED)
+          .text(Point::new(40, 550), b"Loading SOS...", colors::BLUE, colors::RED)
+          .blit(Point::new(200, 100), Point::new(40, 550),  200, 20)
+          .blit(Point::new(150, 150), Point::new(50, 50), 350, 350);
+
+        let dirty = fb.execute(&dl);
+        printk!(Debug, "loop {}: {} cmds, dirty {:?}\n\r", g, dl.len(), dirty);
+    }
+    fb.with_double_buffer(false);
+}
 
-        fb.spread_circle(Point{x: 400, y: 100}, 90, Rgba::from(0, g as u8, 0xee));
-
-        fb.draw_rect(Point{x:199, y: 199}, 202, 102, Rgba::from(0x00, g as u8, 0xff));
-        fb.fill_rect(Point{x:200, y: 200}, 200, 100, Rgba::from(0x80, g as u8, 0x80));
-
-        fb.draw_rect(Point{x:199, y: 309}, 302, 102, Rgba::from(0x00, g as u8, 0xff));
-        fb.fill_rect(Point{x:200, y: 310}, 300, 100, Rgba::from(0xa0, g as u8, 0x80));
-
-        fb.draw_rect(Point{x:199, y: 419}, 392, 102, Rgba::from(0x00, g as u8, 0xff));
-        fb.fill_rect(Point{x:200, y: 420}, 390, 100, Rgba::from(0xe0, g as u8, 0x80));
-
-        fb.draw_char(Point{x:300, y: 550}, b'A', Rgba(0x000000ff), Rgba(0x00ff0000));
-        fb.draw_str(Point{x:40, y: 550}, b"Loading SOS...", Rgba(0x000000ff), Rgba(0x00ff0000));
-        fb.blit_copy(Point{x: 200, y: 100}, Point{x: 40, y: 550},  200, 20);
-        fb.blit_copy(Point{x: 150, y: 150}, Point{x: 50, y: 50}, 350, 350);
+/// compare fill strategies, run with feature prof
+#[cfg(feature = "prof")]
+fn bench_framebuffer(fb: &mut Framebuffer) {
+    use kern::driver::video::framebuffer::FillMethod;
 
-        printk!(Debug, "loop {}\n\r", g);
+    let rect = Rect {top_left: Point::new(0, 0), width: fb.width, height: fb.height};
+    for &method in [FillMethod::PerPixel, FillMethod::RowCopy, FillMethod::Words, FillMethod::RepStos].iter() {
+        let cycles = fb.bench_fill(rect, colors::BLACK, 10, method);
+        printk!(Info, "bench_fill {:?} {}x{}: {} cycles/fill\n\r",
+            method, rect.width, rect.height, cycles / 10);
     }
 }
 
+#[cfg(not(feature = "prof"))]
+fn bench_framebuffer(_fb: &mut Framebuffer) {}
+
+/// same workload each round, memory freed by a round must be reused by
+/// the next one, so heap watermark stays where the first round left it
 fn test_kheap_allocator() {
-    for _ in 0..10 {
-        let mut v = vec![1,2,3,4];
-        let b = alloc::boxed::Box::new(0xcafe);
-        printk!(Debug, "v = {:?}, b = {:?}\n\r", v, b);
-        let vs = vec!["Loading", "SOS2", "\n\r"];
-        for s in vs {
-            printk!(Debug, "{} ", s);
+    let mut watermark = 0;
+    for round in 0..10 {
+        {
+            let mut v = vec![1,2,3,4];
+            let b = alloc::boxed::Box::new(0xcafe);
+            printk!(Debug, "v = {:?}, b = {:?}\n\r", v, b);
+            let vs = vec!["Loading", "SOS2", "\n\r"];
+            for s in vs {
+                log!("{} ", s);
+            }
+
+            for i in 1..0x1000 * 40 {
+                v.push(i);
+            }
         }
 
-        for i in 1..0x1000 * 40 {
-            v.push(i);
+        if round == 0 {
+            watermark = kheap::peak();
         }
+        test_assert!(kheap::peak() <= watermark,
+            "kheap watermark grows: {:#x} -> {:#x} at round {}", watermark, kheap::peak(), round);
     }
 
-    loop {}
+    // a bump allocator would pass the watermark checks as well. with irqs
+    // off nothing else allocates in between, so the freed block is the
+    // first fit again.
+    let oflags = unsafe { kern::arch::cpu::push_flags() };
+    let first = {
+        let v: collections::Vec<u8> = collections::Vec::with_capacity(0x10000);
+        v.as_ptr() as usize
+    };
+    let again = {
+        let v: collections::Vec<u8> = collections::Vec::with_capacity(0x10000);
+        v.as_ptr() as usize
+    };
+    unsafe { kern::arch::cpu::pop_flags(oflags); }
+    test_assert_eq!(first, again);
+
+    memory::log_stats(Info);
 }
 
 extern {
@@ -124,9 +166,10 @@ pub extern fn kernel_main(mb2_header: usize) {
     }
 
     con::clear();
-    printk!(Info, "Loading SOS2....\n\r");
-
     let mbinfo = unsafe { multiboot2::load(mb2_header) };
+    // no heap until memory::init, see kern::early for what is usable before
+    kern::early::init(mbinfo);
+    kern::version::print_banner(mbinfo);
     printk!(Info, "{:#?}\n\r", mbinfo);
 
     let (pa, pe, sp_top) = unsafe {
@@ -136,13 +179,26 @@ pub extern fn kernel_main(mb2_header: usize) {
 
     let fb = mbinfo.framebuffer_tag().expect("framebuffer tag is unavailale");
     let mm = memory::init(mbinfo);
-
-    //if cfg!(feature = "test") { test_kheap_allocator(); }
+    kern::modules::init(mbinfo);
+    kern::vfs::init();
+    kern::acpi::init();
+    kern::fbdev::init(&fb);
 
     {
         let mut mm = mm.lock();
         interrupts::init(&mut mm);
-        if cfg!(feature = "test") { interrupts::test_idt(); }
+        if cfg!(feature = "test") {
+            interrupts::test_idt();
+        }
+    }
+    // calibrates against PIT, so after interrupts are on
+    kern::apic::init();
+
+    if cfg!(feature = "test") {
+        match kern::selftest::selected(kern::early::cmdline()) {
+            Some(name) => kern::selftest::run_one(name),
+            None => kern::selftest::run(kern::selftest::seed())
+        }
     }
 
     if fb.frame_type == multiboot2::FramebufferType::Rgb {
@@ -151,13 +207,16 @@ pub extern fn kernel_main(mb2_header: usize) {
         let oflags = unsafe { cpu::push_flags() };
         let mut fb = Framebuffer::new(&fb);
         //if cfg!(feature = "test") { display(&mut fb); }
+        if cfg!(feature = "prof") { bench_framebuffer(&mut fb); }
 
-        {
-            let mut term = con::tty1.lock();
-            *term = Console::new_with_fb(fb);
+        unsafe { kern::driver::video::panic_screen::register(&fb); }
+        match Console::new_with_fb(fb) {
+            Some(fbcon) => *con::tty1.lock() = fbcon,
+            None => printk!(Warn, "framebuffer too small for console, keep text mode\n\r")
         }
 
         con::clear();
+        kern::version::print_banner(mbinfo);
         println!("framebuffer console init.\n\r");
         //if cfg!(feature = "test") { for b in 1..127u8 { print!("{}", b as char); } }
         unsafe { cpu::pop_flags(oflags); }
@@ -170,8 +229,7 @@ pub extern fn kernel_main(mb2_header: usize) {
     }
 }
 
-/// Get a stack trace
-/// TODO: extract symbol names from kernel elf
+/// Get a stack trace, symbols are resolved from kernel elf module if loaded
 unsafe fn stack_trace() {
     use core::mem;
     let mut rbp: usize;
@@ -189,7 +247,10 @@ unsafe fn stack_trace() {
                     println!(" {:>016x}: EMPTY RETURN", rbp);
                     break;
                 }
-                println!("  {:>016x}: ret rip {:>016x}", rbp, rip);
+                match kern::kdbg::resolve(rip) {
+                    Some((name, off)) => println!("  {:>016x}: ret rip {:>016x} {}+{:#x}", rbp, rip, name, off),
+                    None => println!("  {:>016x}: ret rip {:>016x}", rbp, rip),
+                }
                 rbp = *(rbp as *const usize);
             } else {
                 println!("  {:>016x}: Invalid", rbp);
@@ -206,10 +267,25 @@ extern fn eh_personality() {}
 
 #[lang = "panic_fmt"] 
 #[no_mangle] pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
-	printk!(Critical, "\n\rPanic at {}:{}\n\r", file, line);
-    printk!(Critical, "    {}\n\r", fmt);
+    kern::logger::flush_on_panic();
+    // console may be the one who panics, avoid it when framebuffer is there
+    let on_screen = kern::driver::video::panic_screen::show("KERNEL PANIC", fmt, Some((file, line)), None);
+    if !on_screen {
+        printk!(Critical, "\n\rPanic at {}:{}\n\r", file, line);
+        printk!(Critical, "    {}\n\r", fmt);
+
+        unsafe { stack_trace(); }
+    }
 
-    unsafe { stack_trace(); }
+    if kern::selftest::panic_exits() {
+        use core::fmt::Write;
+        // CI only reads serial, which panic screen does not write to
+        if on_screen {
+            let _ = write!(serial::EmergencyWriter, "\n\rPanic at {}:{}\n\r    {}\n\r", file, line, fmt);
+        }
+        unsafe { serial::drain(); }
+        kern::arch::cpu::qemu_exit(kern::selftest::EXIT_PANIC);
+    }
 
     loop {
         unsafe { asm!("hlt":::: "volatile"); }
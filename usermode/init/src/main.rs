@@ -5,41 +5,90 @@
 
 extern crate libsos2;
 
-pub fn test() {
-    let mut a0 = 1;
-    let mut a1 = 2;
-    let mut a2 = 3;
-    let mut a3 = 4;
-    let mut a4 = 5;
-    let mut a5 = 6;
-    let buf = b"userspace";
+const SYS_READ: usize = 5;
+const SYS_OPEN: usize = 15;
+const SYS_WRITE: usize = 16;
+const SYS_CLOSE: usize = 21;
+const SYS_GETPID: usize = 11;
+const SYS_GETPPID: usize = 24;
+
+/// rcx and r11 are clobbered by syscall/sysret
+unsafe fn syscall(nr: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    asm!("
+        pushq %rcx
+        pushq %r11
+         syscall
+         popq %r11
+         popq %rcx"
+         :"={rax}"(ret)
+         :"{rax}"(nr),
+         "{rdi}"(a0),
+         "{rsi}"(a1),
+         "{rdx}"(a2)
+         :"rcx", "r11", "memory"
+         :"volatile"
+         );
+    ret
+}
 
+/// decimal digits of n written at the end of buf, return start of them
+fn fmt_num(buf: &mut [u8], mut n: isize) -> usize {
+    let neg = n < 0;
+    let mut i = buf.len();
     loop {
-        unsafe {
-            asm!("
-                pushq %rcx
-                pushq %r11
-                 syscall
-                 popq %r11
-                 popq %rcx"
-                 :
-                 :"{rax}"(16), // write is 16
-                 "{rdi}"(a0),
-                 "{rsi}"(buf as *const _ as usize),
-                 "{rdx}"(9),
-                 "{r8}"(a3),
-                 "{r9}"(a4),
-                 "{r10}"(a5)
-                 :"rcx", "r11"
-                 :"volatile"
-                 ); 
-        }
-        a0 += 1;
-        a1 += 1;
-        a2 += 1;
-        a3 += 1;
-        a4 += 1;
-        a5 += 1;
+        i -= 1;
+        buf[i] = b'0' + (n % 10).abs() as u8;
+        n /= 10;
+        if n == 0 { break; }
+    }
+    if neg {
+        i -= 1;
+        buf[i] = b'-';
+    }
+    i
+}
+
+fn write(msg: &[u8]) {
+    unsafe { syscall(SYS_WRITE, 1, msg.as_ptr() as usize, msg.len()); }
+}
+
+/// append s to buf at len, return new len
+fn append(buf: &mut [u8], len: usize, s: &[u8]) -> usize {
+    buf[len..len + s.len()].copy_from_slice(s);
+    len + s.len()
+}
+
+/// echo a file of kernel ramfs to console
+fn cat(path: &[u8]) {
+    let fd = unsafe { syscall(SYS_OPEN, path.as_ptr() as usize, 0, 0) };
+    if fd < 0 {
+        return;
+    }
+
+    let mut buf = [0u8; 64];
+    loop {
+        let n = unsafe { syscall(SYS_READ, fd as usize, buf.as_mut_ptr() as usize, buf.len()) };
+        if n <= 0 { break; }
+        write(&buf[..n as usize]);
+    }
+    unsafe { syscall(SYS_CLOSE, fd as usize, 0, 0); }
+}
+
+pub fn test() {
+    loop {
+        // ask every round, answers must not change across context switches
+        let (pid, ppid) = unsafe { (syscall(SYS_GETPID, 0, 0, 0), syscall(SYS_GETPPID, 0, 0, 0)) };
+
+        let mut num = [0u8; 24];
+        let mut msg = [0u8; 80];
+        let mut len = append(&mut msg, 0, b"userspace pid ");
+        let start = fmt_num(&mut num, pid);
+        len = append(&mut msg, len, &num[start..]);
+        len = append(&mut msg, len, b" ppid ");
+        let start = fmt_num(&mut num, ppid);
+        len = append(&mut msg, len, &num[start..]);
+        write(&msg[..len]);
 
         let mut i = 1;
         while i < 10000 {
@@ -54,6 +103,7 @@ pub fn test() {
 #[no_mangle]
 #[start]
 pub fn start(_argc: isize, _argv: *const *const u8) -> isize {
+    cat(b"/etc/motd\0");
     test();
     0
 }